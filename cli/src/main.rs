@@ -0,0 +1,335 @@
+use std::{error::Error, path::PathBuf, process::ExitCode};
+
+use clap::{Args, Parser, Subcommand};
+use dicom::{core::dictionary::DataDictionary, dictionary_std::StandardDataDictionary};
+use dicom_object::Tag;
+use dicomweb_client::{DicomWebClient, QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery};
+
+mod output;
+mod retrieve;
+mod store;
+
+use output::Format;
+
+/// Query, retrieve and upload DICOM data from a DICOMweb server
+#[derive(Parser, Debug)]
+#[command(name = "dicomweb", version)]
+struct Cli {
+    /// Base URL of the DICOMweb service
+    #[arg(
+        long,
+        env = "DICOMWEB_URL",
+        default_value = "http://localhost:8080",
+        global = true
+    )]
+    url: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Search for studies, series or instances (QIDO-RS)
+    #[command(subcommand)]
+    Search(SearchLevel),
+    /// Download instances into a folder (WADO-RS)
+    #[command(subcommand)]
+    Retrieve(RetrieveLevel),
+    /// Upload DICOM files, folders are searched recursively (STOW-RS)
+    Store {
+        /// Files or folders to upload
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Only accept instances of this study
+        #[arg(long)]
+        study: Option<String>,
+        /// Number of instances sent per request
+        #[arg(long, default_value_t = 50)]
+        batch_size: usize,
+    },
+    /// Show the metadata of a study, series or instance (WADO-RS)
+    Metadata {
+        study: String,
+        series: Option<String>,
+        /// Requires the series
+        #[arg(requires = "series")]
+        instance: Option<String>,
+        #[arg(long, value_enum, default_value_t = Format::Table)]
+        format: Format,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SearchLevel {
+    Studies(SearchArgs),
+    Series {
+        /// Only search within this study
+        #[arg(long)]
+        study: Option<String>,
+        #[command(flatten)]
+        args: SearchArgs,
+    },
+    Instances {
+        /// Only search within this study
+        #[arg(long)]
+        study: Option<String>,
+        /// Only search within this series, requires the study
+        #[arg(long, requires = "study")]
+        series: Option<String>,
+        #[command(flatten)]
+        args: SearchArgs,
+    },
+}
+
+#[derive(Args, Debug)]
+struct SearchArgs {
+    #[arg(long)]
+    limit: Option<usize>,
+    #[arg(long)]
+    offset: Option<usize>,
+    /// Additional attributes to return, e.g. --includefield=StudyDescription
+    #[arg(long = "includefield")]
+    includefields: Vec<String>,
+    #[arg(long, value_enum, default_value_t = Format::Table)]
+    format: Format,
+    /// Matching attributes, either by keyword or tag. E.g. --PatientID=12345 or --00100020=12345.
+    /// Have to follow all other options
+    #[arg(
+        value_name = "FILTERS",
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    filters: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum RetrieveLevel {
+    Study {
+        study: String,
+        /// Folder the instances are written to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+    Series {
+        study: String,
+        series: String,
+        /// Folder the instances are written to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+    Instance {
+        study: String,
+        series: String,
+        instance: String,
+        /// Folder the instance is written to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+}
+
+/// Parse filters of the form "--Keyword=value" or "--Keyword value"
+fn parse_filters(filters: &[String]) -> Result<Vec<(Tag, String)>, String> {
+    let mut matches = Vec::new();
+    let mut filters = filters.iter();
+    while let Some(filter) = filters.next() {
+        let Some(filter) = filter.strip_prefix("--") else {
+            return Err(format!(
+                "Invalid filter {}, expected --Keyword=value",
+                filter
+            ));
+        };
+        let (name, value) = match filter.split_once('=') {
+            Some((name, value)) => (name, value.to_string()),
+            None => match filters.next() {
+                Some(value) => (filter, value.clone()),
+                None => return Err(format!("Missing value for filter {}", filter)),
+            },
+        };
+        matches.push((parse_tag(name)?, value));
+    }
+    Ok(matches)
+}
+
+fn parse_tag(name: &str) -> Result<Tag, String> {
+    // Tags in the QIDO-RS notation: GGGGEEEE
+    if name.len() == 8 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+        let group = u16::from_str_radix(&name[..4], 16).map_err(|e| e.to_string())?;
+        let element = u16::from_str_radix(&name[4..], 16).map_err(|e| e.to_string())?;
+        return Ok(Tag(group, element));
+    }
+
+    StandardDataDictionary
+        .parse_tag(name)
+        .ok_or_else(|| format!("Unknown attribute {}", name))
+}
+
+async fn search(client: &DicomWebClient, level: SearchLevel) -> Result<(), Box<dyn Error>> {
+    let (results, format) = match level {
+        SearchLevel::Studies(args) => {
+            let query = QidoStudyQuery {
+                limit: args.limit,
+                offset: args.offset,
+                includefields: args.includefields,
+                matches: parse_filters(&args.filters)?,
+                ..Default::default()
+            };
+            (client.search_studies(&query).await?, args.format)
+        }
+        SearchLevel::Series { study, args } => {
+            let query = QidoSeriesQuery {
+                limit: args.limit,
+                offset: args.offset,
+                includefield: (!args.includefields.is_empty())
+                    .then(|| args.includefields.join(",")),
+                matches: parse_filters(&args.filters)?,
+                ..Default::default()
+            };
+            (
+                client.search_series(study.as_deref(), &query).await?,
+                args.format,
+            )
+        }
+        SearchLevel::Instances {
+            study,
+            series,
+            args,
+        } => {
+            let query = QidoInstanceQuery {
+                limit: args.limit,
+                offset: args.offset,
+                includefield: (!args.includefields.is_empty())
+                    .then(|| args.includefields.join(",")),
+                matches: parse_filters(&args.filters)?,
+                ..Default::default()
+            };
+            (
+                client
+                    .search_instances(study.as_deref(), series.as_deref(), &query)
+                    .await?,
+                args.format,
+            )
+        }
+    };
+
+    output::print(&results, format)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let client = DicomWebClient::new(&cli.url);
+
+    match cli.command {
+        Command::Search(level) => search(&client, level).await,
+        Command::Retrieve(level) => match level {
+            RetrieveLevel::Study { study, output } => {
+                retrieve::retrieve_study(&client, &study, &output).await
+            }
+            RetrieveLevel::Series {
+                study,
+                series,
+                output,
+            } => retrieve::retrieve_series(&client, &study, &series, &output).await,
+            RetrieveLevel::Instance {
+                study,
+                series,
+                instance,
+                output,
+            } => retrieve::retrieve_instance(&client, &study, &series, &instance, &output).await,
+        },
+        Command::Store {
+            paths,
+            study,
+            batch_size,
+        } => store::store(&client, &paths, study.as_deref(), batch_size).await,
+        Command::Metadata {
+            study,
+            series,
+            instance,
+            format,
+        } => {
+            let metadata = match (series, instance) {
+                (Some(series), Some(instance)) => {
+                    client
+                        .retrieve_instance_metadata(&study, &series, &instance)
+                        .await?
+                }
+                (Some(series), None) => client.retrieve_series_metadata(&study, &series).await?,
+                _ => client.retrieve_study_metadata(&study).await?,
+            };
+            output::print(&metadata, format)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::dictionary_std::tags;
+
+    use super::*;
+
+    fn filters(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_keywords_and_tags() {
+        assert_eq!(parse_tag("PatientID"), Ok(tags::PATIENT_ID));
+        assert_eq!(parse_tag("00100020"), Ok(tags::PATIENT_ID));
+        assert_eq!(parse_tag("(0010,0020)"), Ok(tags::PATIENT_ID));
+        assert_eq!(parse_tag("0009abcd"), Ok(Tag(0x0009, 0xABCD)));
+        assert!(parse_tag("PatientIdentifier").is_err());
+        assert!(parse_tag("(0010,002G)").is_err());
+    }
+
+    #[test]
+    fn parses_filters_with_inline_and_separate_values() {
+        let matches = parse_filters(&filters(&[
+            "--PatientName=Doe^J*",
+            "--(0008,0060)",
+            "CT",
+            "--00080020=20240101-",
+            "--StudyDescription=a=b",
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            matches,
+            [
+                (tags::PATIENT_NAME, String::from("Doe^J*")),
+                (tags::MODALITY, String::from("CT")),
+                (tags::STUDY_DATE, String::from("20240101-")),
+                (tags::STUDY_DESCRIPTION, String::from("a=b")),
+            ]
+        );
+        assert_eq!(parse_filters(&[]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_malformed_filters() {
+        assert_eq!(
+            parse_filters(&filters(&["PatientID=1"])),
+            Err(String::from(
+                "Invalid filter PatientID=1, expected --Keyword=value"
+            ))
+        );
+        assert_eq!(
+            parse_filters(&filters(&["--PatientID"])),
+            Err(String::from("Missing value for filter PatientID"))
+        );
+        assert_eq!(
+            parse_filters(&filters(&["--Unknown=1"])),
+            Err(String::from("Unknown attribute Unknown"))
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::error::Error;
+
+use clap::ValueEnum;
+use dicom::{
+    core::{dictionary::DataDictionary, value::Value, VR},
+    dictionary_std::StandardDataDictionary,
+};
+use dicom_json::DicomJson;
+use dicom_object::InMemDicomObject;
+
+// Longer values are cut off in the table output
+const MAX_VALUE_LENGTH: usize = 64;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    /// DICOM JSON, as returned by the server
+    Json,
+    /// One attribute per line
+    Table,
+}
+
+pub fn print(datasets: &[InMemDicomObject], format: Format) -> Result<(), Box<dyn Error>> {
+    match format {
+        Format::Json => {
+            let json = serde_json::to_string_pretty(&DicomJson::from(datasets))?;
+            println!("{}", json);
+        }
+        Format::Table => {
+            for (i, dataset) in datasets.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                print_dataset(dataset, 0);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_dataset(dataset: &InMemDicomObject, depth: usize) {
+    let indent = "  ".repeat(depth);
+    for elt in dataset {
+        let tag = elt.header().tag;
+        let vr = elt.header().vr;
+        let name = StandardDataDictionary
+            .by_tag(tag)
+            .map(|entry| entry.alias)
+            .unwrap_or("Unknown");
+
+        match elt.value() {
+            Value::Sequence(seq) => {
+                println!(
+                    "{}{} {} {:<40} [{} item(s)]",
+                    indent,
+                    tag,
+                    vr,
+                    name,
+                    seq.items().len()
+                );
+                for item in seq.items() {
+                    print_dataset(item, depth + 1);
+                }
+            }
+            Value::PixelSequence(seq) => {
+                println!(
+                    "{}{} {} {:<40} <{} fragment(s)>",
+                    indent,
+                    tag,
+                    vr,
+                    name,
+                    seq.fragments().len()
+                );
+            }
+            Value::Primitive(value) => {
+                let text = match vr {
+                    VR::OB | VR::OD | VR::OF | VR::OL | VR::OV | VR::OW | VR::UN => {
+                        format!("<{} bytes>", value.calculate_byte_len())
+                    }
+                    _ => truncate(&value.to_str()),
+                };
+                println!("{}{} {} {:<40} {}", indent, tag, vr, name, text);
+            }
+        }
+    }
+}
+
+fn truncate(text: &str) -> String {
+    let text = text.trim_end_matches(['\0', ' ']);
+    match text.char_indices().nth(MAX_VALUE_LENGTH) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
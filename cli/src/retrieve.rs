@@ -0,0 +1,81 @@
+use std::{error::Error, path::Path};
+
+use dicom_object::{FileDicomObject, InMemDicomObject};
+use dicomweb_client::DicomWebClient;
+use futures_util::{pin_mut, Stream, StreamExt};
+
+pub async fn retrieve_study(
+    client: &DicomWebClient,
+    study_uid: &str,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let instances = client.retrieve_study(study_uid).await?;
+    write_instances(instances, output).await
+}
+
+pub async fn retrieve_series(
+    client: &DicomWebClient,
+    study_uid: &str,
+    series_uid: &str,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let instances = client.retrieve_series(study_uid, series_uid).await?;
+    write_instances(instances, output).await
+}
+
+pub async fn retrieve_instance(
+    client: &DicomWebClient,
+    study_uid: &str,
+    series_uid: &str,
+    instance_uid: &str,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let instance = client
+        .retrieve_instance(study_uid, series_uid, instance_uid)
+        .await?;
+    std::fs::create_dir_all(output)?;
+    write_instance(&instance, output)?;
+    println!("Retrieved 1 instance(s)");
+    Ok(())
+}
+
+/// Write the instances as they arrive, so large studies don't have to fit into memory
+async fn write_instances(
+    instances: impl Stream<Item = Result<FileDicomObject<InMemDicomObject>, dicomweb_client::Error>>,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(output)?;
+    pin_mut!(instances);
+
+    let mut count = 0;
+    while let Some(instance) = instances.next().await {
+        write_instance(&instance?, output)?;
+        count += 1;
+    }
+
+    println!("Retrieved {} instance(s)", count);
+    Ok(())
+}
+
+/// Write an instance to "{output}/{SOPInstanceUID}.dcm"
+fn write_instance(
+    instance: &FileDicomObject<InMemDicomObject>,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let sop_instance_uid = instance
+        .meta()
+        .media_storage_sop_instance_uid
+        .trim_end_matches('\0');
+    // The UID comes from the server, it must not name another path
+    if !is_uid(sop_instance_uid) {
+        return Err(format!("Invalid SOP Instance UID {:?}", sop_instance_uid).into());
+    }
+    let path = output.join(format!("{}.dcm", sop_instance_uid));
+    instance.write_to_file(&path)?;
+    Ok(())
+}
+
+/// UIDs consist of up to 64 digits and dots
+fn is_uid(uid: &str) -> bool {
+    !uid.is_empty() && uid.len() <= 64 && uid.chars().all(|c| c.is_ascii_digit() || c == '.')
+}
@@ -0,0 +1,167 @@
+use std::{error::Error, path::PathBuf};
+
+use dicom_object::{FileDicomObject, InMemDicomObject};
+use dicomweb_client::DicomWebClient;
+use walkdir::WalkDir;
+
+// Media Storage Directory Storage, i.e. the DICOMDIR of a CD
+const MEDIA_STORAGE_DIRECTORY: &str = "1.2.840.10008.1.3.10";
+
+pub async fn store(
+    client: &DicomWebClient,
+    paths: &[PathBuf],
+    study_uid: Option<&str>,
+    batch_size: usize,
+) -> Result<(), Box<dyn Error>> {
+    let files = collect_files(paths);
+
+    let mut stored = 0;
+    let mut skipped = 0;
+    let mut batch: Vec<FileDicomObject<InMemDicomObject>> = Vec::new();
+    for file in &files {
+        // CD imports contain viewers, readmes etc. next to the DICOM files
+        let dcm = match dicom_object::open_file(file) {
+            Ok(dcm) => dcm,
+            Err(_) => {
+                skipped += 1;
+                continue;
+            }
+        };
+        if dcm
+            .meta()
+            .media_storage_sop_class_uid
+            .trim_end_matches('\0')
+            == MEDIA_STORAGE_DIRECTORY
+        {
+            skipped += 1;
+            continue;
+        }
+
+        batch.push(dcm);
+        if batch.len() >= batch_size.max(1) {
+            stored += store_batch(client, study_uid, &mut batch).await?;
+        }
+    }
+    if !batch.is_empty() {
+        stored += store_batch(client, study_uid, &mut batch).await?;
+    }
+
+    println!("Stored {} instance(s), skipped {} file(s)", stored, skipped);
+    Ok(())
+}
+
+async fn store_batch(
+    client: &DicomWebClient,
+    study_uid: Option<&str>,
+    batch: &mut Vec<FileDicomObject<InMemDicomObject>>,
+) -> Result<usize, Box<dyn Error>> {
+    client.store_instances(study_uid, batch).await?;
+    let count = batch.len();
+    batch.clear();
+    println!("Uploaded {} instance(s)", count);
+    Ok(count)
+}
+
+/// Expand folders into the files they contain
+fn collect_files(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for path in paths {
+        for entry in WalkDir::new(path).follow_links(true) {
+            match entry {
+                Ok(entry) if entry.file_type().is_file() => files.push(entry.into_path()),
+                Ok(_) => {}
+                Err(e) => eprintln!("Skipping {}", e),
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use axum::middleware;
+    use dicom::{
+        core::{DataElement, VR},
+        dictionary_std::{tags, uids},
+        object::meta::FileMetaTableBuilder,
+    };
+    use dicomweb_client::QidoInstanceQuery;
+    use dicomweb_server::{
+        auth::Authentication,
+        axum::{authenticate, dicomweb_router},
+        backend::InMemoryBackend,
+    };
+
+    use super::*;
+
+    async fn serve() -> DicomWebClient {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let authentication = Arc::new(Authentication::new().with_anonymous_access());
+        let router = dicomweb_router(InMemoryBackend::new())
+            .layer(middleware::from_fn_with_state(authentication, authenticate));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        DicomWebClient::new(&format!("http://{}", addr))
+    }
+
+    /// Write a file of the given SOP class, like it is found on a CD
+    fn write_file(path: PathBuf, sop_class_uid: &str, sop_uid: &str) {
+        let dcm = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SOP_CLASS_UID, VR::UI, sop_class_uid),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, sop_uid),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, "1.2.840.9.1"),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, "1.2.840.9.1.1"),
+        ]);
+        dcm.with_meta(
+            FileMetaTableBuilder::new()
+                .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN)
+                .media_storage_sop_class_uid(sop_class_uid)
+                .media_storage_sop_instance_uid(sop_uid),
+        )
+        .unwrap()
+        .write_to_file(path)
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn uploads_the_dicom_files_of_a_cd() {
+        let cd = std::env::temp_dir().join(format!("dicomweb-cd-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(cd.join("DICOM/ST1")).unwrap();
+        fs::create_dir_all(cd.join("VIEWER")).unwrap();
+        fs::write(cd.join("README.TXT"), "Open VIEWER/VIEWER.EXE").unwrap();
+        fs::write(cd.join("VIEWER/VIEWER.EXE"), [0x4d, 0x5a, 0x90, 0x00]).unwrap();
+        write_file(cd.join("DICOMDIR"), MEDIA_STORAGE_DIRECTORY, "1.2.840.9.2");
+        for number in 1..=3 {
+            write_file(
+                cd.join(format!("DICOM/ST1/IM{}", number)),
+                uids::SECONDARY_CAPTURE_IMAGE_STORAGE,
+                &format!("1.2.840.9.1.1.{}", number),
+            );
+        }
+
+        // Batches of two
+        let client = serve().await;
+        let result = store(&client, std::slice::from_ref(&cd), None, 2).await;
+        fs::remove_dir_all(&cd).unwrap();
+        result.unwrap();
+
+        let mut stored: Vec<String> = client
+            .search_instances(None, None, &QidoInstanceQuery::default())
+            .await
+            .unwrap()
+            .iter()
+            .map(|instance| {
+                let uid = instance.element(tags::SOP_INSTANCE_UID).unwrap();
+                uid.to_str().unwrap().trim_end_matches('\0').to_string()
+            })
+            .collect();
+        stored.sort();
+        assert_eq!(
+            stored,
+            ["1.2.840.9.1.1.1", "1.2.840.9.1.1.2", "1.2.840.9.1.1.3"]
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use derive_more::{Display, From};
+use dicom_object::InMemDicomObject;
+use dicomweb_server::multipart::MultipartError;
+
+mod qido;
+mod stow;
+#[cfg(test)]
+mod testing;
+mod wado;
+
+pub use dicomweb_server::{QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery};
+
+/// Errors which can occur while talking to a DICOMweb server
+#[derive(Debug, Display, From)]
+pub enum Error {
+    #[display(fmt = "HTTP request failed: {}", _0)]
+    Http(reqwest::Error),
+    #[display(fmt = "Server responded with status {}", _0)]
+    Status(reqwest::StatusCode),
+    #[display(fmt = "Unexpected content type: {}", _0)]
+    #[from(ignore)]
+    ContentType(String),
+    /// The server answered with 404 Not Found, or a retrieve contained no instance
+    #[display(fmt = "The requested resource was not found")]
+    #[from(ignore)]
+    NotFound,
+    #[display(fmt = "Invalid multipart response: {}", _0)]
+    Multipart(MultipartError),
+    #[display(fmt = "Invalid DICOM JSON: {}", _0)]
+    Json(serde_json::Error),
+    #[display(fmt = "Invalid DICOM file: {}", _0)]
+    Read(dicom_object::ReadError),
+    #[display(fmt = "Failed to write DICOM file: {}", _0)]
+    Write(dicom_object::WriteError),
+}
+
+impl std::error::Error for Error {}
+
+/// DICOMweb Client
+///
+/// Provides the QIDO-RS, WADO-RS and STOW-RS transactions of a DICOMweb server.
+#[derive(Clone, Debug)]
+pub struct DicomWebClient {
+    http: reqwest::Client,
+    base_url: String,
+}
+
+impl DicomWebClient {
+    /// Create a client for the service at `base_url`, e.g. "http://localhost:8080"
+    pub fn new(base_url: &str) -> DicomWebClient {
+        DicomWebClient::with_http_client(base_url, reqwest::Client::new())
+    }
+
+    /// Create a client which sends its requests with a preconfigured HTTP client.
+    ///
+    /// Use this to set default headers like authorization, or timeouts.
+    pub fn with_http_client(base_url: &str, http: reqwest::Client) -> DicomWebClient {
+        DicomWebClient {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+}
+
+/// Check the status of a response
+fn check_status(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        reqwest::StatusCode::NOT_FOUND => Err(Error::NotFound),
+        status => Err(Error::Status(status)),
+    }
+}
+
+/// Parse DICOM JSON, which is either a single dataset or an array of datasets
+fn parse_dicom_json(body: &[u8]) -> Result<Vec<InMemDicomObject>, Error> {
+    if body.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let datasets = match serde_json::from_slice(body)? {
+        serde_json::Value::Array(datasets) => datasets,
+        dataset => vec![dataset],
+    };
+
+    datasets
+        .into_iter()
+        .map(|dataset| dicom_json::from_value(dataset).map_err(Error::from))
+        .collect()
+}
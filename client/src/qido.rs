@@ -0,0 +1,267 @@
+use dicom_dictionary_std::tags;
+use dicom_object::{InMemDicomObject, Tag};
+use dicomweb_server::{QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery, APPLICATION_DICOM_JSON};
+use reqwest::{header, StatusCode};
+use serde::Serialize;
+
+use crate::{check_status, parse_dicom_json, DicomWebClient, Error};
+
+/// QIDO-RS
+///
+/// See https://www.dicomstandard.org/using/dicomweb/query-qido-rs for more information
+impl DicomWebClient {
+    pub async fn search_studies(
+        &self,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, Error> {
+        // Includefields and matching attributes are not part of the serialized query
+        let mut params: Vec<(String, String)> = query
+            .includefields
+            .iter()
+            .map(|field| (String::from("includefield"), field.clone()))
+            .collect();
+        params.extend(match_params(&query.matches));
+
+        self.search("/studies", query, &params).await
+    }
+
+    pub async fn search_series(
+        &self,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, Error> {
+        let path = match study_uid {
+            Some(study_uid) => format!("/studies/{}/series", study_uid),
+            None => String::from("/series"),
+        };
+
+        // The named attributes are sent as standard matching attributes
+        let mut query = query.clone();
+        let mut matches = std::mem::take(&mut query.matches);
+        if let Some(modality) = query.modality.take() {
+            matches.push((tags::MODALITY, modality));
+        }
+        if let Some(series_instance_uid) = query.series_instance_uid.take() {
+            matches.push((tags::SERIES_INSTANCE_UID, series_instance_uid));
+        }
+        if let Some(series_description) = query.series_description.take() {
+            matches.push((tags::SERIES_DESCRIPTION, series_description));
+        }
+
+        self.search(&path, &query, &match_params(&matches)).await
+    }
+
+    pub async fn search_instances(
+        &self,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, Error> {
+        let path = match (study_uid, series_uid) {
+            (Some(study_uid), Some(series_uid)) => {
+                format!("/studies/{}/series/{}/instances", study_uid, series_uid)
+            }
+            (Some(study_uid), None) => format!("/studies/{}/instances", study_uid),
+            _ => String::from("/instances"),
+        };
+
+        // The named attributes are sent as standard matching attributes
+        let mut query = query.clone();
+        let mut matches = std::mem::take(&mut query.matches);
+        if let Some(sop_instance_uid) = query.sop_instance_uid.take() {
+            matches.push((tags::SOP_INSTANCE_UID, sop_instance_uid));
+        }
+        if let Some(instance_number) = query.instance_number.take() {
+            matches.push((tags::INSTANCE_NUMBER, instance_number));
+        }
+
+        self.search(&path, &query, &match_params(&matches)).await
+    }
+
+    async fn search(
+        &self,
+        path: &str,
+        query: &impl Serialize,
+        params: &[(String, String)],
+    ) -> Result<Vec<InMemDicomObject>, Error> {
+        let response = self
+            .http
+            .get(self.url(path))
+            .header(header::ACCEPT, APPLICATION_DICOM_JSON)
+            .query(query)
+            .query(params)
+            .send()
+            .await?;
+
+        // "No Content" is returned, if there are no matches
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(Vec::new());
+        }
+
+        let body = check_status(response)?.bytes().await?;
+        parse_dicom_json(&body)
+    }
+}
+
+/// Encode matching attributes as query parameters, using their GGGGEEEE tag
+fn match_params(matches: &[(Tag, String)]) -> Vec<(String, String)> {
+    matches
+        .iter()
+        .map(|(tag, value)| {
+            (
+                format!("{:04X}{:04X}", tag.group(), tag.element()),
+                value.clone(),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        http::{StatusCode, Uri},
+        routing::get,
+        Router,
+    };
+    use dicom_dictionary_std::tags;
+
+    use super::*;
+    use crate::testing::{serve, serve_study, CT_SERIES_UID, MR_SERIES_UID, STUDY_UID};
+
+    /// Serve a router answering every search with 204 No Content, which records the
+    /// requested URIs
+    async fn recording_server() -> (DicomWebClient, Arc<Mutex<Vec<String>>>) {
+        let uris = Arc::new(Mutex::new(Vec::new()));
+        let recorded = uris.clone();
+        let router = Router::new().fallback(get(move |uri: Uri| async move {
+            recorded.lock().unwrap().push(uri.to_string());
+            StatusCode::NO_CONTENT
+        }));
+        (serve(router).await, uris)
+    }
+
+    fn uid(dcm: &InMemDicomObject, tag: Tag) -> String {
+        dcm.element(tag)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .trim_end_matches('\0')
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn sends_named_attributes_as_standard_tags() {
+        let (client, uris) = recording_server().await;
+
+        let study_query = QidoStudyQuery {
+            fuzzymatching: Some(true),
+            includefields: vec![String::from("StudyDescription")],
+            matches: vec![(tags::PATIENT_NAME, String::from("Doe^J*"))],
+            ..QidoStudyQuery::default()
+        };
+        let series_query = QidoSeriesQuery {
+            limit: Some(10),
+            modality: Some(String::from("CT")),
+            series_instance_uid: Some(String::from(CT_SERIES_UID)),
+            series_description: Some(String::from("CT series")),
+            matches: vec![(tags::BODY_PART_EXAMINED, String::from("HEAD"))],
+            ..QidoSeriesQuery::default()
+        };
+        let instance_query = QidoInstanceQuery {
+            offset: Some(1),
+            sop_instance_uid: Some(format!("{}.2", CT_SERIES_UID)),
+            instance_number: Some(String::from("2")),
+            ..QidoInstanceQuery::default()
+        };
+        assert!(client
+            .search_studies(&study_query)
+            .await
+            .unwrap()
+            .is_empty());
+        client
+            .search_series(Some(STUDY_UID), &series_query)
+            .await
+            .unwrap();
+        client
+            .search_instances(Some(STUDY_UID), Some(CT_SERIES_UID), &instance_query)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *uris.lock().unwrap(),
+            [
+                String::from(
+                    "/studies?fuzzymatching=true&includefield=StudyDescription&00100010=Doe%5EJ*"
+                ),
+                format!(
+                    "/studies/{}/series?limit=10&00180015=HEAD&00080060=CT&0020000E={}&0008103E=CT+series",
+                    STUDY_UID, CT_SERIES_UID
+                ),
+                format!(
+                    "/studies/{0}/series/{1}/instances?offset=1&00080018={1}.2&00200013=2",
+                    STUDY_UID, CT_SERIES_UID
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn searches_the_server() {
+        let client = serve_study().await;
+
+        let studies = client
+            .search_studies(&QidoStudyQuery {
+                matches: vec![(tags::PATIENT_ID, String::from("CLIENT-1"))],
+                ..QidoStudyQuery::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(studies.len(), 1);
+        assert_eq!(uid(&studies[0], tags::STUDY_INSTANCE_UID), STUDY_UID);
+
+        let series = client
+            .search_series(
+                Some(STUDY_UID),
+                &QidoSeriesQuery {
+                    modality: Some(String::from("MR")),
+                    ..QidoSeriesQuery::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(series.len(), 1);
+        assert_eq!(uid(&series[0], tags::SERIES_INSTANCE_UID), MR_SERIES_UID);
+
+        let instances = client
+            .search_instances(
+                None,
+                None,
+                &QidoInstanceQuery {
+                    instance_number: Some(String::from("2")),
+                    ..QidoInstanceQuery::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(
+            uid(&instances[0], tags::SOP_INSTANCE_UID),
+            format!("{}.2", CT_SERIES_UID)
+        );
+
+        // No matches
+        let series = client
+            .search_series(
+                None,
+                &QidoSeriesQuery {
+                    series_description: Some(String::from("PET series")),
+                    ..QidoSeriesQuery::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(series.is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+use dicom_object::{FileDicomObject, InMemDicomObject};
+use dicomweb_server::{multipart::MultipartWriter, APPLICATION_DICOM_JSON};
+use reqwest::header;
+
+use crate::{check_status, parse_dicom_json, DicomWebClient, Error};
+
+/// STOW-RS
+///
+/// See https://www.dicomstandard.org/using/dicomweb/store-stow-rs for more information
+impl DicomWebClient {
+    /// Store instances, optionally restricted to the given study
+    pub async fn store_instances(
+        &self,
+        study_uid: Option<&str>,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<Vec<InMemDicomObject>, Error> {
+        let mut mp = MultipartWriter::new();
+        for instance in instances {
+            let mut data: Vec<u8> = Vec::new();
+
+            // Write the DICOM file to memory and add it to our stream
+            instance.write_all(&mut data)?;
+            mp.add(&*data, "Content-Type: application/dicom")
+                .expect("writing to memory");
+        }
+
+        // Finish the multipart stream
+        mp.finish();
+
+        let path = match study_uid {
+            Some(study_uid) => format!("/studies/{}", study_uid),
+            None => String::from("/studies"),
+        };
+        let content_type = format!(
+            "multipart/related; type=\"application/dicom\"; boundary={}",
+            mp.boundary
+        );
+
+        let response = self
+            .http
+            .post(self.url(&path))
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::ACCEPT, APPLICATION_DICOM_JSON)
+            .body(mp.data)
+            .send()
+            .await?;
+
+        let body = check_status(response)?.bytes().await?;
+        parse_dicom_json(&body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom_dictionary_std::tags;
+    use dicomweb_server::backend::InMemoryBackend;
+    use futures_util::TryStreamExt;
+
+    use crate::testing::{serve_backend, study, CT_SERIES_UID, STUDY_UID};
+
+    #[tokio::test]
+    async fn stores_instances_for_retrieval() {
+        let client = serve_backend(InMemoryBackend::new()).await;
+
+        let responses = client
+            .store_instances(Some(STUDY_UID), &study())
+            .await
+            .unwrap();
+        // The server responds with the metadata of the stored instances
+        assert_eq!(responses.len(), 3);
+        assert!(responses
+            .iter()
+            .all(|response| response.element(tags::PIXEL_DATA).is_err()));
+
+        let retrieved: Vec<_> = client
+            .retrieve_series(STUDY_UID, CT_SERIES_UID)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(retrieved.len(), 2);
+        for instance in &retrieved {
+            let stored = study()
+                .into_iter()
+                .find(|stored| {
+                    stored.meta().media_storage_sop_instance_uid()
+                        == instance.meta().media_storage_sop_instance_uid()
+                })
+                .unwrap();
+            assert_eq!(
+                instance
+                    .element(tags::PIXEL_DATA)
+                    .unwrap()
+                    .to_bytes()
+                    .unwrap(),
+                stored
+                    .element(tags::PIXEL_DATA)
+                    .unwrap()
+                    .to_bytes()
+                    .unwrap()
+            );
+        }
+    }
+}
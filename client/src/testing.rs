@@ -0,0 +1,123 @@
+//! Fixtures of the tests
+
+use std::sync::Arc;
+
+use axum::{middleware, Router};
+use dicom_core::{DataElement, PrimitiveValue, VR};
+use dicom_dictionary_std::{tags, uids};
+use dicom_object::{meta::FileMetaTableBuilder, FileDicomObject, InMemDicomObject};
+use dicomweb_server::{
+    auth::{Authentication, Principal},
+    axum::{authenticate, dicomweb_router},
+    backend::{DicomWebBackend, InMemoryBackend},
+};
+
+use crate::DicomWebClient;
+
+pub(crate) const STUDY_UID: &str = "1.2.826.0.1.3680043.2.1125.1";
+pub(crate) const CT_SERIES_UID: &str = "1.2.826.0.1.3680043.2.1125.1.1";
+pub(crate) const MR_SERIES_UID: &str = "1.2.826.0.1.3680043.2.1125.1.2";
+
+/// Serve the router on a local port for the lifetime of the test runtime
+pub(crate) async fn serve(router: Router) -> DicomWebClient {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Free local port");
+    let addr = listener.local_addr().expect("Bound address");
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    DicomWebClient::new(&format!("http://{}/", addr))
+}
+
+/// Serve the backend without authentication
+pub(crate) async fn serve_backend(backend: impl DicomWebBackend + 'static) -> DicomWebClient {
+    let authentication = Arc::new(Authentication::new().with_anonymous_access());
+    serve(
+        dicomweb_router(backend)
+            .layer(middleware::from_fn_with_state(authentication, authenticate)),
+    )
+    .await
+}
+
+/// Serve the test study: a CT series of two images with two frames, and an MR series of
+/// one image with a single frame
+pub(crate) async fn serve_study() -> DicomWebClient {
+    let backend = InMemoryBackend::new();
+    backend
+        .store_instances(&Principal::anonymous(), &study())
+        .await
+        .expect("Stored in memory");
+    serve_backend(backend).await
+}
+
+pub(crate) fn study() -> Vec<FileDicomObject<InMemDicomObject>> {
+    vec![
+        image(CT_SERIES_UID, "CT", 1, 2),
+        image(CT_SERIES_UID, "CT", 2, 2),
+        image(MR_SERIES_UID, "MR", 1, 1),
+    ]
+}
+
+/// Image of the test study, whose frames of 1x1 pixel hold their frame number
+pub(crate) fn image(
+    series_uid: &str,
+    modality: &str,
+    instance_number: u32,
+    frames: u8,
+) -> FileDicomObject<InMemDicomObject> {
+    let sop_uid = format!("{}.{}", series_uid, instance_number);
+
+    let mut dcm = InMemDicomObject::new_empty();
+    let mut put = |tag, vr, value: PrimitiveValue| dcm.put(DataElement::new(tag, vr, value));
+    put(
+        tags::SOP_CLASS_UID,
+        VR::UI,
+        uids::MULTI_FRAME_GRAYSCALE_BYTE_SECONDARY_CAPTURE_IMAGE_STORAGE.into(),
+    );
+    put(tags::SOP_INSTANCE_UID, VR::UI, sop_uid.as_str().into());
+    put(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID.into());
+    put(tags::SERIES_INSTANCE_UID, VR::UI, series_uid.into());
+    put(tags::PATIENT_ID, VR::LO, "CLIENT-1".into());
+    put(tags::MODALITY, VR::CS, modality.into());
+    put(
+        tags::SERIES_DESCRIPTION,
+        VR::LO,
+        format!("{} series", modality).into(),
+    );
+    put(
+        tags::INSTANCE_NUMBER,
+        VR::IS,
+        instance_number.to_string().into(),
+    );
+    put(tags::SAMPLES_PER_PIXEL, VR::US, PrimitiveValue::from(1_u16));
+    put(
+        tags::PHOTOMETRIC_INTERPRETATION,
+        VR::CS,
+        "MONOCHROME2".into(),
+    );
+    put(tags::NUMBER_OF_FRAMES, VR::IS, frames.to_string().into());
+    put(tags::ROWS, VR::US, PrimitiveValue::from(1_u16));
+    put(tags::COLUMNS, VR::US, PrimitiveValue::from(1_u16));
+    put(tags::BITS_ALLOCATED, VR::US, PrimitiveValue::from(8_u16));
+    put(tags::BITS_STORED, VR::US, PrimitiveValue::from(8_u16));
+    put(tags::HIGH_BIT, VR::US, PrimitiveValue::from(7_u16));
+    put(
+        tags::PIXEL_REPRESENTATION,
+        VR::US,
+        PrimitiveValue::from(0_u16),
+    );
+    // Even length, the odd frame count is padded
+    let mut pixel_data: Vec<u8> = (1..=frames).collect();
+    pixel_data.resize(pixel_data.len() + pixel_data.len() % 2, 0);
+    put(tags::PIXEL_DATA, VR::OB, PrimitiveValue::from(pixel_data));
+
+    dcm.with_meta(
+        FileMetaTableBuilder::new()
+            .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN)
+            .media_storage_sop_class_uid(
+                uids::MULTI_FRAME_GRAYSCALE_BYTE_SECONDARY_CAPTURE_IMAGE_STORAGE,
+            )
+            .media_storage_sop_instance_uid(sop_uid),
+    )
+    .expect("Valid file meta group")
+}
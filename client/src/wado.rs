@@ -0,0 +1,302 @@
+use bytes::Bytes;
+use dicom_object::{FileDicomObject, InMemDicomObject};
+use dicomweb_server::{multipart::MultipartReader, APPLICATION_DICOM_JSON};
+use futures_util::{stream::BoxStream, Stream, StreamExt, TryStreamExt};
+use reqwest::header;
+
+use crate::{check_status, parse_dicom_json, DicomWebClient, Error};
+
+type ByteStream = BoxStream<'static, reqwest::Result<Bytes>>;
+
+/// WADO-RS
+///
+/// See https://www.dicomstandard.org/using/dicomweb/retrieve-wado-rs-and-wado-uri for more information
+impl DicomWebClient {
+    /// Retrieve all instances of a study, as they arrive
+    pub async fn retrieve_study(
+        &self,
+        study_uid: &str,
+    ) -> Result<impl Stream<Item = Result<FileDicomObject<InMemDicomObject>, Error>>, Error> {
+        self.retrieve_instances(&format!("/studies/{}", study_uid))
+            .await
+    }
+
+    /// Retrieve all instances of a series, as they arrive
+    pub async fn retrieve_series(
+        &self,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<impl Stream<Item = Result<FileDicomObject<InMemDicomObject>, Error>>, Error> {
+        self.retrieve_instances(&format!("/studies/{}/series/{}", study_uid, series_uid))
+            .await
+    }
+
+    pub async fn retrieve_instance(
+        &self,
+        study_uid: &str,
+        series_uid: &str,
+        instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, Error> {
+        let instances = self
+            .retrieve_instances(&format!(
+                "/studies/{}/series/{}/instances/{}",
+                study_uid, series_uid, instance_uid
+            ))
+            .await?;
+        futures_util::pin_mut!(instances);
+
+        match instances.next().await {
+            Some(instance) => instance,
+            None => Err(Error::NotFound),
+        }
+    }
+
+    /// Retrieve the pixel data of the given frames (starting at 1), one part per frame
+    pub async fn retrieve_frames(
+        &self,
+        study_uid: &str,
+        series_uid: &str,
+        instance_uid: &str,
+        frames: &[u32],
+    ) -> Result<impl Stream<Item = Result<Bytes, Error>>, Error> {
+        let frame_list: Vec<String> = frames.iter().map(|frame| frame.to_string()).collect();
+        let path = format!(
+            "/studies/{}/series/{}/instances/{}/frames/{}",
+            study_uid,
+            series_uid,
+            instance_uid,
+            frame_list.join(",")
+        );
+
+        let parts = self
+            .retrieve_multipart(&path, "application/octet-stream")
+            .await?;
+        Ok(parts.map_err(Error::from).and_then(|part| async move {
+            part.try_fold(Vec::new(), |mut data, chunk| async move {
+                data.extend_from_slice(&chunk);
+                Ok(data)
+            })
+            .await
+            .map(Bytes::from)
+            .map_err(Error::from)
+        }))
+    }
+
+    pub async fn retrieve_study_metadata(
+        &self,
+        study_uid: &str,
+    ) -> Result<Vec<InMemDicomObject>, Error> {
+        self.retrieve_metadata(&format!("/studies/{}/metadata", study_uid))
+            .await
+    }
+
+    pub async fn retrieve_series_metadata(
+        &self,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<InMemDicomObject>, Error> {
+        self.retrieve_metadata(&format!(
+            "/studies/{}/series/{}/metadata",
+            study_uid, series_uid
+        ))
+        .await
+    }
+
+    pub async fn retrieve_instance_metadata(
+        &self,
+        study_uid: &str,
+        series_uid: &str,
+        instance_uid: &str,
+    ) -> Result<Vec<InMemDicomObject>, Error> {
+        self.retrieve_metadata(&format!(
+            "/studies/{}/series/{}/instances/{}/metadata",
+            study_uid, series_uid, instance_uid
+        ))
+        .await
+    }
+
+    async fn retrieve_metadata(&self, path: &str) -> Result<Vec<InMemDicomObject>, Error> {
+        let response = self
+            .http
+            .get(self.url(path))
+            .header(header::ACCEPT, APPLICATION_DICOM_JSON)
+            .send()
+            .await?;
+
+        let body = check_status(response)?.bytes().await?;
+        parse_dicom_json(&body)
+    }
+
+    async fn retrieve_instances(
+        &self,
+        path: &str,
+    ) -> Result<impl Stream<Item = Result<FileDicomObject<InMemDicomObject>, Error>>, Error> {
+        let parts = self.retrieve_multipart(path, "application/dicom").await?;
+        Ok(parts.map_err(Error::from).and_then(|part| async move {
+            let data = part
+                .try_fold(Vec::new(), |mut data, chunk| async move {
+                    data.extend_from_slice(&chunk);
+                    Ok(data)
+                })
+                .await?;
+            Ok(FileDicomObject::from_reader(data.as_slice())?)
+        }))
+    }
+
+    /// Request a multipart/related response and stream its parts
+    async fn retrieve_multipart(
+        &self,
+        path: &str,
+        part_type: &str,
+    ) -> Result<MultipartReader<ByteStream>, Error> {
+        let response = self
+            .http
+            .get(self.url(path))
+            .header(
+                header::ACCEPT,
+                format!("multipart/related; type=\"{}\"", part_type),
+            )
+            .send()
+            .await?;
+        let response = check_status(response)?;
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(|content_type| content_type.to_string());
+        if !content_type
+            .as_deref()
+            .is_some_and(|content_type| content_type.starts_with("multipart/related"))
+        {
+            return Err(Error::ContentType(content_type.unwrap_or_default()));
+        }
+
+        Ok(MultipartReader::from_content_type(
+            content_type.as_deref(),
+            Box::pin(response.bytes_stream()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::header, routing::get, Router};
+    use dicom_dictionary_std::tags;
+    use dicomweb_server::multipart::MultipartWriter;
+
+    use super::*;
+    use crate::testing::{serve, serve_study, CT_SERIES_UID, MR_SERIES_UID, STUDY_UID};
+
+    async fn sop_uids(
+        instances: impl Stream<Item = Result<FileDicomObject<InMemDicomObject>, Error>>,
+    ) -> Vec<String> {
+        let mut uids: Vec<String> = instances
+            .map(|instance| {
+                let instance = instance.unwrap();
+                let uid = instance.element(tags::SOP_INSTANCE_UID).unwrap();
+                uid.to_str().unwrap().trim_end_matches('\0').to_string()
+            })
+            .collect()
+            .await;
+        uids.sort();
+        uids
+    }
+
+    #[tokio::test]
+    async fn retrieves_multipart_instances() {
+        let client = serve_study().await;
+
+        let study = client.retrieve_study(STUDY_UID).await.unwrap();
+        assert_eq!(
+            sop_uids(study).await,
+            [
+                format!("{}.1", CT_SERIES_UID),
+                format!("{}.2", CT_SERIES_UID),
+                format!("{}.1", MR_SERIES_UID),
+            ]
+        );
+
+        let series = client
+            .retrieve_series(STUDY_UID, CT_SERIES_UID)
+            .await
+            .unwrap();
+        assert_eq!(
+            sop_uids(series).await,
+            [
+                format!("{}.1", CT_SERIES_UID),
+                format!("{}.2", CT_SERIES_UID),
+            ]
+        );
+
+        let instance = client
+            .retrieve_instance(STUDY_UID, MR_SERIES_UID, &format!("{}.1", MR_SERIES_UID))
+            .await
+            .unwrap();
+        assert_eq!(
+            instance.element(tags::MODALITY).unwrap().to_str().unwrap(),
+            "MR"
+        );
+    }
+
+    #[tokio::test]
+    async fn retrieves_one_part_per_frame() {
+        let client = serve_study().await;
+
+        let frames = client
+            .retrieve_frames(
+                STUDY_UID,
+                CT_SERIES_UID,
+                &format!("{}.2", CT_SERIES_UID),
+                &[2, 1],
+            )
+            .await
+            .unwrap();
+        let frames: Vec<Bytes> = frames.try_collect().await.unwrap();
+        assert_eq!(frames, [Bytes::from_static(&[2]), Bytes::from_static(&[1])]);
+    }
+
+    #[tokio::test]
+    async fn retrieves_metadata() {
+        let client = serve_study().await;
+
+        let study = client.retrieve_study_metadata(STUDY_UID).await.unwrap();
+        assert_eq!(study.len(), 3);
+        let series = client
+            .retrieve_series_metadata(STUDY_UID, CT_SERIES_UID)
+            .await
+            .unwrap();
+        assert_eq!(series.len(), 2);
+        let instance = client
+            .retrieve_instance_metadata(STUDY_UID, MR_SERIES_UID, &format!("{}.1", MR_SERIES_UID))
+            .await
+            .unwrap();
+        assert_eq!(instance.len(), 1);
+        assert!(instance[0].element(tags::PIXEL_DATA).is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_instances_are_not_found() {
+        let client = serve_study().await;
+        let missing = client
+            .retrieve_instance(STUDY_UID, CT_SERIES_UID, &format!("{}.3", CT_SERIES_UID))
+            .await;
+        assert!(matches!(missing, Err(Error::NotFound)));
+
+        // Servers may also answer with a multipart response without parts
+        let mut empty = MultipartWriter::new();
+        empty.finish();
+        let content_type = format!(
+            "multipart/related; type=\"application/dicom\"; boundary={}",
+            empty.boundary
+        );
+        let client = serve(Router::new().fallback(get(move || async move {
+            ([(header::CONTENT_TYPE, content_type)], empty.data)
+        })))
+        .await;
+        let missing = client
+            .retrieve_instance(STUDY_UID, CT_SERIES_UID, &format!("{}.3", CT_SERIES_UID))
+            .await;
+        assert!(matches!(missing, Err(Error::NotFound)));
+    }
+}
@@ -0,0 +1,38 @@
+use actix_web::{guard, http::header, web, HttpRequest, Responder};
+
+use super::{header_str, into_response};
+use crate::{api, auth::Authentication};
+
+/// Describe the resources on `OPTIONS`, the same paths as the endpoints
+pub(super) fn capabilities_config(cfg: &mut web::ServiceConfig) {
+    for path in api::capabilities::resource_paths() {
+        let resource = if path.is_empty() {
+            // The service root, with and without trailing slash
+            web::resource(["", "/"])
+        } else {
+            web::resource(format!("/{}", path))
+        };
+        cfg.service(resource.guard(guard::Options()).to(capabilities));
+    }
+}
+
+/// Capabilities
+///
+/// Describe the service root or the resource of the request, the prefix of a scope
+/// becomes the base of the description. The metrics are described if `metrics_config`
+/// serves them below the same prefix.
+async fn capabilities(request: HttpRequest) -> impl Responder {
+    let authentication = request.app_data::<web::Data<Authentication>>();
+    let metrics_path = format!(
+        "{}/metrics",
+        api::capabilities::service_root(request.path()).trim_end_matches('/')
+    );
+    into_response(api::capabilities::capabilities(
+        request.path(),
+        header_str(&request, header::ACCEPT),
+        api::capabilities::Deployment {
+            authentication: authentication.map(|authentication| authentication.as_ref()),
+            metrics: request.resource_map().has_resource(&metrics_path),
+        },
+    ))
+}
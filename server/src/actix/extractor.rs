@@ -1,19 +1,58 @@
-//! MultipartRelated payload support
+//! MultipartRelated payload and authentication support
 
 use actix_utils::future::{ready, Ready};
-use actix_web::{dev::Payload, Error, FromRequest, HttpRequest};
+use actix_web::{
+    dev::Payload, error::InternalError, http::header, web, Error, FromRequest, HttpRequest,
+};
 
-use super::MultipartReader;
+use super::into_response;
+use crate::{
+    api,
+    auth::{Authentication, Principal},
+    multipart::MultipartReader,
+};
 
-impl FromRequest for MultipartReader {
+impl FromRequest for MultipartReader<Payload> {
     type Error = Error;
-    type Future = Ready<Result<MultipartReader, Error>>;
+    type Future = Ready<Result<MultipartReader<Payload>, Error>>;
 
     #[inline]
     fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
-        ready(Ok(match MultipartReader::boundary(req.headers()) {
-            Ok(boundary) => MultipartReader::from_boundary(boundary, payload.take()),
-            Err(err) => MultipartReader::from_error(err),
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok());
+        ready(Ok(MultipartReader::from_content_type(
+            content_type,
+            payload.take(),
+        )))
+    }
+}
+
+/// Authenticates the request with the `web::Data<Authentication>` of the app.
+///
+/// Without authentication in the app data, requests fail with 500 Internal Server Error
+/// instead of being served anonymously. Register an `Authentication` with anonymous access
+/// to serve requests without credentials.
+impl FromRequest for Principal {
+    type Error = Error;
+    type Future = Ready<Result<Principal, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let Some(authentication) = req.app_data::<web::Data<Authentication>>() else {
+            return ready(Err(InternalError::from_response(
+                "Authentication isn't configured",
+                into_response(api::unauthenticated_response()),
+            )
+            .into()));
+        };
+        let principal = api::authenticate(authentication, |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        });
+        ready(principal.map_err(|response| {
+            InternalError::from_response("Unauthorized", into_response(response)).into()
         }))
     }
 }
@@ -0,0 +1,99 @@
+//! Recording and serving of the metrics
+
+use std::{rc::Rc, time::Instant};
+
+use actix_utils::future::{ready, Ready};
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    get, web, Error, HttpMessage, Responder,
+};
+use futures_util::{future::LocalBoxFuture, TryStreamExt};
+
+use super::into_response;
+use crate::{
+    auth::Principal,
+    metrics::{self, Metrics},
+};
+
+/// Records the metrics of the DICOMweb requests, e.g. `App::new().wrap(metrics.clone())`
+impl<S, B> Transform<S, ServiceRequest> for Metrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = MetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MetricsMiddleware {
+            service: Rc::new(service),
+            metrics: self.clone(),
+        }))
+    }
+}
+
+/// Service of the `Metrics` middleware
+pub struct MetricsMiddleware<S> {
+    service: Rc<S>,
+    metrics: Metrics,
+}
+
+impl<S, B> Service<ServiceRequest> for MetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let Some(transaction) = metrics::transaction(request.method().as_str(), request.path())
+        else {
+            return Box::pin(async move { service.call(request).await });
+        };
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+        let received_bytes = metrics.received_bytes(transaction);
+        let payload = request
+            .take_payload()
+            .inspect_ok(move |chunk| received_bytes.inc_by(chunk.len() as u64));
+        request.set_payload(Payload::Stream {
+            payload: Box::pin(payload),
+        });
+        Box::pin(async move {
+            let response = service.call(request).await?;
+            let sent_bytes = match response.response().body().size() {
+                BodySize::Sized(size) => size,
+                _ => 0,
+            };
+            metrics.observe_request(
+                transaction,
+                response.status().as_u16(),
+                start.elapsed(),
+                sent_bytes,
+            );
+            Ok(response)
+        })
+    }
+}
+
+/// Prometheus metrics of the `web::Data<Metrics>`, authenticated like the DICOMweb endpoints
+#[get("/metrics")]
+async fn serve_metrics(_principal: Principal, metrics: web::Data<Metrics>) -> impl Responder {
+    into_response(metrics.response())
+}
+
+/// Serve the metrics at `/metrics`, the app needs the `Metrics` as `web::Data<Metrics>`.
+///
+/// The requests are authenticated with the `web::Data<Authentication>` of the app.
+pub fn metrics_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(serve_metrics);
+}
@@ -1,18 +1,46 @@
+mod capabilities;
 mod extractor;
-mod multipart;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod qido;
 mod stow;
+mod update;
 mod wado;
 
-use multipart::*;
+use std::sync::Arc;
+
+use actix_web::{
+    http::{header::AsHeaderName, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use capabilities::*;
 use qido::*;
 use stow::*;
+use update::*;
 use wado::*;
 
+#[cfg(feature = "metrics")]
+pub use metrics::{metrics_config, MetricsMiddleware};
 pub use qido::qido_config;
 pub use stow::stow_config;
+pub use update::update_config;
 pub use wado::wado_config;
 
+use crate::{api::DicomWebResponse, backend::DicomWebBackend};
+
+/// Wrap a backend as app data for the endpoints
+pub fn backend_data(backend: impl DicomWebBackend + 'static) -> web::Data<dyn DicomWebBackend> {
+    web::Data::from(Arc::new(backend) as Arc<dyn DicomWebBackend>)
+}
+
+/// Serve all endpoints below a route prefix with their own backend, e.g. a
+/// [`DeidentifiedBackend`](crate::backend::DeidentifiedBackend) next to the original data
+pub fn dicomweb_scope(prefix: &str, backend: web::Data<dyn DicomWebBackend>) -> actix_web::Scope {
+    web::scope(prefix)
+        .app_data(backend)
+        .configure(dicomweb_config)
+}
+
 pub fn dicomweb_config(cfg: &mut actix_web::web::ServiceConfig) {
     cfg.service(store_instances)
         .service(store_instances_for_study)
@@ -28,5 +56,184 @@ pub fn dicomweb_config(cfg: &mut actix_web::web::ServiceConfig) {
         .service(retrieve_series)
         .service(retrieve_series_metadata)
         .service(retrieve_study)
-        .service(retrieve_study_metadata);
+        .service(retrieve_study_metadata)
+        .service(update_study)
+        .service(update_series)
+        .configure(capabilities_config);
+}
+
+/// Get a header of the request as string
+fn header_str(request: &HttpRequest, name: impl AsHeaderName) -> Option<&str> {
+    request
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Convert a response of the framework-agnostic core into an actix response
+fn into_response(response: DicomWebResponse) -> HttpResponse {
+    let (parts, body) = response.into_parts();
+
+    // actix-web uses its own version of the http types
+    let status =
+        StatusCode::from_u16(parts.status.as_u16()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut builder = HttpResponse::build(status);
+    for (name, value) in &parts.headers {
+        builder.append_header((name.as_str(), value.as_bytes()));
+    }
+    builder.body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{http::Method, test, App};
+
+    use dicom::{
+        core::{DataElement, VR},
+        dictionary_std::{tags, uids},
+    };
+    use dicom_object::{FileDicomObject, InMemDicomObject};
+
+    use super::*;
+    use crate::{
+        auth::{ApiKeyVerifier, Authentication},
+        backend::{DeidentifiedBackend, InMemoryBackend},
+        testing, Deidentifier,
+    };
+
+    const STUDY_UID: &str = "1.2.840.10008.44.3";
+    const PATIENT_ID: &str = "MRN-9012";
+
+    /// Knee MR slice of a patient, whose study is also shared for research
+    fn knee_mr_slice() -> FileDicomObject<InMemDicomObject> {
+        testing::file(InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SOP_CLASS_UID, VR::UI, uids::MR_IMAGE_STORAGE),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, "1.2.840.10008.44.3.1.1"),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, "1.2.840.10008.44.3.1"),
+            DataElement::new(tags::PATIENT_ID, VR::LO, PATIENT_ID),
+            DataElement::new(tags::MODALITY, VR::CS, "MR"),
+            DataElement::new(tags::BODY_PART_EXAMINED, VR::CS, "KNEE"),
+        ]))
+    }
+
+    #[actix_web::test]
+    async fn serves_deidentified_data_below_a_route_prefix() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend
+            .store_instances(&testing::principal(), &[knee_mr_slice()])
+            .await
+            .unwrap();
+        let research = DeidentifiedBackend::new(backend.clone(), Deidentifier::new("key"));
+        let app = test::init_service(
+            App::new()
+                .service(dicomweb_scope("/research", backend_data(research)))
+                .app_data(backend_data(backend))
+                .app_data(web::Data::new(
+                    Authentication::new().with_anonymous_access(),
+                ))
+                .configure(dicomweb_config),
+        )
+        .await;
+
+        let search = |uri| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Accept", "application/dicom+json"))
+                .to_request()
+        };
+        let original: serde_json::Value =
+            test::call_and_read_body_json(&app, search("/studies")).await;
+        assert_eq!(original[0]["00100020"]["Value"][0], PATIENT_ID);
+
+        let research: serde_json::Value =
+            test::call_and_read_body_json(&app, search("/research/studies")).await;
+        assert_eq!(research.as_array().map(Vec::len), Some(1));
+        assert_ne!(research[0]["00100020"]["Value"][0], PATIENT_ID);
+        assert_ne!(research[0]["0020000D"]["Value"][0], STUDY_UID);
+    }
+
+    #[actix_web::test]
+    async fn rejects_requests_without_authentication() {
+        let app = test::init_service(
+            App::new()
+                .app_data(backend_data(InMemoryBackend::new()))
+                .configure(dicomweb_config),
+        )
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/studies")
+            .insert_header(("Accept", "application/dicom+json"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn describes_the_mounted_resources_and_configured_schemes() {
+        let authentication =
+            Authentication::new().with_verifier(ApiKeyVerifier::new().with_key("key", "client"));
+        let app = App::new()
+            .app_data(backend_data(InMemoryBackend::new()))
+            .app_data(web::Data::new(authentication))
+            .configure(dicomweb_config)
+            .route("/other", web::get().to(HttpResponse::Ok));
+        #[cfg(feature = "metrics")]
+        let app = app
+            .app_data(web::Data::new(crate::metrics::Metrics::new()))
+            .configure(metrics_config);
+        let app = test::init_service(app).await;
+        let options = |uri| {
+            test::TestRequest::default()
+                .method(Method::OPTIONS)
+                .uri(uri)
+                .insert_header(("Accept", "application/vnd.oai.openapi+json"))
+                .to_request()
+        };
+
+        let description: serde_json::Value =
+            test::call_and_read_body_json(&app, options("/")).await;
+        assert!(description["paths"]["/studies/{study}"]["patch"].is_object());
+        assert_eq!(
+            description["paths"]["/metrics"].is_object(),
+            cfg!(feature = "metrics")
+        );
+        let schemes = description["components"]["securitySchemes"]
+            .as_object()
+            .unwrap();
+        assert_eq!(schemes.keys().collect::<Vec<_>>(), ["apiKey"]);
+
+        let response = test::call_service(&app, options("/studies/1.2.3/series")).await;
+        assert_eq!(response.headers().get("Allow").unwrap(), "GET, OPTIONS");
+        let response = test::call_service(&app, options("/other")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[actix_web::test]
+    async fn authenticates_the_metrics() {
+        let authentication =
+            Authentication::new().with_verifier(ApiKeyVerifier::new().with_key("key", "client"));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(authentication))
+                .app_data(web::Data::new(crate::metrics::Metrics::new()))
+                .configure(metrics_config),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/metrics").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = test::TestRequest::get()
+            .uri("/metrics")
+            .insert_header(("X-API-Key", "key"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
 }
@@ -0,0 +1,57 @@
+use actix_web::{http::header, patch, web, HttpRequest, Responder};
+
+use super::{header_str, into_response};
+use crate::{api, auth::Principal, backend::DicomWebBackend, UpdateQuery};
+
+/// Metadata update
+///
+/// Apply a DICOM JSON dataset of changed attributes to every instance of the study
+#[patch("/studies/{study_uid}")]
+pub async fn update_study(
+    request: HttpRequest,
+    backend: web::Data<dyn DicomWebBackend>,
+    principal: Principal,
+    study_uid: web::Path<String>,
+    query: web::Query<UpdateQuery>,
+    body: web::Bytes,
+) -> impl Responder {
+    into_response(
+        api::update::update_study(
+            backend.get_ref(),
+            &principal,
+            &study_uid,
+            header_str(&request, header::CONTENT_TYPE),
+            &query,
+            &body,
+        )
+        .await,
+    )
+}
+
+#[patch("/studies/{study_uid}/series/{series_uid}")]
+pub async fn update_series(
+    request: HttpRequest,
+    backend: web::Data<dyn DicomWebBackend>,
+    principal: Principal,
+    path: web::Path<(String, String)>,
+    query: web::Query<UpdateQuery>,
+    body: web::Bytes,
+) -> impl Responder {
+    let (study_uid, series_uid) = path.into_inner();
+    into_response(
+        api::update::update_series(
+            backend.get_ref(),
+            &principal,
+            &study_uid,
+            &series_uid,
+            header_str(&request, header::CONTENT_TYPE),
+            &query,
+            &body,
+        )
+        .await,
+    )
+}
+
+pub fn update_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(update_study).service(update_series);
+}
@@ -0,0 +1,114 @@
+//! Validators and conditional requests of the WADO-RS responses
+//!
+//! See https://www.rfc-editor.org/rfc/rfc9110#section-13 for more information
+
+use chrono::{DateTime, Utc};
+use http::{header, HeaderValue, StatusCode};
+use uuid::Uuid;
+
+use super::{status_response, DicomWebResponse};
+use crate::backend::ResourceVersion;
+
+// Responses may be stored, but are revalidated on every use, as the studies can change
+const CACHE_CONTROL: &str = "private, no-cache";
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Preconditions of a conditional request
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Preconditions<'a> {
+    pub if_none_match: Option<&'a str>,
+    pub if_modified_since: Option<&'a str>,
+}
+
+impl<'a> Preconditions<'a> {
+    /// Get the preconditions of a request by its headers
+    pub fn from_headers(header: impl Fn(&str) -> Option<&'a str>) -> Preconditions<'a> {
+        Preconditions {
+            if_none_match: header(header::IF_NONE_MATCH.as_str()),
+            if_modified_since: header(header::IF_MODIFIED_SINCE.as_str()),
+        }
+    }
+}
+
+/// ETag and Last-Modified of a response
+pub(crate) struct Validators {
+    etag: String,
+    last_modified: Option<DateTime<Utc>>,
+}
+
+impl Validators {
+    /// Validators of a representation of a versioned resource, e.g. the metadata of a series
+    pub(crate) fn from_version(version: &ResourceVersion, representation: &str) -> Validators {
+        let tag = format!("{}/{}", version.tag, representation);
+        Validators {
+            etag: entity_tag(tag.as_bytes()),
+            last_modified: version.last_modified,
+        }
+    }
+
+    /// Validators of a response body, without a version
+    pub(crate) fn from_content(body: &[u8]) -> Validators {
+        Validators {
+            etag: entity_tag(body),
+            last_modified: None,
+        }
+    }
+
+    /// Validators of the parts of a multipart response, without a version. The body
+    /// itself differs by its random boundary.
+    pub(crate) fn from_parts<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> Validators {
+        let digests: Vec<u8> = parts
+            .into_iter()
+            .flat_map(|part| Uuid::new_v5(&Uuid::NAMESPACE_OID, part).into_bytes())
+            .collect();
+        Validators::from_content(&digests)
+    }
+
+    /// Check if the client's representation is still current
+    pub(crate) fn is_fresh(&self, preconditions: &Preconditions) -> bool {
+        // If-Modified-Since is ignored along with If-None-Match
+        if let Some(if_none_match) = preconditions.if_none_match {
+            return if_none_match.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == self.etag
+            });
+        }
+        let since = preconditions
+            .if_modified_since
+            .and_then(|since| DateTime::parse_from_rfc2822(since).ok());
+        match (since, self.last_modified) {
+            // HTTP dates have a resolution of seconds
+            (Some(since), Some(last_modified)) => last_modified.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn not_modified(&self) -> DicomWebResponse {
+        self.apply(status_response(StatusCode::NOT_MODIFIED))
+    }
+
+    /// Add the validators and the caching policy to a response
+    pub(crate) fn apply(&self, mut response: DicomWebResponse) -> DicomWebResponse {
+        let headers = response.headers_mut();
+        if let Ok(etag) = HeaderValue::from_str(&self.etag) {
+            headers.insert(header::ETAG, etag);
+        }
+        if let Some(last_modified) = self.last_modified {
+            if let Ok(last_modified) =
+                HeaderValue::from_str(&last_modified.format(HTTP_DATE).to_string())
+            {
+                headers.insert(header::LAST_MODIFIED, last_modified);
+            }
+        }
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(CACHE_CONTROL),
+        );
+        response
+    }
+}
+
+/// Strong entity tag, derived from the given data
+fn entity_tag(data: &[u8]) -> String {
+    format!("\"{}\"", Uuid::new_v5(&Uuid::NAMESPACE_OID, data).simple())
+}
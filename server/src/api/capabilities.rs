@@ -0,0 +1,613 @@
+use std::fmt::Write;
+
+use bytes::Bytes;
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use http::{header, Response, StatusCode};
+use serde_json::{json, Map, Value};
+
+use super::{status_response, DicomWebResponse};
+use crate::{
+    auth::{AuthScheme, Authentication},
+    query::{INSTANCE_PARAMS, SERIES_PARAMS, STUDY_PARAMS},
+    APPLICATION_DICOM_JSON,
+};
+
+const APPLICATION_WADL: &str = "application/vnd.sun.wadl+xml";
+const APPLICATION_OPENAPI_JSON: &str = "application/vnd.oai.openapi+json";
+// Frames are decoded to native pixel data
+const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Study,
+    Series,
+    Instance,
+}
+
+#[derive(Clone, Copy)]
+enum Operation {
+    Search(Level),
+    Retrieve,
+    RetrieveMetadata,
+    RetrieveFrames,
+    Store,
+    Update,
+    Metrics,
+}
+
+struct Method {
+    method: &'static str,
+    id: &'static str,
+    operation: Operation,
+}
+
+struct Resource {
+    path: &'static str,
+    methods: &'static [Method],
+}
+
+const fn method(method: &'static str, id: &'static str, operation: Operation) -> Method {
+    Method {
+        method,
+        id,
+        operation,
+    }
+}
+
+/// How the endpoints are served, which is described along with the DICOMweb resources
+#[derive(Clone, Copy, Default)]
+pub struct Deployment<'a> {
+    /// Authentication of the requests, whose verifiers are described as security schemes
+    pub authentication: Option<&'a Authentication>,
+    /// The metrics are served at `metrics` below the service root
+    pub metrics: bool,
+}
+
+// The endpoints served by every framework integration, below the service root
+const RESOURCES: [Resource; 14] = [
+    Resource {
+        path: "studies",
+        methods: &[
+            method("GET", "SearchForStudies", Operation::Search(Level::Study)),
+            method("POST", "StoreInstances", Operation::Store),
+        ],
+    },
+    Resource {
+        path: "series",
+        methods: &[method(
+            "GET",
+            "SearchForSeries",
+            Operation::Search(Level::Series),
+        )],
+    },
+    Resource {
+        path: "instances",
+        methods: &[method(
+            "GET",
+            "SearchForInstances",
+            Operation::Search(Level::Instance),
+        )],
+    },
+    Resource {
+        path: "studies/{study}",
+        methods: &[
+            method("GET", "RetrieveStudy", Operation::Retrieve),
+            method("POST", "StoreStudyInstances", Operation::Store),
+            method("PATCH", "UpdateStudy", Operation::Update),
+        ],
+    },
+    Resource {
+        path: "studies/{study}/metadata",
+        methods: &[method(
+            "GET",
+            "RetrieveStudyMetadata",
+            Operation::RetrieveMetadata,
+        )],
+    },
+    Resource {
+        path: "studies/{study}/series",
+        methods: &[method(
+            "GET",
+            "SearchForStudySeries",
+            Operation::Search(Level::Series),
+        )],
+    },
+    Resource {
+        path: "studies/{study}/instances",
+        methods: &[method(
+            "GET",
+            "SearchForStudyInstances",
+            Operation::Search(Level::Instance),
+        )],
+    },
+    Resource {
+        path: "studies/{study}/series/{series}",
+        methods: &[
+            method("GET", "RetrieveSeries", Operation::Retrieve),
+            method("PATCH", "UpdateSeries", Operation::Update),
+        ],
+    },
+    Resource {
+        path: "studies/{study}/series/{series}/metadata",
+        methods: &[method(
+            "GET",
+            "RetrieveSeriesMetadata",
+            Operation::RetrieveMetadata,
+        )],
+    },
+    Resource {
+        path: "studies/{study}/series/{series}/instances",
+        methods: &[method(
+            "GET",
+            "SearchForSeriesInstances",
+            Operation::Search(Level::Instance),
+        )],
+    },
+    Resource {
+        path: "studies/{study}/series/{series}/instances/{instance}",
+        methods: &[method("GET", "RetrieveInstance", Operation::Retrieve)],
+    },
+    Resource {
+        path: "studies/{study}/series/{series}/instances/{instance}/metadata",
+        methods: &[method(
+            "GET",
+            "RetrieveInstanceMetadata",
+            Operation::RetrieveMetadata,
+        )],
+    },
+    Resource {
+        path: "studies/{study}/series/{series}/instances/{instance}/frames/{frames}",
+        methods: &[method("GET", "RetrieveFrames", Operation::RetrieveFrames)],
+    },
+    // The service root only describes the others
+    Resource {
+        path: "",
+        methods: &[],
+    },
+];
+
+const METRICS_RESOURCE: Resource = Resource {
+    path: "metrics",
+    methods: &[method("GET", "RetrieveMetrics", Operation::Metrics)],
+};
+
+/// Paths of the DICOMweb resources below the service root, with their parameters in braces,
+/// e.g. `studies/{study}`
+pub fn resource_paths() -> impl Iterator<Item = &'static str> {
+    RESOURCES.iter().map(|resource| resource.path)
+}
+
+/// Resources of a deployment
+fn resources(deployment: Deployment<'_>) -> Vec<&'static Resource> {
+    let mut resources: Vec<&'static Resource> = RESOURCES.iter().collect();
+    if deployment.metrics {
+        resources.push(&METRICS_RESOURCE);
+    }
+    resources
+}
+
+/// Query parameter of an operation
+struct Param {
+    name: String,
+    description: &'static str,
+    repeating: bool,
+}
+
+impl Operation {
+    fn transaction(self) -> &'static str {
+        match self {
+            Operation::Search(_) => "QIDO-RS",
+            Operation::Retrieve | Operation::RetrieveMetadata | Operation::RetrieveFrames => {
+                "WADO-RS"
+            }
+            Operation::Store => "STOW-RS",
+            Operation::Update => "Metadata update",
+            Operation::Metrics => "Metrics",
+        }
+    }
+
+    fn query_params(self) -> Vec<Param> {
+        let names: &[&str] = match self {
+            Operation::Search(Level::Study) => &STUDY_PARAMS,
+            Operation::Search(Level::Series) => &SERIES_PARAMS,
+            Operation::Search(Level::Instance) => &INSTANCE_PARAMS,
+            Operation::Update => &["generateuids"],
+            _ => &[],
+        };
+        let mut params: Vec<Param> = names
+            .iter()
+            .map(|name| Param {
+                name: name.to_string(),
+                description: "",
+                repeating: *name == "includefield",
+            })
+            .collect();
+        if let Operation::Search(_) = self {
+            params.push(Param {
+                name: "{attributeID}".to_string(),
+                description: "Matching attribute, by keyword or as GGGGEEEE tag",
+                repeating: true,
+            });
+        }
+        params
+    }
+
+    /// Media types of the request body
+    fn request_media_types(self) -> Vec<String> {
+        match self {
+            Operation::Store => transfer_syntaxes(|ts| ts.can_decode_dataset())
+                .into_iter()
+                .map(|uid| {
+                    format!(
+                        "multipart/related; type=\"application/dicom\"; transfer-syntax={}",
+                        uid
+                    )
+                })
+                .collect(),
+            Operation::Update => vec![
+                APPLICATION_DICOM_JSON.to_string(),
+                mime::APPLICATION_JSON.to_string(),
+            ],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Media types of the response body
+    fn response_media_types(self) -> Vec<String> {
+        match self {
+            Operation::Search(_)
+            | Operation::RetrieveMetadata
+            | Operation::Store
+            | Operation::Update => vec![
+                APPLICATION_DICOM_JSON.to_string(),
+                mime::APPLICATION_JSON.to_string(),
+            ],
+            // Instances are returned with the transfer syntax they were stored with
+            Operation::Retrieve => {
+                vec!["multipart/related; type=\"application/dicom\"; transfer-syntax=*".to_string()]
+            }
+            Operation::RetrieveFrames => vec![format!(
+                "multipart/related; type=\"application/octet-stream\"; transfer-syntax={}",
+                EXPLICIT_VR_LITTLE_ENDIAN
+            )],
+            // Prometheus text format
+            Operation::Metrics => vec!["text/plain; version=0.0.4".to_string()],
+        }
+    }
+
+    /// Transfer syntaxes of the stored instances, which the operation supports
+    fn source_transfer_syntaxes(self) -> Option<Vec<&'static str>> {
+        match self {
+            Operation::Store => Some(transfer_syntaxes(|ts| ts.can_decode_dataset())),
+            Operation::RetrieveFrames => Some(transfer_syntaxes(|ts| ts.can_decode_all())),
+            _ => None,
+        }
+    }
+}
+
+/// Transfer syntaxes of the registry, which is extended by the enabled codecs
+fn transfer_syntaxes(
+    filter: impl Fn(&dicom::encoding::TransferSyntax) -> bool,
+) -> Vec<&'static str> {
+    let mut uids: Vec<&'static str> = TransferSyntaxRegistry
+        .iter()
+        .filter(|ts| filter(ts))
+        .map(|ts| ts.uid())
+        .collect();
+    uids.sort_unstable();
+    uids
+}
+
+fn path_params(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+}
+
+/// Find the resource at the end of a request path, so the endpoints can be nested below
+/// any prefix. Returns the path of the service root and the resource.
+fn resolve(path: &str, resources: &[&'static Resource]) -> (String, &'static Resource) {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let depth = |resource: &Resource| resource.path.split('/').filter(|s| !s.is_empty()).count();
+    let matches = |resource: &Resource| {
+        let template: Vec<&str> = resource.path.split('/').filter(|s| !s.is_empty()).collect();
+        template.len() <= segments.len()
+            && segments[segments.len() - template.len()..]
+                .iter()
+                .zip(&template)
+                .all(|(segment, expected)| expected.starts_with('{') || segment == expected)
+    };
+    let resource = resources
+        .iter()
+        .copied()
+        .filter(|resource| matches(resource))
+        .max_by_key(|resource| depth(resource))
+        .unwrap_or(&RESOURCES[RESOURCES.len() - 1]);
+    let base = format!(
+        "/{}",
+        segments[..segments.len() - depth(resource)].join("/")
+    );
+    (base, resource)
+}
+
+/// Methods allowed on the resource at exactly `path` below the service root, including
+/// OPTIONS, `None` if there is no such resource
+pub fn allowed_methods(path: &str, deployment: Deployment<'_>) -> Option<Vec<&'static str>> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    resources(deployment)
+        .into_iter()
+        .find(|resource| {
+            let template: Vec<&str> = resource.path.split('/').filter(|s| !s.is_empty()).collect();
+            template.len() == segments.len()
+                && segments
+                    .iter()
+                    .zip(&template)
+                    .all(|(segment, expected)| expected.starts_with('{') || segment == expected)
+        })
+        .map(allow)
+}
+
+fn allow(resource: &Resource) -> Vec<&'static str> {
+    resource
+        .methods
+        .iter()
+        .map(|method| method.method)
+        .chain(["OPTIONS"])
+        .collect()
+}
+
+/// Path of the service root of a request to any of the resources
+pub fn service_root(path: &str) -> String {
+    resolve(path, &RESOURCES.iter().collect::<Vec<_>>()).0
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Wadl,
+    OpenApi,
+}
+
+/// Format requested by an Accept header, WADL is the default of PS3.18 8.9
+fn requested_format(accept: Option<&str>) -> Option<Format> {
+    let Some(accept) = accept else {
+        return Some(Format::Wadl);
+    };
+    accept
+        .split(',')
+        .filter_map(|media_range| media_range.trim().parse::<mime::Mime>().ok())
+        .filter(|media_range| {
+            media_range
+                .get_param("q")
+                .is_none_or(|q| q.as_str().parse::<f32>().map_or(true, |q| q > 0.0))
+        })
+        .find_map(|media_range| match media_range.essence_str() {
+            APPLICATION_WADL | "application/xml" | "text/xml" | "*/*" | "application/*" => {
+                Some(Format::Wadl)
+            }
+            APPLICATION_OPENAPI_JSON | "application/json" => Some(Format::OpenApi),
+            _ => None,
+        })
+}
+
+/// Capabilities
+///
+/// Describe the resource at the end of `path`, or all resources at the service root,
+/// as WADL or as OpenAPI JSON. The description is generated from the endpoints of the
+/// deployment, the verifiers of its authentication and the transfer syntaxes of the enabled
+/// codecs.
+/// See https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_8.9
+pub fn capabilities(
+    path: &str,
+    accept: Option<&str>,
+    deployment: Deployment<'_>,
+) -> DicomWebResponse {
+    let Some(format) = requested_format(accept) else {
+        return status_response(StatusCode::NOT_ACCEPTABLE);
+    };
+    let all = resources(deployment);
+    let (base, resource) = resolve(path, &all);
+    let resources: Vec<&Resource> = if resource.path.is_empty() {
+        all.into_iter().filter(|r| !r.path.is_empty()).collect()
+    } else {
+        vec![resource]
+    };
+
+    let (content_type, body) = match format {
+        Format::Wadl => (APPLICATION_WADL, wadl(&base, &resources)),
+        Format::OpenApi => (
+            APPLICATION_OPENAPI_JSON,
+            serde_json::to_string_pretty(&openapi(&base, &resources, deployment))
+                .unwrap_or_default(),
+        ),
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ALLOW, allow(resource).join(", "))
+        .body(Bytes::from(body))
+        .expect("valid response")
+}
+
+fn openapi(base: &str, resources: &[&Resource], deployment: Deployment<'_>) -> Value {
+    let mut paths = Map::new();
+    for resource in resources {
+        let mut item = Map::new();
+        for method in resource.methods {
+            let operation = method.operation;
+            let mut parameters: Vec<Value> = path_params(resource.path)
+                .map(|name| {
+                    json!({
+                        "name": name,
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    })
+                })
+                .collect();
+            for param in operation.query_params() {
+                parameters.push(if param.name.starts_with('{') {
+                    // Any attribute can be matched, e.g. `PatientID=12345`
+                    json!({
+                        "name": "match",
+                        "in": "query",
+                        "description": param.description,
+                        "style": "form",
+                        "explode": true,
+                        "schema": {
+                            "type": "object",
+                            "additionalProperties": { "type": "string" },
+                        },
+                    })
+                } else {
+                    json!({
+                        "name": param.name,
+                        "in": "query",
+                        "schema": { "type": "string" },
+                    })
+                });
+            }
+            let content = |media_types: Vec<String>| -> Map<String, Value> {
+                media_types
+                    .into_iter()
+                    .map(|media_type| (media_type, json!({})))
+                    .collect()
+            };
+            let mut value = json!({
+                "operationId": method.id,
+                "tags": [operation.transaction()],
+                "parameters": parameters,
+                "responses": {
+                    "200": {
+                        "description": "Success",
+                        "content": content(operation.response_media_types()),
+                    },
+                },
+            });
+            let request_media_types = operation.request_media_types();
+            if !request_media_types.is_empty() {
+                value["requestBody"] = json!({
+                    "required": true,
+                    "content": content(request_media_types),
+                });
+            }
+            if let Some(transfer_syntaxes) = operation.source_transfer_syntaxes() {
+                value["x-transfer-syntaxes"] = json!(transfer_syntaxes);
+            }
+            item.insert(method.method.to_ascii_lowercase(), value);
+        }
+        paths.insert(format!("/{}", resource.path), Value::Object(item));
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "DICOMweb",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": base }],
+        "paths": paths,
+        "components": { "securitySchemes": security_schemes(deployment.authentication) },
+    })
+}
+
+/// Schemes of the configured verifiers
+fn security_schemes(authentication: Option<&Authentication>) -> Value {
+    let mut schemes = Map::new();
+    let Some(authentication) = authentication else {
+        return Value::Object(schemes);
+    };
+    for scheme in authentication.schemes() {
+        let (name, value) = match scheme {
+            AuthScheme::ApiKey => (
+                "apiKey",
+                json!({ "type": "apiKey", "in": "header", "name": authentication.api_key_header() }),
+            ),
+            AuthScheme::Bearer => (
+                "bearer",
+                json!({ "type": "http", "scheme": "bearer", "bearerFormat": "JWT" }),
+            ),
+            AuthScheme::Basic => ("basic", json!({ "type": "http", "scheme": "basic" })),
+            AuthScheme::Anonymous | AuthScheme::AeTitle => continue,
+        };
+        schemes.insert(name.to_string(), value);
+    }
+    Value::Object(schemes)
+}
+
+fn wadl(base: &str, resources: &[&Resource]) -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<application xmlns=\"http://wadl.dev.java.net/2009/02\" xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\">\n");
+    let _ = writeln!(
+        xml,
+        "  <resources base=\"{}\">",
+        escape(&format!("{}/", base.trim_end_matches('/')))
+    );
+    for resource in resources {
+        let _ = writeln!(xml, "    <resource path=\"{}\">", escape(resource.path));
+        for name in path_params(resource.path) {
+            let _ = writeln!(
+                xml,
+                "      <param name=\"{}\" style=\"template\" type=\"xsd:string\" required=\"true\"/>",
+                escape(name)
+            );
+        }
+        for method in resource.methods {
+            let operation = method.operation;
+            let _ = writeln!(
+                xml,
+                "      <method name=\"{}\" id=\"{}\">",
+                method.method, method.id
+            );
+            let _ = writeln!(
+                xml,
+                "        <doc title=\"{}\"/>",
+                escape(operation.transaction())
+            );
+            xml.push_str("        <request>\n");
+            for param in operation.query_params() {
+                let _ = write!(
+                    xml,
+                    "          <param name=\"{}\" style=\"query\" type=\"xsd:string\"",
+                    escape(&param.name)
+                );
+                if param.repeating {
+                    xml.push_str(" repeating=\"true\"");
+                }
+                if param.description.is_empty() {
+                    xml.push_str("/>\n");
+                } else {
+                    let _ = writeln!(xml, "><doc>{}</doc></param>", escape(param.description));
+                }
+            }
+            for media_type in operation.request_media_types() {
+                let _ = writeln!(
+                    xml,
+                    "          <representation mediaType=\"{}\"/>",
+                    escape(&media_type)
+                );
+            }
+            xml.push_str("        </request>\n");
+            xml.push_str("        <response status=\"200\">\n");
+            for media_type in operation.response_media_types() {
+                let _ = writeln!(
+                    xml,
+                    "          <representation mediaType=\"{}\"/>",
+                    escape(&media_type)
+                );
+            }
+            xml.push_str("        </response>\n");
+            xml.push_str("      </method>\n");
+        }
+        xml.push_str("    </resource>\n");
+    }
+    xml.push_str("  </resources>\n");
+    xml.push_str("</application>\n");
+    xml
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
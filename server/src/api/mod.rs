@@ -0,0 +1,171 @@
+//! Framework-agnostic implementation of the DICOMweb endpoints
+//!
+//! The framework integrations only extract the path, query, headers and body
+//! of a request and pass them to these functions. Negotiation, multipart handling
+//! and response building happen here, so every integration behaves the same.
+
+use bytes::Bytes;
+use dicom_json::DicomJson;
+use dicom_object::{FileDicomObject, InMemDicomObject};
+use http::{header, Response, StatusCode};
+
+use crate::{
+    auth::{AuthError, Authentication, Principal},
+    backend::{without_bulk_data, BackendError, NotFound},
+    multipart::MultipartWriter,
+    APPLICATION_DICOM_JSON,
+};
+
+pub mod caching;
+pub mod capabilities;
+pub mod qido;
+pub mod stow;
+pub mod update;
+pub mod wado;
+
+/// Response of a DICOMweb endpoint, convertible to the response type of any framework
+pub type DicomWebResponse = Response<Bytes>;
+
+/// Get the media type of a Content-Type header, without its parameters
+pub fn media_type(content_type: Option<&str>) -> Option<String> {
+    content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|media_type| media_type.trim().to_ascii_lowercase())
+}
+
+/// Check if an Accept header allows a DICOM JSON response
+pub fn accepts_dicom_json(accept: Option<&str>) -> bool {
+    // See https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_8.7.5
+    // "The response to a request without an Accept header field shall be 406 (Not Acceptable)"
+    let Some(accept) = accept else {
+        return false;
+    };
+
+    accept
+        .split(',')
+        .filter_map(|media_range| media_range.trim().parse::<mime::Mime>().ok())
+        .filter(|media_range| {
+            // Media ranges with a quality of 0 are not acceptable
+            media_range
+                .get_param("q")
+                .is_none_or(|q| q.as_str().parse::<f32>().map_or(true, |q| q > 0.0))
+        })
+        .any(|media_range| {
+            media_range.essence_str() == mime::APPLICATION_JSON.essence_str()
+                || media_range.essence_str() == APPLICATION_DICOM_JSON
+        })
+}
+
+/// Authenticate a request by its headers, or respond with 401 Unauthorized
+#[allow(clippy::result_large_err)]
+pub fn authenticate<'a>(
+    authentication: &Authentication,
+    header: impl Fn(&str) -> Option<&'a str>,
+) -> Result<Principal, DicomWebResponse> {
+    let authorization = header(header::AUTHORIZATION.as_str());
+    let api_key = header(authentication.api_key_header());
+    authentication
+        .authenticate(authorization, api_key)
+        .map_err(|e| {
+            log::debug!("Rejecting request: {}", e);
+            let mut response = error_response(StatusCode::UNAUTHORIZED, e);
+            for challenge in authentication.challenges() {
+                if let Ok(value) = challenge.parse() {
+                    response
+                        .headers_mut()
+                        .append(header::WWW_AUTHENTICATE, value);
+                }
+            }
+            response
+        })
+}
+
+/// Respond to a request which no authentication was configured for, rather than serving it
+/// anonymously. Anonymous access is enabled with [`Authentication::with_anonymous_access`].
+#[cfg(any(feature = "actix", feature = "axum", feature = "tower"))]
+pub(crate) fn unauthenticated_response() -> DicomWebResponse {
+    log::error!("Rejecting request: no authentication is configured for the endpoint");
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "No authentication is configured for the endpoint",
+    )
+}
+
+pub(crate) fn error_response(status: StatusCode, message: impl ToString) -> DicomWebResponse {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, mime::TEXT_PLAIN_UTF_8.as_ref())
+        .body(Bytes::from(message.to_string()))
+        .expect("valid response")
+}
+
+/// Respond to a backend error, studies the principal may not access are answered like unknown ones
+pub(crate) fn backend_error_response(e: BackendError) -> DicomWebResponse {
+    if let Some(AuthError::Denied) = e.downcast_ref::<AuthError>() {
+        return status_response(StatusCode::NOT_FOUND);
+    }
+    if e.is::<NotFound>() {
+        return status_response(StatusCode::NOT_FOUND);
+    }
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, e)
+}
+
+/// Respond to a failed store, which the principal isn't allowed to
+pub(crate) fn store_error_response(e: BackendError) -> DicomWebResponse {
+    if let Some(AuthError::Denied) = e.downcast_ref::<AuthError>() {
+        return error_response(StatusCode::FORBIDDEN, e);
+    }
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, e)
+}
+
+pub(crate) fn status_response(status: StatusCode) -> DicomWebResponse {
+    Response::builder()
+        .status(status)
+        .body(Bytes::new())
+        .expect("valid response")
+}
+
+pub(crate) fn json_response(dcm_list: Vec<InMemDicomObject>) -> DicomWebResponse {
+    match serde_json::to_vec(&DicomJson::from(dcm_list)) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Bytes::from(json))
+            .expect("valid response"),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Respond with the metadata of the given files, without their bulk data
+pub(crate) fn metadata_response(
+    dcm_files: Vec<FileDicomObject<InMemDicomObject>>,
+) -> DicomWebResponse {
+    json_response(dcm_files.into_iter().map(without_bulk_data).collect())
+}
+
+/// Respond with a multipart/related body, containing one part of `part_type` per item
+pub(crate) fn multipart_response<'a>(
+    parts: impl IntoIterator<Item = &'a [u8]>,
+    part_type: &str,
+) -> DicomWebResponse {
+    let mut mp = MultipartWriter::new();
+    for part in parts {
+        if let Err(e) = mp.add(part, &format!("Content-Type: {}", part_type)) {
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, e);
+        }
+    }
+
+    // Finish the multipart stream
+    mp.finish();
+
+    let content_type = format!(
+        "multipart/related; type=\"{}\"; boundary={}",
+        part_type, mp.boundary
+    );
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Bytes::from(mp.data))
+        .expect("valid response")
+}
@@ -0,0 +1,210 @@
+use dicom_object::InMemDicomObject;
+use http::StatusCode;
+use tracing::{field::Empty, Instrument};
+
+use super::{
+    accepts_dicom_json, backend_error_response, error_response, json_response, status_response,
+    DicomWebResponse,
+};
+use crate::{
+    auth::Principal,
+    backend::{BackendError, DicomWebBackend},
+    QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery,
+};
+
+// Number of results returned, if the query has no limit
+const DEFAULT_LIMIT: usize = 100;
+
+/// QIDO-RS
+///
+/// The query string is parsed here, parameters which aren't part of the query
+/// structs are matching attributes.
+/// See https://www.dicomstandard.org/using/dicomweb/query-qido-rs for more information
+#[tracing::instrument(skip_all, fields(query_params = %param_names(query), results = Empty))]
+pub async fn search_studies(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    accept: Option<&str>,
+    query: Option<&str>,
+) -> DicomWebResponse {
+    if !accepts_dicom_json(accept) {
+        return status_response(StatusCode::NOT_ACCEPTABLE);
+    }
+
+    let mut query = match QidoStudyQuery::from_query_string(query.unwrap_or("")) {
+        Ok(query) => query,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    query.limit.get_or_insert(DEFAULT_LIMIT);
+
+    // Get the matching DICOM objects from the backend
+    search_response(
+        backend
+            .search_study(principal, &query)
+            .instrument(tracing::info_span!("backend.search_study"))
+            .await,
+    )
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(study_instance_uid = study_uid, query_params = %param_names(query), results = Empty)
+)]
+pub async fn search_series(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    accept: Option<&str>,
+    study_uid: Option<&str>,
+    query: Option<&str>,
+) -> DicomWebResponse {
+    if !accepts_dicom_json(accept) {
+        return status_response(StatusCode::NOT_ACCEPTABLE);
+    }
+
+    let mut query = match QidoSeriesQuery::from_query_string(query.unwrap_or("")) {
+        Ok(query) => query,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    query.limit.get_or_insert(DEFAULT_LIMIT);
+
+    search_response(
+        backend
+            .search_series(principal, study_uid, &query)
+            .instrument(tracing::info_span!("backend.search_series"))
+            .await,
+    )
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        study_instance_uid = study_uid,
+        series_instance_uid = series_uid,
+        query_params = %param_names(query),
+        results = Empty
+    )
+)]
+pub async fn search_instances(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    accept: Option<&str>,
+    study_uid: Option<&str>,
+    series_uid: Option<&str>,
+    query: Option<&str>,
+) -> DicomWebResponse {
+    if !accepts_dicom_json(accept) {
+        return status_response(StatusCode::NOT_ACCEPTABLE);
+    }
+
+    let mut query = match QidoInstanceQuery::from_query_string(query.unwrap_or("")) {
+        Ok(query) => query,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    query.limit.get_or_insert(DEFAULT_LIMIT);
+
+    search_response(
+        backend
+            .search_instances(principal, study_uid, series_uid, &query)
+            .instrument(tracing::info_span!("backend.search_instances"))
+            .await,
+    )
+}
+
+/// Names of the query parameters for the spans, their values may identify the patient
+fn param_names(query: Option<&str>) -> String {
+    let mut names: Vec<String> = Vec::new();
+    for (name, _) in
+        serde_urlencoded::from_str::<Vec<(String, String)>>(query.unwrap_or("")).unwrap_or_default()
+    {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names.join(",")
+}
+
+fn search_response(result: Result<Vec<InMemDicomObject>, BackendError>) -> DicomWebResponse {
+    match result {
+        Ok(dcm_list) => {
+            tracing::Span::current().record("results", dcm_list.len());
+            json_response(dcm_list)
+        }
+        Err(e) => backend_error_response(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt::Debug,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        instrument::WithSubscriber,
+        span, Event, Metadata, Subscriber,
+    };
+
+    use super::*;
+    use crate::{backend::InMemoryBackend, testing};
+
+    /// Collects the fields of all spans
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl Visit for Collector {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+            span.record(&mut self.clone());
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &span::Id, values: &span::Record<'_>) {
+            values.record(&mut self.clone());
+        }
+
+        fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+        fn event(&self, _event: &Event<'_>) {}
+
+        fn enter(&self, _span: &span::Id) {}
+
+        fn exit(&self, _span: &span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn spans_omit_the_matching_values() {
+        let collector = Collector::default();
+        let response = search_studies(
+            &InMemoryBackend::new(),
+            &testing::principal(),
+            Some("application/dicom+json"),
+            Some("PatientName=Doe%5EJohn&PatientID=PID-1&limit=10"),
+        )
+        .with_subscriber(collector.clone())
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let fields = collector.0.lock().unwrap();
+        assert!(fields.contains(&(
+            "query_params".to_string(),
+            "PatientName,PatientID,limit".to_string()
+        )));
+        assert!(!fields
+            .iter()
+            .any(|(_, value)| value.contains("Doe") || value.contains("PID-1")));
+    }
+}
@@ -0,0 +1,252 @@
+use bytes::Bytes;
+use dicom::dictionary_std::tags;
+use dicom_object::{FileDicomObject, InMemDicomObject};
+use futures_util::{Stream, StreamExt};
+use http::StatusCode;
+use tracing::{field::Empty, Instrument};
+
+use super::{
+    error_response, media_type, metadata_response, store_error_response, DicomWebResponse,
+};
+use crate::{
+    auth::Principal,
+    backend::{BackendError, DicomWebBackend},
+    multipart::MultipartReader,
+    RejectionNote,
+};
+
+#[tracing::instrument(name = "parse_multipart", skip_all, fields(instances = Empty))]
+async fn collect_dicom_files<S, E>(
+    content_type: Option<&str>,
+    body: S,
+) -> Result<Vec<FileDicomObject<InMemDicomObject>>, String>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut dicom_files = Vec::new();
+
+    let mut multipart = MultipartReader::from_content_type(content_type, body);
+    // iterate over multipart stream
+    while let Some(item) = multipart.next().await {
+        let mut obj = match item {
+            Ok(obj) => obj,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let inner_content_type = obj.content_type();
+        match inner_content_type {
+            Some(inner_content_type) => {
+                if *inner_content_type == "application/dicom" {
+                    let mut data: Vec<u8> = Vec::new();
+
+                    // Merge chunks into one array
+                    while let Some(chunk) = obj.next().await {
+                        match chunk {
+                            Ok(chunk) => data.extend_from_slice(&chunk),
+                            Err(e) => return Err(e.to_string()),
+                        }
+                    }
+                    dicom_files.push(FileDicomObject::from_reader(data.as_slice()));
+                } else {
+                    return Err(format!("Unsupported content type: {}", inner_content_type));
+                }
+            }
+            None => return Err(String::from("Missing content type")),
+        }
+    }
+
+    // Filter the failed DICOM files
+    let dicom_files: Vec<_> = dicom_files
+        .into_iter()
+        .filter_map(|dcm| match dcm {
+            Ok(dcm) => Some(dcm),
+            Err(e) => {
+                log::error!("Failed to parse DICOM file: {}", e);
+                None
+            }
+        })
+        .collect();
+    tracing::Span::current().record("instances", dicom_files.len());
+
+    Ok(dicom_files)
+}
+
+/// Apply the rejection notes among the instances, then store all of them.
+///
+/// Shared by STOW-RS and the DIMSE storage SCP.
+pub async fn ingest_instances(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    instances: &[FileDicomObject<InMemDicomObject>],
+) -> Result<(), BackendError> {
+    backend
+        .store_instances(principal, instances)
+        .instrument(tracing::info_span!("backend.store_instances"))
+        .await?;
+
+    // Rejection notes hide or delete the instances they reference. The notes
+    // themselves are stored first like any other instance to keep them for auditing.
+    for dcm in instances {
+        if let Some(note) = RejectionNote::from_dicom(dcm) {
+            backend
+                .reject_instances(principal, &note)
+                .instrument(tracing::info_span!("backend.reject_instances"))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// STOW-RS
+///
+/// Store the instances of a multipart/related body. If a study is given, instances
+/// of other studies are skipped.
+/// See https://www.dicomstandard.org/using/dicomweb/store-stow-rs for more information
+#[tracing::instrument(skip_all, fields(study_instance_uid = study_uid, results = Empty))]
+pub async fn store_instances<S, E>(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    study_uid: Option<&str>,
+    content_type: Option<&str>,
+    body: S,
+) -> DicomWebResponse
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    // Check if the content type is multipart/related
+    if media_type(content_type).as_deref() != Some("multipart/related") {
+        return error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported media type");
+    }
+
+    // Collect the DICOM files
+    let mut dicom_files = match collect_dicom_files(content_type, body).await {
+        Ok(dicom_files) => dicom_files,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    if let Some(study_uid) = study_uid {
+        dicom_files.retain(|dcm| {
+            let matches = dcm
+                .element(tags::STUDY_INSTANCE_UID)
+                .ok()
+                .and_then(|elt| elt.to_str().ok())
+                .is_some_and(|uid| uid.trim_end_matches('\0') == study_uid);
+            if !matches {
+                log::warn!(
+                    "Skipping instance, which is not part of study {}",
+                    study_uid
+                );
+            }
+            matches
+        });
+    }
+
+    if let Err(e) = ingest_instances(backend, principal, &dicom_files).await {
+        return store_error_response(e);
+    }
+    tracing::Span::current().record("results", dicom_files.len());
+
+    // Respond with the stored instances, without their pixel data
+    metadata_response(dicom_files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use dicom::{
+        core::{value::DataSetSequence, DataElement, Length, VR},
+        dictionary_std::uids,
+    };
+
+    use super::*;
+    use crate::{testing, DicomWebServer, RejectionReason};
+
+    const STUDY_UID: &str = "1.2.840.10008.26.1";
+    const REJECTED_SERIES_UID: &str = "1.2.840.10008.26.1.1";
+    const REJECTED_SOP_UID: &str = "1.2.840.10008.26.1.1.1";
+    const NOTE_SERIES_UID: &str = "1.2.840.10008.26.1.2";
+    const NOTE_SOP_UID: &str = "1.2.840.10008.26.1.2.1";
+
+    static NOTE_STORED: AtomicBool = AtomicBool::new(false);
+    static NOTE_APPLIED: AtomicBool = AtomicBool::new(false);
+
+    fn sequence(tag: dicom_object::Tag, item: InMemDicomObject) -> DataElement<InMemDicomObject> {
+        DataElement::new(
+            tag,
+            VR::SQ,
+            DataSetSequence::new(vec![item], Length::UNDEFINED),
+        )
+    }
+
+    /// Key object selection document, which rejects a CT slice for quality reasons
+    fn rejection_note() -> FileDicomObject<InMemDicomObject> {
+        let series = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, REJECTED_SERIES_UID),
+            sequence(
+                tags::REFERENCED_SOP_SEQUENCE,
+                InMemDicomObject::from_element_iter([
+                    DataElement::new(
+                        tags::REFERENCED_SOP_CLASS_UID,
+                        VR::UI,
+                        uids::CT_IMAGE_STORAGE,
+                    ),
+                    DataElement::new(tags::REFERENCED_SOP_INSTANCE_UID, VR::UI, REJECTED_SOP_UID),
+                ]),
+            ),
+        ]);
+        testing::file(InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                uids::KEY_OBJECT_SELECTION_DOCUMENT_STORAGE,
+            ),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, NOTE_SOP_UID),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, NOTE_SERIES_UID),
+            DataElement::new(tags::MODALITY, VR::CS, "KO"),
+            sequence(
+                tags::CONCEPT_NAME_CODE_SEQUENCE,
+                InMemDicomObject::from_element_iter([
+                    DataElement::new(tags::CODE_VALUE, VR::SH, "113001"),
+                    DataElement::new(tags::CODING_SCHEME_DESIGNATOR, VR::SH, "DCM"),
+                    DataElement::new(tags::CODE_MEANING, VR::LO, "Rejected for Quality Reasons"),
+                ]),
+            ),
+            sequence(
+                tags::CURRENT_REQUESTED_PROCEDURE_EVIDENCE_SEQUENCE,
+                InMemDicomObject::from_element_iter([
+                    DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+                    sequence(tags::REFERENCED_SERIES_SEQUENCE, series),
+                ]),
+            ),
+        ]))
+    }
+
+    #[tokio::test]
+    async fn stores_rejection_notes_before_rejecting() {
+        let server = DicomWebServer {
+            store_instances: |_| {
+                NOTE_STORED.store(true, Ordering::SeqCst);
+                Ok(())
+            },
+            reject_instances: |note| match NOTE_STORED.load(Ordering::SeqCst) {
+                true => {
+                    assert_eq!(note.reason, RejectionReason::QualityReasons);
+                    assert_eq!(note.instances[0].sop_instance_uid, REJECTED_SOP_UID);
+                    NOTE_APPLIED.store(true, Ordering::SeqCst);
+                    Ok(())
+                }
+                false => Err("The rejection note wasn't stored".into()),
+            },
+            ..DicomWebServer::default()
+        };
+
+        ingest_instances(&server, &testing::principal(), &[rejection_note()])
+            .await
+            .unwrap();
+        assert!(NOTE_APPLIED.load(Ordering::SeqCst));
+    }
+}
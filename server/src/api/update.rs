@@ -0,0 +1,161 @@
+use dicom_object::{FileDicomObject, InMemDicomObject};
+use http::StatusCode;
+use tracing::{field::Empty, Instrument};
+
+use super::{
+    backend_error_response, error_response, media_type, metadata_response, status_response,
+    store_error_response, DicomWebResponse,
+};
+use crate::{
+    auth::Principal, backend::DicomWebBackend, InstanceReference, InstanceUpdate, UpdateQuery,
+    APPLICATION_DICOM_JSON,
+};
+
+/// Metadata update
+///
+/// Apply a DICOM JSON dataset of changed attributes to every instance of the study
+#[tracing::instrument(skip_all, fields(study_instance_uid = study_uid, results = Empty))]
+pub async fn update_study(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    study_uid: &str,
+    content_type: Option<&str>,
+    query: &UpdateQuery,
+    body: &[u8],
+) -> DicomWebResponse {
+    match backend
+        .retrieve_study(principal, study_uid)
+        .instrument(tracing::info_span!("backend.retrieve_study"))
+        .await
+    {
+        Ok(dcm_files) => {
+            update_instances(
+                backend,
+                principal,
+                dcm_files,
+                false,
+                content_type,
+                query,
+                body,
+            )
+            .await
+        }
+        Err(e) => backend_error_response(e),
+    }
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(study_instance_uid = study_uid, series_instance_uid = series_uid, results = Empty)
+)]
+pub async fn update_series(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    study_uid: &str,
+    series_uid: &str,
+    content_type: Option<&str>,
+    query: &UpdateQuery,
+    body: &[u8],
+) -> DicomWebResponse {
+    match backend
+        .retrieve_series(principal, study_uid, series_uid)
+        .instrument(tracing::info_span!("backend.retrieve_series"))
+        .await
+    {
+        Ok(dcm_files) => {
+            update_instances(
+                backend,
+                principal,
+                dcm_files,
+                true,
+                content_type,
+                query,
+                body,
+            )
+            .await
+        }
+        Err(e) => backend_error_response(e),
+    }
+}
+
+async fn update_instances(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    mut dcm_files: Vec<FileDicomObject<InMemDicomObject>>,
+    series: bool,
+    content_type: Option<&str>,
+    query: &UpdateQuery,
+    body: &[u8],
+) -> DicomWebResponse {
+    let media_type = media_type(content_type);
+    if media_type.as_deref() != Some(APPLICATION_DICOM_JSON)
+        && media_type.as_deref() != Some(mime::APPLICATION_JSON.essence_str())
+    {
+        return error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported media type");
+    }
+
+    if dcm_files.is_empty() {
+        return status_response(StatusCode::NOT_FOUND);
+    }
+
+    let generate_uids = query.generateuids.unwrap_or(false);
+    let mut update = match InstanceUpdate::from_json(body, generate_uids) {
+        Ok(update) if series => update.for_series(),
+        Ok(update) => update,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    // Remember the original instances, in case they get replaced
+    let originals: Vec<InstanceReference> = dcm_files
+        .iter()
+        .filter_map(|dcm| InstanceReference::from_dicom(dcm))
+        .collect();
+
+    for dcm in &mut dcm_files {
+        update.apply(dcm);
+    }
+
+    // Store the updated files
+    if let Err(e) = backend
+        .update_instances(principal, &dcm_files)
+        .instrument(tracing::info_span!("backend.update_instances"))
+        .await
+    {
+        return store_error_response(e);
+    }
+
+    // Instances with new UIDs don't overwrite the originals
+    if generate_uids {
+        if let Err(e) = backend
+            .delete_instances(principal, &originals)
+            .instrument(tracing::info_span!("backend.delete_instances"))
+            .await
+        {
+            // Remove the updated copies again, so the originals aren't duplicated
+            let updated: Vec<InstanceReference> = dcm_files
+                .iter()
+                .filter_map(|dcm| InstanceReference::from_dicom(dcm))
+                .collect();
+            if let Err(rollback) = backend
+                .delete_instances(principal, &updated)
+                .instrument(tracing::info_span!("backend.delete_instances"))
+                .await
+            {
+                log::error!("Failed to remove the updated instances: {}", rollback);
+                return store_error_response(
+                    format!(
+                        "The originals were kept next to the updated instances: {}",
+                        e
+                    )
+                    .into(),
+                );
+            }
+            return store_error_response(e);
+        }
+    }
+
+    tracing::Span::current().record("results", dcm_files.len());
+
+    // Respond with the updated instances, without their pixel data
+    metadata_response(dcm_files)
+}
@@ -0,0 +1,459 @@
+use std::future::Future;
+
+use dicom_object::{FileDicomObject, InMemDicomObject};
+use http::StatusCode;
+use tracing::{field::Empty, Instrument};
+
+use super::{
+    backend_error_response,
+    caching::{Preconditions, Validators},
+    error_response, json_response, metadata_response, multipart_response, status_response,
+    DicomWebResponse,
+};
+use crate::{auth::Principal, backend::DicomWebBackend};
+
+/// WADO-RS
+///
+/// See https://www.dicomstandard.org/using/dicomweb/retrieve-wado-rs-and-wado-uri for more information
+#[tracing::instrument(skip_all, fields(study_instance_uid = study_uid, results = Empty))]
+pub async fn retrieve_study(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    study_uid: &str,
+    preconditions: &Preconditions<'_>,
+) -> DicomWebResponse {
+    conditional_response(
+        backend,
+        principal,
+        study_uid,
+        None,
+        study_uid,
+        preconditions,
+        async {
+            match backend
+                .retrieve_study(principal, study_uid)
+                .instrument(tracing::info_span!("backend.retrieve_study"))
+                .await
+            {
+                Ok(dcm_files) => {
+                    record_results(dcm_files.len());
+                    instances_response(&dcm_files)
+                }
+                Err(e) => (backend_error_response(e), None),
+            }
+        },
+    )
+    .await
+}
+
+#[tracing::instrument(skip_all, fields(study_instance_uid = study_uid, results = Empty))]
+pub async fn retrieve_study_metadata(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    study_uid: &str,
+    preconditions: &Preconditions<'_>,
+) -> DicomWebResponse {
+    conditional_response(
+        backend,
+        principal,
+        study_uid,
+        None,
+        &format!("{}/metadata", study_uid),
+        preconditions,
+        async {
+            match backend
+                .retrieve_study(principal, study_uid)
+                .instrument(tracing::info_span!("backend.retrieve_study"))
+                .await
+            {
+                Ok(dcm_files) if dcm_files.is_empty() => (not_found(), None),
+                Ok(dcm_files) => {
+                    record_results(dcm_files.len());
+                    (metadata_response(dcm_files), None)
+                }
+                Err(e) => (backend_error_response(e), None),
+            }
+        },
+    )
+    .await
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(study_instance_uid = study_uid, series_instance_uid = series_uid, results = Empty)
+)]
+pub async fn retrieve_series(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    study_uid: &str,
+    series_uid: &str,
+    preconditions: &Preconditions<'_>,
+) -> DicomWebResponse {
+    conditional_response(
+        backend,
+        principal,
+        study_uid,
+        Some(series_uid),
+        &format!("{}/{}", study_uid, series_uid),
+        preconditions,
+        async {
+            match backend
+                .retrieve_series(principal, study_uid, series_uid)
+                .instrument(tracing::info_span!("backend.retrieve_series"))
+                .await
+            {
+                Ok(dcm_files) => {
+                    record_results(dcm_files.len());
+                    instances_response(&dcm_files)
+                }
+                Err(e) => (backend_error_response(e), None),
+            }
+        },
+    )
+    .await
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(study_instance_uid = study_uid, series_instance_uid = series_uid, results = Empty)
+)]
+pub async fn retrieve_series_metadata(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    study_uid: &str,
+    series_uid: &str,
+    preconditions: &Preconditions<'_>,
+) -> DicomWebResponse {
+    conditional_response(
+        backend,
+        principal,
+        study_uid,
+        Some(series_uid),
+        &format!("{}/{}/metadata", study_uid, series_uid),
+        preconditions,
+        async {
+            match backend
+                .retrieve_series_metadata(principal, study_uid, series_uid)
+                .instrument(tracing::info_span!("backend.retrieve_series_metadata"))
+                .await
+            {
+                Ok(dcm_list) if dcm_list.is_empty() => (not_found(), None),
+                Ok(dcm_list) => {
+                    record_results(dcm_list.len());
+                    (json_response(dcm_list), None)
+                }
+                Err(e) => (backend_error_response(e), None),
+            }
+        },
+    )
+    .await
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        study_instance_uid = study_uid,
+        series_instance_uid = series_uid,
+        sop_instance_uid = instance_uid,
+        results = Empty
+    )
+)]
+pub async fn retrieve_instance(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    study_uid: &str,
+    series_uid: &str,
+    instance_uid: &str,
+    preconditions: &Preconditions<'_>,
+) -> DicomWebResponse {
+    conditional_response(
+        backend,
+        principal,
+        study_uid,
+        Some(series_uid),
+        &format!("{}/{}/{}", study_uid, series_uid, instance_uid),
+        preconditions,
+        async {
+            match backend
+                .retrieve_instance(principal, study_uid, series_uid, instance_uid)
+                .instrument(tracing::info_span!("backend.retrieve_instance"))
+                .await
+            {
+                Ok(dcm_file) => {
+                    record_results(1);
+                    instances_response(&[dcm_file])
+                }
+                Err(e) => (backend_error_response(e), None),
+            }
+        },
+    )
+    .await
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        study_instance_uid = study_uid,
+        series_instance_uid = series_uid,
+        sop_instance_uid = instance_uid,
+        results = Empty
+    )
+)]
+pub async fn retrieve_instance_metadata(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    study_uid: &str,
+    series_uid: &str,
+    instance_uid: &str,
+    preconditions: &Preconditions<'_>,
+) -> DicomWebResponse {
+    conditional_response(
+        backend,
+        principal,
+        study_uid,
+        Some(series_uid),
+        &format!("{}/{}/{}/metadata", study_uid, series_uid, instance_uid),
+        preconditions,
+        async {
+            match backend
+                .retrieve_instance(principal, study_uid, series_uid, instance_uid)
+                .instrument(tracing::info_span!("backend.retrieve_instance"))
+                .await
+            {
+                Ok(dcm_file) => {
+                    record_results(1);
+                    (metadata_response(vec![dcm_file]), None)
+                }
+                Err(e) => (backend_error_response(e), None),
+            }
+        },
+    )
+    .await
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        study_instance_uid = study_uid,
+        series_instance_uid = series_uid,
+        sop_instance_uid = instance_uid,
+        frames = frame_list,
+        results = Empty
+    )
+)]
+pub async fn retrieve_instance_frames(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    study_uid: &str,
+    series_uid: &str,
+    instance_uid: &str,
+    frame_list: &str,
+    preconditions: &Preconditions<'_>,
+) -> DicomWebResponse {
+    // Frames are numbered from 1
+    let frames: Vec<u32> = match frame_list
+        .split(',')
+        .map(|frame| frame.trim().parse::<u32>().ok().filter(|frame| *frame > 0))
+        .collect()
+    {
+        Some(frames) => frames,
+        None => return error_response(StatusCode::BAD_REQUEST, "Invalid frame list"),
+    };
+
+    conditional_response(
+        backend,
+        principal,
+        study_uid,
+        Some(series_uid),
+        &format!(
+            "{}/{}/{}/frames/{}",
+            study_uid, series_uid, instance_uid, frame_list
+        ),
+        preconditions,
+        async {
+            match backend
+                .retrieve_frames(principal, study_uid, series_uid, instance_uid, &frames)
+                .instrument(tracing::info_span!("backend.retrieve_frames"))
+                .await
+            {
+                Ok(frames) => {
+                    record_results(frames.len());
+                    let parts = || frames.iter().map(|frame| frame.as_ref());
+                    (
+                        multipart_response(parts(), "application/octet-stream"),
+                        Some(Validators::from_parts(parts())),
+                    )
+                }
+                Err(e) => (backend_error_response(e), None),
+            }
+        },
+    )
+    .await
+}
+
+/// Respond to a retrieval, unless the client's representation is still current.
+///
+/// The validators are taken from the version of the backend, which avoids the retrieval
+/// of unmodified resources, or are otherwise derived from the content returned along with
+/// the response, or from its body.
+async fn conditional_response(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    study_uid: &str,
+    series_uid: Option<&str>,
+    representation: &str,
+    preconditions: &Preconditions<'_>,
+    respond: impl Future<Output = (DicomWebResponse, Option<Validators>)>,
+) -> DicomWebResponse {
+    let validators = match backend
+        .version(principal, study_uid, series_uid)
+        .instrument(tracing::info_span!("backend.version"))
+        .await
+    {
+        Ok(version) => version.map(|version| Validators::from_version(&version, representation)),
+        Err(e) => return backend_error_response(e),
+    };
+    if let Some(validators) = &validators {
+        if validators.is_fresh(preconditions) {
+            return validators.not_modified();
+        }
+    }
+
+    let (response, content_validators) = respond.await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let validators = validators
+        .or(content_validators)
+        .unwrap_or_else(|| Validators::from_content(response.body()));
+    if validators.is_fresh(preconditions) {
+        return validators.not_modified();
+    }
+    validators.apply(response)
+}
+
+/// Unknown studies and series, and the ones without any visible instance, don't exist
+fn not_found() -> DicomWebResponse {
+    status_response(StatusCode::NOT_FOUND)
+}
+
+fn instances_response(
+    dcm_files: &[FileDicomObject<InMemDicomObject>],
+) -> (DicomWebResponse, Option<Validators>) {
+    if dcm_files.is_empty() {
+        return (not_found(), None);
+    }
+    let mut parts: Vec<Vec<u8>> = Vec::with_capacity(dcm_files.len());
+    for dcm_file in dcm_files {
+        let mut data: Vec<u8> = Vec::new();
+
+        // Write the DICOM file to memory and add it to our stream
+        if let Err(e) = dcm_file.write_all(&mut data) {
+            return (error_response(StatusCode::INTERNAL_SERVER_ERROR, e), None);
+        }
+        parts.push(data);
+    }
+
+    (
+        multipart_response(parts.iter().map(Vec::as_slice), "application/dicom"),
+        Some(Validators::from_parts(parts.iter().map(Vec::as_slice))),
+    )
+}
+
+fn record_results(results: usize) {
+    tracing::Span::current().record("results", results);
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{DataElement, VR},
+        dictionary_std::{tags, uids},
+    };
+    use http::header;
+
+    use super::*;
+    use crate::{backend::InMemoryBackend, testing};
+
+    const STUDY_UID: &str = "1.2.840.10008.49.1";
+    const SERIES_UID: &str = "1.2.840.10008.49.1.1";
+    const SOP_UID: &str = "1.2.840.10008.49.1.1.1";
+    const UNKNOWN_UID: &str = "1.2.840.10008.49.9";
+
+    /// Backend with the bitewing radiograph of a dental checkup
+    async fn dental_backend() -> InMemoryBackend {
+        let mut bitewing = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                uids::DIGITAL_INTRA_ORAL_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+            ),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, SOP_UID),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, SERIES_UID),
+            DataElement::new(tags::MODALITY, VR::CS, "IO"),
+        ]);
+        bitewing.extend(testing::grayscale_frames(2, 2, 1));
+
+        let backend = InMemoryBackend::new();
+        backend
+            .store_instances(&testing::principal(), &[testing::file(bitewing)])
+            .await
+            .unwrap();
+        backend
+    }
+
+    #[tokio::test]
+    async fn multipart_responses_keep_their_etag() {
+        let backend = dental_backend().await;
+        let retrieve = |preconditions| {
+            let backend = &backend;
+            async move {
+                retrieve_study(backend, &testing::principal(), STUDY_UID, &preconditions).await
+            }
+        };
+
+        let first = retrieve(Preconditions::default()).await;
+        let second = retrieve(Preconditions::default()).await;
+        let etag = first.headers()[header::ETAG].to_str().unwrap();
+        assert_ne!(first.body(), second.body());
+        assert_eq!(second.headers()[header::ETAG], etag);
+
+        let revalidated = retrieve(Preconditions {
+            if_none_match: Some(etag),
+            ..Preconditions::default()
+        })
+        .await;
+        assert_eq!(revalidated.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn unknown_resources_are_not_found() {
+        let backend = dental_backend().await;
+        let principal = testing::principal();
+        let preconditions = Preconditions::default();
+
+        let responses = [
+            retrieve_study(&backend, &principal, UNKNOWN_UID, &preconditions).await,
+            retrieve_study_metadata(&backend, &principal, UNKNOWN_UID, &preconditions).await,
+            retrieve_series(&backend, &principal, STUDY_UID, UNKNOWN_UID, &preconditions).await,
+            retrieve_series_metadata(&backend, &principal, STUDY_UID, UNKNOWN_UID, &preconditions)
+                .await,
+            retrieve_instance(
+                &backend,
+                &principal,
+                STUDY_UID,
+                SERIES_UID,
+                UNKNOWN_UID,
+                &preconditions,
+            )
+            .await,
+        ];
+        for response in responses {
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+        let response =
+            retrieve_series(&backend, &principal, STUDY_UID, SERIES_UID, &preconditions).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
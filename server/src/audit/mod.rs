@@ -0,0 +1,295 @@
+//! Audit trail of the DICOMweb transactions
+//!
+//! [`AuditMessage`]s follow the DICOM audit message schema of PS3.15 A.5 and are
+//! written to [`AuditSink`]s. Use [`crate::backend::AuditedBackend`] to audit every
+//! search, retrieval, store, update, rejection and deletion of a backend.
+//! See https://dicom.nema.org/medical/dicom/current/output/html/part15.html#sect_A.5
+
+use std::fmt::Write;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::backend::BackendError;
+
+mod sink;
+
+#[cfg(unix)]
+pub use sink::SyslogSink;
+pub use sink::{FileSink, MemorySink};
+
+/// Audited event, see PS3.15 A.5.3
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A QIDO-RS search
+    Query,
+    /// A WADO-RS retrieval was requested
+    BeginTransferring,
+    /// Instances were retrieved with WADO-RS or stored with STOW-RS
+    InstancesTransferred,
+    /// Instances were updated, rejected or deleted
+    InstancesAccessed,
+}
+
+impl AuditEvent {
+    fn code(self) -> (&'static str, &'static str) {
+        match self {
+            AuditEvent::Query => ("110112", "Query"),
+            AuditEvent::BeginTransferring => ("110102", "Begin Transferring DICOM Instances"),
+            AuditEvent::InstancesTransferred => ("110104", "DICOM Instances Transferred"),
+            AuditEvent::InstancesAccessed => ("110103", "DICOM Instances Accessed"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventAction {
+    Create,
+    Read,
+    Update,
+    Delete,
+    Execute,
+}
+
+impl EventAction {
+    fn code(self) -> &'static str {
+        match self {
+            EventAction::Create => "C",
+            EventAction::Read => "R",
+            EventAction::Update => "U",
+            EventAction::Delete => "D",
+            EventAction::Execute => "E",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventOutcome {
+    Success,
+    MinorFailure,
+    SeriousFailure,
+    MajorFailure,
+}
+
+impl EventOutcome {
+    fn code(self) -> u8 {
+        match self {
+            EventOutcome::Success => 0,
+            EventOutcome::MinorFailure => 4,
+            EventOutcome::SeriousFailure => 8,
+            EventOutcome::MajorFailure => 12,
+        }
+    }
+}
+
+/// Patient whose data was accessed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuditPatient {
+    pub id: String,
+    pub issuer: Option<String>,
+    pub name: Option<String>,
+}
+
+/// Study whose instances were accessed, with the number of instances per SOP class
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuditStudy {
+    pub uid: String,
+    pub sop_classes: Vec<(String, usize)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditMessage {
+    pub event: AuditEvent,
+    pub action: EventAction,
+    pub outcome: EventOutcome,
+    pub time: DateTime<Utc>,
+    /// Subject of the principal, who requested the transaction
+    pub user_id: String,
+    /// Identifies the server, which audited the transaction
+    pub source_id: String,
+    /// Query parameters of a search
+    pub query: Option<String>,
+    pub patients: Vec<AuditPatient>,
+    pub studies: Vec<AuditStudy>,
+}
+
+impl AuditMessage {
+    pub fn new(
+        event: AuditEvent,
+        action: EventAction,
+        user_id: impl Into<String>,
+        source_id: impl Into<String>,
+    ) -> AuditMessage {
+        AuditMessage {
+            event,
+            action,
+            outcome: EventOutcome::Success,
+            time: Utc::now(),
+            user_id: user_id.into(),
+            source_id: source_id.into(),
+            query: None,
+            patients: Vec::new(),
+            studies: Vec::new(),
+        }
+    }
+
+    /// Serialize to the XML of the DICOM audit message schema, on a single line
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?><AuditMessage>");
+
+        let (event_code, event_name) = self.event.code();
+        let _ = write!(
+            xml,
+            "<EventIdentification EventActionCode=\"{}\" EventDateTime=\"{}\" EventOutcomeIndicator=\"{}\">",
+            self.action.code(),
+            self.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.outcome.code()
+        );
+        push_code(&mut xml, "EventID", event_code, "DCM", event_name);
+        xml.push_str("</EventIdentification>");
+
+        // Instances are transferred from the server to the requestor, unless they are stored
+        let (user_role, source_role) = match (self.event, self.action) {
+            (AuditEvent::Query, _) | (AuditEvent::InstancesAccessed, _) => (None, None),
+            (_, EventAction::Create) => (Some(SOURCE_ROLE), Some(DESTINATION_ROLE)),
+            _ => (Some(DESTINATION_ROLE), Some(SOURCE_ROLE)),
+        };
+        push_participant(&mut xml, &self.user_id, true, user_role);
+        push_participant(&mut xml, &self.source_id, false, source_role);
+
+        let _ = write!(
+            xml,
+            "<AuditSourceIdentification AuditSourceID=\"{}\">",
+            escape(&self.source_id)
+        );
+        push_code(
+            &mut xml,
+            "AuditSourceTypeCode",
+            "4",
+            "DCM",
+            "Application Server Process Tier",
+        );
+        xml.push_str("</AuditSourceIdentification>");
+
+        for patient in &self.patients {
+            let id = match &patient.issuer {
+                Some(issuer) => format!("{}^^^{}", patient.id, issuer),
+                None => patient.id.clone(),
+            };
+            let _ = write!(
+                xml,
+                "<ParticipantObjectIdentification ParticipantObjectID=\"{}\" ParticipantObjectTypeCode=\"1\" ParticipantObjectTypeCodeRole=\"1\">",
+                escape(&id)
+            );
+            push_code(
+                &mut xml,
+                "ParticipantObjectIDTypeCode",
+                "2",
+                "RFC-3881",
+                "Patient Number",
+            );
+            if let Some(name) = &patient.name {
+                let _ = write!(
+                    xml,
+                    "<ParticipantObjectName>{}</ParticipantObjectName>",
+                    escape(name)
+                );
+            }
+            xml.push_str("</ParticipantObjectIdentification>");
+        }
+
+        for study in &self.studies {
+            let _ = write!(
+                xml,
+                "<ParticipantObjectIdentification ParticipantObjectID=\"{}\" ParticipantObjectTypeCode=\"2\" ParticipantObjectTypeCodeRole=\"3\">",
+                escape(&study.uid)
+            );
+            push_code(
+                &mut xml,
+                "ParticipantObjectIDTypeCode",
+                "110180",
+                "DCM",
+                "Study Instance UID",
+            );
+            for (sop_class_uid, instances) in &study.sop_classes {
+                let _ = write!(
+                    xml,
+                    "<SOPClass UID=\"{}\" NumberOfInstances=\"{}\"/>",
+                    escape(sop_class_uid),
+                    instances
+                );
+            }
+            xml.push_str("</ParticipantObjectIdentification>");
+        }
+
+        if let Some(query) = &self.query {
+            // The query is identified by the SOP class of the Study Root C-FIND
+            xml.push_str("<ParticipantObjectIdentification ParticipantObjectID=\"1.2.840.10008.5.1.4.1.2.2.1\" ParticipantObjectTypeCode=\"2\" ParticipantObjectTypeCodeRole=\"24\">");
+            push_code(
+                &mut xml,
+                "ParticipantObjectIDTypeCode",
+                "110181",
+                "DCM",
+                "SOP Class UID",
+            );
+            let _ = write!(
+                xml,
+                "<ParticipantObjectQuery>{}</ParticipantObjectQuery>",
+                STANDARD.encode(query)
+            );
+            xml.push_str("</ParticipantObjectIdentification>");
+        }
+
+        xml.push_str("</AuditMessage>");
+        xml
+    }
+}
+
+const SOURCE_ROLE: (&str, &str) = ("110153", "Source Role ID");
+const DESTINATION_ROLE: (&str, &str) = ("110152", "Destination Role ID");
+
+fn push_code(xml: &mut String, element: &str, code: &str, system: &str, text: &str) {
+    let _ = write!(
+        xml,
+        "<{} csd-code=\"{}\" codeSystemName=\"{}\" originalText=\"{}\"/>",
+        element, code, system, text
+    );
+}
+
+fn push_participant(
+    xml: &mut String,
+    user_id: &str,
+    is_requestor: bool,
+    role: Option<(&str, &str)>,
+) {
+    let _ = write!(
+        xml,
+        "<ActiveParticipant UserID=\"{}\" UserIsRequestor=\"{}\">",
+        escape(user_id),
+        is_requestor
+    );
+    if let Some((code, text)) = role {
+        push_code(xml, "RoleIDCode", code, "DCM", text);
+    }
+    xml.push_str("</ActiveParticipant>");
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Destination of the audit messages
+pub trait AuditSink: Send + Sync {
+    fn emit(&self, message: &AuditMessage) -> Result<(), BackendError>;
+}
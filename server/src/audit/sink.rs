@@ -0,0 +1,120 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use super::{AuditMessage, AuditSink};
+use crate::backend::BackendError;
+
+/// Appends the messages to a file, one XML document per line
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub fn open(path: impl AsRef<Path>) -> Result<FileSink, BackendError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for FileSink {
+    fn emit(&self, message: &AuditMessage) -> Result<(), BackendError> {
+        let mut line = message.to_xml();
+        line.push('\n');
+        let mut file = self.file.lock().map_err(|_| "The audit file is poisoned")?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Keeps the messages in memory, e.g. to check them in tests.
+///
+/// Clones share the messages, so keep one to read them after passing the sink on.
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    messages: Arc<Mutex<Vec<AuditMessage>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+
+    pub fn messages(&self) -> Vec<AuditMessage> {
+        self.messages
+            .lock()
+            .map(|messages| messages.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl AuditSink for MemorySink {
+    fn emit(&self, message: &AuditMessage) -> Result<(), BackendError> {
+        self.messages
+            .lock()
+            .map_err(|_| "The audit messages are poisoned")?
+            .push(message.clone());
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+mod syslog {
+    use std::{os::unix::net::UnixDatagram, path::Path};
+
+    use chrono::SecondsFormat;
+
+    use super::super::{AuditMessage, AuditSink};
+    use crate::backend::BackendError;
+
+    // Facility 10 (security/authorization) with severity 5 (notice)
+    const PRIORITY: u8 = 85;
+
+    /// Sends the messages to the local syslog daemon, formatted like RFC 5424 with the
+    /// MSGID of the IHE audit trail
+    pub struct SyslogSink {
+        socket: UnixDatagram,
+        app_name: String,
+    }
+
+    impl SyslogSink {
+        /// Connect to a syslog socket, usually "/dev/log"
+        pub fn connect(path: impl AsRef<Path>) -> Result<SyslogSink, BackendError> {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            Ok(SyslogSink {
+                socket,
+                app_name: String::from("dicomweb"),
+            })
+        }
+
+        /// Send the messages with this APP-NAME instead of "dicomweb"
+        pub fn with_app_name(mut self, app_name: impl Into<String>) -> SyslogSink {
+            self.app_name = app_name.into();
+            self
+        }
+    }
+
+    impl AuditSink for SyslogSink {
+        fn emit(&self, message: &AuditMessage) -> Result<(), BackendError> {
+            let line = format!(
+                "<{}>1 {} - {} {} IHE+RFC-3881 - {}",
+                PRIORITY,
+                message.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+                self.app_name,
+                std::process::id(),
+                message.to_xml()
+            );
+            self.socket.send(line.as_bytes())?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use syslog::SyslogSink;
@@ -0,0 +1,51 @@
+use super::{AuthError, AuthScheme, Credentials, Principal, Verifier};
+
+/// Verifies static API keys, each issued to a named client
+#[derive(Default)]
+pub struct ApiKeyVerifier {
+    keys: Vec<(String, String)>,
+}
+
+impl ApiKeyVerifier {
+    pub fn new() -> ApiKeyVerifier {
+        ApiKeyVerifier::default()
+    }
+
+    /// Accept `key` as the principal `subject`
+    pub fn with_key(
+        mut self,
+        key: impl Into<String>,
+        subject: impl Into<String>,
+    ) -> ApiKeyVerifier {
+        self.keys.push((key.into(), subject.into()));
+        self
+    }
+}
+
+/// Compare without returning early, so the time doesn't tell how much of a key matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+impl Verifier for ApiKeyVerifier {
+    fn verify(&self, credentials: &Credentials) -> Option<Result<Principal, AuthError>> {
+        let Credentials::ApiKey(api_key) = credentials else {
+            return None;
+        };
+
+        let subject = self
+            .keys
+            .iter()
+            .filter(|(key, _)| constant_time_eq(key.as_bytes(), api_key.as_bytes()))
+            .map(|(_, subject)| subject)
+            .next();
+        Some(match subject {
+            Some(subject) => Ok(Principal::new(subject.clone(), AuthScheme::ApiKey)),
+            None => Err(AuthError::Invalid("Unknown API key".to_string())),
+        })
+    }
+
+    fn scheme(&self) -> Option<AuthScheme> {
+        Some(AuthScheme::ApiKey)
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use dicom_object::{InMemDicomObject, Tag};
+use serde_json::Value;
+
+use super::Principal;
+use crate::backend::BackendError;
+
+/// Decides which studies a principal may see.
+///
+/// The decision is made per study on its study-level attributes, so it applies to every
+/// series and instance of the study. Implementations can match attributes like the
+/// InstitutionName, or look up the StudyInstanceUID in their own access control list.
+#[async_trait]
+pub trait Authorizer: Send + Sync {
+    /// Attributes the decisions depend on, they are included in the study searches
+    fn attributes(&self) -> Vec<Tag> {
+        Vec::new()
+    }
+
+    async fn authorize_study(
+        &self,
+        principal: &Principal,
+        study: &InMemDicomObject,
+    ) -> Result<bool, BackendError>;
+
+    /// Whether the principal may store instances into the study or reject its instances,
+    /// by default if it may see the study.
+    ///
+    /// Stored instances are checked with their own attributes, and with the ones of their
+    /// study if it exists already.
+    async fn authorize_write(
+        &self,
+        principal: &Principal,
+        study: &InMemDicomObject,
+    ) -> Result<bool, BackendError> {
+        self.authorize_study(principal, study).await
+    }
+}
+
+/// Grants access to the studies with one of the values of an attribute granted to the
+/// principal, e.g. the InstitutionName of the sites of a radiologist
+pub struct AttributeAuthorizer {
+    tag: Tag,
+    grants: HashMap<String, Vec<String>>,
+    claim: Option<String>,
+}
+
+impl AttributeAuthorizer {
+    pub fn new(tag: Tag) -> AttributeAuthorizer {
+        AttributeAuthorizer {
+            tag,
+            grants: HashMap::new(),
+            claim: None,
+        }
+    }
+
+    /// Grant the principal `subject` access to the studies with this value
+    pub fn with_grant(
+        mut self,
+        subject: impl Into<String>,
+        value: impl Into<String>,
+    ) -> AttributeAuthorizer {
+        self.grants
+            .entry(subject.into())
+            .or_default()
+            .push(value.into());
+        self
+    }
+
+    /// Also grant the values of this token claim, a string or an array of strings
+    pub fn with_claim(mut self, claim: impl Into<String>) -> AttributeAuthorizer {
+        self.claim = Some(claim.into());
+        self
+    }
+
+    fn is_granted(&self, principal: &Principal, value: &str) -> bool {
+        let granted = self
+            .grants
+            .get(&principal.subject)
+            .is_some_and(|values| values.iter().any(|granted| granted == value));
+        let claimed = || match self
+            .claim
+            .as_ref()
+            .and_then(|claim| principal.claims.get(claim))
+        {
+            Some(Value::String(claimed)) => claimed == value,
+            Some(Value::Array(claimed)) => claimed.iter().any(|claimed| claimed == value),
+            _ => false,
+        };
+        granted || claimed()
+    }
+}
+
+#[async_trait]
+impl Authorizer for AttributeAuthorizer {
+    fn attributes(&self) -> Vec<Tag> {
+        vec![self.tag]
+    }
+
+    async fn authorize_study(
+        &self,
+        principal: &Principal,
+        study: &InMemDicomObject,
+    ) -> Result<bool, BackendError> {
+        let Some(values) = study.get(self.tag).and_then(|elt| elt.to_multi_str().ok()) else {
+            return Ok(false);
+        };
+        Ok(values
+            .iter()
+            .map(|value| value.trim_end_matches('\0').trim())
+            .any(|value| self.is_granted(principal, value)))
+    }
+}
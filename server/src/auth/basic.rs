@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+
+use super::{AuthError, AuthScheme, Credentials, Principal, Verifier};
+
+/// Verifies Basic auth against Argon2 password hashes in PHC format,
+/// e.g. `$argon2id$v=19$m=19456,t=2,p=1$...`
+pub struct BasicVerifier {
+    realm: String,
+    users: HashMap<String, String>,
+}
+
+impl BasicVerifier {
+    /// Challenge clients with the given realm
+    pub fn new(realm: impl Into<String>) -> BasicVerifier {
+        BasicVerifier {
+            realm: realm.into(),
+            users: HashMap::new(),
+        }
+    }
+
+    pub fn with_user(
+        mut self,
+        username: impl Into<String>,
+        password_hash: impl Into<String>,
+    ) -> BasicVerifier {
+        self.users.insert(username.into(), password_hash.into());
+        self
+    }
+}
+
+impl Verifier for BasicVerifier {
+    fn verify(&self, credentials: &Credentials) -> Option<Result<Principal, AuthError>> {
+        let Credentials::Basic { username, password } = credentials else {
+            return None;
+        };
+
+        let verified =
+            self.users.get(username).is_some_and(|password_hash| {
+                match PasswordHash::new(password_hash) {
+                    Ok(password_hash) => Argon2::default()
+                        .verify_password(password.as_bytes(), &password_hash)
+                        .is_ok(),
+                    Err(e) => {
+                        log::error!("Invalid password hash of user {}: {}", username, e);
+                        false
+                    }
+                }
+            });
+        Some(if verified {
+            Ok(Principal::new(username.clone(), AuthScheme::Basic))
+        } else {
+            Err(AuthError::Invalid(
+                "Invalid user name or password".to_string(),
+            ))
+        })
+    }
+
+    fn challenge(&self) -> Option<String> {
+        Some(format!("Basic realm=\"{}\"", self.realm))
+    }
+
+    fn scheme(&self) -> Option<AuthScheme> {
+        Some(AuthScheme::Basic)
+    }
+}
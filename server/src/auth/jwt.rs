@@ -0,0 +1,244 @@
+use std::{path::Path, str::FromStr};
+
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde_json::{Map, Value};
+
+use super::{AuthError, AuthScheme, Credentials, Principal, Verifier};
+use crate::backend::BackendError;
+
+const HMAC_ALGORITHMS: [Algorithm; 3] = [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+const RSA_ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+];
+const EC_ALGORITHMS: [Algorithm; 2] = [Algorithm::ES256, Algorithm::ES384];
+
+struct Key {
+    id: Option<String>,
+    key: DecodingKey,
+    // Tokens are only verified with algorithms of the key's family
+    algorithms: Vec<Algorithm>,
+}
+
+/// Verifies bearer JWTs signed with a static key or one of a JWKS file.
+///
+/// Tokens must not be expired, their `sub` claim becomes the subject of the principal.
+pub struct JwtVerifier {
+    keys: Vec<Key>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl JwtVerifier {
+    fn with_keys(keys: Vec<Key>) -> JwtVerifier {
+        JwtVerifier {
+            keys,
+            issuer: None,
+            audience: None,
+        }
+    }
+
+    /// Verify HMAC signed tokens with a shared secret
+    pub fn from_secret(secret: &[u8]) -> JwtVerifier {
+        JwtVerifier::with_keys(vec![Key {
+            id: None,
+            key: DecodingKey::from_secret(secret),
+            algorithms: HMAC_ALGORITHMS.to_vec(),
+        }])
+    }
+
+    /// Verify tokens with a PEM encoded RSA, EC or Ed25519 public key
+    pub fn from_pem(pem: &[u8]) -> Result<JwtVerifier, BackendError> {
+        let (key, algorithms) = if let Ok(key) = DecodingKey::from_rsa_pem(pem) {
+            (key, RSA_ALGORITHMS.to_vec())
+        } else if let Ok(key) = DecodingKey::from_ec_pem(pem) {
+            (key, EC_ALGORITHMS.to_vec())
+        } else if let Ok(key) = DecodingKey::from_ed_pem(pem) {
+            (key, vec![Algorithm::EdDSA])
+        } else {
+            return Err("Unsupported public key".into());
+        };
+        Ok(JwtVerifier::with_keys(vec![Key {
+            id: None,
+            key,
+            algorithms,
+        }]))
+    }
+
+    /// Verify tokens with the keys of a JSON Web Key Set file
+    pub fn from_jwks_file(path: impl AsRef<Path>) -> Result<JwtVerifier, BackendError> {
+        let jwks: JwkSet = serde_json::from_slice(&std::fs::read(path)?)?;
+        let keys = jwks
+            .keys
+            .iter()
+            .filter_map(|jwk| match jwk_key(jwk) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    log::warn!("Skipping key {:?}: {}", jwk.common.key_id, e);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if keys.is_empty() {
+            return Err("The JWKS has no usable keys".into());
+        }
+        Ok(JwtVerifier::with_keys(keys))
+    }
+
+    /// Only accept tokens of this issuer
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> JwtVerifier {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    /// Only accept tokens for this audience, without one the `aud` claim isn't checked
+    pub fn with_audience(mut self, audience: impl Into<String>) -> JwtVerifier {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Decode a token, `None` if no key fits its key id and algorithm
+    fn decode(&self, token: &str) -> Option<Result<Principal, AuthError>> {
+        let header = match decode_header(token) {
+            Ok(header) => header,
+            Err(e) => return Some(Err(AuthError::Invalid(format!("Malformed token: {}", e)))),
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.validate_aud = self.audience.is_some();
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+            validation.required_spec_claims.insert("aud".to_string());
+        }
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            validation.required_spec_claims.insert("iss".to_string());
+        }
+
+        let mut error = None;
+        let candidates = self.keys.iter().filter(|key| {
+            key.algorithms.contains(&header.alg)
+                && (key.id.is_none() || header.kid.is_none() || key.id == header.kid)
+        });
+        for key in candidates {
+            match decode::<Map<String, Value>>(token, &key.key, &validation) {
+                Ok(data) => {
+                    let subject = data
+                        .claims
+                        .get("sub")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default();
+                    let mut principal = Principal::new(subject, AuthScheme::Bearer);
+                    principal.claims = data.claims;
+                    return Some(Ok(principal));
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        error.map(|e| Err(AuthError::Invalid(format!("Invalid token: {}", e))))
+    }
+}
+
+/// Decoding key of a JWK with the algorithms it may be used with
+fn jwk_key(jwk: &Jwk) -> Result<Key, BackendError> {
+    let algorithms = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+        (Some(algorithm), _) => vec![Algorithm::from_str(&algorithm.to_string())?],
+        (None, AlgorithmParameters::RSA(_)) => RSA_ALGORITHMS.to_vec(),
+        (None, AlgorithmParameters::EllipticCurve(params)) => match params.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => return Err("Unsupported curve".into()),
+        },
+        (None, AlgorithmParameters::OctetKeyPair(_)) => vec![Algorithm::EdDSA],
+        (None, AlgorithmParameters::OctetKey(_)) => HMAC_ALGORITHMS.to_vec(),
+    };
+    Ok(Key {
+        id: jwk.common.key_id.clone(),
+        key: DecodingKey::from_jwk(jwk)?,
+        algorithms,
+    })
+}
+
+impl Verifier for JwtVerifier {
+    fn verify(&self, credentials: &Credentials) -> Option<Result<Principal, AuthError>> {
+        match credentials {
+            Credentials::Bearer(token) => self.decode(token),
+            _ => None,
+        }
+    }
+
+    fn challenge(&self) -> Option<String> {
+        Some("Bearer".to_string())
+    }
+
+    fn scheme(&self) -> Option<AuthScheme> {
+        Some(AuthScheme::Bearer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"a secret of the identity provider";
+
+    fn token(kid: &str, claims: Value) -> Credentials {
+        let header = Header {
+            kid: Some(kid.to_string()),
+            ..Header::new(Algorithm::HS256)
+        };
+        Credentials::Bearer(encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap())
+    }
+
+    #[test]
+    fn verifies_tokens_with_the_keys_of_a_jwks_file() {
+        let jwks = json!({
+            "keys": [{ "kty": "oct", "kid": "idp-1", "alg": "HS256", "k": URL_SAFE_NO_PAD.encode(SECRET) }],
+        });
+        let path =
+            std::env::temp_dir().join(format!("dicomweb-jwks-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, jwks.to_string()).unwrap();
+        let verifier = JwtVerifier::from_jwks_file(&path)
+            .unwrap()
+            .with_issuer("https://idp.example.com");
+        let _ = std::fs::remove_file(path);
+        let exp = chrono::Utc::now().timestamp() + 60;
+
+        let principal = verifier
+            .verify(&token(
+                "idp-1",
+                json!({ "sub": "doctor", "iss": "https://idp.example.com", "exp": exp }),
+            ))
+            .unwrap()
+            .unwrap();
+        assert_eq!(principal.subject, "doctor");
+        assert_eq!(principal.scheme, AuthScheme::Bearer);
+
+        let other_issuer = token(
+            "idp-1",
+            json!({ "sub": "doctor", "iss": "https://other.example.com", "exp": exp }),
+        );
+        assert!(matches!(
+            verifier.verify(&other_issuer),
+            Some(Err(AuthError::Invalid(_)))
+        ));
+        // Tokens of unknown keys are left to other verifiers
+        let other_key = token(
+            "idp-2",
+            json!({ "sub": "doctor", "iss": "https://idp.example.com", "exp": exp }),
+        );
+        assert!(verifier.verify(&other_key).is_none());
+    }
+}
@@ -0,0 +1,267 @@
+//! Authentication of the DICOMweb requests
+//!
+//! [`Authentication`] checks the credentials of a request with pluggable
+//! [`Verifier`]s and yields the [`Principal`], which is passed to every backend call.
+//! The verifiers of bearer tokens and Basic auth need the `auth` feature.
+//! An [`Authorizer`] restricts the studies a principal may see and store, e.g. the
+//! [`SmartAuthorizer`] by the SMART on FHIR scopes of a token.
+
+use std::fmt;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{Map, Value};
+
+mod api_key;
+mod authorizer;
+#[cfg(feature = "auth")]
+mod basic;
+#[cfg(feature = "auth")]
+mod jwt;
+mod smart;
+
+pub use api_key::ApiKeyVerifier;
+pub use authorizer::{AttributeAuthorizer, Authorizer};
+#[cfg(feature = "auth")]
+pub use basic::BasicVerifier;
+#[cfg(feature = "auth")]
+pub use jwt::JwtVerifier;
+pub use smart::SmartAuthorizer;
+
+// Header carrying API keys, unless configured otherwise
+const DEFAULT_API_KEY_HEADER: &str = "X-API-Key";
+
+/// How a principal was authenticated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AuthScheme {
+    #[default]
+    Anonymous,
+    Bearer,
+    Basic,
+    ApiKey,
+    /// Calling AE title of a DIMSE association
+    AeTitle,
+}
+
+/// User or client on whose behalf a request is served
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Principal {
+    /// User name, subject of a token, name of an API key or AE title
+    pub subject: String,
+    pub scheme: AuthScheme,
+    /// Claims of a bearer token, empty for the other schemes
+    pub claims: Map<String, Value>,
+}
+
+impl Principal {
+    pub fn new(subject: impl Into<String>, scheme: AuthScheme) -> Principal {
+        Principal {
+            subject: subject.into(),
+            scheme,
+            claims: Map::new(),
+        }
+    }
+
+    /// Principal of requests without credentials, if authentication is disabled or optional
+    pub fn anonymous() -> Principal {
+        Principal::default()
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.scheme == AuthScheme::Anonymous
+    }
+}
+
+/// Credentials presented with a request
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Bearer(String),
+    Basic { username: String, password: String },
+    ApiKey(String),
+}
+
+impl Credentials {
+    /// Read the credentials of the `Authorization` header, or else of the API key header
+    pub fn from_headers(
+        authorization: Option<&str>,
+        api_key: Option<&str>,
+    ) -> Result<Option<Credentials>, AuthError> {
+        let Some(authorization) = authorization else {
+            return Ok(api_key.map(|api_key| Credentials::ApiKey(api_key.trim().to_string())));
+        };
+
+        let (scheme, value) = authorization
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| AuthError::Invalid("Malformed Authorization header".to_string()))?;
+        // Authentication schemes are case-insensitive
+        match scheme.to_ascii_lowercase().as_str() {
+            "bearer" => Ok(Some(Credentials::Bearer(value.trim().to_string()))),
+            "basic" => {
+                let decoded = STANDARD
+                    .decode(value.trim())
+                    .ok()
+                    .and_then(|decoded| String::from_utf8(decoded).ok())
+                    .ok_or_else(|| AuthError::Invalid("Malformed Basic credentials".to_string()))?;
+                let (username, password) = decoded
+                    .split_once(':')
+                    .ok_or_else(|| AuthError::Invalid("Malformed Basic credentials".to_string()))?;
+                Ok(Some(Credentials::Basic {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                }))
+            }
+            _ => Err(AuthError::Invalid(format!(
+                "Unsupported authentication scheme {}",
+                scheme
+            ))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// The request has no credentials
+    Missing,
+    /// The credentials are malformed, unknown or expired
+    Invalid(String),
+    /// The principal may not access the study
+    Denied,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "Authentication required"),
+            AuthError::Invalid(message) => write!(f, "{}", message),
+            AuthError::Denied => write!(f, "Access denied"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Check of one kind of credentials
+pub trait Verifier: Send + Sync {
+    /// Authenticate the credentials, `None` if they are of another kind or can't be checked,
+    /// e.g. tokens signed with an unknown key
+    fn verify(&self, credentials: &Credentials) -> Option<Result<Principal, AuthError>>;
+
+    /// Challenge of 401 responses, e.g. `Basic realm="DICOMweb"`
+    fn challenge(&self) -> Option<String> {
+        None
+    }
+
+    /// Scheme of the accepted credentials, which is described in the capabilities
+    fn scheme(&self) -> Option<AuthScheme> {
+        None
+    }
+}
+
+/// Authentication of the requests by the verifiers of their kind of credentials.
+///
+/// Requests without credentials are rejected, unless anonymous access is enabled.
+pub struct Authentication {
+    verifiers: Vec<Box<dyn Verifier>>,
+    api_key_header: String,
+    anonymous_access: bool,
+}
+
+impl Default for Authentication {
+    fn default() -> Self {
+        Authentication::new()
+    }
+}
+
+impl Authentication {
+    pub fn new() -> Authentication {
+        Authentication {
+            verifiers: Vec::new(),
+            api_key_header: DEFAULT_API_KEY_HEADER.to_string(),
+            anonymous_access: false,
+        }
+    }
+
+    pub fn with_verifier(mut self, verifier: impl Verifier + 'static) -> Authentication {
+        self.verifiers.push(Box::new(verifier));
+        self
+    }
+
+    /// Read API keys from this header instead of `X-API-Key`
+    pub fn with_api_key_header(mut self, name: impl Into<String>) -> Authentication {
+        self.api_key_header = name.into();
+        self
+    }
+
+    /// Serve requests without credentials as the anonymous principal
+    pub fn with_anonymous_access(mut self) -> Authentication {
+        self.anonymous_access = true;
+        self
+    }
+
+    pub fn api_key_header(&self) -> &str {
+        &self.api_key_header
+    }
+
+    /// Authenticate a request by its `Authorization` and API key headers
+    pub fn authenticate(
+        &self,
+        authorization: Option<&str>,
+        api_key: Option<&str>,
+    ) -> Result<Principal, AuthError> {
+        let Some(credentials) = Credentials::from_headers(authorization, api_key)? else {
+            return if self.anonymous_access {
+                Ok(Principal::anonymous())
+            } else {
+                Err(AuthError::Missing)
+            };
+        };
+
+        // Several verifiers may accept the same kind, e.g. tokens of different issuers
+        let mut error = None;
+        for result in self
+            .verifiers
+            .iter()
+            .filter_map(|verifier| verifier.verify(&credentials))
+        {
+            match result {
+                Ok(principal) => return Ok(principal),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        Err(error.unwrap_or_else(|| {
+            AuthError::Invalid("No verifier accepts the credentials".to_string())
+        }))
+    }
+
+    /// Schemes of the verifiers, in the order they were added
+    pub fn schemes(&self) -> Vec<AuthScheme> {
+        let mut schemes: Vec<AuthScheme> = Vec::new();
+        for scheme in self
+            .verifiers
+            .iter()
+            .filter_map(|verifier| verifier.scheme())
+        {
+            if !schemes.contains(&scheme) {
+                schemes.push(scheme);
+            }
+        }
+        schemes
+    }
+
+    /// Challenges for the `WWW-Authenticate` header of 401 responses
+    pub fn challenges(&self) -> Vec<String> {
+        let mut challenges: Vec<String> = Vec::new();
+        for challenge in self
+            .verifiers
+            .iter()
+            .filter_map(|verifier| verifier.challenge())
+        {
+            if !challenges.contains(&challenge) {
+                challenges.push(challenge);
+            }
+        }
+        challenges
+    }
+}
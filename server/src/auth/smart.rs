@@ -0,0 +1,344 @@
+use async_trait::async_trait;
+use dicom::dictionary_std::tags;
+use dicom_object::{InMemDicomObject, Tag};
+use serde_json::Value;
+
+use super::{Authorizer, Principal};
+use crate::backend::BackendError;
+
+// Claim with the id of the launched patient, unless configured otherwise
+const DEFAULT_PATIENT_CLAIM: &str = "patient";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+}
+
+/// Grants access by the SMART on FHIR scopes of a bearer token.
+///
+/// Scopes of `ImagingStudy` or all resources are mapped to reading and storing studies,
+/// e.g. `patient/ImagingStudy.read` or `user/*.cruds`. `user/` and `system/` scopes apply
+/// to all studies, `patient/` scopes only to the ones of the patient in the `patient`
+/// claim, whose id is compared to the PatientID.
+pub struct SmartAuthorizer {
+    patient_claim: String,
+    issuer_of_patient_id: Option<String>,
+}
+
+impl Default for SmartAuthorizer {
+    fn default() -> Self {
+        SmartAuthorizer::new()
+    }
+}
+
+impl SmartAuthorizer {
+    pub fn new() -> SmartAuthorizer {
+        SmartAuthorizer {
+            patient_claim: DEFAULT_PATIENT_CLAIM.to_string(),
+            issuer_of_patient_id: None,
+        }
+    }
+
+    /// Read the launched patient from this claim instead of `patient`
+    pub fn with_patient_claim(mut self, claim: impl Into<String>) -> SmartAuthorizer {
+        self.patient_claim = claim.into();
+        self
+    }
+
+    /// Only match patient ids issued by this IssuerOfPatientID
+    pub fn with_issuer_of_patient_id(mut self, issuer: impl Into<String>) -> SmartAuthorizer {
+        self.issuer_of_patient_id = Some(issuer.into());
+        self
+    }
+
+    /// Check if the study belongs to the launched patient
+    fn is_patient(&self, principal: &Principal, study: &InMemDicomObject) -> bool {
+        let Some(patient) = principal
+            .claims
+            .get(&self.patient_claim)
+            .and_then(Value::as_str)
+        else {
+            return false;
+        };
+        // The claim may hold a reference instead of the id
+        let patient = patient.strip_prefix("Patient/").unwrap_or(patient);
+
+        let issuer_matches = self.issuer_of_patient_id.as_ref().is_none_or(|issuer| {
+            string_value(study, tags::ISSUER_OF_PATIENT_ID).as_deref() == Some(issuer)
+        });
+        issuer_matches && string_value(study, tags::PATIENT_ID).as_deref() == Some(patient)
+    }
+
+    fn authorize(&self, principal: &Principal, study: &InMemDicomObject, access: Access) -> bool {
+        scopes(principal)
+            .into_iter()
+            .filter_map(|scope| scope_context(scope, access))
+            .any(|context| match context {
+                "user" | "system" => true,
+                "patient" => self.is_patient(principal, study),
+                _ => false,
+            })
+    }
+}
+
+fn string_value(dcm: &InMemDicomObject, tag: Tag) -> Option<String> {
+    dcm.get(tag)
+        .and_then(|elt| elt.to_str().ok())
+        .map(|value| value.trim_end_matches('\0').trim().to_string())
+}
+
+/// Scopes of a token, either a space separated `scope` claim or a `scp` array
+fn scopes(principal: &Principal) -> Vec<&str> {
+    match principal
+        .claims
+        .get("scope")
+        .or_else(|| principal.claims.get("scp"))
+    {
+        Some(Value::String(scopes)) => scopes.split_whitespace().collect(),
+        Some(Value::Array(scopes)) => scopes.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Context of a scope granting the access, e.g. `patient` for `patient/ImagingStudy.rs`
+fn scope_context(scope: &str, access: Access) -> Option<&str> {
+    let (context, scope) = scope.split_once('/')?;
+    let (resource, permissions) = scope.split_once('.')?;
+    if resource != "ImagingStudy" && resource != "*" {
+        return None;
+    }
+
+    let granted = match permissions {
+        "*" => true,
+        "read" => access == Access::Read,
+        "write" => access == Access::Write,
+        // SMART v2 permissions out of "cruds". Scopes narrowed by search parameters,
+        // like `?category=...`, don't match, as studies can't be checked against them.
+        permissions if permissions.chars().all(|c| "cruds".contains(c)) => match access {
+            Access::Read => permissions.contains('r'),
+            Access::Write => permissions.contains('c') || permissions.contains('u'),
+        },
+        _ => false,
+    };
+    granted.then_some(context)
+}
+
+#[async_trait]
+impl Authorizer for SmartAuthorizer {
+    fn attributes(&self) -> Vec<Tag> {
+        vec![tags::PATIENT_ID, tags::ISSUER_OF_PATIENT_ID]
+    }
+
+    async fn authorize_study(
+        &self,
+        principal: &Principal,
+        study: &InMemDicomObject,
+    ) -> Result<bool, BackendError> {
+        Ok(self.authorize(principal, study, Access::Read))
+    }
+
+    async fn authorize_write(
+        &self,
+        principal: &Principal,
+        study: &InMemDicomObject,
+    ) -> Result<bool, BackendError> {
+        Ok(self.authorize(principal, study, Access::Write))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{DataElement, VR},
+        dictionary_std::uids,
+    };
+    use dicom_object::FileDicomObject;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        auth::{AuthError, AuthScheme},
+        backend::{AuthorizedBackend, DicomWebBackend, InMemoryBackend},
+        testing, InstanceReference, QidoStudyQuery,
+    };
+
+    fn token(claims: Value) -> Principal {
+        let mut principal = Principal::new("doctor", AuthScheme::Bearer);
+        principal.claims = claims.as_object().cloned().expect("claims object");
+        principal
+    }
+
+    fn study(patient_id: &str, issuer: Option<&str>) -> InMemDicomObject {
+        let mut study = InMemDicomObject::new_empty();
+        study.put(DataElement::new(tags::PATIENT_ID, VR::LO, patient_id));
+        if let Some(issuer) = issuer {
+            study.put(DataElement::new(tags::ISSUER_OF_PATIENT_ID, VR::LO, issuer));
+        }
+        study
+    }
+
+    /// Read and write access of a token to a study of its patient
+    async fn access(authorizer: &SmartAuthorizer, principal: &Principal) -> (bool, bool) {
+        let study = study("PID-1", None);
+        (
+            authorizer.authorize_study(principal, &study).await.unwrap(),
+            authorizer.authorize_write(principal, &study).await.unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn maps_scopes_to_read_and_write_access() {
+        let authorizer = SmartAuthorizer::new();
+        let cases = [
+            ("patient/*.read", (true, false)),
+            ("user/ImagingStudy.rs", (true, false)),
+            ("user/ImagingStudy.write", (false, true)),
+            ("system/ImagingStudy.*", (true, true)),
+            ("user/*.cruds", (true, true)),
+            ("user/ImagingStudy.cu", (false, true)),
+            ("user/ImagingStudy.d", (false, false)),
+            // Scopes narrowed by search parameters can't be checked against studies
+            ("user/ImagingStudy.rs?category=radiology", (false, false)),
+            ("user/ImagingStudy.cruds?patient=PID-1", (false, false)),
+            // Unknown or malformed scopes
+            ("user/Observation.read", (false, false)),
+            ("admin/ImagingStudy.read", (false, false)),
+            ("user/ImagingStudy.rx", (false, false)),
+            ("user/ImagingStudy.", (false, false)),
+            ("user/ImagingStudy", (false, false)),
+            ("ImagingStudy.read", (false, false)),
+            ("launch/patient", (false, false)),
+        ];
+        for (scope, expected) in cases {
+            let principal = token(json!({ "scope": scope, "patient": "PID-1" }));
+            assert_eq!(access(&authorizer, &principal).await, expected, "{}", scope);
+        }
+
+        // Scopes are also read from a `scp` array, and a token without any gets nothing
+        let principal = token(json!({ "scp": ["openid", "user/ImagingStudy.read"] }));
+        assert_eq!(access(&authorizer, &principal).await, (true, false));
+        let principal = token(json!({ "patient": "PID-1" }));
+        assert_eq!(access(&authorizer, &principal).await, (false, false));
+    }
+
+    #[tokio::test]
+    async fn limits_patient_scopes_to_the_launched_patient() {
+        let authorizer = SmartAuthorizer::new();
+        let granted = |patient: Option<&str>| {
+            let mut claims = json!({ "scope": "patient/ImagingStudy.read" });
+            if let Some(patient) = patient {
+                claims["patient"] = json!(patient);
+            }
+            token(claims)
+        };
+        let own = study("PID-1", None);
+        let other = study("PID-2", None);
+
+        for patient in ["PID-1", "Patient/PID-1"] {
+            let principal = granted(Some(patient));
+            assert!(authorizer.authorize_study(&principal, &own).await.unwrap());
+            assert!(!authorizer
+                .authorize_study(&principal, &other)
+                .await
+                .unwrap());
+        }
+        let principal = granted(None);
+        assert!(!authorizer.authorize_study(&principal, &own).await.unwrap());
+
+        // The claim can be renamed
+        let authorizer = SmartAuthorizer::new().with_patient_claim("fhirUser");
+        let principal = token(json!({
+            "scope": "patient/ImagingStudy.read",
+            "patient": "PID-2",
+            "fhirUser": "Patient/PID-1",
+        }));
+        assert!(authorizer.authorize_study(&principal, &own).await.unwrap());
+        assert!(!authorizer
+            .authorize_study(&principal, &other)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn denies_patient_ids_of_another_issuer() {
+        let authorizer = SmartAuthorizer::new().with_issuer_of_patient_id("HOSPITAL-A");
+        let principal = token(json!({ "scope": "patient/ImagingStudy.read", "patient": "PID-1" }));
+
+        let same = study("PID-1", Some("HOSPITAL-A"));
+        assert!(authorizer.authorize_study(&principal, &same).await.unwrap());
+        for study in [study("PID-1", Some("HOSPITAL-B")), study("PID-1", None)] {
+            assert!(!authorizer
+                .authorize_study(&principal, &study)
+                .await
+                .unwrap());
+        }
+    }
+
+    /// Photo of a patient's dermatology visit, the series and instance UIDs extend the study UID
+    fn dermatology_photo(patient_id: &str, study_uid: &str) -> FileDicomObject<InMemDicomObject> {
+        let series_uid = format!("{}.1", study_uid);
+        let sop_uid = format!("{}.1", series_uid);
+        testing::file(InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                uids::VL_PHOTOGRAPHIC_IMAGE_STORAGE,
+            ),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, sop_uid.as_str()),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, study_uid),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, series_uid.as_str()),
+            DataElement::new(tags::PATIENT_ID, VR::LO, patient_id),
+            DataElement::new(tags::MODALITY, VR::CS, "XC"),
+        ]))
+    }
+
+    #[tokio::test]
+    async fn restricts_a_backend_to_the_scopes_of_the_launched_patient() {
+        let backend = InMemoryBackend::new();
+        backend
+            .store_instances(
+                &testing::principal(),
+                &[
+                    dermatology_photo("PID-1", "4.1"),
+                    dermatology_photo("PID-2", "4.2"),
+                ],
+            )
+            .await
+            .unwrap();
+        let backend = AuthorizedBackend::new(backend, SmartAuthorizer::new());
+        let principal = token(json!({ "scope": "patient/ImagingStudy.rs", "patient": "PID-1" }));
+        let is_denied = |error: BackendError| {
+            matches!(error.downcast_ref::<AuthError>(), Some(AuthError::Denied))
+        };
+
+        let studies = backend
+            .search_study(&principal, &QidoStudyQuery::default())
+            .await
+            .unwrap();
+        let study_uids: Vec<_> = studies
+            .iter()
+            .filter_map(|study| string_value(study, tags::STUDY_INSTANCE_UID))
+            .collect();
+        assert_eq!(study_uids, ["4.1"]);
+        let error = backend.retrieve_study(&principal, "4.2").await.unwrap_err();
+        assert!(is_denied(error));
+
+        // Without a write scope, not even the patient's own study can be changed
+        let error = backend
+            .store_instances(&principal, &[dermatology_photo("PID-1", "4.1")])
+            .await
+            .unwrap_err();
+        assert!(is_denied(error));
+        let reference = InstanceReference {
+            study_instance_uid: "4.1".to_string(),
+            series_instance_uid: "4.1.1".to_string(),
+            sop_instance_uid: "4.1.1.1".to_string(),
+        };
+        let error = backend
+            .delete_instances(&principal, &[reference])
+            .await
+            .unwrap_err();
+        assert!(is_denied(error));
+    }
+}
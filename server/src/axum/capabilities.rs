@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::OriginalUri,
+    http::{header, HeaderMap},
+    response::Response,
+    Extension,
+};
+
+use super::{header_str, into_response};
+use crate::{api, auth::Authentication};
+
+/// Capabilities
+///
+/// Describe the service root or the resource of the request, the prefix of a nesting
+/// router becomes the base of the description. The security schemes are taken from the
+/// `authenticate` middleware, the metrics of `metrics_router` are served next to the
+/// nested router and therefore not described.
+pub async fn capabilities(
+    OriginalUri(uri): OriginalUri,
+    authentication: Option<Extension<Arc<Authentication>>>,
+    headers: HeaderMap,
+) -> Response {
+    into_response(api::capabilities::capabilities(
+        uri.path(),
+        header_str(&headers, header::ACCEPT),
+        api::capabilities::Deployment {
+            authentication: authentication
+                .as_ref()
+                .map(|Extension(authentication)| authentication.as_ref()),
+            metrics: false,
+        },
+    ))
+}
@@ -0,0 +1,201 @@
+mod capabilities;
+mod qido;
+mod stow;
+mod update;
+mod wado;
+
+use std::sync::Arc;
+#[cfg(feature = "metrics")]
+use std::time::Instant;
+
+use ::axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequestParts, Request, State},
+    http::{header::AsHeaderName, request::Parts, HeaderMap},
+    middleware::Next,
+    response::Response,
+    routing::{get, options},
+    Router,
+};
+use capabilities::*;
+use qido::*;
+use stow::*;
+use update::*;
+use wado::*;
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{self, Metrics};
+use crate::{
+    api::{self, DicomWebResponse},
+    auth::{Authentication, Principal},
+    backend::DicomWebBackend,
+};
+#[cfg(feature = "metrics")]
+use ::axum::body::HttpBody;
+#[cfg(feature = "metrics")]
+use futures_util::TryStreamExt;
+
+type Backend = ::axum::extract::State<Arc<dyn DicomWebBackend>>;
+
+/// Create a router with all QIDO-RS, WADO-RS and STOW-RS endpoints, which describe their
+/// capabilities on `OPTIONS`.
+///
+/// The router can be nested into an application, e.g. `app.nest("/dicomweb", dicomweb_router(backend))`
+pub fn dicomweb_router(backend: impl DicomWebBackend + 'static) -> Router {
+    Router::new()
+        .route("/", options(capabilities))
+        .route(
+            "/studies",
+            get(search_studies_all)
+                .post(store_instances)
+                .options(capabilities),
+        )
+        .route("/series", get(search_series_all).options(capabilities))
+        .route(
+            "/instances",
+            get(search_instances_all).options(capabilities),
+        )
+        .route(
+            "/studies/:study_uid",
+            get(retrieve_study)
+                .post(store_instances_for_study)
+                .patch(update_study)
+                .options(capabilities),
+        )
+        .route(
+            "/studies/:study_uid/metadata",
+            get(retrieve_study_metadata).options(capabilities),
+        )
+        .route(
+            "/studies/:study_uid/series",
+            get(search_series_study_level).options(capabilities),
+        )
+        .route(
+            "/studies/:study_uid/instances",
+            get(search_instances_study_level).options(capabilities),
+        )
+        .route(
+            "/studies/:study_uid/series/:series_uid",
+            get(retrieve_series)
+                .patch(update_series)
+                .options(capabilities),
+        )
+        .route(
+            "/studies/:study_uid/series/:series_uid/metadata",
+            get(retrieve_series_metadata).options(capabilities),
+        )
+        .route(
+            "/studies/:study_uid/series/:series_uid/instances",
+            get(search_instances_series_level).options(capabilities),
+        )
+        .route(
+            "/studies/:study_uid/series/:series_uid/instances/:instance_uid",
+            get(retrieve_instance).options(capabilities),
+        )
+        .route(
+            "/studies/:study_uid/series/:series_uid/instances/:instance_uid/metadata",
+            get(retrieve_instance_metadata).options(capabilities),
+        )
+        .route(
+            "/studies/:study_uid/series/:series_uid/instances/:instance_uid/frames/:frame_list",
+            get(retrieve_instance_frames).options(capabilities),
+        )
+        .with_state(Arc::new(backend) as Arc<dyn DicomWebBackend>)
+}
+
+/// Middleware authenticating the requests for the principal of the backend calls.
+///
+/// Layer it onto the router, e.g.
+/// `router.layer(middleware::from_fn_with_state(Arc::new(authentication), authenticate))`.
+/// Without it, requests fail with 500 Internal Server Error instead of being served
+/// anonymously, use an `Authentication` with anonymous access to serve requests without
+/// credentials.
+pub async fn authenticate(
+    State(authentication): State<Arc<Authentication>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match api::authenticate(&authentication, |name| header_str(request.headers(), name)) {
+        Ok(principal) => {
+            request.extensions_mut().insert(principal);
+            // Describes the security schemes in the capabilities
+            request.extensions_mut().insert(authentication);
+            next.run(request).await
+        }
+        Err(response) => into_response(response),
+    }
+}
+
+/// Middleware recording the metrics of the DICOMweb requests.
+///
+/// Layer it onto the router after `authenticate`, so rejected requests are counted too, e.g.
+/// `router.layer(middleware::from_fn_with_state(metrics.clone(), record_metrics))`.
+#[cfg(feature = "metrics")]
+pub async fn record_metrics(
+    State(metrics): State<Metrics>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(transaction) = metrics::transaction(request.method().as_str(), request.uri().path())
+    else {
+        return next.run(request).await;
+    };
+    let start = Instant::now();
+    let received_bytes = metrics.received_bytes(transaction);
+    let request = request.map(|body| {
+        Body::from_stream(
+            body.into_data_stream()
+                .inspect_ok(move |chunk| received_bytes.inc_by(chunk.len() as u64)),
+        )
+    });
+    let response = next.run(request).await;
+    metrics.observe_request(
+        transaction,
+        response.status().as_u16(),
+        start.elapsed(),
+        response.body().size_hint().exact().unwrap_or(0),
+    );
+    response
+}
+
+/// Create a router serving the metrics at `/metrics`, e.g. `app.merge(metrics_router(metrics))`.
+///
+/// Like the DICOMweb endpoints, the requests must be authenticated by the `authenticate`
+/// middleware.
+#[cfg(feature = "metrics")]
+pub fn metrics_router(metrics: Metrics) -> Router {
+    Router::new()
+        .route("/metrics", get(serve_metrics))
+        .with_state(metrics)
+}
+
+#[cfg(feature = "metrics")]
+async fn serve_metrics(_principal: Principal, State(metrics): State<Metrics>) -> Response {
+    into_response(metrics.response())
+}
+
+/// Principal inserted by the `authenticate` middleware, requests which weren't authenticated
+/// are rejected
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Principal {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Principal>()
+            .cloned()
+            .ok_or_else(|| into_response(api::unauthenticated_response()))
+    }
+}
+
+/// Get a header of the request as string
+fn header_str(headers: &HeaderMap, name: impl AsHeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Convert a response of the framework-agnostic core into an axum response
+fn into_response(response: DicomWebResponse) -> Response {
+    response.map(Body::from)
+}
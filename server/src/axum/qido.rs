@@ -0,0 +1,121 @@
+use axum::{
+    extract::{Path, RawQuery, State},
+    http::{header, HeaderMap},
+    response::Response,
+};
+
+use super::{header_str, into_response, Backend};
+use crate::{api, auth::Principal};
+
+pub async fn search_studies_all(
+    State(backend): Backend,
+    principal: Principal,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Response {
+    into_response(
+        api::qido::search_studies(
+            backend.as_ref(),
+            &principal,
+            header_str(&headers, header::ACCEPT),
+            query.as_deref(),
+        )
+        .await,
+    )
+}
+
+pub async fn search_series_study_level(
+    State(backend): Backend,
+    principal: Principal,
+    headers: HeaderMap,
+    Path(study_uid): Path<String>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    into_response(
+        api::qido::search_series(
+            backend.as_ref(),
+            &principal,
+            header_str(&headers, header::ACCEPT),
+            Some(&study_uid),
+            query.as_deref(),
+        )
+        .await,
+    )
+}
+
+pub async fn search_instances_study_level(
+    State(backend): Backend,
+    principal: Principal,
+    headers: HeaderMap,
+    Path(study_uid): Path<String>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    into_response(
+        api::qido::search_instances(
+            backend.as_ref(),
+            &principal,
+            header_str(&headers, header::ACCEPT),
+            Some(&study_uid),
+            None,
+            query.as_deref(),
+        )
+        .await,
+    )
+}
+
+pub async fn search_series_all(
+    State(backend): Backend,
+    principal: Principal,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Response {
+    into_response(
+        api::qido::search_series(
+            backend.as_ref(),
+            &principal,
+            header_str(&headers, header::ACCEPT),
+            None,
+            query.as_deref(),
+        )
+        .await,
+    )
+}
+
+pub async fn search_instances_series_level(
+    State(backend): Backend,
+    principal: Principal,
+    headers: HeaderMap,
+    Path((study_uid, series_uid)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    into_response(
+        api::qido::search_instances(
+            backend.as_ref(),
+            &principal,
+            header_str(&headers, header::ACCEPT),
+            Some(&study_uid),
+            Some(&series_uid),
+            query.as_deref(),
+        )
+        .await,
+    )
+}
+
+pub async fn search_instances_all(
+    State(backend): Backend,
+    principal: Principal,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Response {
+    into_response(
+        api::qido::search_instances(
+            backend.as_ref(),
+            &principal,
+            header_str(&headers, header::ACCEPT),
+            None,
+            None,
+            query.as_deref(),
+        )
+        .await,
+    )
+}
@@ -0,0 +1,49 @@
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{header, HeaderMap},
+    response::Response,
+};
+
+use super::{header_str, into_response, Backend};
+use crate::{api, auth::Principal};
+
+/// STOW-RS
+///
+/// See https://www.dicomstandard.org/using/dicomweb/store-stow-rs for more information
+pub async fn store_instances(
+    State(backend): Backend,
+    principal: Principal,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    into_response(
+        api::stow::store_instances(
+            backend.as_ref(),
+            &principal,
+            None,
+            header_str(&headers, header::CONTENT_TYPE),
+            body.into_data_stream(),
+        )
+        .await,
+    )
+}
+
+pub async fn store_instances_for_study(
+    State(backend): Backend,
+    principal: Principal,
+    Path(study_uid): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    into_response(
+        api::stow::store_instances(
+            backend.as_ref(),
+            &principal,
+            Some(&study_uid),
+            header_str(&headers, header::CONTENT_TYPE),
+            body.into_data_stream(),
+        )
+        .await,
+    )
+}
@@ -0,0 +1,55 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap},
+    response::Response,
+};
+
+use super::{header_str, into_response, Backend};
+use crate::{api, auth::Principal, UpdateQuery};
+
+/// Metadata update
+///
+/// Apply a DICOM JSON dataset of changed attributes to every instance of the study
+pub async fn update_study(
+    State(backend): Backend,
+    principal: Principal,
+    Path(study_uid): Path<String>,
+    Query(query): Query<UpdateQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    into_response(
+        api::update::update_study(
+            backend.as_ref(),
+            &principal,
+            &study_uid,
+            header_str(&headers, header::CONTENT_TYPE),
+            &query,
+            &body,
+        )
+        .await,
+    )
+}
+
+pub async fn update_series(
+    State(backend): Backend,
+    principal: Principal,
+    Path((study_uid, series_uid)): Path<(String, String)>,
+    Query(query): Query<UpdateQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    into_response(
+        api::update::update_series(
+            backend.as_ref(),
+            &principal,
+            &study_uid,
+            &series_uid,
+            header_str(&headers, header::CONTENT_TYPE),
+            &query,
+            &body,
+        )
+        .await,
+    )
+}
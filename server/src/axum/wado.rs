@@ -0,0 +1,142 @@
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    response::Response,
+};
+
+use super::{header_str, into_response, Backend};
+use crate::{
+    api::{self, caching::Preconditions},
+    auth::Principal,
+};
+
+/// WADO-RS
+///
+///
+pub async fn retrieve_study(
+    State(backend): Backend,
+    principal: Principal,
+    Path(study_uid): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    into_response(
+        api::wado::retrieve_study(
+            backend.as_ref(),
+            &principal,
+            &study_uid,
+            &Preconditions::from_headers(|name| header_str(&headers, name)),
+        )
+        .await,
+    )
+}
+
+pub async fn retrieve_study_metadata(
+    State(backend): Backend,
+    principal: Principal,
+    Path(study_uid): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    into_response(
+        api::wado::retrieve_study_metadata(
+            backend.as_ref(),
+            &principal,
+            &study_uid,
+            &Preconditions::from_headers(|name| header_str(&headers, name)),
+        )
+        .await,
+    )
+}
+
+pub async fn retrieve_series(
+    State(backend): Backend,
+    principal: Principal,
+    Path((study_uid, series_uid)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    into_response(
+        api::wado::retrieve_series(
+            backend.as_ref(),
+            &principal,
+            &study_uid,
+            &series_uid,
+            &Preconditions::from_headers(|name| header_str(&headers, name)),
+        )
+        .await,
+    )
+}
+
+pub async fn retrieve_series_metadata(
+    State(backend): Backend,
+    principal: Principal,
+    Path((study_uid, series_uid)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    into_response(
+        api::wado::retrieve_series_metadata(
+            backend.as_ref(),
+            &principal,
+            &study_uid,
+            &series_uid,
+            &Preconditions::from_headers(|name| header_str(&headers, name)),
+        )
+        .await,
+    )
+}
+
+pub async fn retrieve_instance(
+    State(backend): Backend,
+    principal: Principal,
+    Path((study_uid, series_uid, instance_uid)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    into_response(
+        api::wado::retrieve_instance(
+            backend.as_ref(),
+            &principal,
+            &study_uid,
+            &series_uid,
+            &instance_uid,
+            &Preconditions::from_headers(|name| header_str(&headers, name)),
+        )
+        .await,
+    )
+}
+
+pub async fn retrieve_instance_metadata(
+    State(backend): Backend,
+    principal: Principal,
+    Path((study_uid, series_uid, instance_uid)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    into_response(
+        api::wado::retrieve_instance_metadata(
+            backend.as_ref(),
+            &principal,
+            &study_uid,
+            &series_uid,
+            &instance_uid,
+            &Preconditions::from_headers(|name| header_str(&headers, name)),
+        )
+        .await,
+    )
+}
+
+pub async fn retrieve_instance_frames(
+    State(backend): Backend,
+    principal: Principal,
+    Path((study_uid, series_uid, instance_uid, frame_list)): Path<(String, String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    into_response(
+        api::wado::retrieve_instance_frames(
+            backend.as_ref(),
+            &principal,
+            &study_uid,
+            &series_uid,
+            &instance_uid,
+            &frame_list,
+            &Preconditions::from_headers(|name| header_str(&headers, name)),
+        )
+        .await,
+    )
+}
@@ -0,0 +1,706 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use dicom::dictionary_std::tags;
+use dicom_object::{FileDicomObject, InMemDicomObject, Tag};
+use serde::Serialize;
+
+use super::{search, BackendError, DicomWebBackend, ResourceVersion};
+use crate::{
+    audit::{
+        AuditEvent, AuditMessage, AuditPatient, AuditSink, AuditStudy, EventAction, EventOutcome,
+    },
+    auth::Principal,
+    InstanceReference, QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery, RejectionNote,
+    RejectionReason,
+};
+
+// Audit source ID, unless configured otherwise
+const DEFAULT_SOURCE_ID: &str = "DICOMWEB";
+// PatientID, IssuerOfPatientID and PatientName, included in the search of the instance of
+// retrieved frames
+const PATIENT_FIELDS: &str = "00100020,00100021,00100010";
+
+/// Audits the searches, retrievals, stores and changes of a backend.
+///
+/// Searches are audited as Query, retrievals as Begin Transferring followed by DICOM
+/// Instances Transferred and stores as DICOM Instances Transferred, each with the
+/// patients and studies involved. Metadata updates, rejections and deletions are audited
+/// as DICOM Instances Accessed. Wrap an [`AuthorizedBackend`](super::AuthorizedBackend)
+/// to audit denied accesses as failures. The sinks are written on the blocking threads of
+/// the tokio runtime.
+pub struct AuditedBackend<B> {
+    backend: B,
+    sinks: Vec<Arc<dyn AuditSink>>,
+    source_id: String,
+}
+
+impl<B: DicomWebBackend> AuditedBackend<B> {
+    pub fn new(backend: B) -> AuditedBackend<B> {
+        AuditedBackend {
+            backend,
+            sinks: Vec::new(),
+            source_id: DEFAULT_SOURCE_ID.to_string(),
+        }
+    }
+
+    /// Write the audit messages to this sink, in addition to the others
+    pub fn with_sink(mut self, sink: impl AuditSink + 'static) -> AuditedBackend<B> {
+        self.sinks.push(Arc::new(sink));
+        self
+    }
+
+    /// Identify the server in the audit messages, e.g. by its AE title
+    pub fn with_source_id(mut self, source_id: impl Into<String>) -> AuditedBackend<B> {
+        self.source_id = source_id.into();
+        self
+    }
+
+    fn message(
+        &self,
+        event: AuditEvent,
+        action: EventAction,
+        principal: &Principal,
+    ) -> AuditMessage {
+        let user_id = if principal.is_anonymous() {
+            "anonymous"
+        } else {
+            principal.subject.as_str()
+        };
+        AuditMessage::new(event, action, user_id, self.source_id.as_str())
+    }
+
+    /// A failed audit doesn't fail the transaction, but is logged
+    async fn emit(&self, message: AuditMessage) {
+        let sinks = self.sinks.clone();
+        let emitted = tokio::task::spawn_blocking(move || {
+            for sink in &sinks {
+                if let Err(e) = sink.emit(&message) {
+                    log::error!("Failed to emit audit message: {}", e);
+                }
+            }
+        })
+        .await;
+        if let Err(e) = emitted {
+            log::error!("Failed to emit audit message: {}", e);
+        }
+    }
+
+    async fn audit_query(
+        &self,
+        principal: &Principal,
+        query: String,
+        result: &Result<Vec<InMemDicomObject>, BackendError>,
+    ) {
+        let mut message = self.message(AuditEvent::Query, EventAction::Execute, principal);
+        message.query = Some(query);
+        message.outcome = outcome(result);
+        if let Ok(results) = result {
+            (message.patients, message.studies) = participants(results);
+        }
+        self.emit(message).await;
+    }
+
+    async fn audit_begin_transferring(&self, principal: &Principal, study_uid: &str) {
+        let mut message = self.message(
+            AuditEvent::BeginTransferring,
+            EventAction::Execute,
+            principal,
+        );
+        message.studies = vec![AuditStudy {
+            uid: study_uid.to_string(),
+            sop_classes: Vec::new(),
+        }];
+        self.emit(message).await;
+    }
+
+    /// Audit the transferred instances, or else the study the transfer was requested for
+    async fn audit_transferred(
+        &self,
+        principal: &Principal,
+        action: EventAction,
+        study_uid: Option<&str>,
+        instances: &[&InMemDicomObject],
+        outcome: EventOutcome,
+    ) {
+        let mut message = self.message(AuditEvent::InstancesTransferred, action, principal);
+        message.outcome = outcome;
+        (message.patients, message.studies) = participants(instances.iter().copied());
+        if message.studies.is_empty() {
+            message.studies.extend(study_uid.map(|uid| AuditStudy {
+                uid: uid.to_string(),
+                sop_classes: Vec::new(),
+            }));
+        }
+        self.emit(message).await;
+    }
+
+    async fn audit_retrieved(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        result: &Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError>,
+    ) {
+        let instances: Vec<&InMemDicomObject> = match result {
+            Ok(instances) => instances.iter().map(|instance| &**instance).collect(),
+            Err(_) => Vec::new(),
+        };
+        self.audit_transferred(
+            principal,
+            EventAction::Read,
+            Some(study_uid),
+            &instances,
+            outcome(result),
+        )
+        .await;
+    }
+
+    /// Instance of retrieved frames with its patient, so the retrieval is attributed to them.
+    /// Paths with wildcards or lists aren't searched, they never match a single instance.
+    async fn frames_instance(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Vec<InMemDicomObject> {
+        if ![study_uid, series_uid, sop_instance_uid]
+            .iter()
+            .all(|uid| search::is_uid(uid))
+        {
+            return Vec::new();
+        }
+        let query = QidoInstanceQuery {
+            sop_instance_uid: Some(sop_instance_uid.to_string()),
+            includefield: Some(PATIENT_FIELDS.to_string()),
+            ..Default::default()
+        };
+        match self
+            .backend
+            .search_instances(principal, Some(study_uid), Some(series_uid), &query)
+            .await
+        {
+            Ok(instances) => instances,
+            Err(e) => {
+                log::warn!("Failed to search the instance of retrieved frames: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Audit the instances which were changed, by the studies they belong to
+    async fn audit_accessed(
+        &self,
+        principal: &Principal,
+        action: EventAction,
+        instances: &[InstanceReference],
+        outcome: EventOutcome,
+    ) {
+        let mut message = self.message(AuditEvent::InstancesAccessed, action, principal);
+        message.outcome = outcome;
+        for reference in instances {
+            if !message
+                .studies
+                .iter()
+                .any(|study| study.uid == reference.study_instance_uid)
+            {
+                message.studies.push(AuditStudy {
+                    uid: reference.study_instance_uid.clone(),
+                    sop_classes: Vec::new(),
+                });
+            }
+        }
+        self.emit(message).await;
+    }
+}
+
+fn outcome<T>(result: &Result<T, BackendError>) -> EventOutcome {
+    match result {
+        Ok(_) => EventOutcome::Success,
+        Err(_) => EventOutcome::SeriousFailure,
+    }
+}
+
+fn string_value(dcm: &InMemDicomObject, tag: Tag) -> Option<String> {
+    dcm.get(tag)
+        .and_then(|elt| elt.to_str().ok())
+        .map(|value| value.trim_end_matches('\0').trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Patients and studies of search results or instances, counting the instances per SOP class
+fn participants<'a>(
+    datasets: impl IntoIterator<Item = &'a InMemDicomObject>,
+) -> (Vec<AuditPatient>, Vec<AuditStudy>) {
+    let mut patients: Vec<AuditPatient> = Vec::new();
+    let mut studies: Vec<AuditStudy> = Vec::new();
+    for dcm in datasets {
+        if let Some(id) = string_value(dcm, tags::PATIENT_ID) {
+            let patient = AuditPatient {
+                id,
+                issuer: string_value(dcm, tags::ISSUER_OF_PATIENT_ID),
+                name: string_value(dcm, tags::PATIENT_NAME),
+            };
+            if !patients
+                .iter()
+                .any(|known| known.id == patient.id && known.issuer == patient.issuer)
+            {
+                patients.push(patient);
+            }
+        }
+
+        let Some(study_uid) = search::uid(dcm, tags::STUDY_INSTANCE_UID) else {
+            continue;
+        };
+        let index = match studies.iter().position(|study| study.uid == study_uid) {
+            Some(index) => index,
+            None => {
+                studies.push(AuditStudy {
+                    uid: study_uid,
+                    sop_classes: Vec::new(),
+                });
+                studies.len() - 1
+            }
+        };
+        if let Some(sop_class_uid) = search::uid(dcm, tags::SOP_CLASS_UID) {
+            let sop_classes = &mut studies[index].sop_classes;
+            match sop_classes
+                .iter_mut()
+                .find(|(uid, _)| *uid == sop_class_uid)
+            {
+                Some((_, instances)) => *instances += 1,
+                None => sop_classes.push((sop_class_uid, 1)),
+            }
+        }
+    }
+    (patients, studies)
+}
+
+/// Query parameters of a search, with the matching attributes as tags
+fn query_string(
+    query: &impl Serialize,
+    mut params: Vec<(String, String)>,
+    matches: &[(Tag, String)],
+) -> String {
+    params.extend(matches.iter().map(|(tag, value)| {
+        (
+            format!("{:04X}{:04X}", tag.group(), tag.element()),
+            value.clone(),
+        )
+    }));
+    let mut query = serde_urlencoded::to_string(query).unwrap_or_default();
+    let params = serde_urlencoded::to_string(&params).unwrap_or_default();
+    if !query.is_empty() && !params.is_empty() {
+        query.push('&');
+    }
+    query.push_str(&params);
+    query
+}
+
+/// UIDs of the search path, as matching parameters
+fn path_params(uids: &[(Tag, Option<&str>)]) -> Vec<(String, String)> {
+    uids.iter()
+        .filter_map(|(tag, uid)| {
+            uid.map(|uid| {
+                (
+                    format!("{:04X}{:04X}", tag.group(), tag.element()),
+                    uid.to_string(),
+                )
+            })
+        })
+        .collect()
+}
+
+#[async_trait]
+impl<B: DicomWebBackend> DicomWebBackend for AuditedBackend<B> {
+    async fn search_study(
+        &self,
+        principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let result = self.backend.search_study(principal, query).await;
+        let includefields = query
+            .includefields
+            .iter()
+            .map(|field| (String::from("includefield"), field.clone()))
+            .collect();
+        self.audit_query(
+            principal,
+            query_string(query, includefields, &query.matches),
+            &result,
+        )
+        .await;
+        result
+    }
+
+    async fn search_series(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let result = self
+            .backend
+            .search_series(principal, study_uid, query)
+            .await;
+        let params = path_params(&[(tags::STUDY_INSTANCE_UID, study_uid)]);
+        self.audit_query(
+            principal,
+            query_string(query, params, &query.matches),
+            &result,
+        )
+        .await;
+        result
+    }
+
+    async fn search_instances(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let result = self
+            .backend
+            .search_instances(principal, study_uid, series_uid, query)
+            .await;
+        let params = path_params(&[
+            (tags::STUDY_INSTANCE_UID, study_uid),
+            (tags::SERIES_INSTANCE_UID, series_uid),
+        ]);
+        self.audit_query(
+            principal,
+            query_string(query, params, &query.matches),
+            &result,
+        )
+        .await;
+        result
+    }
+
+    async fn retrieve_study(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.audit_begin_transferring(principal, study_uid).await;
+        let result = self.backend.retrieve_study(principal, study_uid).await;
+        self.audit_retrieved(principal, study_uid, &result).await;
+        result
+    }
+
+    async fn retrieve_series(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.audit_begin_transferring(principal, study_uid).await;
+        let result = self
+            .backend
+            .retrieve_series(principal, study_uid, series_uid)
+            .await;
+        self.audit_retrieved(principal, study_uid, &result).await;
+        result
+    }
+
+    async fn retrieve_series_metadata(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.audit_begin_transferring(principal, study_uid).await;
+        let result = self
+            .backend
+            .retrieve_series_metadata(principal, study_uid, series_uid)
+            .await;
+        let instances: Vec<&InMemDicomObject> = result.iter().flatten().collect();
+        self.audit_transferred(
+            principal,
+            EventAction::Read,
+            Some(study_uid),
+            &instances,
+            outcome(&result),
+        )
+        .await;
+        result
+    }
+
+    async fn retrieve_instance(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        self.audit_begin_transferring(principal, study_uid).await;
+        let result = self
+            .backend
+            .retrieve_instance(principal, study_uid, series_uid, sop_instance_uid)
+            .await;
+        let instances: Vec<&InMemDicomObject> = result.iter().map(|instance| &**instance).collect();
+        self.audit_transferred(
+            principal,
+            EventAction::Read,
+            Some(study_uid),
+            &instances,
+            outcome(&result),
+        )
+        .await;
+        result
+    }
+
+    async fn retrieve_frames(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+        frames: &[u32],
+    ) -> Result<Vec<Bytes>, BackendError> {
+        self.audit_begin_transferring(principal, study_uid).await;
+        let result = self
+            .backend
+            .retrieve_frames(principal, study_uid, series_uid, sop_instance_uid, frames)
+            .await;
+        let instances = self
+            .frames_instance(principal, study_uid, series_uid, sop_instance_uid)
+            .await;
+        self.audit_transferred(
+            principal,
+            EventAction::Read,
+            Some(study_uid),
+            &instances.iter().collect::<Vec<_>>(),
+            outcome(&result),
+        )
+        .await;
+        result
+    }
+
+    // Nothing is disclosed, so there is nothing to audit
+    async fn version(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: Option<&str>,
+    ) -> Result<Option<ResourceVersion>, BackendError> {
+        self.backend.version(principal, study_uid, series_uid).await
+    }
+
+    async fn store_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        let result = self.backend.store_instances(principal, instances).await;
+        let datasets: Vec<&InMemDicomObject> =
+            instances.iter().map(|instance| &**instance).collect();
+        self.audit_transferred(
+            principal,
+            EventAction::Create,
+            None,
+            &datasets,
+            outcome(&result),
+        )
+        .await;
+        result
+    }
+
+    async fn update_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        let result = self.backend.update_instances(principal, instances).await;
+        let mut message = self.message(
+            AuditEvent::InstancesAccessed,
+            EventAction::Update,
+            principal,
+        );
+        message.outcome = outcome(&result);
+        (message.patients, message.studies) =
+            participants(instances.iter().map(|instance| &**instance));
+        self.emit(message).await;
+        result
+    }
+
+    async fn reject_instances(
+        &self,
+        principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        let result = self.backend.reject_instances(principal, note).await;
+        // Instances past their retention period are deleted, all others are hidden
+        let action = if note.reason == RejectionReason::DataRetentionPolicyExpired {
+            EventAction::Delete
+        } else {
+            EventAction::Update
+        };
+        self.audit_accessed(principal, action, &note.instances, outcome(&result))
+            .await;
+        result
+    }
+
+    async fn delete_instances(
+        &self,
+        principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        let result = self.backend.delete_instances(principal, instances).await;
+        self.audit_accessed(principal, EventAction::Delete, instances, outcome(&result))
+            .await;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{DataElement, VR},
+        dictionary_std::uids,
+    };
+
+    use super::*;
+    use crate::{
+        audit::MemorySink,
+        backend::{CachedBackend, InMemoryBackend},
+        cache::FrameCache,
+        testing,
+    };
+
+    const STUDY_UID: &str = "1.2.840.10008.43.1";
+    const SERIES_UID: &str = "1.2.840.10008.43.1.1";
+    const SOP_UID: &str = "1.2.840.10008.43.1.1.1";
+    const PATIENT_ID: &str = "MRN-4711";
+
+    /// CT slice of a patient registered at HOSPITAL-A, with a single frame of 2x2 pixels
+    fn patient_ct_slice() -> FileDicomObject<InMemDicomObject> {
+        let mut dcm = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SOP_CLASS_UID, VR::UI, uids::CT_IMAGE_STORAGE),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, SOP_UID),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, SERIES_UID),
+            DataElement::new(tags::PATIENT_ID, VR::LO, PATIENT_ID),
+            DataElement::new(tags::ISSUER_OF_PATIENT_ID, VR::LO, "HOSPITAL-A"),
+            DataElement::new(tags::PATIENT_NAME, VR::PN, "Doe^John"),
+            DataElement::new(tags::MODALITY, VR::CS, "CT"),
+        ]);
+        dcm.extend(testing::grayscale_frames(2, 2, 1));
+        testing::file(dcm)
+    }
+
+    #[tokio::test]
+    async fn audits_updates_rejections_and_deletions() {
+        let sink = MemorySink::new();
+        let backend = AuditedBackend::new(InMemoryBackend::new()).with_sink(sink.clone());
+        let principal = testing::principal();
+        let instance = patient_ct_slice();
+        let reference = InstanceReference::from_dicom(&instance).unwrap();
+        backend
+            .store_instances(&principal, std::slice::from_ref(&instance))
+            .await
+            .unwrap();
+        backend
+            .update_instances(&principal, &[instance])
+            .await
+            .unwrap();
+        let note = RejectionNote {
+            reason: RejectionReason::QualityReasons,
+            sop_instance_uid: "1.2.840.10008.43.1.2.1".to_string(),
+            instances: vec![reference.clone()],
+        };
+        backend.reject_instances(&principal, &note).await.unwrap();
+        backend
+            .delete_instances(&principal, &[reference])
+            .await
+            .unwrap();
+
+        let messages = sink.messages();
+        let events: Vec<(AuditEvent, EventAction)> = messages
+            .iter()
+            .map(|message| (message.event, message.action))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (AuditEvent::InstancesTransferred, EventAction::Create),
+                (AuditEvent::InstancesAccessed, EventAction::Update),
+                (AuditEvent::InstancesAccessed, EventAction::Update),
+                (AuditEvent::InstancesAccessed, EventAction::Delete),
+            ]
+        );
+        assert_eq!(messages[1].patients[0].id, PATIENT_ID);
+        assert!(messages[1..]
+            .iter()
+            .all(|message| message.studies[0].uid == STUDY_UID));
+    }
+
+    #[tokio::test]
+    async fn audits_updates_passed_on_by_other_decorators() {
+        let sink = MemorySink::new();
+        let backend = CachedBackend::new(
+            AuditedBackend::new(InMemoryBackend::new()).with_sink(sink.clone()),
+            FrameCache::new(1 << 20),
+        );
+        let principal = testing::principal();
+        let instance = patient_ct_slice();
+        backend
+            .store_instances(&principal, std::slice::from_ref(&instance))
+            .await
+            .unwrap();
+        backend
+            .update_instances(&principal, &[instance])
+            .await
+            .unwrap();
+
+        let messages = sink.messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].event, AuditEvent::InstancesAccessed);
+        assert_eq!(messages[1].action, EventAction::Update);
+    }
+
+    #[tokio::test]
+    async fn attributes_retrieved_frames_to_their_patient() {
+        let sink = MemorySink::new();
+        let backend = AuditedBackend::new(InMemoryBackend::new()).with_sink(sink.clone());
+        let principal = testing::principal();
+        backend
+            .store_instances(&principal, &[patient_ct_slice()])
+            .await
+            .unwrap();
+
+        backend
+            .retrieve_frames(&principal, STUDY_UID, SERIES_UID, SOP_UID, &[1])
+            .await
+            .unwrap();
+        let messages = sink.messages();
+        let transferred = messages.last().unwrap();
+        assert_eq!(transferred.event, AuditEvent::InstancesTransferred);
+        assert_eq!(transferred.patients.len(), 1);
+        assert_eq!(transferred.patients[0].id, PATIENT_ID);
+        assert_eq!(transferred.patients[0].name.as_deref(), Some("Doe^John"));
+        assert_eq!(
+            transferred.patients[0].issuer.as_deref(),
+            Some("HOSPITAL-A")
+        );
+        assert_eq!(transferred.studies[0].uid, STUDY_UID);
+
+        // A failed retrieval of an unknown instance still names the requested study
+        backend
+            .retrieve_frames(
+                &principal,
+                STUDY_UID,
+                SERIES_UID,
+                "1.2.840.10008.43.1.1.9",
+                &[1],
+            )
+            .await
+            .unwrap_err();
+        let messages = sink.messages();
+        let failed = messages.last().unwrap();
+        assert!(failed.patients.is_empty());
+        assert_eq!(failed.studies[0].uid, STUDY_UID);
+        assert_eq!(failed.outcome, EventOutcome::SeriousFailure);
+    }
+}
@@ -0,0 +1,661 @@
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use dicom::dictionary_std::tags;
+use dicom_object::{FileDicomObject, InMemDicomObject, Tag};
+use futures_util::{future::BoxFuture, FutureExt};
+
+use super::{search, BackendError, DicomWebBackend, ResourceVersion};
+use crate::{
+    auth::{AuthError, Authorizer, Principal},
+    InstanceReference, QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery, RejectionNote,
+    STUDY_TAGS,
+};
+
+// Number of matches fetched from the backend at once by searches, unless configured otherwise
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Restricts a backend to the studies the principal is authorized for.
+///
+/// Searches fetch the matches from the backend page by page and filter them before their
+/// limit and offset are applied, so hidden studies neither shorten nor shift the pages. Retrievals of a hidden study fail with
+/// [`AuthError::Denied`], which is answered like an unknown study. Stores, rejections
+/// and deletions need the write access of [`Authorizer::authorize_write`], to the referenced
+/// studies and the ones the referenced instances are actually stored in.
+pub struct AuthorizedBackend<B> {
+    backend: B,
+    authorizer: Box<dyn Authorizer>,
+    page_size: usize,
+}
+
+impl<B: DicomWebBackend> AuthorizedBackend<B> {
+    pub fn new(backend: B, authorizer: impl Authorizer + 'static) -> AuthorizedBackend<B> {
+        AuthorizedBackend {
+            backend,
+            authorizer: Box::new(authorizer),
+            page_size: DEFAULT_PAGE_SIZE,
+        }
+    }
+
+    /// Fetch the matches of searches from the backend in pages of this size, the studies
+    /// of each page are authorized with a single search
+    pub fn with_page_size(mut self, page_size: usize) -> AuthorizedBackend<B> {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    /// Search all studies of a query with the attributes needed by the authorizer
+    fn study_query(&self, query: &QidoStudyQuery) -> QidoStudyQuery {
+        let mut includefields = query.includefields.clone();
+        includefields.extend(
+            self.authorizer
+                .attributes()
+                .iter()
+                .map(|tag| format!("{:04X}{:04X}", tag.group(), tag.element())),
+        );
+        QidoStudyQuery {
+            limit: None,
+            offset: None,
+            includefields,
+            ..query.clone()
+        }
+    }
+
+    /// Study-level attributes of the existing ones of the given studies by their UID,
+    /// searched at once with a UID list.
+    ///
+    /// Values which aren't a single UID, e.g. wildcards or lists, never match a study.
+    async fn studies(
+        &self,
+        principal: &Principal,
+        study_uids: &[&str],
+    ) -> Result<HashMap<String, InMemDicomObject>, BackendError> {
+        let mut study_uids: Vec<&str> = study_uids
+            .iter()
+            .copied()
+            .filter(|uid| search::is_uid(uid))
+            .collect();
+        study_uids.sort_unstable();
+        study_uids.dedup();
+        if study_uids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        // Rejected instances still belong to the study
+        let query = self.study_query(&QidoStudyQuery {
+            includerejected: Some(true),
+            matches: vec![(tags::STUDY_INSTANCE_UID, study_uids.join("\\"))],
+            ..Default::default()
+        });
+        Ok(self
+            .backend
+            .search_study(principal, &query)
+            .await?
+            .into_iter()
+            .filter_map(|study| Some((search::uid(&study, tags::STUDY_INSTANCE_UID)?, study)))
+            .filter(|(uid, _)| study_uids.binary_search(&uid.as_str()).is_ok())
+            .collect())
+    }
+
+    /// Study-level attributes of a study, `None` if it doesn't exist
+    async fn study(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Option<InMemDicomObject>, BackendError> {
+        Ok(self
+            .studies(principal, &[study_uid])
+            .await?
+            .remove(study_uid))
+    }
+
+    /// Studies the referenced instances are actually stored in, along with the referenced
+    /// ones, so a reference with a foreign study can't pass the authorization
+    async fn referenced_studies(
+        &self,
+        principal: &Principal,
+        references: &[InstanceReference],
+    ) -> Result<Vec<String>, BackendError> {
+        let mut study_uids = Vec::new();
+        for reference in references {
+            if !search::is_uid(&reference.sop_instance_uid) {
+                return Err(
+                    format!("Invalid SOP Instance UID {}", reference.sop_instance_uid).into(),
+                );
+            }
+            study_uids.push(reference.study_instance_uid.clone());
+            let query = QidoInstanceQuery {
+                includerejected: Some(true),
+                matches: vec![(tags::SOP_INSTANCE_UID, reference.sop_instance_uid.clone())],
+                ..Default::default()
+            };
+            let instances = self
+                .backend
+                .search_instances(principal, None, None, &query)
+                .await?;
+            study_uids.extend(
+                instances
+                    .iter()
+                    .filter_map(|instance| search::uid(instance, tags::STUDY_INSTANCE_UID)),
+            );
+        }
+        Ok(study_uids)
+    }
+
+    async fn is_authorized(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+    ) -> Result<bool, BackendError> {
+        match self.study(principal, study_uid).await? {
+            Some(study) => self.authorizer.authorize_study(principal, &study).await,
+            None => Ok(false),
+        }
+    }
+
+    async fn check_study(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+    ) -> Result<(), BackendError> {
+        if self.is_authorized(principal, study_uid).await? {
+            Ok(())
+        } else {
+            log::info!(
+                "Denied access of {} to study {}",
+                principal.subject,
+                study_uid
+            );
+            Err(AuthError::Denied.into())
+        }
+    }
+
+    /// Check the write access to the existing ones of the given studies
+    async fn check_write(
+        &self,
+        principal: &Principal,
+        study_uids: &[&str],
+    ) -> Result<(), BackendError> {
+        for (study_uid, study) in self.studies(principal, study_uids).await? {
+            if !self.authorizer.authorize_write(principal, &study).await? {
+                log::info!(
+                    "Denied write access of {} to study {}",
+                    principal.subject,
+                    study_uid
+                );
+                return Err(AuthError::Denied.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the write access to the instances and to the existing studies they're stored in
+    async fn check_store(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        for instance in instances {
+            if !self.authorizer.authorize_write(principal, instance).await? {
+                log::info!(
+                    "Denied storing of {:?} by {}",
+                    search::uid(instance, tags::SOP_INSTANCE_UID),
+                    principal.subject
+                );
+                return Err(AuthError::Denied.into());
+            }
+        }
+        let study_uids: Vec<String> = instances
+            .iter()
+            .filter_map(|instance| search::uid(instance, tags::STUDY_INSTANCE_UID))
+            .collect();
+        let study_uids: Vec<&str> = study_uids.iter().map(String::as_str).collect();
+        self.check_write(principal, &study_uids).await
+    }
+
+    /// Keep the series or instances of authorized studies, the studies are searched at once
+    async fn filter_by_study(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        results: Vec<InMemDicomObject>,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let uids: Vec<Option<String>> = results
+            .iter()
+            .map(|result| {
+                search::uid(result, tags::STUDY_INSTANCE_UID)
+                    .or_else(|| study_uid.map(str::to_string))
+            })
+            .collect();
+        let study_uids: Vec<&str> = uids.iter().flatten().map(String::as_str).collect();
+        let mut authorized = HashSet::new();
+        for (uid, study) in self.studies(principal, &study_uids).await? {
+            if self.authorizer.authorize_study(principal, &study).await? {
+                authorized.insert(uid);
+            }
+        }
+        Ok(results
+            .into_iter()
+            .zip(uids)
+            .filter(|(_, uid)| uid.as_ref().is_some_and(|uid| authorized.contains(uid)))
+            .map(|(result, _)| result)
+            .collect())
+    }
+
+    /// Fetch the matches page by page, keeping the authorized ones, until the page of them
+    /// requested by the offset and limit is complete. A page is fetched with the offset
+    /// of its first match and yields the number of matches and the authorized ones.
+    async fn paginate<'a>(
+        &self,
+        offset: Option<usize>,
+        limit: Option<usize>,
+        mut fetch: impl FnMut(
+                usize,
+                usize,
+            )
+                -> BoxFuture<'a, Result<(usize, Vec<InMemDicomObject>), BackendError>>
+            + Send,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let wanted = limit.map(|limit| offset.unwrap_or(0) + limit);
+        let mut results = Vec::new();
+        let mut start = 0;
+        loop {
+            let (fetched, authorized) = fetch(start, self.page_size).await?;
+            results.extend(authorized);
+            start += fetched;
+            if fetched < self.page_size || wanted.is_some_and(|wanted| results.len() >= wanted) {
+                break;
+            }
+        }
+        Ok(search::paginate(results, offset, limit))
+    }
+}
+
+#[async_trait]
+impl<B: DicomWebBackend> DicomWebBackend for AuthorizedBackend<B> {
+    async fn search_study(
+        &self,
+        principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        // Attributes which were only included for the authorizer are removed again
+        let included = search::included_tags(query.includefields.iter().map(String::as_str));
+        let added: Vec<Tag> = self
+            .authorizer
+            .attributes()
+            .into_iter()
+            .filter(|tag| {
+                !STUDY_TAGS.contains(tag)
+                    && included
+                        .as_ref()
+                        .is_some_and(|included| !included.contains(tag))
+                    && !query.matches.iter().any(|(matched, _)| matched == tag)
+            })
+            .collect();
+
+        let study_query = self.study_query(query);
+        let study_query = &study_query;
+        let added = &added;
+        self.paginate(query.offset, query.limit, |offset, limit| {
+            async move {
+                let page = QidoStudyQuery {
+                    offset: Some(offset),
+                    limit: Some(limit),
+                    ..study_query.clone()
+                };
+                let studies = self.backend.search_study(principal, &page).await?;
+                let fetched = studies.len();
+                let mut results = Vec::new();
+                for mut study in studies {
+                    if self.authorizer.authorize_study(principal, &study).await? {
+                        for tag in added {
+                            study.remove_element(*tag);
+                        }
+                        results.push(study);
+                    }
+                }
+                Ok((fetched, results))
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    async fn search_series(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.paginate(query.offset, query.limit, |offset, limit| {
+            async move {
+                let page = QidoSeriesQuery {
+                    offset: Some(offset),
+                    limit: Some(limit),
+                    ..query.clone()
+                };
+                let series = self
+                    .backend
+                    .search_series(principal, study_uid, &page)
+                    .await?;
+                let fetched = series.len();
+                Ok((
+                    fetched,
+                    self.filter_by_study(principal, study_uid, series).await?,
+                ))
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    async fn search_instances(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.paginate(query.offset, query.limit, |offset, limit| {
+            async move {
+                let page = QidoInstanceQuery {
+                    offset: Some(offset),
+                    limit: Some(limit),
+                    ..query.clone()
+                };
+                let instances = self
+                    .backend
+                    .search_instances(principal, study_uid, series_uid, &page)
+                    .await?;
+                let fetched = instances.len();
+                Ok((
+                    fetched,
+                    self.filter_by_study(principal, study_uid, instances)
+                        .await?,
+                ))
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    async fn retrieve_study(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.check_study(principal, study_uid).await?;
+        self.backend.retrieve_study(principal, study_uid).await
+    }
+
+    async fn retrieve_series(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.check_study(principal, study_uid).await?;
+        self.backend
+            .retrieve_series(principal, study_uid, series_uid)
+            .await
+    }
+
+    async fn retrieve_series_metadata(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.check_study(principal, study_uid).await?;
+        self.backend
+            .retrieve_series_metadata(principal, study_uid, series_uid)
+            .await
+    }
+
+    async fn retrieve_instance(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        self.check_study(principal, study_uid).await?;
+        self.backend
+            .retrieve_instance(principal, study_uid, series_uid, sop_instance_uid)
+            .await
+    }
+
+    async fn retrieve_frames(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+        frames: &[u32],
+    ) -> Result<Vec<Bytes>, BackendError> {
+        self.check_study(principal, study_uid).await?;
+        self.backend
+            .retrieve_frames(principal, study_uid, series_uid, sop_instance_uid, frames)
+            .await
+    }
+
+    async fn version(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: Option<&str>,
+    ) -> Result<Option<ResourceVersion>, BackendError> {
+        self.check_study(principal, study_uid).await?;
+        self.backend.version(principal, study_uid, series_uid).await
+    }
+
+    async fn store_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        self.check_store(principal, instances).await?;
+        self.backend.store_instances(principal, instances).await
+    }
+
+    async fn update_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        self.check_store(principal, instances).await?;
+        self.backend.update_instances(principal, instances).await
+    }
+
+    async fn reject_instances(
+        &self,
+        principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        let study_uids = self.referenced_studies(principal, &note.instances).await?;
+        let study_uids: Vec<&str> = study_uids.iter().map(String::as_str).collect();
+        self.check_write(principal, &study_uids).await?;
+        self.backend.reject_instances(principal, note).await
+    }
+
+    async fn delete_instances(
+        &self,
+        principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        let study_uids = self.referenced_studies(principal, instances).await?;
+        let study_uids: Vec<&str> = study_uids.iter().map(String::as_str).collect();
+        self.check_write(principal, &study_uids).await?;
+        self.backend.delete_instances(principal, instances).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{DataElement, VR},
+        dictionary_std::uids,
+    };
+
+    use super::*;
+    use crate::{
+        auth::{AttributeAuthorizer, AuthScheme},
+        backend::InMemoryBackend,
+        testing,
+    };
+
+    const ALICE_STUDY_UID: &str = "1.2.840.10008.41.1";
+    const OTHER_STUDY_UID: &str = "1.2.840.10008.41.2";
+
+    /// Radiograph of a clinic patient, whose series and instance UIDs extend the study UID
+    fn clinic_radiograph(study_uid: &str, patient_id: &str) -> FileDicomObject<InMemDicomObject> {
+        let series_uid = series_uid(study_uid);
+        let sop_uid = sop_uid(study_uid);
+        testing::file(InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                uids::DIGITAL_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+            ),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, sop_uid.as_str()),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, study_uid),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, series_uid.as_str()),
+            DataElement::new(tags::PATIENT_ID, VR::LO, patient_id),
+            DataElement::new(tags::MODALITY, VR::CS, "DX"),
+        ]))
+    }
+
+    fn series_uid(study_uid: &str) -> String {
+        format!("{}.1", study_uid)
+    }
+
+    fn sop_uid(study_uid: &str) -> String {
+        format!("{}.1.1", study_uid)
+    }
+
+    /// Backend with a study of alice's patient, and one of another patient
+    async fn authorized_backend() -> AuthorizedBackend<InMemoryBackend> {
+        let backend = InMemoryBackend::new();
+        backend
+            .store_instances(
+                &testing::principal(),
+                &[
+                    clinic_radiograph(ALICE_STUDY_UID, "ALICE-PATIENT"),
+                    clinic_radiograph(OTHER_STUDY_UID, "OTHER-PATIENT"),
+                ],
+            )
+            .await
+            .unwrap();
+        let authorizer =
+            AttributeAuthorizer::new(tags::PATIENT_ID).with_grant("alice", "ALICE-PATIENT");
+        AuthorizedBackend::new(backend, authorizer)
+    }
+
+    fn alice() -> Principal {
+        Principal::new("alice", AuthScheme::Basic)
+    }
+
+    fn is_denied(error: &BackendError) -> bool {
+        matches!(error.downcast_ref::<AuthError>(), Some(AuthError::Denied))
+    }
+
+    #[tokio::test]
+    async fn denies_wildcards_and_lists_as_study() {
+        let backend = authorized_backend().await;
+        backend
+            .retrieve_study(&alice(), ALICE_STUDY_UID)
+            .await
+            .unwrap();
+        let list = format!("{}\\{}", ALICE_STUDY_UID, OTHER_STUDY_UID);
+        for study_uid in ["*", "1.2.840.*", &list] {
+            let error = backend
+                .retrieve_study(&alice(), study_uid)
+                .await
+                .unwrap_err();
+            assert!(is_denied(&error), "{}", study_uid);
+        }
+    }
+
+    #[tokio::test]
+    async fn denies_references_into_foreign_studies() {
+        let backend = authorized_backend().await;
+        // The SOP Instance of the other study, disguised as one of the authorized study
+        let reference = InstanceReference {
+            study_instance_uid: ALICE_STUDY_UID.to_string(),
+            series_instance_uid: series_uid(ALICE_STUDY_UID),
+            sop_instance_uid: sop_uid(OTHER_STUDY_UID),
+        };
+        let error = backend
+            .delete_instances(&alice(), &[reference])
+            .await
+            .unwrap_err();
+        assert!(is_denied(&error));
+
+        let remaining = backend
+            .backend
+            .retrieve_study(&testing::principal(), OTHER_STUDY_UID)
+            .await
+            .unwrap();
+        assert_eq!(remaining.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn pages_through_the_matches_of_searches() {
+        // Six studies, of which alice may access the odd ones
+        let backend = InMemoryBackend::new();
+        let instances: Vec<_> = (1..=6)
+            .map(|number| {
+                let patient_id = if number % 2 == 1 {
+                    "ALICE-PATIENT"
+                } else {
+                    "OTHER-PATIENT"
+                };
+                clinic_radiograph(&format!("1.2.840.10008.41.3.{}", number), patient_id)
+            })
+            .collect();
+        backend
+            .store_instances(&testing::principal(), &instances)
+            .await
+            .unwrap();
+        let authorizer =
+            AttributeAuthorizer::new(tags::PATIENT_ID).with_grant("alice", "ALICE-PATIENT");
+        let backend = AuthorizedBackend::new(backend, authorizer).with_page_size(2);
+        let study_uids = |results: Vec<InMemDicomObject>| -> Vec<String> {
+            results
+                .iter()
+                .filter_map(|result| search::uid(result, tags::STUDY_INSTANCE_UID))
+                .collect()
+        };
+
+        let query = QidoStudyQuery {
+            offset: Some(1),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let studies = backend.search_study(&alice(), &query).await.unwrap();
+        assert_eq!(
+            study_uids(studies),
+            ["1.2.840.10008.41.3.3", "1.2.840.10008.41.3.5"]
+        );
+
+        let query = QidoSeriesQuery {
+            offset: Some(1),
+            ..Default::default()
+        };
+        let series = backend.search_series(&alice(), None, &query).await.unwrap();
+        assert_eq!(
+            study_uids(series),
+            ["1.2.840.10008.41.3.3", "1.2.840.10008.41.3.5"]
+        );
+
+        let query = QidoInstanceQuery {
+            limit: Some(1),
+            ..Default::default()
+        };
+        let instances = backend
+            .search_instances(&alice(), None, None, &query)
+            .await
+            .unwrap();
+        assert_eq!(study_uids(instances), ["1.2.840.10008.41.3.1"]);
+    }
+}
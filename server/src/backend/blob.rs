@@ -0,0 +1,391 @@
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::PathBuf,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use dicom::{
+    dictionary_std::{tags, uids},
+    encoding::{Endianness, TransferSyntaxIndex},
+    transfer_syntax::TransferSyntaxRegistry,
+};
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use dicom_object::{FileDicomObject, InMemDicomObject};
+use futures_util::{
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
+
+use super::BackendError;
+
+// Size of the chunks, in which blobs are read and written
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+pub type BlobStream<'a> = BoxStream<'a, Result<Bytes, BackendError>>;
+
+/// Storage of the Part 10 files behind a database backend.
+///
+/// The files are addressed by keys of the form `{study}/{series}/{sop}.dcm`.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Write a blob from a stream of chunks, replacing an existing one
+    async fn put(&self, key: &str, chunks: BlobStream<'_>) -> Result<(), BackendError>;
+
+    /// Read a blob as a stream of chunks
+    async fn get(&self, key: &str) -> Result<BlobStream<'static>, BackendError>;
+
+    /// Read a byte range of a blob
+    async fn get_range(&self, key: &str, range: Range<u64>) -> Result<Bytes, BackendError>;
+
+    async fn delete(&self, key: &str) -> Result<(), BackendError>;
+}
+
+/// Keeps the blobs as files below a root directory
+pub struct FileBlobStore {
+    root: PathBuf,
+}
+
+impl FileBlobStore {
+    /// Store files below the root directory, which is created when the first file is written
+    pub fn new(root: impl Into<PathBuf>) -> FileBlobStore {
+        FileBlobStore { root: root.into() }
+    }
+}
+
+#[async_trait]
+impl BlobStore for FileBlobStore {
+    async fn put(&self, key: &str, mut chunks: BlobStream<'_>) -> Result<(), BackendError> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = File::create(path)?;
+        while let Some(chunk) = chunks.try_next().await? {
+            file.write_all(&chunk)?;
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<BlobStream<'static>, BackendError> {
+        let file = File::open(self.root.join(key))?;
+        let chunks = stream::try_unfold(file, |mut file| async move {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            (&mut file)
+                .take(CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some((Bytes::from(chunk), file)))
+            }
+        });
+        Ok(chunks.boxed())
+    }
+
+    async fn get_range(&self, key: &str, range: Range<u64>) -> Result<Bytes, BackendError> {
+        let mut file = File::open(self.root.join(key))?;
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut data = vec![0; (range.end - range.start) as usize];
+        file.read_exact(&mut data)?;
+        Ok(Bytes::from(data))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BackendError> {
+        match fs::remove_file(self.root.join(key)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+pub use self::s3::S3BlobStore;
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use std::{ops::Range, sync::Arc};
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use futures_util::{StreamExt, TryStreamExt};
+    use object_store::{aws::AmazonS3Builder, path::Path, ObjectStore, WriteMultipart};
+
+    use super::{BlobStore, BlobStream};
+    use crate::backend::BackendError;
+
+    // Blobs up to this size are uploaded with a single request, larger ones in parts of this size
+    const PART_SIZE: usize = 8 * 1024 * 1024;
+    // Number of parts, which are uploaded at the same time
+    const MAX_CONCURRENT_PARTS: usize = 4;
+
+    /// Keeps the blobs in an S3-compatible bucket.
+    ///
+    /// Uploads and downloads are streamed, frames are read with ranged GETs.
+    pub struct S3BlobStore {
+        store: Arc<dyn ObjectStore>,
+    }
+
+    impl S3BlobStore {
+        /// Use a bucket, which is accessed with the configuration from the `AWS_*`
+        /// environment variables. For MinIO, set `AWS_ENDPOINT` and `AWS_ALLOW_HTTP`.
+        pub fn from_env(bucket: &str) -> Result<S3BlobStore, BackendError> {
+            let store = AmazonS3Builder::from_env()
+                .with_bucket_name(bucket)
+                .build()?;
+            Ok(S3BlobStore::new(store))
+        }
+
+        /// Use any object store, e.g. `object_store::memory::InMemory` in tests
+        pub fn new(store: impl ObjectStore) -> S3BlobStore {
+            S3BlobStore {
+                store: Arc::new(store),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl BlobStore for S3BlobStore {
+        async fn put(&self, key: &str, mut chunks: BlobStream<'_>) -> Result<(), BackendError> {
+            let path = Path::from(key);
+
+            let mut buffered = Vec::new();
+            while buffered.len() < PART_SIZE {
+                match chunks.try_next().await? {
+                    Some(chunk) => buffered.extend_from_slice(&chunk),
+                    None => {
+                        self.store.put(&path, Bytes::from(buffered).into()).await?;
+                        return Ok(());
+                    }
+                }
+            }
+
+            let mut upload = WriteMultipart::new_with_chunk_size(
+                self.store.put_multipart(&path).await?,
+                PART_SIZE,
+            );
+            upload.put(Bytes::from(buffered));
+            loop {
+                let chunk = match chunks.try_next().await {
+                    Ok(Some(chunk)) => chunk,
+                    Ok(None) => break,
+                    Err(e) => {
+                        upload.abort().await?;
+                        return Err(e);
+                    }
+                };
+                upload.wait_for_capacity(MAX_CONCURRENT_PARTS).await?;
+                upload.put(chunk);
+            }
+            upload.finish().await?;
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> Result<BlobStream<'static>, BackendError> {
+            let result = self.store.get(&Path::from(key)).await?;
+            Ok(result.into_stream().map_err(BackendError::from).boxed())
+        }
+
+        async fn get_range(&self, key: &str, range: Range<u64>) -> Result<Bytes, BackendError> {
+            let range = range.start as usize..range.end as usize;
+            Ok(self.store.get_range(&Path::from(key), range).await?)
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), BackendError> {
+            match self.store.delete(&Path::from(key)).await {
+                Err(object_store::Error::NotFound { .. }) => Ok(()),
+                result => Ok(result?),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use futures_util::stream;
+        use object_store::memory::InMemory;
+
+        use super::*;
+
+        async fn read(blobs: &S3BlobStore, key: &str) -> Vec<u8> {
+            let chunks: Vec<Bytes> = blobs.get(key).await.unwrap().try_collect().await.unwrap();
+            chunks.concat()
+        }
+
+        #[tokio::test]
+        async fn streams_small_and_multipart_blobs() {
+            let blobs = S3BlobStore::new(InMemory::new());
+
+            blobs
+                .put(
+                    "small.dcm",
+                    stream::iter([Ok(Bytes::from_static(b"DICM"))]).boxed(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(read(&blobs, "small.dcm").await, b"DICM");
+
+            // Three chunks of 5 MiB exceed a part, so the blob is uploaded in parts
+            let data: Vec<u8> = (0..3 * 5 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+            let chunks: Vec<Result<Bytes, BackendError>> = data
+                .chunks(5 * 1024 * 1024)
+                .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                .collect();
+            blobs
+                .put("large.dcm", stream::iter(chunks).boxed())
+                .await
+                .unwrap();
+            assert_eq!(read(&blobs, "large.dcm").await, data);
+
+            let start = PART_SIZE as u64 - 10;
+            let range = blobs
+                .get_range("large.dcm", start..start + 20)
+                .await
+                .unwrap();
+            assert_eq!(range, data[start as usize..start as usize + 20]);
+
+            blobs.delete("large.dcm").await.unwrap();
+            assert!(blobs.get("large.dcm").await.is_err());
+            // Deleting a missing blob succeeds
+            blobs.delete("large.dcm").await.unwrap();
+        }
+    }
+}
+
+/// Position of the uncompressed frames inside a stored file
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+#[derive(Clone, Copy)]
+pub(crate) struct FrameLayout {
+    /// Offset of the pixel data value
+    pub(crate) offset: u64,
+    /// Bytes per frame
+    pub(crate) length: u64,
+    pub(crate) count: u64,
+}
+
+/// Write an instance as Part 10 file, the frames are located if they can be read by range
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub(crate) fn encode(
+    instance: &FileDicomObject<InMemDicomObject>,
+) -> Result<(Bytes, Option<FrameLayout>), BackendError> {
+    let mut data = Vec::new();
+    instance.write_all(&mut data)?;
+    let layout = frame_layout(instance, &data)?;
+    Ok((Bytes::from(data), layout))
+}
+
+/// Only native little endian pixel data with whole bytes per frame is located
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+fn frame_layout(
+    instance: &FileDicomObject<InMemDicomObject>,
+    data: &[u8],
+) -> Result<Option<FrameLayout>, BackendError> {
+    let transfer_syntax = instance.meta().transfer_syntax().trim_end_matches('\0');
+    let Some(ts) = TransferSyntaxRegistry.get(transfer_syntax) else {
+        return Ok(None);
+    };
+    if !ts.is_codec_free() || ts.endianness() != Endianness::Little {
+        return Ok(None);
+    }
+    let number = |tag| -> Option<u64> {
+        instance
+            .element(tag)
+            .ok()
+            .and_then(|elt| elt.to_int::<u64>().ok())
+    };
+    let (Some(rows), Some(columns), Some(bits_allocated)) = (
+        number(tags::ROWS),
+        number(tags::COLUMNS),
+        number(tags::BITS_ALLOCATED),
+    ) else {
+        return Ok(None);
+    };
+    if bits_allocated % 8 != 0 || instance.element(tags::PIXEL_DATA).is_err() {
+        return Ok(None);
+    }
+    let length = rows * columns * number(tags::SAMPLES_PER_PIXEL).unwrap_or(1) * bits_allocated / 8;
+    let count = number(tags::NUMBER_OF_FRAMES).unwrap_or(1);
+
+    // The pixel data starts after all attributes before it
+    let mut header = Vec::new();
+    InMemDicomObject::from_element_iter(
+        instance
+            .iter()
+            .filter(|elt| elt.header().tag < tags::PIXEL_DATA)
+            .cloned(),
+    )
+    .with_exact_meta(instance.meta().clone())
+    .write_all(&mut header)?;
+    let start = header.len();
+    let explicit_vr = transfer_syntax != uids::IMPLICIT_VR_LITTLE_ENDIAN;
+    let header_length = if explicit_vr { 12 } else { 8 };
+    let Some(element_header) = data.get(start..start + header_length) else {
+        return Ok(None);
+    };
+    let value_length = u32::from_le_bytes(
+        element_header[header_length - 4..]
+            .try_into()
+            .expect("4 bytes"),
+    );
+    if element_header[..4] != [0xE0, 0x7F, 0x10, 0x00]
+        || value_length == u32::MAX
+        || u64::from(value_length) < length * count
+        || length == 0
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(FrameLayout {
+        offset: (start + header_length) as u64,
+        length,
+        count,
+    }))
+}
+
+/// Read and parse a stored file
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub(crate) async fn read_instance(
+    blobs: &dyn BlobStore,
+    key: &str,
+) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+    let mut chunks = blobs.get(key).await?;
+    let mut data = Vec::new();
+    while let Some(chunk) = chunks.try_next().await? {
+        data.extend_from_slice(&chunk);
+    }
+    Ok(FileDicomObject::from_reader(data.as_slice())?)
+}
+
+/// Read frames, numbered from 1, with a ranged read each
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub(crate) async fn read_frames(
+    blobs: &dyn BlobStore,
+    key: &str,
+    layout: FrameLayout,
+    frames: &[u32],
+) -> Result<Vec<Bytes>, BackendError> {
+    let mut results = Vec::with_capacity(frames.len());
+    for &frame in frames {
+        let frame = u64::from(frame);
+        if frame == 0 || frame > layout.count {
+            return Err(format!("Frame {} doesn't exist", frame).into());
+        }
+        let start = layout.offset + (frame - 1) * layout.length;
+        results.push(blobs.get_range(key, start..start + layout.length).await?);
+    }
+    Ok(results)
+}
+
+/// Split a file into the chunks of an upload
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub(crate) fn chunks(data: Bytes) -> BlobStream<'static> {
+    let count = data.len().div_ceil(CHUNK_SIZE);
+    stream::iter((0..count).map(move |index| {
+        let start = index * CHUNK_SIZE;
+        Ok(data.slice(start..data.len().min(start + CHUNK_SIZE)))
+    }))
+    .boxed()
+}
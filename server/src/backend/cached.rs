@@ -0,0 +1,409 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use dicom_json::DicomJson;
+use dicom_object::{FileDicomObject, InMemDicomObject};
+
+use super::{instance_frames, BackendError, DicomWebBackend, ResourceVersion};
+use crate::{
+    auth::Principal,
+    cache::{CacheKind, FrameCache},
+    InstanceReference, QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery, RejectionNote,
+};
+
+/// Caches the instances, frames and series metadata of a backend in a [`FrameCache`].
+///
+/// Frames missing from the cache are decoded from the instance, if it was cached by a
+/// retrieval, and otherwise retrieved from the backend, which may read just the requested
+/// ones. Only the requested frames are cached. The entries are keyed by the study, series
+/// and SOP Instance UID of the request, so a request with a mismatched path never hits the
+/// entry of another study. Stores, updates, rejections and deletions invalidate the entries of their
+/// instances and series.
+/// The cache is shared by all principals, so wrap it into an
+/// [`AuthorizedBackend`](super::AuthorizedBackend) or
+/// [`DeidentifiedBackend`](super::DeidentifiedBackend) and not the other way around.
+pub struct CachedBackend<B> {
+    backend: B,
+    cache: FrameCache,
+}
+
+impl<B: DicomWebBackend> CachedBackend<B> {
+    pub fn new(backend: B, cache: FrameCache) -> CachedBackend<B> {
+        CachedBackend { backend, cache }
+    }
+
+    fn invalidate(&self, study_uid: &str, series_uid: &str, sop_instance_uid: &str) {
+        let id = instance_id(study_uid, series_uid, sop_instance_uid);
+        self.cache.invalidate(CacheKind::EncodedFrames, &id, None);
+        self.cache
+            .invalidate(CacheKind::DecodedFrames, &id, Some(&format!("{}/", id)));
+        self.cache.invalidate(
+            CacheKind::SeriesMetadata,
+            &format!("{}/{}", study_uid, series_uid),
+            None,
+        );
+    }
+
+    fn invalidate_instances(&self, instances: &[FileDicomObject<InMemDicomObject>]) {
+        for instance in instances {
+            if let Some(reference) = InstanceReference::from_dicom(instance) {
+                self.invalidate(
+                    &reference.study_instance_uid,
+                    &reference.series_instance_uid,
+                    &reference.sop_instance_uid,
+                );
+            }
+        }
+    }
+}
+
+/// Id of an instance by its full path
+fn instance_id(study_uid: &str, series_uid: &str, sop_instance_uid: &str) -> String {
+    format!("{}/{}/{}", study_uid, series_uid, sop_instance_uid)
+}
+
+/// Id of a decoded frame, numbered from 1
+fn frame_id(instance_id: &str, frame: u32) -> String {
+    format!("{}/{}", instance_id, frame)
+}
+
+#[async_trait]
+impl<B: DicomWebBackend> DicomWebBackend for CachedBackend<B> {
+    async fn search_study(
+        &self,
+        principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.backend.search_study(principal, query).await
+    }
+
+    async fn search_series(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.backend
+            .search_series(principal, study_uid, query)
+            .await
+    }
+
+    async fn search_instances(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.backend
+            .search_instances(principal, study_uid, series_uid, query)
+            .await
+    }
+
+    async fn retrieve_study(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.backend.retrieve_study(principal, study_uid).await
+    }
+
+    async fn retrieve_series(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.backend
+            .retrieve_series(principal, study_uid, series_uid)
+            .await
+    }
+
+    async fn retrieve_series_metadata(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let id = format!("{}/{}", study_uid, series_uid);
+        if let Some(json) = self.cache.get(CacheKind::SeriesMetadata, &id) {
+            let instances: Vec<DicomJson<InMemDicomObject>> = serde_json::from_slice(&json)?;
+            return Ok(instances.into_iter().map(DicomJson::into_inner).collect());
+        }
+        let instances = self
+            .backend
+            .retrieve_series_metadata(principal, study_uid, series_uid)
+            .await?;
+        // Unknown series are not cached, they may be stored elsewhere meanwhile
+        if !instances.is_empty() {
+            let json = serde_json::to_vec(&DicomJson::from(instances.as_slice()))?;
+            self.cache
+                .insert(CacheKind::SeriesMetadata, &id, Bytes::from(json));
+        }
+        Ok(instances)
+    }
+
+    async fn retrieve_instance(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        let id = instance_id(study_uid, series_uid, sop_instance_uid);
+        if let Some(data) = self.cache.get(CacheKind::EncodedFrames, &id) {
+            return Ok(FileDicomObject::from_reader(&data[..])?);
+        }
+        let instance = self
+            .backend
+            .retrieve_instance(principal, study_uid, series_uid, sop_instance_uid)
+            .await?;
+        let mut data = Vec::new();
+        instance.write_all(&mut data)?;
+        self.cache
+            .insert(CacheKind::EncodedFrames, &id, Bytes::from(data));
+        Ok(instance)
+    }
+
+    async fn retrieve_frames(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+        frames: &[u32],
+    ) -> Result<Vec<Bytes>, BackendError> {
+        let id = instance_id(study_uid, series_uid, sop_instance_uid);
+        let mut cached: Vec<Option<Bytes>> = frames
+            .iter()
+            .map(|&frame| {
+                self.cache
+                    .get(CacheKind::DecodedFrames, &frame_id(&id, frame))
+            })
+            .collect();
+        let mut missing: Vec<u32> = frames
+            .iter()
+            .zip(&cached)
+            .filter(|(_, data)| data.is_none())
+            .map(|(&frame, _)| frame)
+            .collect();
+        if missing.is_empty() {
+            return Ok(cached.into_iter().flatten().collect());
+        }
+        missing.sort_unstable();
+        missing.dedup();
+
+        // The frames of a cached instance are decoded without a retrieval
+        let retrieved = match self.cache.get(CacheKind::EncodedFrames, &id) {
+            Some(data) => instance_frames(&FileDicomObject::from_reader(&data[..])?, &missing)?,
+            None => {
+                self.backend
+                    .retrieve_frames(principal, study_uid, series_uid, sop_instance_uid, &missing)
+                    .await?
+            }
+        };
+        if retrieved.len() != missing.len() {
+            return Err("Backend returned an unexpected number of frames".into());
+        }
+        for (&frame, data) in missing.iter().zip(&retrieved) {
+            self.cache.insert(
+                CacheKind::DecodedFrames,
+                &frame_id(&id, frame),
+                data.clone(),
+            );
+        }
+        for (&frame, data) in frames.iter().zip(cached.iter_mut()) {
+            if data.is_none() {
+                let index = missing
+                    .binary_search(&frame)
+                    .map_err(|_| "Frame not retrieved")?;
+                *data = Some(retrieved[index].clone());
+            }
+        }
+        Ok(cached.into_iter().flatten().collect())
+    }
+
+    async fn version(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: Option<&str>,
+    ) -> Result<Option<ResourceVersion>, BackendError> {
+        self.backend.version(principal, study_uid, series_uid).await
+    }
+
+    async fn store_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        let result = self.backend.store_instances(principal, instances).await;
+        // Some instances may have been stored, even if others failed
+        self.invalidate_instances(instances);
+        result
+    }
+
+    async fn update_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        let result = self.backend.update_instances(principal, instances).await;
+        self.invalidate_instances(instances);
+        result
+    }
+
+    async fn reject_instances(
+        &self,
+        principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        let result = self.backend.reject_instances(principal, note).await;
+        for instance in &note.instances {
+            self.invalidate(
+                &instance.study_instance_uid,
+                &instance.series_instance_uid,
+                &instance.sop_instance_uid,
+            );
+        }
+        result
+    }
+
+    async fn delete_instances(
+        &self,
+        principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        let result = self.backend.delete_instances(principal, instances).await;
+        for instance in instances {
+            self.invalidate(
+                &instance.study_instance_uid,
+                &instance.series_instance_uid,
+                &instance.sop_instance_uid,
+            );
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{DataElement, VR},
+        dictionary_std::{tags, uids},
+    };
+
+    use super::*;
+    use crate::{backend::InMemoryBackend, testing};
+
+    const STUDY_UID: &str = "1.2.840.10008.50.1";
+    const SERIES_UID: &str = "1.2.840.10008.50.1.1";
+    const SOP_UID: &str = "1.2.840.10008.50.1.1.1";
+
+    /// Cached backend with a cardiac cine of two 2x2 frames, which a viewer pages through
+    async fn cached_backend() -> CachedBackend<InMemoryBackend> {
+        let mut cine = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SOP_CLASS_UID, VR::UI, uids::ENHANCED_MR_IMAGE_STORAGE),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, SOP_UID),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, SERIES_UID),
+            DataElement::new(tags::MODALITY, VR::CS, "MR"),
+            DataElement::new(tags::BODY_PART_EXAMINED, VR::CS, "HEART"),
+        ]);
+        cine.extend(testing::grayscale_frames(2, 2, 2));
+
+        let backend = InMemoryBackend::new();
+        backend
+            .store_instances(&testing::principal(), &[testing::file(cine)])
+            .await
+            .unwrap();
+        CachedBackend::new(backend, FrameCache::new(1 << 20))
+    }
+
+    #[tokio::test]
+    async fn caches_the_requested_frames() {
+        let backend = cached_backend().await;
+        let principal = testing::principal();
+
+        let frames = backend
+            .retrieve_frames(&principal, STUDY_UID, SERIES_UID, SOP_UID, &[2])
+            .await
+            .unwrap();
+        assert_eq!(frames, vec![Bytes::from_static(&[2, 2, 2, 2])]);
+        let stats = backend.cache.stats();
+        assert_eq!(stats.decoded_frames.misses, 1);
+        // Only the requested frame is cached
+        assert_eq!(stats.memory_bytes, 4);
+
+        let frames = backend
+            .retrieve_frames(&principal, STUDY_UID, SERIES_UID, SOP_UID, &[2, 1])
+            .await
+            .unwrap();
+        assert_eq!(
+            frames,
+            vec![
+                Bytes::from_static(&[2, 2, 2, 2]),
+                Bytes::from_static(&[1, 1, 1, 1])
+            ]
+        );
+        assert_eq!(backend.cache.stats().decoded_frames.hits, 1);
+    }
+
+    #[tokio::test]
+    async fn mismatched_path_misses_the_cache() {
+        let backend = cached_backend().await;
+        let principal = testing::principal();
+        backend
+            .retrieve_frames(&principal, STUDY_UID, SERIES_UID, SOP_UID, &[1])
+            .await
+            .unwrap();
+        backend
+            .retrieve_instance(&principal, STUDY_UID, SERIES_UID, SOP_UID)
+            .await
+            .unwrap();
+
+        let frames = backend
+            .retrieve_frames(&principal, "1.2.840.10008.50.2", SERIES_UID, SOP_UID, &[1])
+            .await;
+        assert!(frames.is_err());
+        let instance = backend
+            .retrieve_instance(&principal, "1.2.840.10008.50.2", SERIES_UID, SOP_UID)
+            .await;
+        assert!(instance.is_err());
+
+        let stats = backend.cache.stats();
+        assert_eq!(stats.decoded_frames.hits, 0);
+        assert_eq!(stats.encoded_frames.hits, 0);
+    }
+
+    #[tokio::test]
+    async fn decodes_the_frames_of_a_cached_instance() {
+        let backend = cached_backend().await;
+        let principal = testing::principal();
+        backend
+            .retrieve_instance(&principal, STUDY_UID, SERIES_UID, SOP_UID)
+            .await
+            .unwrap();
+        // The inner backend no longer has the instance, so the frame can only come from the cache
+        backend
+            .backend
+            .delete_instances(
+                &principal,
+                &[InstanceReference {
+                    study_instance_uid: STUDY_UID.to_string(),
+                    series_instance_uid: SERIES_UID.to_string(),
+                    sop_instance_uid: SOP_UID.to_string(),
+                }],
+            )
+            .await
+            .unwrap();
+
+        let frames = backend
+            .retrieve_frames(&principal, STUDY_UID, SERIES_UID, SOP_UID, &[2])
+            .await
+            .unwrap();
+        assert_eq!(frames, vec![Bytes::from_static(&[2, 2, 2, 2])]);
+        let stats = backend.cache.stats();
+        assert_eq!(stats.encoded_frames.hits, 1);
+        assert_eq!(stats.decoded_frames.misses, 1);
+    }
+}
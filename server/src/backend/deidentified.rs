@@ -0,0 +1,718 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use dicom::dictionary_std::tags;
+use dicom_object::{FileDicomObject, InMemDicomObject, Tag};
+
+use super::{search, BackendError, DicomWebBackend, NotFound, ResourceVersion};
+use crate::{
+    auth::{AuthError, Principal},
+    Deidentifier, InstanceReference, QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery,
+    RejectionNote,
+};
+
+// UIDs of the search and retrieval paths, which are replaced unless the UIDs are retained
+const PATH_UIDS: [Tag; 3] = [
+    tags::STUDY_INSTANCE_UID,
+    tags::SERIES_INSTANCE_UID,
+    tags::SOP_INSTANCE_UID,
+];
+
+/// De-identifies the search results and retrieved instances of a backend.
+///
+/// Responses carry the replaced UIDs, which are mapped back to the originals when they
+/// are requested, so the de-identified studies can be navigated like the others. UIDs the
+/// [`Deidentifier`] doesn't remember anymore, e.g. after a restart, are looked up by
+/// replacing the UIDs of the studies, series or instances visible to the principal. Searches
+/// matching attributes which are removed or replaced find nothing. Frames are passed
+/// through unless pixel data is redacted, and stores, rejections and deletions are denied.
+#[allow(clippy::type_complexity)]
+pub struct DeidentifiedBackend<B> {
+    backend: B,
+    deidentifier: Deidentifier,
+    selector: Option<Box<dyn Fn(&Principal) -> bool + Send + Sync>>,
+    base_url: Option<String>,
+}
+
+impl<B: DicomWebBackend> DeidentifiedBackend<B> {
+    pub fn new(backend: B, deidentifier: Deidentifier) -> DeidentifiedBackend<B> {
+        DeidentifiedBackend {
+            backend,
+            deidentifier,
+            selector: None,
+            base_url: None,
+        }
+    }
+
+    /// Only de-identify the data for the selected principals, the others get the originals
+    pub fn with_selector(
+        mut self,
+        selector: impl Fn(&Principal) -> bool + Send + Sync + 'static,
+    ) -> DeidentifiedBackend<B> {
+        self.selector = Some(Box::new(selector));
+        self
+    }
+
+    /// Return the RetrieveURL of the search results, with the replaced UIDs below this URL.
+    /// Without it the RetrieveURL is removed, as it contains the original UIDs.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> DeidentifiedBackend<B> {
+        self.base_url = Some(base_url.into().trim_end_matches('/').to_string());
+        self
+    }
+
+    fn is_selected(&self, principal: &Principal) -> bool {
+        self.selector
+            .as_ref()
+            .is_none_or(|selector| selector(principal))
+    }
+
+    /// Original of a requested UID of a level, 0 for studies to 2 for instances, within the
+    /// original study and series if given. `None` if it is no replaced UID.
+    async fn original_uid(
+        &self,
+        principal: &Principal,
+        uid: &str,
+        level: usize,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+    ) -> Result<Option<String>, BackendError> {
+        if let Some(original) = self.deidentifier.original_uid(uid) {
+            return Ok(Some(original));
+        }
+        // Only UIDs like the replacements are looked up
+        if !uid.starts_with("2.25.") || !search::is_uid(uid) {
+            return Ok(None);
+        }
+
+        // Replacing the candidates remembers them again
+        let (candidates, tag) = match level {
+            0 => {
+                let query = QidoStudyQuery {
+                    includerejected: Some(true),
+                    ..Default::default()
+                };
+                let studies = self.backend.search_study(principal, &query).await?;
+                (studies, tags::STUDY_INSTANCE_UID)
+            }
+            1 => {
+                let query = QidoSeriesQuery {
+                    includerejected: Some(true),
+                    ..Default::default()
+                };
+                let series = self
+                    .backend
+                    .search_series(principal, study_uid, &query)
+                    .await?;
+                (series, tags::SERIES_INSTANCE_UID)
+            }
+            _ => {
+                let query = QidoInstanceQuery {
+                    includerejected: Some(true),
+                    ..Default::default()
+                };
+                let instances = self
+                    .backend
+                    .search_instances(principal, study_uid, series_uid, &query)
+                    .await?;
+                (instances, tags::SOP_INSTANCE_UID)
+            }
+        };
+        let original = candidates
+            .iter()
+            .filter_map(|candidate| search::uid(candidate, tag))
+            .find(|original| self.deidentifier.replace_uid(original) == uid);
+        if original.is_none() {
+            log::debug!("Unknown de-identified UID {}", uid);
+        }
+        Ok(original)
+    }
+
+    /// Originals of the study, series and instance UIDs of a path, `None` if one is unknown
+    async fn original_path(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: Option<&str>,
+        sop_instance_uid: Option<&str>,
+    ) -> Result<Option<(String, Option<String>, Option<String>)>, BackendError> {
+        let Some(study_uid) = self
+            .original_uid(principal, study_uid, 0, None, None)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let Some(series_uid) = self
+            .original_path_uid(principal, series_uid, 1, Some(&study_uid), None)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let Some(sop_instance_uid) = self
+            .original_path_uid(
+                principal,
+                sop_instance_uid,
+                2,
+                Some(&study_uid),
+                series_uid.as_deref(),
+            )
+            .await?
+        else {
+            return Ok(None);
+        };
+        Ok(Some((study_uid, series_uid, sop_instance_uid)))
+    }
+
+    /// Original of an optional path UID, the outer `None` if it is unknown
+    async fn original_path_uid(
+        &self,
+        principal: &Principal,
+        uid: Option<&str>,
+        level: usize,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+    ) -> Result<Option<Option<String>>, BackendError> {
+        match uid {
+            Some(uid) => Ok(self
+                .original_uid(principal, uid, level, study_uid, series_uid)
+                .await?
+                .map(Some)),
+            None => Ok(Some(None)),
+        }
+    }
+
+    /// Original of a matched UID list, `None` if none of the UIDs was returned
+    async fn original_uid_list(
+        &self,
+        principal: &Principal,
+        uids: &str,
+        level: usize,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+    ) -> Result<Option<String>, BackendError> {
+        let mut originals = Vec::new();
+        for uid in uids.split('\\') {
+            originals.extend(
+                self.original_uid(principal, uid.trim(), level, study_uid, series_uid)
+                    .await?,
+            );
+        }
+        Ok((!originals.is_empty()).then(|| originals.join("\\")))
+    }
+
+    /// Matches on the original data within the original study and series of the path,
+    /// `None` if the query can't match de-identified data
+    async fn original_matches(
+        &self,
+        principal: &Principal,
+        matches: &[(Tag, String)],
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+    ) -> Result<Option<Vec<(Tag, String)>>, BackendError> {
+        let mut originals = Vec::with_capacity(matches.len());
+        for (tag, value) in matches {
+            let original = match PATH_UIDS.iter().position(|uid_tag| uid_tag == tag) {
+                Some(level) => {
+                    let study_uid = study_uid.filter(|_| level > 0);
+                    let series_uid = series_uid.filter(|_| level > 1);
+                    self.original_uid_list(principal, value, level, study_uid, series_uid)
+                        .await?
+                }
+                None if self.deidentifier.is_retained(*tag) => Some(value.clone()),
+                None => None,
+            };
+            match original {
+                Some(original) => originals.push((*tag, original)),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(originals))
+    }
+
+    /// De-identify a search result, whose RetrieveURL has the UIDs of the given levels
+    fn deidentify_result(&self, mut result: InMemDicomObject, levels: usize) -> InMemDicomObject {
+        result.remove_element(tags::RETRIEVE_URL);
+        self.deidentifier.deidentify(&mut result);
+        if let Some(base_url) = &self.base_url {
+            let url = PATH_UIDS
+                .into_iter()
+                .zip(["studies", "series", "instances"])
+                .take(levels)
+                .map(|(tag, resource)| {
+                    search::uid(&result, tag).map(|uid| format!("/{}/{}", resource, uid))
+                })
+                .collect::<Option<String>>();
+            if let Some(url) = url {
+                search::put_retrieve_url(&mut result, format!("{}{}", base_url, url));
+            }
+        }
+        result
+    }
+
+    fn deidentify_results(
+        &self,
+        results: Result<Vec<InMemDicomObject>, BackendError>,
+        levels: usize,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        Ok(results?
+            .into_iter()
+            .map(|result| self.deidentify_result(result, levels))
+            .collect())
+    }
+
+    fn deidentify_instances(
+        &self,
+        instances: Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError>,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        let mut instances = instances?;
+        for instance in &mut instances {
+            self.deidentifier.deidentify_file(instance)?;
+        }
+        Ok(instances)
+    }
+
+    fn deny_write(&self, principal: &Principal) -> Result<(), BackendError> {
+        if self.is_selected(principal) {
+            log::info!(
+                "Denied write of {} to de-identified data",
+                principal.subject
+            );
+            return Err(AuthError::Denied.into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<B: DicomWebBackend> DicomWebBackend for DeidentifiedBackend<B> {
+    async fn search_study(
+        &self,
+        principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        if !self.is_selected(principal) {
+            return self.backend.search_study(principal, query).await;
+        }
+        let Some(matches) = self
+            .original_matches(principal, &query.matches, None, None)
+            .await?
+        else {
+            return Ok(Vec::new());
+        };
+        let query = QidoStudyQuery {
+            matches,
+            ..query.clone()
+        };
+        let results = self.backend.search_study(principal, &query).await;
+        self.deidentify_results(results, 1)
+    }
+
+    async fn search_series(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        if !self.is_selected(principal) {
+            return self
+                .backend
+                .search_series(principal, study_uid, query)
+                .await;
+        }
+        if query.series_description.is_some()
+            && !self.deidentifier.is_retained(tags::SERIES_DESCRIPTION)
+        {
+            return Ok(Vec::new());
+        }
+        let Some(study_uid) = self
+            .original_path_uid(principal, study_uid, 0, None, None)
+            .await?
+        else {
+            return Ok(Vec::new());
+        };
+        let Some(matches) = self
+            .original_matches(principal, &query.matches, study_uid.as_deref(), None)
+            .await?
+        else {
+            return Ok(Vec::new());
+        };
+        let series_instance_uid = match query.series_instance_uid.as_deref() {
+            Some(uids) => {
+                let original = self
+                    .original_uid_list(principal, uids, 1, study_uid.as_deref(), None)
+                    .await?;
+                if original.is_none() {
+                    return Ok(Vec::new());
+                }
+                original
+            }
+            None => None,
+        };
+        let query = QidoSeriesQuery {
+            series_instance_uid,
+            matches,
+            ..query.clone()
+        };
+        let results = self
+            .backend
+            .search_series(principal, study_uid.as_deref(), &query)
+            .await;
+        self.deidentify_results(results, 2)
+    }
+
+    async fn search_instances(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        if !self.is_selected(principal) {
+            return self
+                .backend
+                .search_instances(principal, study_uid, series_uid, query)
+                .await;
+        }
+        let Some(study_uid) = self
+            .original_path_uid(principal, study_uid, 0, None, None)
+            .await?
+        else {
+            return Ok(Vec::new());
+        };
+        let Some(series_uid) = self
+            .original_path_uid(principal, series_uid, 1, study_uid.as_deref(), None)
+            .await?
+        else {
+            return Ok(Vec::new());
+        };
+        let Some(matches) = self
+            .original_matches(
+                principal,
+                &query.matches,
+                study_uid.as_deref(),
+                series_uid.as_deref(),
+            )
+            .await?
+        else {
+            return Ok(Vec::new());
+        };
+        let sop_instance_uid = match query.sop_instance_uid.as_deref() {
+            Some(uids) => {
+                let original = self
+                    .original_uid_list(
+                        principal,
+                        uids,
+                        2,
+                        study_uid.as_deref(),
+                        series_uid.as_deref(),
+                    )
+                    .await?;
+                if original.is_none() {
+                    return Ok(Vec::new());
+                }
+                original
+            }
+            None => None,
+        };
+        let query = QidoInstanceQuery {
+            sop_instance_uid,
+            matches,
+            ..query.clone()
+        };
+        let results = self
+            .backend
+            .search_instances(
+                principal,
+                study_uid.as_deref(),
+                series_uid.as_deref(),
+                &query,
+            )
+            .await;
+        self.deidentify_results(results, 3)
+    }
+
+    async fn retrieve_study(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        if !self.is_selected(principal) {
+            return self.backend.retrieve_study(principal, study_uid).await;
+        }
+        let Some((study_uid, _, _)) = self.original_path(principal, study_uid, None, None).await?
+        else {
+            return Ok(Vec::new());
+        };
+        let instances = self.backend.retrieve_study(principal, &study_uid).await;
+        self.deidentify_instances(instances)
+    }
+
+    async fn retrieve_series(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        if !self.is_selected(principal) {
+            return self
+                .backend
+                .retrieve_series(principal, study_uid, series_uid)
+                .await;
+        }
+        let Some((study_uid, Some(series_uid), _)) = self
+            .original_path(principal, study_uid, Some(series_uid), None)
+            .await?
+        else {
+            return Ok(Vec::new());
+        };
+        let instances = self
+            .backend
+            .retrieve_series(principal, &study_uid, &series_uid)
+            .await;
+        self.deidentify_instances(instances)
+    }
+
+    async fn retrieve_series_metadata(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        if !self.is_selected(principal) {
+            return self
+                .backend
+                .retrieve_series_metadata(principal, study_uid, series_uid)
+                .await;
+        }
+        if self.deidentifier.has_redactions() {
+            // The redaction of the instances changes their metadata as well
+            let instances = self
+                .retrieve_series(principal, study_uid, series_uid)
+                .await?;
+            return Ok(instances
+                .into_iter()
+                .map(super::without_bulk_data)
+                .collect());
+        }
+        let Some((study_uid, Some(series_uid), _)) = self
+            .original_path(principal, study_uid, Some(series_uid), None)
+            .await?
+        else {
+            return Ok(Vec::new());
+        };
+        let mut instances = self
+            .backend
+            .retrieve_series_metadata(principal, &study_uid, &series_uid)
+            .await?;
+        for instance in &mut instances {
+            self.deidentifier.deidentify(instance);
+        }
+        Ok(instances)
+    }
+
+    async fn retrieve_instance(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        if !self.is_selected(principal) {
+            return self
+                .backend
+                .retrieve_instance(principal, study_uid, series_uid, sop_instance_uid)
+                .await;
+        }
+        let Some((study_uid, Some(series_uid), Some(sop_instance_uid))) = self
+            .original_path(
+                principal,
+                study_uid,
+                Some(series_uid),
+                Some(sop_instance_uid),
+            )
+            .await?
+        else {
+            return Err(NotFound.into());
+        };
+        let mut instance = self
+            .backend
+            .retrieve_instance(principal, &study_uid, &series_uid, &sop_instance_uid)
+            .await?;
+        self.deidentifier.deidentify_file(&mut instance)?;
+        Ok(instance)
+    }
+
+    async fn retrieve_frames(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+        frames: &[u32],
+    ) -> Result<Vec<Bytes>, BackendError> {
+        if !self.is_selected(principal) {
+            return self
+                .backend
+                .retrieve_frames(principal, study_uid, series_uid, sop_instance_uid, frames)
+                .await;
+        }
+        let Some((study_uid, Some(series_uid), Some(sop_instance_uid))) = self
+            .original_path(
+                principal,
+                study_uid,
+                Some(series_uid),
+                Some(sop_instance_uid),
+            )
+            .await?
+        else {
+            return Err(NotFound.into());
+        };
+        if self.deidentifier.has_redactions() {
+            // The frames are taken from the redacted pixel data
+            let mut instance = self
+                .backend
+                .retrieve_instance(principal, &study_uid, &series_uid, &sop_instance_uid)
+                .await?;
+            self.deidentifier.deidentify_file(&mut instance)?;
+            return super::instance_frames(&instance, frames);
+        }
+        self.backend
+            .retrieve_frames(
+                principal,
+                &study_uid,
+                &series_uid,
+                &sop_instance_uid,
+                frames,
+            )
+            .await
+    }
+
+    async fn version(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: Option<&str>,
+    ) -> Result<Option<ResourceVersion>, BackendError> {
+        if !self.is_selected(principal) {
+            return self.backend.version(principal, study_uid, series_uid).await;
+        }
+        let Some((study_uid, series_uid, _)) = self
+            .original_path(principal, study_uid, series_uid, None)
+            .await?
+        else {
+            return Ok(None);
+        };
+        // The de-identified data is another representation of the same version
+        let version = self
+            .backend
+            .version(principal, &study_uid, series_uid.as_deref())
+            .await?;
+        Ok(version.map(|version| ResourceVersion {
+            tag: format!("{}-deidentified", version.tag),
+            ..version
+        }))
+    }
+
+    async fn store_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        self.deny_write(principal)?;
+        self.backend.store_instances(principal, instances).await
+    }
+
+    async fn update_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        self.deny_write(principal)?;
+        self.backend.update_instances(principal, instances).await
+    }
+
+    async fn reject_instances(
+        &self,
+        principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        self.deny_write(principal)?;
+        self.backend.reject_instances(principal, note).await
+    }
+
+    async fn delete_instances(
+        &self,
+        principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        self.deny_write(principal)?;
+        self.backend.delete_instances(principal, instances).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{DataElement, VR},
+        dictionary_std::uids,
+    };
+
+    use super::*;
+    use crate::{backend::InMemoryBackend, testing};
+
+    const STUDY_UID: &str = "1.2.840.10008.44.2";
+    const SERIES_UID: &str = "1.2.840.10008.44.2.1";
+    const SOP_UID: &str = "1.2.840.10008.44.2.1.1";
+    const PATIENT_ID: &str = "MRN-5678";
+
+    /// PET slice of a patient, who takes part in a research study
+    fn research_pet_slice() -> FileDicomObject<InMemDicomObject> {
+        testing::file(InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                uids::POSITRON_EMISSION_TOMOGRAPHY_IMAGE_STORAGE,
+            ),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, SOP_UID),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, SERIES_UID),
+            DataElement::new(tags::PATIENT_ID, VR::LO, PATIENT_ID),
+            DataElement::new(tags::PATIENT_NAME, VR::PN, "Roe^Richard"),
+            DataElement::new(tags::MODALITY, VR::CS, "PT"),
+        ]))
+    }
+
+    #[tokio::test]
+    async fn resolves_forgotten_uids_from_the_backend() {
+        let backend = InMemoryBackend::new();
+        backend
+            .store_instances(&testing::principal(), &[research_pet_slice()])
+            .await
+            .unwrap();
+        // UIDs returned before a restart, which a fresh deidentifier doesn't remember
+        let before = Deidentifier::new("key");
+        let (study_uid, series_uid, sop_uid) = (
+            before.replace_uid(STUDY_UID),
+            before.replace_uid(SERIES_UID),
+            before.replace_uid(SOP_UID),
+        );
+
+        let backend = DeidentifiedBackend::new(backend, Deidentifier::new("key"));
+        let instance = backend
+            .retrieve_instance(&testing::principal(), &study_uid, &series_uid, &sop_uid)
+            .await
+            .unwrap();
+        assert_eq!(
+            search::uid(&instance, tags::SOP_INSTANCE_UID).as_deref(),
+            Some(sop_uid.as_str())
+        );
+        assert_ne!(
+            search::uid(&instance, tags::PATIENT_ID).as_deref(),
+            Some(PATIENT_ID)
+        );
+
+        let unknown = backend
+            .retrieve_study(&testing::principal(), "2.25.1")
+            .await
+            .unwrap();
+        assert!(unknown.is_empty());
+    }
+}
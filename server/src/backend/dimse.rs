@@ -0,0 +1,735 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{TcpListener, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use dicom::{
+    core::{DataElement, PrimitiveValue, VR},
+    dictionary_std::{tags, uids},
+    ul::{ClientAssociation, ClientAssociationOptions, ServerAssociationOptions},
+};
+use dicom_object::{FileDicomObject, InMemDicomObject, Tag};
+use futures_channel::oneshot;
+
+use super::{search, BackendError, DicomWebBackend, NotFound};
+use crate::{
+    auth::Principal,
+    dimse::{
+        self, accepted_transfer_syntax, check_status, encode_dataset, is_pending, receive_message,
+        request, send_message, C_FIND_RQ, C_MOVE_RQ, C_STORE_RQ, STORAGE_SOP_CLASSES,
+    },
+    filter::{attribute_vr, instance_matches, series_matches},
+    InstanceReference, QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery, RejectionNote,
+    INSTANCE_TAGS, SERIES_TAGS, STUDY_TAGS,
+};
+
+/// Bridges DICOMweb to an archive, which only speaks DIMSE.
+///
+/// Searches are sent as C-FIND and stores as C-STORE requests. Retrieves are sent as
+/// C-MOVE requests to the storage SCP of this node, so the archive has to know its AE
+/// title together with the host and port of the storage SCP.
+pub struct DimseBackend {
+    node: Node,
+    moves: Arc<Moves>,
+    base_url: Option<String>,
+}
+
+// Time to wait for the next response of a C-MOVE, the archive sends one per instance
+const RETRIEVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The AE titles of this node and of the archive, and where the archive is reachable
+#[derive(Clone)]
+struct Node {
+    ae_title: String,
+    peer_ae_title: String,
+    peer_host: String,
+    peer_port: u16,
+    retrieve_timeout: Duration,
+}
+
+/// Retrieves, which wait for the instances sent to the storage SCP
+#[derive(Default)]
+struct Moves {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, PendingMove>>,
+}
+
+struct PendingMove {
+    study_uid: String,
+    series_uid: Option<String>,
+    sop_instance_uid: Option<String>,
+    /// Received instances by their SOP Instance UID
+    instances: BTreeMap<String, FileDicomObject<InMemDicomObject>>,
+}
+
+impl PendingMove {
+    fn requests(&self, instance: &InMemDicomObject) -> bool {
+        let matches =
+            |tag, uid: Option<&String>| uid.is_none() || search::uid(instance, tag).as_ref() == uid;
+        matches(tags::STUDY_INSTANCE_UID, Some(&self.study_uid))
+            && matches(tags::SERIES_INSTANCE_UID, self.series_uid.as_ref())
+            && matches(tags::SOP_INSTANCE_UID, self.sop_instance_uid.as_ref())
+    }
+}
+
+impl Moves {
+    fn start(&self, pending: PendingMove) -> Result<u64, BackendError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.pending
+            .lock()
+            .map_err(|_| "The pending moves are poisoned")?
+            .insert(id, pending);
+        Ok(id)
+    }
+
+    fn finish(&self, id: u64) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        let pending = self
+            .pending
+            .lock()
+            .map_err(|_| "The pending moves are poisoned")?
+            .remove(&id)
+            .ok_or("Unknown move")?;
+        Ok(pending.instances.into_values().collect())
+    }
+
+    /// Hand a received instance to all retrieves, which requested it
+    fn deliver(&self, instance: FileDicomObject<InMemDicomObject>) -> Result<(), BackendError> {
+        let sop_uid =
+            search::uid(&instance, tags::SOP_INSTANCE_UID).ok_or("Missing SOP Instance UID")?;
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|_| "The pending moves are poisoned")?;
+        let mut requested = false;
+        for pending in pending.values_mut() {
+            if pending.requests(&instance) {
+                pending.instances.insert(sop_uid.clone(), instance.clone());
+                requested = true;
+            }
+        }
+        if !requested {
+            log::warn!("Discarding instance {}, which wasn't retrieved", sop_uid);
+        }
+        Ok(())
+    }
+}
+
+impl DimseBackend {
+    /// Send the requests as `ae_title` to the archive `peer_ae_title` at the given host and port
+    pub fn new(
+        ae_title: &str,
+        peer_ae_title: &str,
+        peer_host: &str,
+        peer_port: u16,
+    ) -> DimseBackend {
+        DimseBackend {
+            node: Node {
+                ae_title: ae_title.to_string(),
+                peer_ae_title: peer_ae_title.to_string(),
+                peer_host: peer_host.to_string(),
+                peer_port,
+                retrieve_timeout: RETRIEVE_TIMEOUT,
+            },
+            moves: Arc::default(),
+            base_url: None,
+        }
+    }
+
+    /// Listen for the instances of retrieves, e.g. on "0.0.0.0:11112".
+    ///
+    /// Without a storage SCP, retrieves fail.
+    pub fn with_storage_scp(
+        self,
+        address: impl ToSocketAddrs,
+    ) -> Result<DimseBackend, BackendError> {
+        let listener = TcpListener::bind(address)?;
+        let ae_title = self.node.ae_title.clone();
+        let moves = self.moves.clone();
+        thread::spawn(move || accept_stores(listener, &ae_title, &moves));
+        Ok(self)
+    }
+
+    /// Fail a retrieve if the archive doesn't respond to its C-MOVE for this long, one
+    /// minute by default
+    pub fn with_retrieve_timeout(mut self, timeout: Duration) -> DimseBackend {
+        self.node.retrieve_timeout = timeout;
+        self
+    }
+
+    /// Add a RetrieveURL to the search results, based on the URL the server is reachable at
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> DimseBackend {
+        self.base_url = Some(base_url.into().trim_end_matches('/').to_string());
+        self
+    }
+
+    async fn find(
+        &self,
+        level: &str,
+        return_keys: Vec<Tag>,
+        matches: Vec<(Tag, String)>,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let node = self.node.clone();
+        let identifier = identifier(level, &return_keys, &matches);
+        unblock(move || node.find(&identifier)).await
+    }
+
+    async fn retrieve(
+        &self,
+        study_uid: &str,
+        series_uid: Option<&str>,
+        sop_instance_uid: Option<&str>,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        let mut identifier = InMemDicomObject::new_empty();
+        let level = match (series_uid, sop_instance_uid) {
+            (Some(series_uid), Some(sop_instance_uid)) => {
+                put_text(&mut identifier, tags::SERIES_INSTANCE_UID, series_uid);
+                put_text(&mut identifier, tags::SOP_INSTANCE_UID, sop_instance_uid);
+                "IMAGE"
+            }
+            (Some(series_uid), None) => {
+                put_text(&mut identifier, tags::SERIES_INSTANCE_UID, series_uid);
+                "SERIES"
+            }
+            _ => "STUDY",
+        };
+        put_text(&mut identifier, tags::QUERY_RETRIEVE_LEVEL, level);
+        put_text(&mut identifier, tags::STUDY_INSTANCE_UID, study_uid);
+
+        let id = self.moves.start(PendingMove {
+            study_uid: study_uid.to_string(),
+            series_uid: series_uid.map(str::to_string),
+            sop_instance_uid: sop_instance_uid.map(str::to_string),
+            instances: BTreeMap::new(),
+        })?;
+        let node = self.node.clone();
+        let result = unblock(move || node.move_here(&identifier)).await;
+        // The instances are received before the final response
+        let instances = self.moves.finish(id)?;
+        result?;
+        Ok(instances)
+    }
+}
+
+impl Node {
+    fn associate(
+        &self,
+        options: ClientAssociationOptions<'_>,
+    ) -> Result<ClientAssociation, BackendError> {
+        Ok(options
+            .calling_ae_title(self.ae_title.as_str())
+            .called_ae_title(self.peer_ae_title.as_str())
+            .establish((self.peer_host.as_str(), self.peer_port))?)
+    }
+
+    fn find(&self, identifier: &InMemDicomObject) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let sop_class_uid = uids::STUDY_ROOT_QUERY_RETRIEVE_INFORMATION_MODEL_FIND;
+        let mut association =
+            self.associate(ClientAssociationOptions::new().with_abstract_syntax(sop_class_uid))?;
+        let context_id = association.presentation_contexts()[0].id;
+        let ts = accepted_transfer_syntax(&association, context_id)?;
+
+        let data = encode_dataset(identifier, ts.uid())?;
+        let command = request(C_FIND_RQ, sop_class_uid, 1, true);
+        send_message(&mut association, context_id, &command, Some(&data))?;
+
+        let mut results = Vec::new();
+        loop {
+            let response = receive_message(&mut association)?.ok_or("Association released")?;
+            if !is_pending(check_status(&response)?) {
+                break;
+            }
+            if let Some(data) = response.data {
+                results.push(InMemDicomObject::read_dataset_with_ts(&data[..], ts)?);
+            }
+        }
+        association.release()?;
+        Ok(results)
+    }
+
+    /// Request the archive to send instances to the storage SCP of this node
+    fn move_here(&self, identifier: &InMemDicomObject) -> Result<(), BackendError> {
+        let sop_class_uid = uids::STUDY_ROOT_QUERY_RETRIEVE_INFORMATION_MODEL_MOVE;
+        let mut association =
+            self.associate(ClientAssociationOptions::new().with_abstract_syntax(sop_class_uid))?;
+        let context_id = association.presentation_contexts()[0].id;
+        let ts = accepted_transfer_syntax(&association, context_id)?;
+
+        let data = encode_dataset(identifier, ts.uid())?;
+        let mut command = request(C_MOVE_RQ, sop_class_uid, 1, true);
+        put_text(&mut command, tags::MOVE_DESTINATION, &self.ae_title);
+        send_message(&mut association, context_id, &command, Some(&data))?;
+
+        association
+            .inner_stream()
+            .set_read_timeout(Some(self.retrieve_timeout))?;
+        loop {
+            let response = receive_message(&mut association)
+                .map_err(|e| format!("The C-MOVE failed or timed out: {}", e))?
+                .ok_or("Association released")?;
+            if !is_pending(check_status(&response)?) {
+                break;
+            }
+        }
+        association.release()?;
+        Ok(())
+    }
+
+    fn store(&self, instances: &[FileDicomObject<InMemDicomObject>]) -> Result<(), BackendError> {
+        // One presentation context per SOP class and transfer syntax. Native data sets
+        // can also be sent as implicit VR little endian, which every archive accepts.
+        let mut contexts: Vec<(String, String)> = Vec::new();
+        for instance in instances {
+            let context = (
+                instance.meta().media_storage_sop_class_uid().to_string(),
+                instance.meta().transfer_syntax().to_string(),
+            );
+            if !contexts.contains(&context) {
+                contexts.push(context);
+            }
+        }
+        let mut options = ClientAssociationOptions::new();
+        for (sop_class_uid, ts) in &contexts {
+            let mut transfer_syntaxes = vec![ts.as_str()];
+            if is_native(ts) {
+                transfer_syntaxes.push(uids::IMPLICIT_VR_LITTLE_ENDIAN);
+            }
+            options = options.with_presentation_context(sop_class_uid.as_str(), transfer_syntaxes);
+        }
+        let mut association = self.associate(options)?;
+
+        let mut failures = Vec::new();
+        for (message_id, instance) in (1..).zip(instances) {
+            let sop_class_uid = instance.meta().media_storage_sop_class_uid();
+            let sop_instance_uid = instance.meta().media_storage_sop_instance_uid();
+            let context = (
+                sop_class_uid.to_string(),
+                instance.meta().transfer_syntax().to_string(),
+            );
+            // Presentation contexts are numbered in the order of the proposal
+            let context_id = contexts
+                .iter()
+                .position(|proposed| *proposed == context)
+                .map_or(0, |index| (index + 1) as u8);
+            let result = accepted_transfer_syntax(&association, context_id).and_then(|ts| {
+                let data = encode_dataset(instance, ts.uid())?;
+                let mut command = request(C_STORE_RQ, sop_class_uid, message_id, true);
+                put_text(
+                    &mut command,
+                    tags::AFFECTED_SOP_INSTANCE_UID,
+                    sop_instance_uid,
+                );
+                send_message(&mut association, context_id, &command, Some(&data))?;
+                let response = receive_message(&mut association)?.ok_or("Association released")?;
+                check_status(&response)
+            });
+            if let Err(e) = result {
+                log::error!("Failed to store instance {}: {}", sop_instance_uid, e);
+                failures.push(sop_instance_uid.trim_end_matches('\0').to_string());
+            }
+        }
+        association.release()?;
+
+        if !failures.is_empty() {
+            return Err(format!("Failed to store instances {}", failures.join(", ")).into());
+        }
+        Ok(())
+    }
+}
+
+fn is_native(transfer_syntax_uid: &str) -> bool {
+    let uid = transfer_syntax_uid.trim_end_matches('\0');
+    uid == uids::IMPLICIT_VR_LITTLE_ENDIAN || uid == uids::EXPLICIT_VR_LITTLE_ENDIAN
+}
+
+/// Accept associations from the archive and hand the received instances to the retrieves
+fn accept_stores(listener: TcpListener, ae_title: &str, moves: &Arc<Moves>) {
+    let mut options = ServerAssociationOptions::new()
+        .accept_called_ae_title()
+        .ae_title(ae_title.to_string())
+        .with_abstract_syntax(uids::VERIFICATION);
+    for sop_class_uid in STORAGE_SOP_CLASSES {
+        options = options.with_abstract_syntax(sop_class_uid);
+    }
+    let options = Arc::new(options);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let options = options.clone();
+        let moves = moves.clone();
+        thread::spawn(move || {
+            let result = options
+                .establish(stream)
+                .map_err(BackendError::from)
+                .and_then(|mut association| {
+                    dimse::serve_storage(&mut association, |instance| moves.deliver(instance))
+                });
+            if let Err(e) = result {
+                log::error!("Storage association failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Run a blocking DIMSE exchange on its own thread, so the executor isn't blocked
+async fn unblock<T: Send + 'static>(
+    exchange: impl FnOnce() -> Result<T, BackendError> + Send + 'static,
+) -> Result<T, BackendError> {
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = sender.send(exchange());
+    });
+    receiver.await.map_err(|_| "The DIMSE exchange panicked")?
+}
+
+fn put_text(dcm: &mut InMemDicomObject, tag: Tag, value: &str) {
+    dcm.put(DataElement::new(
+        tag,
+        attribute_vr(tag),
+        PrimitiveValue::from(value),
+    ));
+}
+
+/// Build the identifier of a C-FIND request from the matching and the returned attributes
+fn identifier(level: &str, return_keys: &[Tag], matches: &[(Tag, String)]) -> InMemDicomObject {
+    let mut identifier = InMemDicomObject::new_empty();
+    put_text(&mut identifier, tags::SPECIFIC_CHARACTER_SET, "ISO_IR 192");
+    put_text(&mut identifier, tags::QUERY_RETRIEVE_LEVEL, level);
+    for &tag in return_keys {
+        identifier.put(DataElement::empty(tag, attribute_vr(tag)));
+    }
+    // QIDO-RS and C-FIND share the matching syntax of wildcards, ranges and UID lists
+    for (tag, value) in matches {
+        match attribute_vr(*tag) {
+            VR::AE
+            | VR::AS
+            | VR::CS
+            | VR::DA
+            | VR::DS
+            | VR::DT
+            | VR::IS
+            | VR::LO
+            | VR::LT
+            | VR::PN
+            | VR::SH
+            | VR::ST
+            | VR::TM
+            | VR::UC
+            | VR::UI
+            | VR::UR
+            | VR::UT => put_text(&mut identifier, *tag, value),
+            vr => log::debug!("Ignoring matching of {} with VR {}", tag, vr),
+        }
+    }
+    identifier
+}
+
+/// The default attributes of a level and the included fields.
+///
+/// C-FIND can't return all attributes, so `includefield=all` only requests the defaults.
+fn return_keys<'a>(defaults: &[Tag], includefields: impl IntoIterator<Item = &'a str>) -> Vec<Tag> {
+    let mut keys = defaults.to_vec();
+    keys.extend(search::included_tags(includefields).unwrap_or_default());
+    keys
+}
+
+#[async_trait]
+impl DicomWebBackend for DimseBackend {
+    async fn search_study(
+        &self,
+        _principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let keys = return_keys(
+            &[
+                &STUDY_TAGS[..],
+                &[
+                    tags::NUMBER_OF_STUDY_RELATED_SERIES,
+                    tags::NUMBER_OF_STUDY_RELATED_INSTANCES,
+                ],
+            ]
+            .concat(),
+            query.includefields.iter().map(String::as_str),
+        );
+        let studies = self.find("STUDY", keys, query.matches.clone()).await?;
+        let results = studies
+            .iter()
+            .map(|study| search::study_result(study, query, self.base_url.as_deref()))
+            .collect();
+        Ok(search::paginate(results, query.offset, query.limit))
+    }
+
+    async fn search_series(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let keys = return_keys(
+            &[
+                &SERIES_TAGS[..],
+                &[
+                    tags::STUDY_INSTANCE_UID,
+                    tags::NUMBER_OF_SERIES_RELATED_INSTANCES,
+                ],
+            ]
+            .concat(),
+            query
+                .includefield
+                .iter()
+                .flat_map(|fields| fields.split(',')),
+        );
+        let mut matches = series_matches(query);
+        if let Some(study_uid) = study_uid {
+            matches.push((tags::STUDY_INSTANCE_UID, study_uid.to_string()));
+        }
+        let series = self.find("SERIES", keys, matches).await?;
+        let results = series
+            .iter()
+            .map(|series| search::series_result(series, query, self.base_url.as_deref()))
+            .collect();
+        Ok(search::paginate(results, query.offset, query.limit))
+    }
+
+    async fn search_instances(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let keys = return_keys(
+            &[
+                &INSTANCE_TAGS[..],
+                &[
+                    tags::STUDY_INSTANCE_UID,
+                    tags::SERIES_INSTANCE_UID,
+                    tags::ROWS,
+                    tags::COLUMNS,
+                    tags::BITS_ALLOCATED,
+                    tags::NUMBER_OF_FRAMES,
+                ],
+            ]
+            .concat(),
+            query
+                .includefield
+                .iter()
+                .flat_map(|fields| fields.split(',')),
+        );
+        let mut matches = instance_matches(query);
+        if let Some(study_uid) = study_uid {
+            matches.push((tags::STUDY_INSTANCE_UID, study_uid.to_string()));
+        }
+        if let Some(series_uid) = series_uid {
+            matches.push((tags::SERIES_INSTANCE_UID, series_uid.to_string()));
+        }
+        let instances = self.find("IMAGE", keys, matches).await?;
+        let results = instances
+            .iter()
+            .map(|instance| search::instance_result(instance, query, self.base_url.as_deref()))
+            .collect();
+        Ok(search::paginate(results, query.offset, query.limit))
+    }
+
+    async fn retrieve_study(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.retrieve(study_uid, None, None).await
+    }
+
+    async fn retrieve_series(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.retrieve(study_uid, Some(series_uid), None).await
+    }
+
+    async fn retrieve_instance(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        self.retrieve(study_uid, Some(series_uid), Some(sop_instance_uid))
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound.into())
+    }
+
+    async fn store_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        if instances.is_empty() {
+            return Ok(());
+        }
+        let node = self.node.clone();
+        let instances = instances.to_vec();
+        unblock(move || node.store(&instances)).await
+    }
+
+    /// The rejection note is stored like any other instance, so the archive applies it
+    async fn reject_instances(
+        &self,
+        _principal: &Principal,
+        _note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    async fn delete_instances(
+        &self,
+        _principal: &Principal,
+        _instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        Err("Deleting instances isn't supported over DIMSE".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dimse::{response, STATUS_PENDING, STATUS_SUCCESS},
+        testing,
+    };
+
+    const STUDY_UID: &str = "1.2.840.10008.38.1";
+    const SERIES_UID: &str = "1.2.840.10008.38.1.1";
+    const SOP_UID: &str = "1.2.840.10008.38.1.1.1";
+
+    /// PET slice of a prior study, which is only kept in the archive
+    fn archived_pet_slice() -> FileDicomObject<InMemDicomObject> {
+        testing::file(InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                uids::POSITRON_EMISSION_TOMOGRAPHY_IMAGE_STORAGE,
+            ),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, SOP_UID),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, SERIES_UID),
+            DataElement::new(tags::PATIENT_ID, VR::LO, "PRIOR-1"),
+            DataElement::new(tags::MODALITY, VR::CS, "PT"),
+        ]))
+    }
+
+    /// Stand-in for an archive, which answers C-FIND with the PET slice and C-MOVE by
+    /// storing it to the storage SCP at `scp_port`, unless it doesn't answer moves
+    fn archive(scp_port: u16, answers_moves: bool) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let options = ServerAssociationOptions::new()
+                .accept_any()
+                .with_abstract_syntax(uids::STUDY_ROOT_QUERY_RETRIEVE_INFORMATION_MODEL_FIND)
+                .with_abstract_syntax(uids::STUDY_ROOT_QUERY_RETRIEVE_INFORMATION_MODEL_MOVE);
+            for stream in listener.incoming() {
+                let mut association = options.establish(stream.unwrap()).unwrap();
+                while let Ok(Some(request)) = receive_message(&mut association) {
+                    let context_id = request.presentation_context_id;
+                    let instance = archived_pet_slice();
+                    match request.field(tags::COMMAND_FIELD) {
+                        Some(C_FIND_RQ) => {
+                            let mut pending = response(&request, STATUS_PENDING, None);
+                            pending.put(DataElement::new(
+                                tags::COMMAND_DATA_SET_TYPE,
+                                VR::US,
+                                PrimitiveValue::from(0x0000_u16),
+                            ));
+                            let ts = accepted_transfer_syntax(&association, context_id).unwrap();
+                            let data = encode_dataset(&instance, ts.uid()).unwrap();
+                            send_message(&mut association, context_id, &pending, Some(&data))
+                                .unwrap();
+                        }
+                        Some(C_MOVE_RQ) if answers_moves => {
+                            let node = Node {
+                                ae_title: "ARCHIVE".to_string(),
+                                peer_ae_title: "BRIDGE".to_string(),
+                                peer_host: "127.0.0.1".to_string(),
+                                peer_port: scp_port,
+                                retrieve_timeout: RETRIEVE_TIMEOUT,
+                            };
+                            node.store(&[instance]).unwrap();
+                        }
+                        _ => {
+                            thread::sleep(Duration::from_secs(1));
+                            break;
+                        }
+                    }
+                    let done = response(&request, STATUS_SUCCESS, None);
+                    send_message(&mut association, context_id, &done, None).unwrap();
+                }
+            }
+        });
+        port
+    }
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[tokio::test]
+    async fn searches_and_retrieves_from_the_archive() {
+        let scp_port = free_port();
+        let backend = DimseBackend::new("BRIDGE", "ARCHIVE", "127.0.0.1", archive(scp_port, true))
+            .with_storage_scp(("127.0.0.1", scp_port))
+            .unwrap();
+
+        let studies = backend
+            .search_study(&testing::principal(), &QidoStudyQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(studies.len(), 1);
+        assert_eq!(
+            search::uid(&studies[0], tags::STUDY_INSTANCE_UID).as_deref(),
+            Some(STUDY_UID)
+        );
+
+        let instance = backend
+            .retrieve_instance(&testing::principal(), STUDY_UID, SERIES_UID, SOP_UID)
+            .await
+            .unwrap();
+        assert_eq!(
+            search::uid(&instance, tags::SOP_INSTANCE_UID).as_deref(),
+            Some(SOP_UID)
+        );
+    }
+
+    #[tokio::test]
+    async fn retrieves_time_out_without_response() {
+        let scp_port = free_port();
+        let backend = DimseBackend::new("BRIDGE", "ARCHIVE", "127.0.0.1", archive(scp_port, false))
+            .with_storage_scp(("127.0.0.1", scp_port))
+            .unwrap()
+            .with_retrieve_timeout(Duration::from_millis(100));
+
+        let result = backend
+            .retrieve_study(&testing::principal(), STUDY_UID)
+            .await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,585 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use async_trait::async_trait;
+use dicom::dictionary_std::tags;
+use dicom_object::{FileDicomObject, InMemDicomObject, OpenFileOptions};
+use serde::{Deserialize, Serialize};
+
+use super::{instance_path, search, BackendError, DicomWebBackend, NotFound};
+use crate::{
+    auth::Principal, InstanceReference, QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery,
+    RejectionNote, RejectionReason,
+};
+
+// Name of the index file inside the root directory
+const INDEX_FILE: &str = "index.jsonl";
+
+/// Record of the append-only index file
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    Put {
+        path: PathBuf,
+        rejected: bool,
+        attributes: serde_json::Value,
+    },
+    Remove {
+        sop_instance_uid: String,
+    },
+}
+
+struct Entry {
+    /// Path of the file, relative to the root directory
+    path: PathBuf,
+    /// Rejected by an IOCM rejection note, only returned with `includerejected`
+    rejected: bool,
+    attributes: InMemDicomObject,
+}
+
+impl Entry {
+    fn uid(&self, tag: dicom_object::Tag) -> Option<String> {
+        search::uid(&self.attributes, tag)
+    }
+
+    fn record(&self) -> Result<Record, BackendError> {
+        Ok(Record::Put {
+            path: self.path.clone(),
+            rejected: self.rejected,
+            attributes: dicom_json::to_value(&self.attributes)?,
+        })
+    }
+}
+
+struct Index {
+    /// Entries by their SOP Instance UID
+    entries: BTreeMap<String, Entry>,
+    journal: File,
+}
+
+impl Index {
+    fn append(&mut self, record: &Record) -> Result<(), BackendError> {
+        let line = serde_json::to_string(record)?;
+        writeln!(self.journal, "{}", line)?;
+        Ok(())
+    }
+
+    fn searchable(&self, include_rejected: bool) -> impl Iterator<Item = &InMemDicomObject> {
+        self.entries
+            .values()
+            .filter(move |entry| include_rejected || !entry.rejected)
+            .map(|entry| &entry.attributes)
+    }
+}
+
+/// Stores the instances as files below a root directory.
+///
+/// The attributes needed for QIDO-RS are kept in an index, which is persisted as
+/// `index.jsonl` in the root directory and updated on every change. Searches are
+/// answered from the index without reading any DICOM file. The files and the index are
+/// accessed on the blocking threads of the tokio runtime, so the executor isn't blocked.
+pub struct FilesystemBackend {
+    storage: Arc<Storage>,
+    base_url: Option<String>,
+}
+
+/// Files and index of the backend, which are shared with the blocking tasks
+struct Storage {
+    root: PathBuf,
+    index: RwLock<Index>,
+}
+
+impl FilesystemBackend {
+    /// Open the backend in the given directory, which is created if needed.
+    ///
+    /// Without an index file, the directory is scanned once for `.dcm` files.
+    pub fn open(root: impl AsRef<Path>) -> Result<FilesystemBackend, BackendError> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+
+        let index_path = root.join(INDEX_FILE);
+        let entries = if index_path.exists() {
+            replay(&index_path)?
+        } else {
+            scan(&root)?
+        };
+
+        // Rewrite the index without the replaced and removed records
+        let compacted_path = index_path.with_extension("jsonl.tmp");
+        let mut compacted = File::create(&compacted_path)?;
+        for entry in entries.values() {
+            writeln!(compacted, "{}", serde_json::to_string(&entry.record()?)?)?;
+        }
+        compacted.sync_all()?;
+        fs::rename(&compacted_path, &index_path)?;
+
+        let journal = OpenOptions::new().append(true).open(&index_path)?;
+        log::info!("Opened {} with {} instances", root.display(), entries.len());
+
+        Ok(FilesystemBackend {
+            storage: Arc::new(Storage {
+                root,
+                index: RwLock::new(Index { entries, journal }),
+            }),
+            base_url: None,
+        })
+    }
+
+    /// Add a RetrieveURL to the search results, based on the URL the server is reachable at
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> FilesystemBackend {
+        self.base_url = Some(base_url.into().trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Access the storage on a blocking thread
+    async fn unblock<T: Send + 'static>(
+        &self,
+        access: impl FnOnce(&Storage) -> Result<T, BackendError> + Send + 'static,
+    ) -> Result<T, BackendError> {
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || access(&storage)).await?
+    }
+}
+
+impl Storage {
+    fn read_index(&self) -> Result<RwLockReadGuard<'_, Index>, BackendError> {
+        self.index
+            .read()
+            .map_err(|_| "The index is poisoned".into())
+    }
+
+    fn write_index(&self) -> Result<RwLockWriteGuard<'_, Index>, BackendError> {
+        self.index
+            .write()
+            .map_err(|_| "The index is poisoned".into())
+    }
+
+    /// Open the files of all non-rejected instances of a study or series
+    fn open_files(
+        &self,
+        study_uid: &str,
+        series_uid: Option<&str>,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        let paths: Vec<PathBuf> = self
+            .read_index()?
+            .entries
+            .values()
+            .filter(|entry| {
+                !entry.rejected && search::belongs_to(&entry.attributes, study_uid, series_uid)
+            })
+            .map(|entry| self.root.join(&entry.path))
+            .collect();
+
+        paths
+            .iter()
+            .map(|path| FileDicomObject::open_file(path).map_err(BackendError::from))
+            .collect()
+    }
+
+    fn open_file(
+        &self,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        let path = {
+            let index = self.read_index()?;
+            let entry = index
+                .entries
+                .get(sop_instance_uid)
+                .filter(|entry| {
+                    !entry.rejected
+                        && search::belongs_to(&entry.attributes, study_uid, Some(series_uid))
+                })
+                .ok_or(NotFound)?;
+            self.root.join(&entry.path)
+        };
+
+        Ok(FileDicomObject::open_file(path)?)
+    }
+
+    fn store(&self, instances: &[FileDicomObject<InMemDicomObject>]) -> Result<(), BackendError> {
+        for instance in instances {
+            let path = instance_path(instance)?;
+            let sop_uid =
+                search::uid(instance, tags::SOP_INSTANCE_UID).ok_or("Missing SOP Instance UID")?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(self.root.join(parent))?;
+            }
+            instance.write_to_file(self.root.join(&path))?;
+
+            let entry = Entry {
+                path: path.clone(),
+                rejected: false,
+                attributes: search::indexed_attributes(instance),
+            };
+            let previous = {
+                let mut index = self.write_index()?;
+                index.append(&entry.record()?)?;
+                index.entries.insert(sop_uid, entry)
+            };
+            if let Some(previous) = previous {
+                // The instance moved to another study or series
+                if previous.path != path {
+                    if let Err(e) = fs::remove_file(self.root.join(&previous.path)) {
+                        log::warn!("Failed to remove {}: {}", previous.path.display(), e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reject(&self, references: &[InstanceReference]) -> Result<(), BackendError> {
+        let mut index = self.write_index()?;
+        for reference in references {
+            let Some(entry) = index
+                .entries
+                .get_mut(&reference.sop_instance_uid)
+                .filter(|entry| reference.matches(&entry.attributes))
+            else {
+                continue;
+            };
+            entry.rejected = true;
+            let record = entry.record()?;
+            index.append(&record)?;
+        }
+        Ok(())
+    }
+
+    fn delete(&self, references: &[InstanceReference]) -> Result<(), BackendError> {
+        let mut removed = Vec::new();
+        {
+            let mut index = self.write_index()?;
+            for reference in references {
+                if !index
+                    .entries
+                    .get(&reference.sop_instance_uid)
+                    .is_some_and(|entry| reference.matches(&entry.attributes))
+                {
+                    continue;
+                }
+                let Some(entry) = index.entries.remove(&reference.sop_instance_uid) else {
+                    continue;
+                };
+                index.append(&Record::Remove {
+                    sop_instance_uid: reference.sop_instance_uid.clone(),
+                })?;
+                removed.push(entry.path);
+            }
+        }
+
+        for path in removed {
+            if let Err(e) = fs::remove_file(self.root.join(&path)) {
+                log::warn!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the index, later records replace earlier ones
+fn replay(index_path: &Path) -> Result<BTreeMap<String, Entry>, BackendError> {
+    let mut entries = BTreeMap::new();
+    for line in BufReader::new(File::open(index_path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // A crash may leave the last record incomplete
+        let record: Record = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                log::warn!("Skipping invalid index record: {}", e);
+                continue;
+            }
+        };
+        match record {
+            Record::Put {
+                path,
+                rejected,
+                attributes,
+            } => {
+                let attributes: InMemDicomObject = dicom_json::from_value(attributes)?;
+                let entry = Entry {
+                    path,
+                    rejected,
+                    attributes,
+                };
+                if let Some(sop_uid) = entry.uid(tags::SOP_INSTANCE_UID) {
+                    entries.insert(sop_uid, entry);
+                }
+            }
+            Record::Remove { sop_instance_uid } => {
+                entries.remove(&sop_instance_uid);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Index all `.dcm` files below the root directory
+fn scan(root: &Path) -> Result<BTreeMap<String, Entry>, BackendError> {
+    let mut entries = BTreeMap::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for dir_entry in fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            if path.extension().is_none_or(|extension| extension != "dcm") {
+                continue;
+            }
+
+            // The pixel data isn't needed for the index
+            let dcm = match OpenFileOptions::new()
+                .read_until(tags::PIXEL_DATA)
+                .open_file(&path)
+            {
+                Ok(dcm) => dcm,
+                Err(e) => {
+                    log::warn!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let entry = Entry {
+                path: path.strip_prefix(root)?.to_path_buf(),
+                rejected: false,
+                attributes: search::indexed_attributes(&dcm),
+            };
+            if let Some(sop_uid) = entry.uid(tags::SOP_INSTANCE_UID) {
+                entries.insert(sop_uid, entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+#[async_trait]
+impl DicomWebBackend for FilesystemBackend {
+    async fn search_study(
+        &self,
+        _principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let query = query.clone();
+        let base_url = self.base_url.clone();
+        self.unblock(move |storage| {
+            let index = storage.read_index()?;
+            Ok(search::search_studies(
+                index.searchable(query.includerejected.unwrap_or(false)),
+                &query,
+                base_url.as_deref(),
+            ))
+        })
+        .await
+    }
+
+    async fn search_series(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let study_uid = study_uid.map(str::to_string);
+        let query = query.clone();
+        let base_url = self.base_url.clone();
+        self.unblock(move |storage| {
+            let index = storage.read_index()?;
+            Ok(search::search_series(
+                index.searchable(query.includerejected.unwrap_or(false)),
+                study_uid.as_deref(),
+                &query,
+                base_url.as_deref(),
+            ))
+        })
+        .await
+    }
+
+    async fn search_instances(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let study_uid = study_uid.map(str::to_string);
+        let series_uid = series_uid.map(str::to_string);
+        let query = query.clone();
+        let base_url = self.base_url.clone();
+        self.unblock(move |storage| {
+            let index = storage.read_index()?;
+            Ok(search::search_instances(
+                index.searchable(query.includerejected.unwrap_or(false)),
+                study_uid.as_deref(),
+                series_uid.as_deref(),
+                &query,
+                base_url.as_deref(),
+            ))
+        })
+        .await
+    }
+
+    async fn retrieve_study(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        let study_uid = study_uid.to_string();
+        self.unblock(move |storage| storage.open_files(&study_uid, None))
+            .await
+    }
+
+    async fn retrieve_series(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        let study_uid = study_uid.to_string();
+        let series_uid = series_uid.to_string();
+        self.unblock(move |storage| storage.open_files(&study_uid, Some(&series_uid)))
+            .await
+    }
+
+    async fn retrieve_instance(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        let study_uid = study_uid.to_string();
+        let series_uid = series_uid.to_string();
+        let sop_instance_uid = sop_instance_uid.to_string();
+        self.unblock(move |storage| storage.open_file(&study_uid, &series_uid, &sop_instance_uid))
+            .await
+    }
+
+    async fn store_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        let instances = instances.to_vec();
+        self.unblock(move |storage| storage.store(&instances)).await
+    }
+
+    async fn reject_instances(
+        &self,
+        principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        // Instances past their retention period are deleted, all others are hidden
+        if note.reason == RejectionReason::DataRetentionPolicyExpired {
+            return self.delete_instances(principal, &note.instances).await;
+        }
+
+        let references = note.instances.clone();
+        self.unblock(move |storage| storage.reject(&references))
+            .await
+    }
+
+    async fn delete_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        let references = instances.to_vec();
+        self.unblock(move |storage| storage.delete(&references))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{DataElement, VR},
+        dictionary_std::uids,
+    };
+
+    use super::*;
+    use crate::testing;
+
+    const STUDY_UID: &str = "1.2.840.10008.32.1";
+    const PA_SERIES_UID: &str = "1.2.840.10008.32.1.1";
+    const PA_SOP_UID: &str = "1.2.840.10008.32.1.1.1";
+    const LATERAL_SERIES_UID: &str = "1.2.840.10008.32.1.2";
+    const LATERAL_SOP_UID: &str = "1.2.840.10008.32.1.2.1";
+
+    /// Chest radiograph of the given view, each view in its own series
+    fn chest_radiograph(
+        series_uid: &str,
+        sop_uid: &str,
+        view: &str,
+    ) -> FileDicomObject<InMemDicomObject> {
+        testing::file(InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                uids::COMPUTED_RADIOGRAPHY_IMAGE_STORAGE,
+            ),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, sop_uid),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, series_uid),
+            DataElement::new(tags::MODALITY, VR::CS, "CR"),
+            DataElement::new(tags::BODY_PART_EXAMINED, VR::CS, "CHEST"),
+            DataElement::new(tags::VIEW_POSITION, VR::CS, view),
+        ]))
+    }
+
+    #[tokio::test]
+    async fn keeps_the_index_across_restarts() {
+        let root = std::env::temp_dir().join(format!("dicomweb-fs-{}", uuid::Uuid::new_v4()));
+        let backend = FilesystemBackend::open(&root).unwrap();
+        backend
+            .store_instances(
+                &testing::principal(),
+                &[
+                    chest_radiograph(PA_SERIES_UID, PA_SOP_UID, "PA"),
+                    chest_radiograph(LATERAL_SERIES_UID, LATERAL_SOP_UID, "LL"),
+                ],
+            )
+            .await
+            .unwrap();
+        let reference = InstanceReference {
+            study_instance_uid: STUDY_UID.to_string(),
+            series_instance_uid: LATERAL_SERIES_UID.to_string(),
+            sop_instance_uid: LATERAL_SOP_UID.to_string(),
+        };
+        backend
+            .delete_instances(&testing::principal(), &[reference])
+            .await
+            .unwrap();
+        drop(backend);
+
+        let backend = FilesystemBackend::open(&root).unwrap();
+        let series = backend
+            .search_series(
+                &testing::principal(),
+                Some(STUDY_UID),
+                &QidoSeriesQuery::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(series.len(), 1);
+        let instance = backend
+            .retrieve_instance(&testing::principal(), STUDY_UID, PA_SERIES_UID, PA_SOP_UID)
+            .await
+            .unwrap();
+        assert_eq!(
+            search::uid(&instance, tags::SOP_INSTANCE_UID).as_deref(),
+            Some(PA_SOP_UID)
+        );
+
+        let _ = fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,418 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use async_trait::async_trait;
+use dicom::dictionary_std::tags;
+use dicom_object::{FileDicomObject, InMemDicomObject};
+
+use super::{search, BackendError, DicomWebBackend, NotFound};
+use crate::{
+    auth::Principal, InstanceReference, QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery,
+    RejectionNote, RejectionReason,
+};
+
+struct Entry {
+    instance: FileDicomObject<InMemDicomObject>,
+    /// Rejected by an IOCM rejection note, only returned with `includerejected`
+    rejected: bool,
+}
+
+/// Keeps the instances in memory, which makes it a simple backend for tests and demos.
+///
+/// Nothing is persisted, but fixture files can be loaded from a directory.
+#[derive(Default)]
+pub struct InMemoryBackend {
+    /// Instances by their SOP Instance UID
+    instances: RwLock<HashMap<String, Entry>>,
+    base_url: Option<String>,
+}
+
+impl InMemoryBackend {
+    pub fn new() -> InMemoryBackend {
+        InMemoryBackend::default()
+    }
+
+    /// Load all DICOM files below a directory, other files are skipped
+    pub fn from_dir(dir: impl AsRef<Path>) -> Result<InMemoryBackend, BackendError> {
+        let mut instances = HashMap::new();
+        let mut dirs = vec![dir.as_ref().to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for dir_entry in fs::read_dir(&dir)? {
+                let path = dir_entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                let instance = match FileDicomObject::open_file(&path) {
+                    Ok(instance) => instance,
+                    Err(e) => {
+                        log::debug!("Skipping {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                if let Some(sop_uid) = search::uid(&instance, tags::SOP_INSTANCE_UID) {
+                    instances.insert(
+                        sop_uid,
+                        Entry {
+                            instance,
+                            rejected: false,
+                        },
+                    );
+                }
+            }
+        }
+        log::info!("Loaded {} instances", instances.len());
+
+        Ok(InMemoryBackend {
+            instances: RwLock::new(instances),
+            base_url: None,
+        })
+    }
+
+    /// Add a RetrieveURL to the search results, based on the URL the server is reachable at
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> InMemoryBackend {
+        self.base_url = Some(base_url.into().trim_end_matches('/').to_string());
+        self
+    }
+
+    fn read_instances(&self) -> Result<RwLockReadGuard<'_, HashMap<String, Entry>>, BackendError> {
+        self.instances
+            .read()
+            .map_err(|_| "The instances are poisoned".into())
+    }
+
+    fn write_instances(
+        &self,
+    ) -> Result<RwLockWriteGuard<'_, HashMap<String, Entry>>, BackendError> {
+        self.instances
+            .write()
+            .map_err(|_| "The instances are poisoned".into())
+    }
+
+    /// Copy all non-rejected instances of a study or series
+    fn find(
+        &self,
+        study_uid: &str,
+        series_uid: Option<&str>,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        let instances = self.read_instances()?;
+        let mut entries: Vec<(&String, &Entry)> = instances
+            .iter()
+            .filter(|(_, entry)| {
+                !entry.rejected && search::belongs_to(&entry.instance, study_uid, series_uid)
+            })
+            .collect();
+        entries.sort_by_key(|(sop_uid, _)| *sop_uid);
+        Ok(entries
+            .into_iter()
+            .map(|(_, entry)| entry.instance.clone())
+            .collect())
+    }
+}
+
+/// Datasets of the instances in the order of their SOP Instance UIDs
+fn searchable(
+    instances: &HashMap<String, Entry>,
+    include_rejected: Option<bool>,
+) -> Vec<&InMemDicomObject> {
+    let mut entries: Vec<(&String, &Entry)> = instances
+        .iter()
+        .filter(|(_, entry)| include_rejected.unwrap_or(false) || !entry.rejected)
+        .collect();
+    entries.sort_by_key(|(sop_uid, _)| *sop_uid);
+    entries
+        .into_iter()
+        .map(|(_, entry)| &*entry.instance)
+        .collect()
+}
+
+#[async_trait]
+impl DicomWebBackend for InMemoryBackend {
+    async fn search_study(
+        &self,
+        _principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let instances = self.read_instances()?;
+        Ok(search::search_studies(
+            searchable(&instances, query.includerejected),
+            query,
+            self.base_url.as_deref(),
+        ))
+    }
+
+    async fn search_series(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let instances = self.read_instances()?;
+        Ok(search::search_series(
+            searchable(&instances, query.includerejected),
+            study_uid,
+            query,
+            self.base_url.as_deref(),
+        ))
+    }
+
+    async fn search_instances(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let instances = self.read_instances()?;
+        Ok(search::search_instances(
+            searchable(&instances, query.includerejected),
+            study_uid,
+            series_uid,
+            query,
+            self.base_url.as_deref(),
+        ))
+    }
+
+    async fn retrieve_study(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.find(study_uid, None)
+    }
+
+    async fn retrieve_series(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.find(study_uid, Some(series_uid))
+    }
+
+    async fn retrieve_instance(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        let instances = self.read_instances()?;
+        let entry = instances
+            .get(sop_instance_uid)
+            .filter(|entry| {
+                !entry.rejected && search::belongs_to(&entry.instance, study_uid, Some(series_uid))
+            })
+            .ok_or(NotFound)?;
+        Ok(entry.instance.clone())
+    }
+
+    async fn store_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        let mut stored = self.write_instances()?;
+        for instance in instances {
+            let sop_uid =
+                search::uid(instance, tags::SOP_INSTANCE_UID).ok_or("Missing SOP Instance UID")?;
+            stored.insert(
+                sop_uid,
+                Entry {
+                    instance: instance.clone(),
+                    rejected: false,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    async fn reject_instances(
+        &self,
+        principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        // Instances past their retention period are deleted, all others are hidden
+        if note.reason == RejectionReason::DataRetentionPolicyExpired {
+            return self.delete_instances(principal, &note.instances).await;
+        }
+
+        let mut instances = self.write_instances()?;
+        for reference in &note.instances {
+            if let Some(entry) = instances
+                .get_mut(&reference.sop_instance_uid)
+                .filter(|entry| reference.matches(&entry.instance))
+            {
+                entry.rejected = true;
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        let mut stored = self.write_instances()?;
+        for reference in instances {
+            if stored
+                .get(&reference.sop_instance_uid)
+                .is_some_and(|entry| reference.matches(&entry.instance))
+            {
+                stored.remove(&reference.sop_instance_uid);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{DataElement, VR},
+        dictionary_std::uids,
+    };
+
+    use super::*;
+    use crate::testing;
+
+    const STUDY_UID: &str = "1.2.840.10008.36.1";
+    const SERIES_UID: &str = "1.2.840.10008.36.1.1";
+    const FIRST_SOP_UID: &str = "1.2.840.10008.36.1.1.1";
+    const SECOND_SOP_UID: &str = "1.2.840.10008.36.1.1.2";
+    const NOTE_SOP_UID: &str = "1.2.840.10008.36.1.2.1";
+
+    /// Run of an angiography series
+    fn angiography_run(sop_uid: &str) -> FileDicomObject<InMemDicomObject> {
+        testing::file(InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                uids::X_RAY_ANGIOGRAPHIC_IMAGE_STORAGE,
+            ),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, sop_uid),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, SERIES_UID),
+            DataElement::new(tags::MODALITY, VR::CS, "XA"),
+        ]))
+    }
+
+    /// Backend with two runs of the same series
+    async fn backend() -> InMemoryBackend {
+        let backend = InMemoryBackend::new();
+        backend
+            .store_instances(
+                &testing::principal(),
+                &[
+                    angiography_run(FIRST_SOP_UID),
+                    angiography_run(SECOND_SOP_UID),
+                ],
+            )
+            .await
+            .unwrap();
+        backend
+    }
+
+    fn reference(sop_uid: &str) -> InstanceReference {
+        InstanceReference {
+            study_instance_uid: STUDY_UID.to_string(),
+            series_instance_uid: SERIES_UID.to_string(),
+            sop_instance_uid: sop_uid.to_string(),
+        }
+    }
+
+    async fn sop_uids(backend: &InMemoryBackend, includerejected: Option<bool>) -> Vec<String> {
+        let query = QidoInstanceQuery {
+            includerejected,
+            ..Default::default()
+        };
+        backend
+            .search_instances(&testing::principal(), None, None, &query)
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|instance| search::uid(instance, tags::SOP_INSTANCE_UID))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn loads_the_dicom_files_below_a_directory() {
+        let root = std::env::temp_dir().join(format!("dicomweb-memory-{}", uuid::Uuid::new_v4()));
+        let nested = root.join("series");
+        fs::create_dir_all(&nested).unwrap();
+        angiography_run(FIRST_SOP_UID)
+            .write_to_file(root.join("first.dcm"))
+            .unwrap();
+        angiography_run(SECOND_SOP_UID)
+            .write_to_file(nested.join("IM0001"))
+            .unwrap();
+        fs::write(root.join("DICOMDIR.txt"), "not a DICOM file").unwrap();
+
+        let backend = InMemoryBackend::from_dir(&root).unwrap();
+        assert_eq!(
+            sop_uids(&backend, None).await,
+            [FIRST_SOP_UID, SECOND_SOP_UID]
+        );
+        let instance = backend
+            .retrieve_instance(&testing::principal(), STUDY_UID, SERIES_UID, SECOND_SOP_UID)
+            .await
+            .unwrap();
+        assert_eq!(
+            search::uid(&instance, tags::SOP_INSTANCE_UID).as_deref(),
+            Some(SECOND_SOP_UID)
+        );
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn hides_rejected_instances_unless_included() {
+        let backend = backend().await;
+        let principal = testing::principal();
+        let note = RejectionNote {
+            reason: RejectionReason::QualityReasons,
+            sop_instance_uid: NOTE_SOP_UID.to_string(),
+            instances: vec![reference(FIRST_SOP_UID)],
+        };
+        backend.reject_instances(&principal, &note).await.unwrap();
+
+        assert_eq!(sop_uids(&backend, None).await, [SECOND_SOP_UID]);
+        assert_eq!(sop_uids(&backend, Some(false)).await, [SECOND_SOP_UID]);
+        assert_eq!(
+            sop_uids(&backend, Some(true)).await,
+            [FIRST_SOP_UID, SECOND_SOP_UID]
+        );
+        let series = backend
+            .retrieve_series(&principal, STUDY_UID, SERIES_UID)
+            .await
+            .unwrap();
+        assert_eq!(series.len(), 1);
+        assert!(backend
+            .retrieve_instance(&principal, STUDY_UID, SERIES_UID, FIRST_SOP_UID)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn purges_instances_past_their_retention_period() {
+        let backend = backend().await;
+        let principal = testing::principal();
+        // A reference into another study doesn't purge the instance
+        let mut foreign = reference(SECOND_SOP_UID);
+        foreign.study_instance_uid = "1.2.840.10008.36.2".to_string();
+        let note = RejectionNote {
+            reason: RejectionReason::DataRetentionPolicyExpired,
+            sop_instance_uid: NOTE_SOP_UID.to_string(),
+            instances: vec![reference(FIRST_SOP_UID), foreign],
+        };
+        backend.reject_instances(&principal, &note).await.unwrap();
+
+        assert_eq!(sop_uids(&backend, Some(true)).await, [SECOND_SOP_UID]);
+    }
+}
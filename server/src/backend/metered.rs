@@ -0,0 +1,217 @@
+use std::{future::Future, time::Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use dicom_object::{FileDicomObject, InMemDicomObject};
+
+use super::{BackendError, DicomWebBackend, ResourceVersion};
+use crate::{
+    auth::Principal, metrics::Metrics, InstanceReference, QidoInstanceQuery, QidoSeriesQuery,
+    QidoStudyQuery, RejectionNote,
+};
+
+/// Measures the duration of the calls to a backend and counts the stored instances.
+///
+/// Stores from STOW-RS and the DIMSE storage SCP are both counted, as stored or failed.
+pub struct MeteredBackend<B> {
+    backend: B,
+    metrics: Metrics,
+}
+
+impl<B: DicomWebBackend> MeteredBackend<B> {
+    pub fn new(backend: B, metrics: Metrics) -> MeteredBackend<B> {
+        MeteredBackend { backend, metrics }
+    }
+
+    async fn timed<T>(&self, operation: &str, call: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let result = call.await;
+        self.metrics.observe_backend(operation, start.elapsed());
+        result
+    }
+}
+
+#[async_trait]
+impl<B: DicomWebBackend> DicomWebBackend for MeteredBackend<B> {
+    async fn search_study(
+        &self,
+        principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.timed("search_study", self.backend.search_study(principal, query))
+            .await
+    }
+
+    async fn search_series(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.timed(
+            "search_series",
+            self.backend.search_series(principal, study_uid, query),
+        )
+        .await
+    }
+
+    async fn search_instances(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.timed(
+            "search_instances",
+            self.backend
+                .search_instances(principal, study_uid, series_uid, query),
+        )
+        .await
+    }
+
+    async fn retrieve_study(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.timed(
+            "retrieve_study",
+            self.backend.retrieve_study(principal, study_uid),
+        )
+        .await
+    }
+
+    async fn retrieve_series(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.timed(
+            "retrieve_series",
+            self.backend
+                .retrieve_series(principal, study_uid, series_uid),
+        )
+        .await
+    }
+
+    async fn retrieve_series_metadata(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.timed(
+            "retrieve_series_metadata",
+            self.backend
+                .retrieve_series_metadata(principal, study_uid, series_uid),
+        )
+        .await
+    }
+
+    async fn retrieve_instance(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        self.timed(
+            "retrieve_instance",
+            self.backend
+                .retrieve_instance(principal, study_uid, series_uid, sop_instance_uid),
+        )
+        .await
+    }
+
+    async fn retrieve_frames(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+        frames: &[u32],
+    ) -> Result<Vec<Bytes>, BackendError> {
+        self.timed(
+            "retrieve_frames",
+            self.backend.retrieve_frames(
+                principal,
+                study_uid,
+                series_uid,
+                sop_instance_uid,
+                frames,
+            ),
+        )
+        .await
+    }
+
+    async fn version(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: Option<&str>,
+    ) -> Result<Option<ResourceVersion>, BackendError> {
+        self.timed(
+            "version",
+            self.backend.version(principal, study_uid, series_uid),
+        )
+        .await
+    }
+
+    async fn store_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        let result = self
+            .timed(
+                "store_instances",
+                self.backend.store_instances(principal, instances),
+            )
+            .await;
+        self.metrics
+            .count_instances(result.is_ok(), instances.len());
+        result
+    }
+
+    async fn update_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        let result = self
+            .timed(
+                "update_instances",
+                self.backend.update_instances(principal, instances),
+            )
+            .await;
+        self.metrics
+            .count_instances(result.is_ok(), instances.len());
+        result
+    }
+
+    async fn reject_instances(
+        &self,
+        principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        self.timed(
+            "reject_instances",
+            self.backend.reject_instances(principal, note),
+        )
+        .await
+    }
+
+    async fn delete_instances(
+        &self,
+        principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        self.timed(
+            "delete_instances",
+            self.backend.delete_instances(principal, instances),
+        )
+        .await
+    }
+}
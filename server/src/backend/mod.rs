@@ -0,0 +1,466 @@
+//! Storage backends
+//!
+//! A backend answers the QIDO-RS searches and stores and retrieves the instances
+//! for the WADO-RS and STOW-RS endpoints.
+
+use std::{fmt, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use dicom::dictionary_std::tags;
+use dicom_object::{FileDicomObject, InMemDicomObject, Tag};
+use dicom_pixeldata::PixelDecoder;
+
+use crate::{
+    auth::Principal, DicomWebServer, InstanceReference, QidoInstanceQuery, QidoSeriesQuery,
+    QidoStudyQuery, RejectionNote,
+};
+
+mod audited;
+mod authorized;
+mod blob;
+mod cached;
+mod deidentified;
+#[cfg(feature = "dimse")]
+mod dimse;
+mod filesystem;
+mod memory;
+#[cfg(feature = "metrics")]
+mod metered;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "proxy")]
+mod proxy;
+pub(crate) mod search;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+mod sql;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod versioned;
+
+pub use audited::AuditedBackend;
+pub use authorized::AuthorizedBackend;
+#[cfg(feature = "s3")]
+pub use blob::S3BlobStore;
+pub use blob::{BlobStore, BlobStream, FileBlobStore};
+pub use cached::CachedBackend;
+pub use deidentified::DeidentifiedBackend;
+#[cfg(feature = "dimse")]
+pub use dimse::DimseBackend;
+pub use filesystem::FilesystemBackend;
+pub use memory::InMemoryBackend;
+#[cfg(feature = "metrics")]
+pub use metered::MeteredBackend;
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresBackend;
+#[cfg(feature = "proxy")]
+pub use proxy::ProxyBackend;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteBackend;
+pub use versioned::{ResourceVersion, VersionedBackend};
+
+pub type BackendError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The requested instance doesn't exist, or is hidden by a rejection. Responded to with
+/// 404 Not Found.
+#[derive(Debug)]
+pub struct NotFound;
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No instance found")
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// Storage behind the DICOMweb endpoints.
+///
+/// Searches apply the limit and offset of their query themselves, so backends can
+/// push them into their storage.
+#[async_trait]
+pub trait DicomWebBackend: Send + Sync {
+    async fn search_study(
+        &self,
+        principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError>;
+
+    async fn search_series(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError>;
+
+    async fn search_instances(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError>;
+
+    async fn retrieve_study(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError>;
+
+    async fn retrieve_series(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError>;
+
+    async fn retrieve_instance(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError>;
+
+    /// Metadata of the instances of a series, without their bulk data.
+    ///
+    /// By default the whole series is retrieved.
+    async fn retrieve_series_metadata(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let instances = self
+            .retrieve_series(principal, study_uid, series_uid)
+            .await?;
+        Ok(instances.into_iter().map(without_bulk_data).collect())
+    }
+
+    /// Frames of an instance, numbered from 1.
+    ///
+    /// By default the whole instance is retrieved and its pixel data decoded.
+    async fn retrieve_frames(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+        frames: &[u32],
+    ) -> Result<Vec<Bytes>, BackendError> {
+        let instance = self
+            .retrieve_instance(principal, study_uid, series_uid, sop_instance_uid)
+            .await?;
+        instance_frames(&instance, frames)
+    }
+
+    /// Version of a study, or of a series and its instances, which changes with their content.
+    ///
+    /// Lets the WADO-RS endpoints answer conditional requests without retrieving the
+    /// instances. By default there is none, and the ETags are derived from the content.
+    async fn version(
+        &self,
+        _principal: &Principal,
+        _study_uid: &str,
+        _series_uid: Option<&str>,
+    ) -> Result<Option<ResourceVersion>, BackendError> {
+        Ok(None)
+    }
+
+    async fn store_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError>;
+
+    /// Store the instances of a metadata update, which replace the stored ones with the
+    /// same UIDs. Stores them like any others, unless the backend tells updates apart.
+    async fn update_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        self.store_instances(principal, instances).await
+    }
+
+    /// Hide or delete the instances referenced by a received rejection note
+    async fn reject_instances(
+        &self,
+        principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError>;
+
+    /// Remove instances which were replaced by an update with new UIDs
+    async fn delete_instances(
+        &self,
+        principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError>;
+}
+
+/// Lets the HTTP endpoints and a DIMSE listener share one backend
+#[async_trait]
+impl<B: DicomWebBackend + ?Sized> DicomWebBackend for Arc<B> {
+    async fn search_study(
+        &self,
+        principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.as_ref().search_study(principal, query).await
+    }
+
+    async fn search_series(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.as_ref()
+            .search_series(principal, study_uid, query)
+            .await
+    }
+
+    async fn search_instances(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.as_ref()
+            .search_instances(principal, study_uid, series_uid, query)
+            .await
+    }
+
+    async fn retrieve_study(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.as_ref().retrieve_study(principal, study_uid).await
+    }
+
+    async fn retrieve_series(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.as_ref()
+            .retrieve_series(principal, study_uid, series_uid)
+            .await
+    }
+
+    async fn retrieve_series_metadata(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.as_ref()
+            .retrieve_series_metadata(principal, study_uid, series_uid)
+            .await
+    }
+
+    async fn retrieve_instance(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        self.as_ref()
+            .retrieve_instance(principal, study_uid, series_uid, sop_instance_uid)
+            .await
+    }
+
+    async fn retrieve_frames(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+        frames: &[u32],
+    ) -> Result<Vec<Bytes>, BackendError> {
+        self.as_ref()
+            .retrieve_frames(principal, study_uid, series_uid, sop_instance_uid, frames)
+            .await
+    }
+
+    async fn version(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: Option<&str>,
+    ) -> Result<Option<ResourceVersion>, BackendError> {
+        self.as_ref()
+            .version(principal, study_uid, series_uid)
+            .await
+    }
+
+    async fn store_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        self.as_ref().store_instances(principal, instances).await
+    }
+
+    async fn update_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        self.as_ref().update_instances(principal, instances).await
+    }
+
+    async fn reject_instances(
+        &self,
+        principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        self.as_ref().reject_instances(principal, note).await
+    }
+
+    async fn delete_instances(
+        &self,
+        principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        self.as_ref().delete_instances(principal, instances).await
+    }
+}
+
+/// UIDs become path components, so only digits and dots are allowed
+fn checked_uid(dcm: &InMemDicomObject, tag: Tag) -> Result<String, BackendError> {
+    let uid = search::uid(dcm, tag).ok_or_else(|| format!("Missing UID {}", tag))?;
+    if !search::is_uid(&uid) {
+        return Err(format!("Invalid UID {}", uid).into());
+    }
+    Ok(uid)
+}
+
+/// Relative path of a stored instance, `{study}/{series}/{sop}.dcm`
+pub(crate) fn instance_path(dcm: &InMemDicomObject) -> Result<PathBuf, BackendError> {
+    let study_uid = checked_uid(dcm, tags::STUDY_INSTANCE_UID)?;
+    let series_uid = checked_uid(dcm, tags::SERIES_INSTANCE_UID)?;
+    let sop_uid = checked_uid(dcm, tags::SOP_INSTANCE_UID)?;
+    Ok(PathBuf::from(study_uid)
+        .join(series_uid)
+        .join(format!("{}.dcm", sop_uid)))
+}
+
+/// Dataset of an instance without its pixel data
+pub(crate) fn without_bulk_data(instance: FileDicomObject<InMemDicomObject>) -> InMemDicomObject {
+    let mut dcm = instance.into_inner();
+    dcm.remove_element(tags::PIXEL_DATA);
+    dcm
+}
+
+/// Decode the given frames, numbered from 1, of an instance
+pub(crate) fn instance_frames(
+    instance: &FileDicomObject<InMemDicomObject>,
+    frames: &[u32],
+) -> Result<Vec<Bytes>, BackendError> {
+    let pixel_data = instance.decode_pixel_data()?;
+    frames
+        .iter()
+        .map(|&frame| {
+            if frame == 0 || frame > pixel_data.number_of_frames() {
+                return Err(format!("Frame {} doesn't exist", frame).into());
+            }
+            Ok(Bytes::copy_from_slice(pixel_data.frame_data(frame - 1)?))
+        })
+        .collect()
+}
+
+// The callbacks return errors, which can't be sent across threads
+fn callback_error(e: Box<dyn std::error::Error>) -> BackendError {
+    e.to_string().into()
+}
+
+/// The callbacks return every match, the limit and offset are applied here.
+#[async_trait]
+impl DicomWebBackend for DicomWebServer {
+    async fn search_study(
+        &self,
+        _principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let results = (self.search_study)(query).map_err(callback_error)?;
+        Ok(search::paginate(results, query.offset, query.limit))
+    }
+
+    async fn search_series(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let results = (self.search_series)(study_uid, query).map_err(callback_error)?;
+        Ok(search::paginate(results, query.offset, query.limit))
+    }
+
+    async fn search_instances(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let results =
+            (self.search_instances)(study_uid, series_uid, query).map_err(callback_error)?;
+        Ok(search::paginate(results, query.offset, query.limit))
+    }
+
+    async fn retrieve_study(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        (self.retrieve_study)(study_uid).map_err(callback_error)
+    }
+
+    async fn retrieve_series(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        (self.retrieve_series)(study_uid, series_uid).map_err(callback_error)
+    }
+
+    async fn retrieve_instance(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        (self.retrieve_instance)(study_uid, series_uid, sop_instance_uid).map_err(callback_error)
+    }
+
+    async fn store_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        (self.store_instances)(instances).map_err(callback_error)
+    }
+
+    async fn reject_instances(
+        &self,
+        _principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        (self.reject_instances)(note).map_err(callback_error)
+    }
+
+    async fn delete_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        (self.delete_instances)(instances).map_err(callback_error)
+    }
+}
@@ -0,0 +1,670 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod, Transaction};
+use dicom::dictionary_std::tags;
+use dicom_object::{FileDicomObject, InMemDicomObject, Tag};
+use tokio_postgres::{types::ToSql, NoTls};
+
+use super::{
+    blob::{self, FrameLayout},
+    instance_frames, instance_path, search,
+    sql::{
+        instance_row, pagination, series_row, study_row, text, visible, Dialect, Filter, Level,
+        REMOVE_ORPHANS, STORE_INSTANCE, STORE_SERIES, STORE_STUDY,
+    },
+    BackendError, BlobStore, DicomWebBackend, FileBlobStore, NotFound,
+};
+use crate::{
+    auth::Principal,
+    filter::{instance_matches, series_matches},
+    InstanceReference, QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery, RejectionNote,
+    RejectionReason,
+};
+
+// The trigram indexes on the person names are used by fuzzy matching and wildcards
+const SCHEMA: &str = "
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE TABLE IF NOT EXISTS studies (
+    study_instance_uid TEXT PRIMARY KEY,
+    patient_id TEXT,
+    issuer_of_patient_id TEXT,
+    patient_name TEXT,
+    patient_birth_date TEXT,
+    patient_sex TEXT,
+    study_date TEXT,
+    study_time TEXT,
+    accession_number TEXT,
+    referring_physician_name TEXT,
+    study_id TEXT,
+    study_description TEXT,
+    attributes JSONB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS series (
+    series_instance_uid TEXT PRIMARY KEY,
+    study_instance_uid TEXT NOT NULL REFERENCES studies(study_instance_uid),
+    modality TEXT,
+    series_number TEXT,
+    series_description TEXT,
+    performed_procedure_step_start_date TEXT,
+    performed_procedure_step_start_time TEXT,
+    body_part_examined TEXT,
+    attributes JSONB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS instances (
+    sop_instance_uid TEXT PRIMARY KEY,
+    series_instance_uid TEXT NOT NULL REFERENCES series(series_instance_uid),
+    study_instance_uid TEXT NOT NULL,
+    sop_class_uid TEXT,
+    instance_number TEXT,
+    path TEXT NOT NULL,
+    frame_offset BIGINT,
+    frame_length BIGINT,
+    frame_count BIGINT,
+    rejected BOOLEAN NOT NULL DEFAULT FALSE,
+    attributes JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS studies_patient_id ON studies (patient_id, issuer_of_patient_id);
+CREATE INDEX IF NOT EXISTS studies_patient_name ON studies USING gin (patient_name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS studies_patient_name_lower ON studies (lower(patient_name));
+CREATE INDEX IF NOT EXISTS studies_date ON studies (study_date);
+CREATE INDEX IF NOT EXISTS studies_accession_number ON studies (accession_number);
+CREATE INDEX IF NOT EXISTS studies_referring_physician_name
+    ON studies USING gin (referring_physician_name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS series_study ON series (study_instance_uid);
+CREATE INDEX IF NOT EXISTS series_modality ON series (modality);
+CREATE INDEX IF NOT EXISTS instances_series ON instances (series_instance_uid);
+CREATE INDEX IF NOT EXISTS instances_study ON instances (study_instance_uid);
+";
+
+// Bulk data, which isn't kept in the database
+const BULK_DATA_TAGS: [Tag; 3] = [
+    tags::PIXEL_DATA,
+    tags::FLOAT_PIXEL_DATA,
+    tags::DOUBLE_FLOAT_PIXEL_DATA,
+];
+
+/// All attributes of an instance except its pixel data
+fn dataset_attributes(dcm: &InMemDicomObject) -> InMemDicomObject {
+    InMemDicomObject::from_element_iter(
+        dcm.iter()
+            .filter(|elt| !BULK_DATA_TAGS.contains(&elt.header().tag))
+            .cloned(),
+    )
+}
+
+fn parse_attributes(json: serde_json::Value) -> Result<InMemDicomObject, BackendError> {
+    Ok(dicom_json::from_value(json)?)
+}
+
+fn sql_params<T: ToSql + Sync>(params: &[T]) -> Vec<&(dyn ToSql + Sync)> {
+    params
+        .iter()
+        .map(|param| param as &(dyn ToSql + Sync))
+        .collect()
+}
+
+/// Keeps the index in a PostgreSQL database, the instances are stored as files below
+/// a root directory or in another [`BlobStore`].
+///
+/// The matching attributes have their own columns, while the whole dataset without
+/// its pixel data is kept as JSONB. So any attribute can be matched and returned with
+/// `includefield`. Fuzzy matching of person names uses the `pg_trgm` extension, which
+/// is created together with the tables.
+pub struct PostgresBackend {
+    pool: Pool,
+    blobs: Box<dyn BlobStore>,
+    base_url: Option<String>,
+}
+
+impl PostgresBackend {
+    /// Connect to the database, e.g. `host=localhost user=postgres dbname=dicomweb`,
+    /// and store files below the root directory
+    pub async fn connect(
+        config: &str,
+        root: impl AsRef<Path>,
+    ) -> Result<PostgresBackend, BackendError> {
+        let manager = Manager::from_config(
+            config.parse()?,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        PostgresBackend::from_pool(Pool::builder(manager).build()?, root).await
+    }
+
+    /// Use an existing connection pool, the tables are created if needed
+    pub async fn from_pool(
+        pool: Pool,
+        root: impl AsRef<Path>,
+    ) -> Result<PostgresBackend, BackendError> {
+        pool.get().await?.batch_execute(SCHEMA).await?;
+
+        Ok(PostgresBackend {
+            pool,
+            blobs: Box::new(FileBlobStore::new(root.as_ref())),
+            base_url: None,
+        })
+    }
+
+    /// Store the instances in the given blob store instead of the root directory
+    pub fn with_blob_store(mut self, blobs: impl BlobStore + 'static) -> PostgresBackend {
+        self.blobs = Box::new(blobs);
+        self
+    }
+
+    /// Add a RetrieveURL to the search results, based on the URL the server is reachable at
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> PostgresBackend {
+        self.base_url = Some(base_url.into().trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Read the files of the non-rejected instances, which match the condition
+    async fn open_files(
+        &self,
+        condition: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query(
+                &format!(
+                    "SELECT path FROM instances WHERE NOT rejected AND {} ORDER BY sop_instance_uid",
+                    condition
+                ),
+                params,
+            )
+            .await?;
+
+        let mut instances = Vec::with_capacity(rows.len());
+        for row in rows {
+            let path: String = row.get(0);
+            instances.push(blob::read_instance(self.blobs.as_ref(), &path).await?);
+        }
+        Ok(instances)
+    }
+
+    async fn remove_blobs(&self, paths: Vec<String>) {
+        for path in paths {
+            if let Err(e) = self.blobs.delete(&path).await {
+                log::warn!("Failed to remove {}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Insert or update the rows of an instance and its series and study
+async fn store_instance(
+    transaction: &Transaction<'_>,
+    instance: &InMemDicomObject,
+    path: &str,
+    layout: Option<FrameLayout>,
+) -> Result<Option<String>, BackendError> {
+    let attributes = dataset_attributes(instance);
+    let json = dicom_json::to_value(&attributes)?;
+    let sop_uid = text(&attributes, tags::SOP_INSTANCE_UID);
+
+    let study_row = study_row(&attributes);
+    let mut values = sql_params(&study_row);
+    values.push(&json);
+    transaction.execute(STORE_STUDY, &values).await?;
+    let series_row = series_row(&attributes);
+    let mut values = sql_params(&series_row);
+    values.push(&json);
+    transaction.execute(STORE_SERIES, &values).await?;
+
+    let previous_path: Option<String> = transaction
+        .query_opt(
+            "SELECT path FROM instances WHERE sop_instance_uid = $1 FOR UPDATE",
+            &[&sop_uid],
+        )
+        .await?
+        .map(|row| row.get(0));
+    let offset = layout.map(|layout| layout.offset as i64);
+    let length = layout.map(|layout| layout.length as i64);
+    let count = layout.map(|layout| layout.count as i64);
+    let instance_row = instance_row(&attributes);
+    let mut values = sql_params(&instance_row);
+    values.extend([
+        &path as &(dyn ToSql + Sync),
+        &offset,
+        &length,
+        &count,
+        &json,
+    ]);
+    transaction.execute(STORE_INSTANCE, &values).await?;
+
+    Ok(previous_path.filter(|previous_path| previous_path != path))
+}
+
+/// Study, series and SOP Instance UIDs of the references as columns, instances only
+/// match a reference with all three
+fn reference_columns(references: &[InstanceReference]) -> (Vec<&str>, Vec<&str>, Vec<&str>) {
+    (
+        references
+            .iter()
+            .map(|reference| reference.study_instance_uid.as_str())
+            .collect(),
+        references
+            .iter()
+            .map(|reference| reference.series_instance_uid.as_str())
+            .collect(),
+        references
+            .iter()
+            .map(|reference| reference.sop_instance_uid.as_str())
+            .collect(),
+    )
+}
+
+/// Remove the series and studies without instances
+async fn remove_orphans(client: &tokio_postgres::Client) -> Result<(), BackendError> {
+    client.batch_execute(REMOVE_ORPHANS).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl DicomWebBackend for PostgresBackend {
+    async fn search_study(
+        &self,
+        _principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let mut filter = Filter::new(Dialect::Postgres);
+        filter.push_matches(
+            Level::Study,
+            &query.matches,
+            query.fuzzymatching.unwrap_or(false),
+        );
+
+        let visible = visible("ci", query.includerejected);
+        let sql = format!(
+            "SELECT st.attributes,
+                 (SELECT string_agg(DISTINCT ms.modality, ',') FROM series ms
+                     WHERE ms.study_instance_uid = st.study_instance_uid),
+                 (SELECT COUNT(DISTINCT ci.series_instance_uid) FROM instances ci
+                     WHERE ci.study_instance_uid = st.study_instance_uid{visible}),
+                 (SELECT COUNT(*) FROM instances ci
+                     WHERE ci.study_instance_uid = st.study_instance_uid{visible})
+             FROM studies st
+             WHERE EXISTS (SELECT 1 FROM instances ci
+                 WHERE ci.study_instance_uid = st.study_instance_uid{visible}){}
+             ORDER BY st.study_instance_uid{}",
+            filter.sql(),
+            pagination(Dialect::Postgres, query.limit, query.offset),
+        );
+
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query(&sql, &sql_params(&filter.params))
+            .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let mut study = parse_attributes(row.get(0))?;
+                let modalities: Option<String> = row.get(1);
+                let series: i64 = row.get(2);
+                let instances: i64 = row.get(3);
+                let modalities = modalities
+                    .iter()
+                    .flat_map(|modalities| modalities.split(','))
+                    .map(str::to_string)
+                    .collect();
+                search::put_study_attributes(
+                    &mut study,
+                    modalities,
+                    series as usize,
+                    instances as usize,
+                );
+                Ok(search::study_result(
+                    &study,
+                    query,
+                    self.base_url.as_deref(),
+                ))
+            })
+            .collect()
+    }
+
+    async fn search_series(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let mut filter = Filter::new(Dialect::Postgres);
+        if let Some(study_uid) = study_uid {
+            filter.push_equals("se.study_instance_uid", study_uid);
+        }
+        filter.push_matches(Level::Series, &series_matches(query), false);
+
+        let visible = visible("ci", query.includerejected);
+        let sql = format!(
+            "SELECT se.attributes,
+                 (SELECT COUNT(*) FROM instances ci
+                     WHERE ci.series_instance_uid = se.series_instance_uid{visible})
+             FROM series se
+             JOIN studies st ON st.study_instance_uid = se.study_instance_uid
+             WHERE EXISTS (SELECT 1 FROM instances ci
+                 WHERE ci.series_instance_uid = se.series_instance_uid{visible}){}
+             ORDER BY se.series_instance_uid{}",
+            filter.sql(),
+            pagination(Dialect::Postgres, query.limit, query.offset),
+        );
+
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query(&sql, &sql_params(&filter.params))
+            .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let mut series = parse_attributes(row.get(0))?;
+                let instances: i64 = row.get(1);
+                search::put_series_attributes(&mut series, instances as usize);
+                Ok(search::series_result(
+                    &series,
+                    query,
+                    self.base_url.as_deref(),
+                ))
+            })
+            .collect()
+    }
+
+    async fn search_instances(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let mut filter = Filter::new(Dialect::Postgres);
+        if let Some(study_uid) = study_uid {
+            filter.push_equals("i.study_instance_uid", study_uid);
+        }
+        if let Some(series_uid) = series_uid {
+            filter.push_equals("i.series_instance_uid", series_uid);
+        }
+        filter.push_matches(Level::Instance, &instance_matches(query), false);
+
+        let sql = format!(
+            "SELECT i.attributes FROM instances i
+             JOIN series se ON se.series_instance_uid = i.series_instance_uid
+             JOIN studies st ON st.study_instance_uid = se.study_instance_uid
+             WHERE TRUE{}{}
+             ORDER BY i.sop_instance_uid{}",
+            visible("i", query.includerejected),
+            filter.sql(),
+            pagination(Dialect::Postgres, query.limit, query.offset),
+        );
+
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query(&sql, &sql_params(&filter.params))
+            .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let instance = parse_attributes(row.get(0))?;
+                Ok(search::instance_result(
+                    &instance,
+                    query,
+                    self.base_url.as_deref(),
+                ))
+            })
+            .collect()
+    }
+
+    async fn retrieve_study(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.open_files("study_instance_uid = $1", &[&study_uid])
+            .await
+    }
+
+    async fn retrieve_series(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.open_files(
+            "study_instance_uid = $1 AND series_instance_uid = $2",
+            &[&study_uid, &series_uid],
+        )
+        .await
+    }
+
+    async fn retrieve_instance(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        self.open_files(
+            "study_instance_uid = $1 AND series_instance_uid = $2 AND sop_instance_uid = $3",
+            &[&study_uid, &series_uid, &sop_instance_uid],
+        )
+        .await?
+        .pop()
+        .ok_or_else(|| NotFound.into())
+    }
+
+    async fn retrieve_frames(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+        frames: &[u32],
+    ) -> Result<Vec<Bytes>, BackendError> {
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_opt(
+                "SELECT path, frame_offset, frame_length, frame_count FROM instances
+                 WHERE NOT rejected AND study_instance_uid = $1
+                     AND series_instance_uid = $2 AND sop_instance_uid = $3",
+                &[&study_uid, &series_uid, &sop_instance_uid],
+            )
+            .await?
+            .ok_or(NotFound)?;
+        let path: String = row.get(0);
+        let column = |index| row.get::<_, Option<i64>>(index);
+        let layout = match (column(1), column(2), column(3)) {
+            (Some(offset), Some(length), Some(count)) => Some(FrameLayout {
+                offset: offset as u64,
+                length: length as u64,
+                count: count as u64,
+            }),
+            _ => None,
+        };
+
+        // Uncompressed frames are read by range, all others are decoded
+        match layout {
+            Some(layout) => blob::read_frames(self.blobs.as_ref(), &path, layout, frames).await,
+            None => instance_frames(
+                &blob::read_instance(self.blobs.as_ref(), &path).await?,
+                frames,
+            ),
+        }
+    }
+
+    async fn store_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        let mut stored = Vec::with_capacity(instances.len());
+        for instance in instances {
+            let path = instance_path(instance)?.to_string_lossy().into_owned();
+            let (data, layout) = blob::encode(instance)?;
+            self.blobs.put(&path, blob::chunks(data)).await?;
+            stored.push((instance, path, layout));
+        }
+
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        let mut replaced = Vec::new();
+        for (instance, path, layout) in stored {
+            replaced.extend(store_instance(&transaction, instance, &path, layout).await?);
+        }
+        transaction.commit().await?;
+        remove_orphans(&client).await?;
+
+        // The instance moved to another study or series
+        self.remove_blobs(replaced).await;
+        Ok(())
+    }
+
+    async fn reject_instances(
+        &self,
+        principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        // Instances past their retention period are deleted, all others are hidden
+        if note.reason == RejectionReason::DataRetentionPolicyExpired {
+            return self.delete_instances(principal, &note.instances).await;
+        }
+
+        let (studies, series, sops) = reference_columns(&note.instances);
+        self.pool
+            .get()
+            .await?
+            .execute(
+                "UPDATE instances SET rejected = TRUE
+                 WHERE (study_instance_uid, series_instance_uid, sop_instance_uid) IN
+                     (SELECT * FROM unnest($1::text[], $2::text[], $3::text[]))",
+                &[&studies, &series, &sops],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        let (studies, series, sops) = reference_columns(instances);
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "DELETE FROM instances
+                 WHERE (study_instance_uid, series_instance_uid, sop_instance_uid) IN
+                     (SELECT * FROM unnest($1::text[], $2::text[], $3::text[]))
+                 RETURNING path",
+                &[&studies, &series, &sops],
+            )
+            .await?;
+        remove_orphans(&client).await?;
+
+        self.remove_blobs(rows.iter().map(|row| row.get(0)).collect())
+            .await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{DataElement, VR},
+        dictionary_std::uids,
+    };
+
+    use super::*;
+    use crate::testing;
+
+    // Connection string of a disposable database, e.g. `host=localhost user=postgres dbname=test`
+    const DATABASE_ENV: &str = "DICOMWEB_TEST_POSTGRES";
+
+    /// Screening mammogram of a study, whose series and instance UIDs extend the study UID
+    fn screening_mammogram(study_uid: &str) -> FileDicomObject<InMemDicomObject> {
+        let series_uid = format!("{}.1", study_uid);
+        let sop_uid = format!("{}.1", series_uid);
+        testing::file(InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                uids::DIGITAL_MAMMOGRAPHY_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+            ),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, sop_uid.as_str()),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, study_uid),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, series_uid.as_str()),
+            DataElement::new(tags::PATIENT_ID, VR::LO, "SCREENING-1"),
+            DataElement::new(tags::PATIENT_NAME, VR::PN, "Roe^Jane"),
+            DataElement::new(tags::MODALITY, VR::CS, "MG"),
+        ]))
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DICOMWEB_TEST_POSTGRES"]
+    async fn stores_searches_and_deletes_instances() {
+        let config = std::env::var(DATABASE_ENV)
+            .unwrap_or_else(|_| panic!("{} must hold a connection string", DATABASE_ENV));
+        let root = std::env::temp_dir().join(format!("dicomweb-postgres-{}", uuid::Uuid::new_v4()));
+        let backend = PostgresBackend::connect(&config, &root).await.unwrap();
+
+        // Unique UIDs keep runs against the same database apart
+        let study_uid = format!("2.25.{}", uuid::Uuid::new_v4().as_u128());
+        let series_uid = format!("{}.1", study_uid);
+        let sop_uid = format!("{}.1", series_uid);
+        let principal = testing::principal();
+        backend
+            .store_instances(&principal, &[screening_mammogram(&study_uid)])
+            .await
+            .unwrap();
+
+        let query = QidoStudyQuery {
+            matches: vec![(tags::STUDY_INSTANCE_UID, study_uid.clone())],
+            ..QidoStudyQuery::default()
+        };
+        let studies = backend.search_study(&principal, &query).await.unwrap();
+        assert_eq!(studies.len(), 1);
+        assert_eq!(
+            text(&studies[0], tags::PATIENT_NAME).as_deref(),
+            Some("Roe^Jane")
+        );
+        let instance = backend
+            .retrieve_instance(&principal, &study_uid, &series_uid, &sop_uid)
+            .await
+            .unwrap();
+        assert_eq!(
+            text(&instance, tags::SOP_INSTANCE_UID).as_deref(),
+            Some(sop_uid.as_str())
+        );
+
+        let reference = InstanceReference {
+            study_instance_uid: study_uid.clone(),
+            series_instance_uid: series_uid.clone(),
+            sop_instance_uid: sop_uid.clone(),
+        };
+        backend
+            .delete_instances(&principal, &[reference])
+            .await
+            .unwrap();
+        assert!(backend
+            .search_study(&principal, &query)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(backend
+            .retrieve_instance(&principal, &study_uid, &series_uid, &sop_uid)
+            .await
+            .is_err());
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
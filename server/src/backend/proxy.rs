@@ -0,0 +1,481 @@
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use dicom::dictionary_std::tags;
+use dicom_object::{FileDicomObject, InMemDicomObject, Tag};
+use futures_util::{StreamExt, TryStreamExt};
+use reqwest::{header, Response, StatusCode};
+use serde_json::Value;
+
+use super::{BackendError, NotFound};
+use crate::{
+    auth::Principal,
+    backend::DicomWebBackend,
+    filter::{instance_matches, series_matches},
+    multipart::{MultipartReader, MultipartWriter},
+    InstanceReference, QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery, RejectionNote,
+    APPLICATION_DICOM_JSON,
+};
+
+/// Forwards QIDO-RS, WADO-RS and STOW-RS to an upstream DICOMweb server, e.g. a PACS.
+///
+/// Multipart responses are read part by part while they arrive, each part is decoded
+/// as soon as it is complete. URLs of the upstream in the search results and metadata
+/// are replaced by the public URL of the proxy.
+pub struct ProxyBackend {
+    http: reqwest::Client,
+    upstream: String,
+    base_url: String,
+}
+
+impl ProxyBackend {
+    /// Forward to the service at `upstream`, e.g. "http://pacs:8042/dicom-web", for clients
+    /// which reach the proxy at `base_url`, e.g. "https://proxy/dicom-web"
+    pub fn new(upstream: &str, base_url: &str) -> ProxyBackend {
+        ProxyBackend::with_http_client(upstream, base_url, reqwest::Client::new())
+    }
+
+    /// Forward with a preconfigured HTTP client.
+    ///
+    /// Use this to set the credentials of the upstream, or timeouts.
+    pub fn with_http_client(upstream: &str, base_url: &str, http: reqwest::Client) -> ProxyBackend {
+        ProxyBackend {
+            http,
+            upstream: upstream.trim_end_matches('/').to_string(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.upstream, path)
+    }
+
+    /// Request DICOM JSON, e.g. search results or metadata
+    async fn search(
+        &self,
+        path: &str,
+        params: &[(String, String)],
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let response = self
+            .http
+            .get(self.url(path))
+            .header(header::ACCEPT, APPLICATION_DICOM_JSON)
+            .query(params)
+            .send()
+            .await?;
+
+        // "No Content" is returned, if there are no matches, "Not Found" for unknown studies
+        if response.status() == StatusCode::NO_CONTENT || response.status() == StatusCode::NOT_FOUND
+        {
+            return Ok(Vec::new());
+        }
+
+        let body = check_status(response).await?.bytes().await?;
+        if body.is_empty() {
+            return Ok(Vec::new());
+        }
+        let datasets = match serde_json::from_slice(&body)? {
+            Value::Array(datasets) => datasets,
+            dataset => vec![dataset],
+        };
+
+        datasets
+            .into_iter()
+            .map(|mut dataset| {
+                self.rewrite_urls(&mut dataset);
+                Ok(dicom_json::from_value(dataset)?)
+            })
+            .collect()
+    }
+
+    /// Point the URLs of a DICOM JSON dataset at the proxy instead of the upstream.
+    ///
+    /// Bulk data references can't be read into a dataset, so the attributes are kept
+    /// without value. Their bulk data is available from WADO-RS of the proxy.
+    fn rewrite_urls(&self, dataset: &mut Value) {
+        let Value::Object(elements) = dataset else {
+            return;
+        };
+        for (tag, element) in elements.iter_mut() {
+            let Value::Object(fields) = element else {
+                continue;
+            };
+            if let Some(uri) = fields.remove("BulkDataURI") {
+                log::debug!("Omitting bulk data {} of {}", uri, tag);
+                continue;
+            }
+
+            let vr = fields
+                .get("vr")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            match (vr.as_str(), fields.get_mut("Value")) {
+                ("SQ", Some(Value::Array(items))) => {
+                    items.iter_mut().for_each(|item| self.rewrite_urls(item));
+                }
+                ("UR", Some(Value::Array(values))) => {
+                    for value in values.iter_mut() {
+                        if let Value::String(url) = value {
+                            *url = self.rewrite_url(url);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// URL of the proxy for a URL of the upstream, other URLs are kept
+    fn rewrite_url(&self, url: &str) -> String {
+        match url.strip_prefix(&self.upstream) {
+            Some(path) if path.is_empty() || path.starts_with('/') => {
+                format!("{}{}", self.base_url, path)
+            }
+            _ => url.to_string(),
+        }
+    }
+
+    /// Request a multipart/related response and decode its parts, as soon as each of
+    /// them arrived
+    async fn retrieve_multipart<T>(
+        &self,
+        path: &str,
+        part_type: &str,
+        decode: impl Fn(Bytes) -> Result<T, BackendError>,
+    ) -> Result<Option<Vec<T>>, BackendError> {
+        let response = self
+            .http
+            .get(self.url(path))
+            .header(
+                header::ACCEPT,
+                format!(
+                    "multipart/related; type=\"{}\"; transfer-syntax=*",
+                    part_type
+                ),
+            )
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = check_status(response).await?;
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(str::to_string);
+        let mut multipart =
+            MultipartReader::from_content_type(content_type.as_deref(), response.bytes_stream());
+
+        let mut parts = Vec::new();
+        while let Some(part) = multipart.next().await {
+            let data = part?
+                .try_fold(BytesMut::new(), |mut data, chunk| async move {
+                    data.extend_from_slice(&chunk);
+                    Ok(data)
+                })
+                .await?;
+            parts.push(decode(data.freeze())?);
+        }
+        Ok(Some(parts))
+    }
+
+    async fn retrieve_instances(
+        &self,
+        path: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        Ok(self
+            .retrieve_multipart(path, "application/dicom", |part| {
+                Ok(FileDicomObject::from_reader(&part[..])?)
+            })
+            .await?
+            .unwrap_or_default())
+    }
+}
+
+/// Fail with the status and message of an unsuccessful response
+async fn check_status(response: Response) -> Result<Response, BackendError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let message = response.text().await.unwrap_or_default();
+    Err(format!("Upstream responded with status {}: {}", status, message).into())
+}
+
+/// Encode the paging, included fields and matching attributes of a query as parameters
+fn query_params<'a>(
+    limit: Option<usize>,
+    offset: Option<usize>,
+    includefields: impl IntoIterator<Item = &'a str>,
+    includerejected: Option<bool>,
+    matches: &[(Tag, String)],
+) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    if let Some(limit) = limit {
+        params.push((String::from("limit"), limit.to_string()));
+    }
+    if let Some(offset) = offset {
+        params.push((String::from("offset"), offset.to_string()));
+    }
+    for field in includefields {
+        params.push((String::from("includefield"), field.to_string()));
+    }
+    if let Some(includerejected) = includerejected {
+        params.push((String::from("includerejected"), includerejected.to_string()));
+    }
+    for (tag, value) in matches {
+        params.push((
+            format!("{:04X}{:04X}", tag.group(), tag.element()),
+            value.clone(),
+        ));
+    }
+    params
+}
+
+#[async_trait]
+impl DicomWebBackend for ProxyBackend {
+    async fn search_study(
+        &self,
+        _principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let mut params = query_params(
+            query.limit,
+            query.offset,
+            query.includefields.iter().map(String::as_str),
+            query.includerejected,
+            &query.matches,
+        );
+        if let Some(fuzzymatching) = query.fuzzymatching {
+            params.push((String::from("fuzzymatching"), fuzzymatching.to_string()));
+        }
+        self.search("/studies", &params).await
+    }
+
+    async fn search_series(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let path = match study_uid {
+            Some(study_uid) => format!("/studies/{}/series", study_uid),
+            None => String::from("/series"),
+        };
+        let params = query_params(
+            query.limit,
+            query.offset,
+            query
+                .includefield
+                .iter()
+                .flat_map(|fields| fields.split(',')),
+            query.includerejected,
+            &series_matches(query),
+        );
+        self.search(&path, &params).await
+    }
+
+    async fn search_instances(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let path = match (study_uid, series_uid) {
+            (Some(study_uid), Some(series_uid)) => {
+                format!("/studies/{}/series/{}/instances", study_uid, series_uid)
+            }
+            (Some(study_uid), None) => format!("/studies/{}/instances", study_uid),
+            _ => String::from("/instances"),
+        };
+        let params = query_params(
+            query.limit,
+            query.offset,
+            query
+                .includefield
+                .iter()
+                .flat_map(|fields| fields.split(',')),
+            query.includerejected,
+            &instance_matches(query),
+        );
+        self.search(&path, &params).await
+    }
+
+    async fn retrieve_study(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.retrieve_instances(&format!("/studies/{}", study_uid))
+            .await
+    }
+
+    async fn retrieve_series(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.retrieve_instances(&format!("/studies/{}/series/{}", study_uid, series_uid))
+            .await
+    }
+
+    /// The metadata of the upstream, so the instances aren't transferred
+    async fn retrieve_series_metadata(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let path = format!("/studies/{}/series/{}/metadata", study_uid, series_uid);
+        let mut instances = self.search(&path, &[]).await?;
+        for instance in &mut instances {
+            instance.remove_element(tags::PIXEL_DATA);
+        }
+        Ok(instances)
+    }
+
+    async fn retrieve_instance(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        self.retrieve_instances(&format!(
+            "/studies/{}/series/{}/instances/{}",
+            study_uid, series_uid, sop_instance_uid
+        ))
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| NotFound.into())
+    }
+
+    /// The upstream sends the frames, they are passed on without decoding
+    async fn retrieve_frames(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+        frames: &[u32],
+    ) -> Result<Vec<Bytes>, BackendError> {
+        let frame_list: Vec<String> = frames.iter().map(|frame| frame.to_string()).collect();
+        let path = format!(
+            "/studies/{}/series/{}/instances/{}/frames/{}",
+            study_uid,
+            series_uid,
+            sop_instance_uid,
+            frame_list.join(",")
+        );
+        self.retrieve_multipart(&path, "application/octet-stream", Ok)
+            .await?
+            .ok_or_else(|| NotFound.into())
+    }
+
+    async fn store_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        if instances.is_empty() {
+            return Ok(());
+        }
+
+        let mut mp = MultipartWriter::new();
+        for instance in instances {
+            let mut data: Vec<u8> = Vec::new();
+            instance.write_all(&mut data)?;
+            mp.add(&*data, "Content-Type: application/dicom")?;
+        }
+        mp.finish();
+
+        let content_type = format!(
+            "multipart/related; type=\"application/dicom\"; boundary={}",
+            mp.boundary
+        );
+        let response = self
+            .http
+            .post(self.url("/studies"))
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::ACCEPT, APPLICATION_DICOM_JSON)
+            .body(mp.data)
+            .send()
+            .await?;
+
+        // "Accepted" means, that the upstream stored the instances with warnings
+        if response.status() == StatusCode::ACCEPTED {
+            log::warn!("Upstream stored the instances with warnings");
+        }
+        check_status(response).await?;
+        Ok(())
+    }
+
+    /// The rejection note is stored like any other instance, so the upstream applies it
+    async fn reject_instances(
+        &self,
+        _principal: &Principal,
+        _note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// DELETE isn't part of DICOMweb, but it is supported on instances by most servers
+    async fn delete_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        for reference in instances {
+            let response = self
+                .http
+                .delete(self.url(&format!(
+                    "/studies/{}/series/{}/instances/{}",
+                    reference.study_instance_uid,
+                    reference.series_instance_uid,
+                    reference.sop_instance_uid
+                )))
+                .send()
+                .await?;
+            if response.status() != StatusCode::NOT_FOUND {
+                check_status(response).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn points_urls_at_the_proxy() {
+        let proxy = ProxyBackend::new("http://pacs/dicom-web/", "https://proxy/dicomweb");
+        let mut dataset = json!({
+            "00081190": {"vr": "UR", "Value": ["http://pacs/dicom-web/studies/1.2.3"]},
+            "00081199": {"vr": "SQ", "Value": [{
+                "00081190": {"vr": "UR", "Value": ["http://pacs/dicom-web-other/studies/1.2.3"]}
+            }]},
+            "7FE00010": {"vr": "OB", "BulkDataURI": "http://pacs/dicom-web/studies/1.2.3/bulk"}
+        });
+        proxy.rewrite_urls(&mut dataset);
+
+        assert_eq!(
+            dataset["00081190"]["Value"][0],
+            "https://proxy/dicomweb/studies/1.2.3"
+        );
+        assert_eq!(
+            dataset["00081199"]["Value"][0]["00081190"]["Value"][0],
+            "http://pacs/dicom-web-other/studies/1.2.3"
+        );
+        let dcm: InMemDicomObject = dicom_json::from_value(dataset).unwrap();
+        assert!(dcm.element(tags::PIXEL_DATA).is_ok());
+    }
+}
@@ -0,0 +1,345 @@
+//! QIDO-RS results from the attributes of the stored instances
+
+use std::collections::BTreeMap;
+
+use dicom::{
+    core::{DataElement, PrimitiveValue, VR},
+    dictionary_std::tags,
+};
+use dicom_object::{InMemDicomObject, Tag};
+
+use crate::{
+    filter::{instance_filter, series_filter, study_filter},
+    query::parse_attribute,
+    QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery, INSTANCE_TAGS, SERIES_TAGS, STUDY_TAGS,
+};
+
+// Additional attributes, which are kept for matching and includefield
+const EXTRA_TAGS: [Tag; 12] = [
+    tags::STUDY_DESCRIPTION,
+    tags::PATIENT_BIRTH_DATE,
+    tags::PATIENT_SEX,
+    tags::ISSUER_OF_PATIENT_ID,
+    tags::INSTITUTION_NAME,
+    tags::SERIES_DESCRIPTION,
+    tags::BODY_PART_EXAMINED,
+    tags::NUMBER_OF_FRAMES,
+    tags::ROWS,
+    tags::COLUMNS,
+    tags::BITS_ALLOCATED,
+    tags::TRANSFER_SYNTAX_UID,
+];
+
+// Instance attributes, which are returned by default
+// See http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_10.6.3.3.html#table_10.6.3-5
+const INSTANCE_DEFAULT_TAGS: [Tag; 4] = [
+    tags::ROWS,
+    tags::COLUMNS,
+    tags::BITS_ALLOCATED,
+    tags::NUMBER_OF_FRAMES,
+];
+
+/// Apply the offset and limit of a query
+pub(crate) fn paginate<T>(results: Vec<T>, offset: Option<usize>, limit: Option<usize>) -> Vec<T> {
+    results
+        .into_iter()
+        .skip(offset.unwrap_or(0))
+        .take(limit.unwrap_or(usize::MAX))
+        .collect()
+}
+
+/// Keep the attributes of an instance, which can be searched for
+pub(crate) fn indexed_attributes(dcm: &InMemDicomObject) -> InMemDicomObject {
+    InMemDicomObject::from_element_iter(
+        dcm.iter()
+            .filter(|elt| is_indexed(elt.header().tag))
+            .cloned(),
+    )
+}
+
+fn is_indexed(tag: Tag) -> bool {
+    STUDY_TAGS.contains(&tag)
+        || SERIES_TAGS.contains(&tag)
+        || INSTANCE_TAGS.contains(&tag)
+        || EXTRA_TAGS.contains(&tag)
+        || tag == tags::STUDY_INSTANCE_UID
+        || tag == tags::SERIES_INSTANCE_UID
+}
+
+/// Check that a value is a single UID, without wildcards or lists
+pub(crate) fn is_uid(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_digit() || c == '.')
+}
+
+pub(crate) fn uid(dcm: &InMemDicomObject, tag: Tag) -> Option<String> {
+    dcm.get(tag)
+        .and_then(|elt| elt.to_str().ok())
+        .map(|uid| uid.trim_end_matches('\0').to_string())
+}
+
+/// Check that an instance belongs to the study and, if given, the series
+pub(crate) fn belongs_to(
+    dcm: &InMemDicomObject,
+    study_uid: &str,
+    series_uid: Option<&str>,
+) -> bool {
+    uid(dcm, tags::STUDY_INSTANCE_UID).as_deref() == Some(study_uid)
+        && series_uid.is_none_or(|series_uid| {
+            uid(dcm, tags::SERIES_INSTANCE_UID).as_deref() == Some(series_uid)
+        })
+}
+
+/// Resolve the includefield parameters to tags, `None` means all attributes
+pub(crate) fn included_tags<'a>(
+    includefields: impl IntoIterator<Item = &'a str>,
+) -> Option<Vec<Tag>> {
+    let mut included = Vec::new();
+    for field in includefields {
+        if field == "all" {
+            return None;
+        }
+        included.extend(parse_attribute(field));
+    }
+    Some(included)
+}
+
+/// Copy the returned attributes from the dataset
+fn project(
+    dcm: &InMemDicomObject,
+    default_tags: &[Tag],
+    included: &Option<Vec<Tag>>,
+    matches: &[(Tag, String)],
+) -> InMemDicomObject {
+    InMemDicomObject::from_element_iter(
+        dcm.iter()
+            .filter(|elt| {
+                let tag = elt.header().tag;
+                default_tags.contains(&tag)
+                    || included
+                        .as_ref()
+                        .is_none_or(|included| included.contains(&tag))
+                    || matches.iter().any(|(matched, _)| *matched == tag)
+            })
+            .cloned(),
+    )
+}
+
+fn put_count(dcm: &mut InMemDicomObject, tag: Tag, count: usize) {
+    dcm.put(DataElement::new(
+        tag,
+        VR::IS,
+        PrimitiveValue::from(count.to_string()),
+    ));
+}
+
+pub(crate) fn put_retrieve_url(dcm: &mut InMemDicomObject, url: String) {
+    dcm.put(DataElement::new(
+        tags::RETRIEVE_URL,
+        VR::UR,
+        PrimitiveValue::from(url),
+    ));
+}
+
+/// Group instances by an UID, in the order of the UIDs
+fn group_by<'a>(
+    instances: impl IntoIterator<Item = &'a InMemDicomObject>,
+    tag: Tag,
+) -> BTreeMap<String, Vec<&'a InMemDicomObject>> {
+    let mut groups: BTreeMap<String, Vec<&InMemDicomObject>> = BTreeMap::new();
+    for instance in instances {
+        if let Some(uid) = uid(instance, tag) {
+            groups.entry(uid).or_default().push(instance);
+        }
+    }
+    groups
+}
+
+/// Add the attributes, which describe the whole study
+pub(crate) fn put_study_attributes(
+    study: &mut InMemDicomObject,
+    mut modalities: Vec<String>,
+    series: usize,
+    instances: usize,
+) {
+    modalities.sort();
+    modalities.dedup();
+    study.put(DataElement::new(
+        tags::MODALITIES_IN_STUDY,
+        VR::CS,
+        PrimitiveValue::Strs(modalities.into()),
+    ));
+    put_count(study, tags::NUMBER_OF_STUDY_RELATED_SERIES, series);
+    put_count(study, tags::NUMBER_OF_STUDY_RELATED_INSTANCES, instances);
+}
+
+/// Add the attributes, which describe the whole series
+pub(crate) fn put_series_attributes(series: &mut InMemDicomObject, instances: usize) {
+    put_count(series, tags::NUMBER_OF_SERIES_RELATED_INSTANCES, instances);
+}
+
+/// Select the returned attributes of a matching study and add its RetrieveURL
+pub(crate) fn study_result(
+    study: &InMemDicomObject,
+    query: &QidoStudyQuery,
+    base_url: Option<&str>,
+) -> InMemDicomObject {
+    let included = included_tags(query.includefields.iter().map(String::as_str));
+    let mut result = project(
+        study,
+        &[
+            &STUDY_TAGS[..],
+            &[
+                tags::NUMBER_OF_STUDY_RELATED_SERIES,
+                tags::NUMBER_OF_STUDY_RELATED_INSTANCES,
+            ],
+        ]
+        .concat(),
+        &included,
+        &query.matches,
+    );
+    if let (Some(base_url), Some(study_uid)) = (base_url, uid(study, tags::STUDY_INSTANCE_UID)) {
+        put_retrieve_url(&mut result, format!("{}/studies/{}", base_url, study_uid));
+    }
+    result
+}
+
+/// Select the returned attributes of a matching series and add its RetrieveURL
+pub(crate) fn series_result(
+    series: &InMemDicomObject,
+    query: &QidoSeriesQuery,
+    base_url: Option<&str>,
+) -> InMemDicomObject {
+    let included = included_tags(query.includefield.iter().flat_map(|field| field.split(',')));
+    let mut result = project(
+        series,
+        &[
+            &SERIES_TAGS[..],
+            &[
+                tags::STUDY_INSTANCE_UID,
+                tags::NUMBER_OF_SERIES_RELATED_INSTANCES,
+            ],
+        ]
+        .concat(),
+        &included,
+        &query.matches,
+    );
+    if let (Some(base_url), Some(study_uid), Some(series_uid)) = (
+        base_url,
+        uid(series, tags::STUDY_INSTANCE_UID),
+        uid(series, tags::SERIES_INSTANCE_UID),
+    ) {
+        put_retrieve_url(
+            &mut result,
+            format!("{}/studies/{}/series/{}", base_url, study_uid, series_uid),
+        );
+    }
+    result
+}
+
+/// Select the returned attributes of a matching instance and add its RetrieveURL
+pub(crate) fn instance_result(
+    instance: &InMemDicomObject,
+    query: &QidoInstanceQuery,
+    base_url: Option<&str>,
+) -> InMemDicomObject {
+    let included = included_tags(query.includefield.iter().flat_map(|field| field.split(',')));
+    let mut result = project(
+        instance,
+        &[
+            &INSTANCE_TAGS[..],
+            &INSTANCE_DEFAULT_TAGS[..],
+            &[tags::STUDY_INSTANCE_UID, tags::SERIES_INSTANCE_UID],
+        ]
+        .concat(),
+        &included,
+        &query.matches,
+    );
+    if let (Some(base_url), Some(study_uid), Some(series_uid), Some(sop_uid)) = (
+        base_url,
+        uid(instance, tags::STUDY_INSTANCE_UID),
+        uid(instance, tags::SERIES_INSTANCE_UID),
+        uid(instance, tags::SOP_INSTANCE_UID),
+    ) {
+        put_retrieve_url(
+            &mut result,
+            format!(
+                "{}/studies/{}/series/{}/instances/{}",
+                base_url, study_uid, series_uid, sop_uid
+            ),
+        );
+    }
+    result
+}
+
+/// Search the studies of the given instances
+pub(crate) fn search_studies<'a>(
+    instances: impl IntoIterator<Item = &'a InMemDicomObject>,
+    query: &QidoStudyQuery,
+    base_url: Option<&str>,
+) -> Vec<InMemDicomObject> {
+    let mut studies = Vec::new();
+    for instances in group_by(instances, tags::STUDY_INSTANCE_UID).into_values() {
+        let mut study = instances[0].clone();
+        let series = group_by(instances.iter().copied(), tags::SERIES_INSTANCE_UID);
+        let modalities = instances
+            .iter()
+            .filter_map(|instance| instance.get(tags::MODALITY))
+            .filter_map(|modality| modality.to_str().ok())
+            .map(|modality| modality.trim_end_matches([' ', '\0']).to_string())
+            .collect();
+        put_study_attributes(&mut study, modalities, series.len(), instances.len());
+
+        if study_filter(&study, query) {
+            studies.push(study_result(&study, query, base_url));
+        }
+    }
+
+    paginate(studies, query.offset, query.limit)
+}
+
+/// Search the series of the given instances, optionally within a study
+pub(crate) fn search_series<'a>(
+    instances: impl IntoIterator<Item = &'a InMemDicomObject>,
+    study_uid: Option<&str>,
+    query: &QidoSeriesQuery,
+    base_url: Option<&str>,
+) -> Vec<InMemDicomObject> {
+    let instances = instances
+        .into_iter()
+        .filter(|instance| study_uid.is_none_or(|study_uid| belongs_to(instance, study_uid, None)));
+
+    let mut series_list = Vec::new();
+    for instances in group_by(instances, tags::SERIES_INSTANCE_UID).into_values() {
+        let mut series = instances[0].clone();
+        put_series_attributes(&mut series, instances.len());
+
+        if series_filter(&series, query) {
+            series_list.push(series_result(&series, query, base_url));
+        }
+    }
+
+    paginate(series_list, query.offset, query.limit)
+}
+
+/// Search the given instances, optionally within a study and series
+pub(crate) fn search_instances<'a>(
+    instances: impl IntoIterator<Item = &'a InMemDicomObject>,
+    study_uid: Option<&str>,
+    series_uid: Option<&str>,
+    query: &QidoInstanceQuery,
+    base_url: Option<&str>,
+) -> Vec<InMemDicomObject> {
+    let results = instances
+        .into_iter()
+        .filter(|instance| {
+            study_uid.is_none_or(|study_uid| {
+                uid(instance, tags::STUDY_INSTANCE_UID).as_deref() == Some(study_uid)
+            }) && series_uid.is_none_or(|series_uid| {
+                uid(instance, tags::SERIES_INSTANCE_UID).as_deref() == Some(series_uid)
+            }) && instance_filter(instance, query)
+        })
+        .map(|instance| instance_result(instance, query, base_url))
+        .collect();
+
+    paginate(results, query.offset, query.limit)
+}
@@ -0,0 +1,401 @@
+//! Translation of QIDO-RS matching into SQL conditions
+
+use dicom::{core::VR, dictionary_std::tags};
+use dicom_object::{InMemDicomObject, Tag};
+
+use crate::{attribute_vr, AttributeMatch};
+
+// Matching attributes, which have their own column. The patient attributes are kept
+// on the study row, as the PatientID is neither unique without its issuer nor required.
+const PATIENT_COLUMNS: [(Tag, &str); 5] = [
+    (tags::PATIENT_ID, "st.patient_id"),
+    (tags::ISSUER_OF_PATIENT_ID, "st.issuer_of_patient_id"),
+    (tags::PATIENT_NAME, "st.patient_name"),
+    (tags::PATIENT_BIRTH_DATE, "st.patient_birth_date"),
+    (tags::PATIENT_SEX, "st.patient_sex"),
+];
+const STUDY_COLUMNS: [(Tag, &str); 7] = [
+    (tags::STUDY_INSTANCE_UID, "st.study_instance_uid"),
+    (tags::STUDY_DATE, "st.study_date"),
+    (tags::STUDY_TIME, "st.study_time"),
+    (tags::ACCESSION_NUMBER, "st.accession_number"),
+    (
+        tags::REFERRING_PHYSICIAN_NAME,
+        "st.referring_physician_name",
+    ),
+    (tags::STUDY_ID, "st.study_id"),
+    (tags::STUDY_DESCRIPTION, "st.study_description"),
+];
+const SERIES_COLUMNS: [(Tag, &str); 7] = [
+    (tags::SERIES_INSTANCE_UID, "se.series_instance_uid"),
+    (tags::MODALITY, "se.modality"),
+    (tags::SERIES_NUMBER, "se.series_number"),
+    (tags::SERIES_DESCRIPTION, "se.series_description"),
+    (
+        tags::PERFORMED_PROCEDURE_STEP_START_DATE,
+        "se.performed_procedure_step_start_date",
+    ),
+    (
+        tags::PERFORMED_PROCEDURE_STEP_START_TIME,
+        "se.performed_procedure_step_start_time",
+    ),
+    (tags::BODY_PART_EXAMINED, "se.body_part_examined"),
+];
+const INSTANCE_COLUMNS: [(Tag, &str); 3] = [
+    (tags::SOP_INSTANCE_UID, "i.sop_instance_uid"),
+    (tags::SOP_CLASS_UID, "i.sop_class_uid"),
+    (tags::INSTANCE_NUMBER, "i.instance_number"),
+];
+
+// Statements shared by the database backends. SQLite binds the numbered parameters in
+// the order of their first use, like positional ones.
+pub(crate) const STORE_STUDY: &str = "
+INSERT INTO studies (study_instance_uid, patient_id, issuer_of_patient_id, patient_name,
+    patient_birth_date, patient_sex, study_date, study_time, accession_number,
+    referring_physician_name, study_id, study_description, attributes)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+ON CONFLICT (study_instance_uid) DO UPDATE SET
+    patient_id = excluded.patient_id,
+    issuer_of_patient_id = excluded.issuer_of_patient_id,
+    patient_name = excluded.patient_name,
+    patient_birth_date = excluded.patient_birth_date,
+    patient_sex = excluded.patient_sex,
+    study_date = excluded.study_date,
+    study_time = excluded.study_time,
+    accession_number = excluded.accession_number,
+    referring_physician_name = excluded.referring_physician_name,
+    study_id = excluded.study_id,
+    study_description = excluded.study_description,
+    attributes = excluded.attributes";
+pub(crate) const STORE_SERIES: &str = "
+INSERT INTO series (series_instance_uid, study_instance_uid, modality, series_number,
+    series_description, performed_procedure_step_start_date,
+    performed_procedure_step_start_time, body_part_examined, attributes)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+ON CONFLICT (series_instance_uid) DO UPDATE SET
+    study_instance_uid = excluded.study_instance_uid,
+    modality = excluded.modality,
+    series_number = excluded.series_number,
+    series_description = excluded.series_description,
+    performed_procedure_step_start_date = excluded.performed_procedure_step_start_date,
+    performed_procedure_step_start_time = excluded.performed_procedure_step_start_time,
+    body_part_examined = excluded.body_part_examined,
+    attributes = excluded.attributes";
+pub(crate) const STORE_INSTANCE: &str = "
+INSERT INTO instances (sop_instance_uid, series_instance_uid, study_instance_uid,
+    sop_class_uid, instance_number, path, frame_offset, frame_length, frame_count,
+    rejected, attributes)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, FALSE, $10)
+ON CONFLICT (sop_instance_uid) DO UPDATE SET
+    series_instance_uid = excluded.series_instance_uid,
+    study_instance_uid = excluded.study_instance_uid,
+    sop_class_uid = excluded.sop_class_uid,
+    instance_number = excluded.instance_number,
+    path = excluded.path,
+    frame_offset = excluded.frame_offset,
+    frame_length = excluded.frame_length,
+    frame_count = excluded.frame_count,
+    rejected = FALSE,
+    attributes = excluded.attributes";
+/// Remove the series and studies without instances
+pub(crate) const REMOVE_ORPHANS: &str = "
+DELETE FROM series WHERE NOT EXISTS
+    (SELECT 1 FROM instances i WHERE i.series_instance_uid = series.series_instance_uid);
+DELETE FROM studies WHERE NOT EXISTS
+    (SELECT 1 FROM series se WHERE se.study_instance_uid = studies.study_instance_uid);";
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Dialect {
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+    #[cfg(feature = "postgres")]
+    Postgres,
+}
+
+/// Level of a search, which determines the joined tables
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Level {
+    Study,
+    Series,
+    Instance,
+}
+
+impl Level {
+    /// Column of a matching attribute, other attributes are read from the DICOM JSON
+    fn column(self, dialect: Dialect, tag: Tag) -> String {
+        let mut columns = PATIENT_COLUMNS.iter().chain(STUDY_COLUMNS.iter());
+        let column = match self {
+            Level::Study => columns.find(|(column_tag, _)| *column_tag == tag),
+            Level::Series => columns
+                .chain(SERIES_COLUMNS.iter())
+                .find(|(column_tag, _)| *column_tag == tag),
+            Level::Instance => columns
+                .chain(SERIES_COLUMNS.iter())
+                .chain(INSTANCE_COLUMNS.iter())
+                .find(|(column_tag, _)| *column_tag == tag),
+        };
+        if let Some((_, column)) = column {
+            return column.to_string();
+        }
+
+        let table = match self {
+            Level::Study => "st",
+            Level::Series => "se",
+            Level::Instance => "i",
+        };
+        // Only the first value of a multi-valued attribute is matched. Numbers
+        // are compared as text, like the values of the query.
+        let person_name = attribute_vr(tag) == VR::PN;
+        let tag = format!("{:04X}{:04X}", tag.group(), tag.element());
+        match dialect {
+            #[cfg(feature = "sqlite")]
+            Dialect::Sqlite => format!(
+                "CAST(json_extract({}.attributes, '$.\"{}\".Value[0]{}') AS TEXT)",
+                table,
+                tag,
+                if person_name { ".Alphabetic" } else { "" }
+            ),
+            #[cfg(feature = "postgres")]
+            Dialect::Postgres => format!(
+                "({}.attributes #>> '{{{},Value,0{}}}')",
+                table,
+                tag,
+                if person_name { ",Alphabetic" } else { "" }
+            ),
+        }
+    }
+}
+
+/// WHERE conditions of a search with their parameters
+pub(crate) struct Filter {
+    dialect: Dialect,
+    clauses: Vec<String>,
+    pub(crate) params: Vec<String>,
+}
+
+impl Filter {
+    pub(crate) fn new(dialect: Dialect) -> Filter {
+        Filter {
+            dialect,
+            clauses: Vec::new(),
+            params: Vec::new(),
+        }
+    }
+
+    /// Add a parameter and get its placeholder
+    pub(crate) fn param(&mut self, value: impl Into<String>) -> String {
+        self.params.push(value.into());
+        match self.dialect {
+            #[cfg(feature = "sqlite")]
+            Dialect::Sqlite => "?".to_string(),
+            #[cfg(feature = "postgres")]
+            Dialect::Postgres => format!("${}", self.params.len()),
+        }
+    }
+
+    pub(crate) fn push_equals(&mut self, column: &str, value: &str) {
+        let param = self.param(value);
+        self.clauses.push(format!("{} = {}", column, param));
+    }
+
+    /// Translate the matching attributes of a query into conditions
+    pub(crate) fn push_matches(&mut self, level: Level, matches: &[(Tag, String)], fuzzy: bool) {
+        for (tag, value) in matches {
+            let vr = attribute_vr(*tag);
+            let attribute_match = AttributeMatch::parse(vr, value);
+            if *tag == tags::MODALITIES_IN_STUDY && level == Level::Study {
+                // Any series of the study has to match
+                if let Some(condition) = self.condition("ms.modality", vr, &attribute_match, fuzzy)
+                {
+                    self.clauses.push(format!(
+                        "EXISTS (SELECT 1 FROM series ms WHERE ms.study_instance_uid = st.study_instance_uid AND {})",
+                        condition
+                    ));
+                }
+            } else {
+                let column = level.column(self.dialect, *tag);
+                if let Some(condition) = self.condition(&column, vr, &attribute_match, fuzzy) {
+                    self.clauses.push(condition);
+                }
+            }
+        }
+    }
+
+    /// Translate a single attribute match.
+    ///
+    /// Person names are compared case insensitive like the in-memory matching,
+    /// wildcards of other attributes are case sensitive.
+    fn condition(
+        &mut self,
+        column: &str,
+        vr: VR,
+        attribute_match: &AttributeMatch,
+        fuzzy: bool,
+    ) -> Option<String> {
+        let person_name = vr == VR::PN;
+        let condition = match attribute_match {
+            AttributeMatch::Universal | AttributeMatch::Range(None, None) => return None,
+            AttributeMatch::Single(value) if person_name && fuzzy => {
+                let prefix = self.param(format!("{}%", escape_like(value)));
+                match self.dialect {
+                    #[cfg(feature = "sqlite")]
+                    Dialect::Sqlite => format!("{} LIKE {} ESCAPE '\\'", column, prefix),
+                    // Also match similar names, using the trigram index
+                    #[cfg(feature = "postgres")]
+                    Dialect::Postgres => {
+                        let value = self.param(value.clone());
+                        format!(
+                            "({} ILIKE {} ESCAPE '\\' OR {} % {})",
+                            column, prefix, column, value
+                        )
+                    }
+                }
+            }
+            AttributeMatch::Single(value) if person_name => {
+                let value = self.param(value.clone());
+                match self.dialect {
+                    #[cfg(feature = "sqlite")]
+                    Dialect::Sqlite => format!("{} = {} COLLATE NOCASE", column, value),
+                    #[cfg(feature = "postgres")]
+                    Dialect::Postgres => format!("lower({}) = lower({})", column, value),
+                }
+            }
+            AttributeMatch::Single(value) => {
+                let value = self.param(value.clone());
+                format!("{} = {}", column, value)
+            }
+            AttributeMatch::Wildcard(pattern) => {
+                let like_pattern = escape_like(pattern).replace('*', "%").replace('?', "_");
+                match self.dialect {
+                    #[cfg(feature = "sqlite")]
+                    Dialect::Sqlite if person_name => {
+                        let pattern = self.param(like_pattern);
+                        format!("{} LIKE {} ESCAPE '\\'", column, pattern)
+                    }
+                    // LIKE ignores the case in SQLite, GLOB doesn't
+                    #[cfg(feature = "sqlite")]
+                    Dialect::Sqlite => {
+                        let pattern = self.param(pattern.replace('[', "[[]"));
+                        format!("{} GLOB {}", column, pattern)
+                    }
+                    #[cfg(feature = "postgres")]
+                    Dialect::Postgres => {
+                        let operator = if person_name { "ILIKE" } else { "LIKE" };
+                        let pattern = self.param(like_pattern);
+                        format!("{} {} {} ESCAPE '\\'", column, operator, pattern)
+                    }
+                }
+            }
+            AttributeMatch::Range(Some(from), Some(to)) => {
+                let from = self.param(from.clone());
+                let to = self.param(to.clone());
+                format!("{} BETWEEN {} AND {}", column, from, to)
+            }
+            AttributeMatch::Range(Some(from), None) => {
+                let from = self.param(from.clone());
+                format!("{} >= {}", column, from)
+            }
+            AttributeMatch::Range(None, Some(to)) => {
+                let to = self.param(to.clone());
+                format!("{} <= {}", column, to)
+            }
+            AttributeMatch::UidList(uids) => {
+                let uids: Vec<String> = uids.iter().map(|uid| self.param(uid.clone())).collect();
+                format!("{} IN ({})", column, uids.join(", "))
+            }
+        };
+        Some(condition)
+    }
+
+    /// The conditions, to be appended to a WHERE clause
+    pub(crate) fn sql(&self) -> String {
+        self.clauses
+            .iter()
+            .map(|clause| format!(" AND {}", clause))
+            .collect()
+    }
+}
+
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// LIMIT and OFFSET of a search
+pub(crate) fn pagination(dialect: Dialect, limit: Option<usize>, offset: Option<usize>) -> String {
+    let offset = offset.unwrap_or(0);
+    match (dialect, limit) {
+        (_, Some(limit)) => format!(" LIMIT {} OFFSET {}", limit, offset),
+        #[cfg(feature = "sqlite")]
+        (Dialect::Sqlite, None) => format!(" LIMIT -1 OFFSET {}", offset),
+        #[cfg(feature = "postgres")]
+        (Dialect::Postgres, None) => format!(" OFFSET {}", offset),
+    }
+}
+
+/// Condition on the instances of a search, which hides rejected instances
+pub(crate) fn visible(table: &str, include_rejected: Option<bool>) -> String {
+    if include_rejected.unwrap_or(false) {
+        String::new()
+    } else {
+        format!(" AND NOT {}.rejected", table)
+    }
+}
+
+/// Columns of [`STORE_STUDY`] before the attributes
+pub(crate) fn study_row(dcm: &InMemDicomObject) -> Vec<Option<String>> {
+    [
+        tags::STUDY_INSTANCE_UID,
+        tags::PATIENT_ID,
+        tags::ISSUER_OF_PATIENT_ID,
+        tags::PATIENT_NAME,
+        tags::PATIENT_BIRTH_DATE,
+        tags::PATIENT_SEX,
+        tags::STUDY_DATE,
+        tags::STUDY_TIME,
+        tags::ACCESSION_NUMBER,
+        tags::REFERRING_PHYSICIAN_NAME,
+        tags::STUDY_ID,
+        tags::STUDY_DESCRIPTION,
+    ]
+    .map(|tag| text(dcm, tag))
+    .to_vec()
+}
+
+/// Columns of [`STORE_SERIES`] before the attributes
+pub(crate) fn series_row(dcm: &InMemDicomObject) -> Vec<Option<String>> {
+    [
+        tags::SERIES_INSTANCE_UID,
+        tags::STUDY_INSTANCE_UID,
+        tags::MODALITY,
+        tags::SERIES_NUMBER,
+        tags::SERIES_DESCRIPTION,
+        tags::PERFORMED_PROCEDURE_STEP_START_DATE,
+        tags::PERFORMED_PROCEDURE_STEP_START_TIME,
+        tags::BODY_PART_EXAMINED,
+    ]
+    .map(|tag| text(dcm, tag))
+    .to_vec()
+}
+
+/// Columns of [`STORE_INSTANCE`] before the path
+pub(crate) fn instance_row(dcm: &InMemDicomObject) -> Vec<Option<String>> {
+    [
+        tags::SOP_INSTANCE_UID,
+        tags::SERIES_INSTANCE_UID,
+        tags::STUDY_INSTANCE_UID,
+        tags::SOP_CLASS_UID,
+        tags::INSTANCE_NUMBER,
+    ]
+    .map(|tag| text(dcm, tag))
+    .to_vec()
+}
+
+/// Value of an attribute as stored in its column
+pub(crate) fn text(dcm: &InMemDicomObject, tag: Tag) -> Option<String> {
+    dcm.get(tag)
+        .and_then(|elt| elt.to_str().ok())
+        .map(|value| value.trim_end_matches([' ', '\0']).to_string())
+}
@@ -0,0 +1,595 @@
+use std::{
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use dicom::dictionary_std::tags;
+use dicom_object::{FileDicomObject, InMemDicomObject};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, ToSql, Transaction};
+
+use super::{
+    blob::{self, FrameLayout},
+    instance_frames, instance_path, search,
+    sql::{
+        instance_row, pagination, series_row, study_row, text, visible, Dialect, Filter, Level,
+        REMOVE_ORPHANS, STORE_INSTANCE, STORE_SERIES, STORE_STUDY,
+    },
+    BackendError, BlobStore, DicomWebBackend, FileBlobStore, NotFound,
+};
+use crate::{
+    auth::Principal,
+    filter::{instance_matches, series_matches},
+    InstanceReference, QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery, RejectionNote,
+    RejectionReason,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS studies (
+    study_instance_uid TEXT PRIMARY KEY,
+    patient_id TEXT,
+    issuer_of_patient_id TEXT,
+    patient_name TEXT,
+    patient_birth_date TEXT,
+    patient_sex TEXT,
+    study_date TEXT,
+    study_time TEXT,
+    accession_number TEXT,
+    referring_physician_name TEXT,
+    study_id TEXT,
+    study_description TEXT,
+    attributes TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS series (
+    series_instance_uid TEXT PRIMARY KEY,
+    study_instance_uid TEXT NOT NULL REFERENCES studies(study_instance_uid),
+    modality TEXT,
+    series_number TEXT,
+    series_description TEXT,
+    performed_procedure_step_start_date TEXT,
+    performed_procedure_step_start_time TEXT,
+    body_part_examined TEXT,
+    attributes TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS instances (
+    sop_instance_uid TEXT PRIMARY KEY,
+    series_instance_uid TEXT NOT NULL REFERENCES series(series_instance_uid),
+    study_instance_uid TEXT NOT NULL,
+    sop_class_uid TEXT,
+    instance_number TEXT,
+    path TEXT NOT NULL,
+    frame_offset INTEGER,
+    frame_length INTEGER,
+    frame_count INTEGER,
+    rejected INTEGER NOT NULL DEFAULT 0,
+    attributes TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS studies_patient_id ON studies(patient_id, issuer_of_patient_id);
+CREATE INDEX IF NOT EXISTS studies_patient_name ON studies(patient_name);
+CREATE INDEX IF NOT EXISTS studies_date ON studies(study_date);
+CREATE INDEX IF NOT EXISTS studies_accession_number ON studies(accession_number);
+CREATE INDEX IF NOT EXISTS series_study ON series(study_instance_uid);
+CREATE INDEX IF NOT EXISTS series_modality ON series(modality);
+CREATE INDEX IF NOT EXISTS instances_series ON instances(series_instance_uid);
+CREATE INDEX IF NOT EXISTS instances_study ON instances(study_instance_uid);
+";
+
+fn parse_attributes(json: &str) -> Result<InMemDicomObject, BackendError> {
+    Ok(dicom_json::from_str(json)?)
+}
+
+/// Keeps the index in an SQLite database, the instances are stored as files below
+/// a root directory or in another [`BlobStore`].
+///
+/// QIDO-RS matches are translated to SQL, so the limit and offset of a search are
+/// applied by the database.
+pub struct SqliteBackend {
+    connection: Mutex<Connection>,
+    blobs: Box<dyn BlobStore>,
+    base_url: Option<String>,
+}
+
+impl SqliteBackend {
+    /// Open the database, which is created if needed, and store files below the root directory
+    pub fn open(
+        database: impl AsRef<Path>,
+        root: impl AsRef<Path>,
+    ) -> Result<SqliteBackend, BackendError> {
+        let connection = Connection::open(database)?;
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(SqliteBackend {
+            connection: Mutex::new(connection),
+            blobs: Box::new(FileBlobStore::new(root.as_ref())),
+            base_url: None,
+        })
+    }
+
+    /// Store the instances in the given blob store instead of the root directory
+    pub fn with_blob_store(mut self, blobs: impl BlobStore + 'static) -> SqliteBackend {
+        self.blobs = Box::new(blobs);
+        self
+    }
+
+    /// Add a RetrieveURL to the search results, based on the URL the server is reachable at
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> SqliteBackend {
+        self.base_url = Some(base_url.into().trim_end_matches('/').to_string());
+        self
+    }
+
+    fn connection(&self) -> Result<MutexGuard<'_, Connection>, BackendError> {
+        self.connection
+            .lock()
+            .map_err(|_| "The database connection is poisoned".into())
+    }
+
+    /// Read the files of the non-rejected instances, which match the condition
+    async fn open_files(
+        &self,
+        condition: &str,
+        params: &[&str],
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        let paths: Vec<String> = {
+            let connection = self.connection()?;
+            let mut statement = connection.prepare(&format!(
+                "SELECT path FROM instances WHERE NOT rejected AND {} ORDER BY sop_instance_uid",
+                condition
+            ))?;
+            let paths = statement
+                .query_map(params_from_iter(params), |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            paths
+        };
+
+        let mut instances = Vec::with_capacity(paths.len());
+        for path in paths {
+            instances.push(blob::read_instance(self.blobs.as_ref(), &path).await?);
+        }
+        Ok(instances)
+    }
+
+    async fn remove_blobs(&self, paths: Vec<String>) {
+        for path in paths {
+            if let Err(e) = self.blobs.delete(&path).await {
+                log::warn!("Failed to remove {}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Insert or update the rows of an instance and its series and study
+fn store_instance(
+    transaction: &Transaction,
+    instance: &InMemDicomObject,
+    path: &str,
+    layout: Option<FrameLayout>,
+) -> Result<Option<String>, BackendError> {
+    let attributes = search::indexed_attributes(instance);
+    let json = dicom_json::to_string(&attributes)?;
+    let sop_uid = text(&attributes, tags::SOP_INSTANCE_UID);
+
+    transaction.execute(
+        STORE_STUDY,
+        params_from_iter(
+            study_row(&attributes)
+                .into_iter()
+                .chain([Some(json.clone())]),
+        ),
+    )?;
+    transaction.execute(
+        STORE_SERIES,
+        params_from_iter(
+            series_row(&attributes)
+                .into_iter()
+                .chain([Some(json.clone())]),
+        ),
+    )?;
+
+    let previous_path: Option<String> = transaction
+        .query_row(
+            "SELECT path FROM instances WHERE sop_instance_uid = ?1",
+            params![sop_uid],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let offset = layout.map(|layout| layout.offset);
+    let length = layout.map(|layout| layout.length);
+    let count = layout.map(|layout| layout.count);
+    let row = instance_row(&attributes);
+    let mut values: Vec<&dyn ToSql> = row.iter().map(|value| value as &dyn ToSql).collect();
+    values.extend([&path as &dyn ToSql, &offset, &length, &count, &json]);
+    transaction.execute(STORE_INSTANCE, values.as_slice())?;
+
+    Ok(previous_path.filter(|previous_path| previous_path != path))
+}
+
+/// Remove the series and studies without instances
+fn remove_orphans(connection: &Connection) -> Result<(), BackendError> {
+    connection.execute_batch(REMOVE_ORPHANS)?;
+    Ok(())
+}
+
+#[async_trait]
+impl DicomWebBackend for SqliteBackend {
+    async fn search_study(
+        &self,
+        _principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let mut filter = Filter::new(Dialect::Sqlite);
+        filter.push_matches(
+            Level::Study,
+            &query.matches,
+            query.fuzzymatching.unwrap_or(false),
+        );
+
+        let visible = visible("ci", query.includerejected);
+        let sql = format!(
+            "SELECT st.attributes,
+                 (SELECT group_concat(DISTINCT ms.modality) FROM series ms
+                     WHERE ms.study_instance_uid = st.study_instance_uid),
+                 (SELECT COUNT(DISTINCT ci.series_instance_uid) FROM instances ci
+                     WHERE ci.study_instance_uid = st.study_instance_uid{visible}),
+                 (SELECT COUNT(*) FROM instances ci
+                     WHERE ci.study_instance_uid = st.study_instance_uid{visible})
+             FROM studies st
+             WHERE EXISTS (SELECT 1 FROM instances ci
+                 WHERE ci.study_instance_uid = st.study_instance_uid{visible}){}
+             ORDER BY st.study_instance_uid{}",
+            filter.sql(),
+            pagination(Dialect::Sqlite, query.limit, query.offset),
+        );
+
+        let rows: Vec<(String, Option<String>, usize, usize)> = {
+            let connection = self.connection()?;
+            let mut statement = connection.prepare(&sql)?;
+            let rows = statement
+                .query_map(params_from_iter(&filter.params), |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                })?
+                .collect::<Result<_, _>>()?;
+            rows
+        };
+
+        rows.into_iter()
+            .map(|(json, modalities, series, instances)| {
+                let mut study = parse_attributes(&json)?;
+                let modalities = modalities
+                    .iter()
+                    .flat_map(|modalities| modalities.split(','))
+                    .map(str::to_string)
+                    .collect();
+                search::put_study_attributes(&mut study, modalities, series, instances);
+                Ok(search::study_result(
+                    &study,
+                    query,
+                    self.base_url.as_deref(),
+                ))
+            })
+            .collect()
+    }
+
+    async fn search_series(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let mut filter = Filter::new(Dialect::Sqlite);
+        if let Some(study_uid) = study_uid {
+            filter.push_equals("se.study_instance_uid", study_uid);
+        }
+        filter.push_matches(Level::Series, &series_matches(query), false);
+
+        let visible = visible("ci", query.includerejected);
+        let sql = format!(
+            "SELECT se.attributes,
+                 (SELECT COUNT(*) FROM instances ci
+                     WHERE ci.series_instance_uid = se.series_instance_uid{visible})
+             FROM series se
+             JOIN studies st ON st.study_instance_uid = se.study_instance_uid
+             WHERE EXISTS (SELECT 1 FROM instances ci
+                 WHERE ci.series_instance_uid = se.series_instance_uid{visible}){}
+             ORDER BY se.series_instance_uid{}",
+            filter.sql(),
+            pagination(Dialect::Sqlite, query.limit, query.offset),
+        );
+
+        let rows: Vec<(String, usize)> = {
+            let connection = self.connection()?;
+            let mut statement = connection.prepare(&sql)?;
+            let rows = statement
+                .query_map(params_from_iter(&filter.params), |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?
+                .collect::<Result<_, _>>()?;
+            rows
+        };
+
+        rows.into_iter()
+            .map(|(json, instances)| {
+                let mut series = parse_attributes(&json)?;
+                search::put_series_attributes(&mut series, instances);
+                Ok(search::series_result(
+                    &series,
+                    query,
+                    self.base_url.as_deref(),
+                ))
+            })
+            .collect()
+    }
+
+    async fn search_instances(
+        &self,
+        _principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        let mut filter = Filter::new(Dialect::Sqlite);
+        if let Some(study_uid) = study_uid {
+            filter.push_equals("i.study_instance_uid", study_uid);
+        }
+        if let Some(series_uid) = series_uid {
+            filter.push_equals("i.series_instance_uid", series_uid);
+        }
+        filter.push_matches(Level::Instance, &instance_matches(query), false);
+
+        let sql = format!(
+            "SELECT i.attributes FROM instances i
+             JOIN series se ON se.series_instance_uid = i.series_instance_uid
+             JOIN studies st ON st.study_instance_uid = se.study_instance_uid
+             WHERE 1 = 1{}{}
+             ORDER BY i.sop_instance_uid{}",
+            visible("i", query.includerejected),
+            filter.sql(),
+            pagination(Dialect::Sqlite, query.limit, query.offset),
+        );
+
+        let rows: Vec<String> = {
+            let connection = self.connection()?;
+            let mut statement = connection.prepare(&sql)?;
+            let rows = statement
+                .query_map(params_from_iter(&filter.params), |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            rows
+        };
+
+        rows.iter()
+            .map(|json| {
+                let instance = parse_attributes(json)?;
+                Ok(search::instance_result(
+                    &instance,
+                    query,
+                    self.base_url.as_deref(),
+                ))
+            })
+            .collect()
+    }
+
+    async fn retrieve_study(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.open_files("study_instance_uid = ?", &[study_uid])
+            .await
+    }
+
+    async fn retrieve_series(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.open_files(
+            "study_instance_uid = ? AND series_instance_uid = ?",
+            &[study_uid, series_uid],
+        )
+        .await
+    }
+
+    async fn retrieve_instance(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        self.open_files(
+            "study_instance_uid = ? AND series_instance_uid = ? AND sop_instance_uid = ?",
+            &[study_uid, series_uid, sop_instance_uid],
+        )
+        .await?
+        .pop()
+        .ok_or_else(|| NotFound.into())
+    }
+
+    async fn retrieve_frames(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+        frames: &[u32],
+    ) -> Result<Vec<Bytes>, BackendError> {
+        let (path, layout) = {
+            let connection = self.connection()?;
+            connection
+                .query_row(
+                    "SELECT path, frame_offset, frame_length, frame_count FROM instances
+                     WHERE NOT rejected AND study_instance_uid = ?1
+                         AND series_instance_uid = ?2 AND sop_instance_uid = ?3",
+                    params![study_uid, series_uid, sop_instance_uid],
+                    |row| {
+                        let path: String = row.get(0)?;
+                        let layout = match (row.get(1)?, row.get(2)?, row.get(3)?) {
+                            (Some(offset), Some(length), Some(count)) => Some(FrameLayout {
+                                offset,
+                                length,
+                                count,
+                            }),
+                            _ => None,
+                        };
+                        Ok((path, layout))
+                    },
+                )
+                .optional()?
+                .ok_or(NotFound)?
+        };
+
+        // Uncompressed frames are read by range, all others are decoded
+        match layout {
+            Some(layout) => blob::read_frames(self.blobs.as_ref(), &path, layout, frames).await,
+            None => instance_frames(
+                &blob::read_instance(self.blobs.as_ref(), &path).await?,
+                frames,
+            ),
+        }
+    }
+
+    async fn store_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        let mut stored = Vec::with_capacity(instances.len());
+        for instance in instances {
+            let path = instance_path(instance)?.to_string_lossy().into_owned();
+            let (data, layout) = blob::encode(instance)?;
+            self.blobs.put(&path, blob::chunks(data)).await?;
+            stored.push((instance, path, layout));
+        }
+
+        let replaced = {
+            let mut connection = self.connection()?;
+            let transaction = connection.transaction()?;
+            let mut replaced = Vec::new();
+            for (instance, path, layout) in stored {
+                replaced.extend(store_instance(&transaction, instance, &path, layout)?);
+            }
+            transaction.commit()?;
+            remove_orphans(&connection)?;
+            replaced
+        };
+
+        // The instance moved to another study or series
+        self.remove_blobs(replaced).await;
+        Ok(())
+    }
+
+    async fn reject_instances(
+        &self,
+        principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        // Instances past their retention period are deleted, all others are hidden
+        if note.reason == RejectionReason::DataRetentionPolicyExpired {
+            return self.delete_instances(principal, &note.instances).await;
+        }
+
+        let connection = self.connection()?;
+        for reference in &note.instances {
+            connection.execute(
+                "UPDATE instances SET rejected = 1 \
+                 WHERE sop_instance_uid = ?1 AND series_instance_uid = ?2 AND study_instance_uid = ?3",
+                params![
+                    reference.sop_instance_uid,
+                    reference.series_instance_uid,
+                    reference.study_instance_uid
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    async fn delete_instances(
+        &self,
+        _principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        let paths = {
+            let connection = self.connection()?;
+            let mut paths = Vec::new();
+            for reference in instances {
+                let path: Option<String> = connection
+                    .query_row(
+                        "DELETE FROM instances \
+                         WHERE sop_instance_uid = ?1 AND series_instance_uid = ?2 \
+                         AND study_instance_uid = ?3 RETURNING path",
+                        params![
+                            reference.sop_instance_uid,
+                            reference.series_instance_uid,
+                            reference.study_instance_uid
+                        ],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                paths.extend(path);
+            }
+            remove_orphans(&connection)?;
+            paths
+        };
+
+        self.remove_blobs(paths).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{DataElement, VR},
+        dictionary_std::uids,
+    };
+
+    use super::*;
+    use crate::testing;
+
+    const DOE_STUDY_UID: &str = "1.2.840.10008.33.1";
+    const ROE_STUDY_UID: &str = "1.2.840.10008.33.2";
+
+    /// Trauma CT of an emergency patient, who wasn't registered yet and has no Patient ID
+    fn trauma_ct(study_uid: &str, patient_name: &str) -> FileDicomObject<InMemDicomObject> {
+        let series_uid = format!("{}.1", study_uid);
+        let sop_uid = format!("{}.1.1", study_uid);
+        testing::file(InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SOP_CLASS_UID, VR::UI, uids::CT_IMAGE_STORAGE),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, sop_uid.as_str()),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, study_uid),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, series_uid.as_str()),
+            DataElement::new(tags::PATIENT_ID, VR::LO, ""),
+            DataElement::new(tags::PATIENT_NAME, VR::PN, patient_name),
+            DataElement::new(tags::MODALITY, VR::CS, "CT"),
+        ]))
+    }
+
+    #[tokio::test]
+    async fn keeps_the_patients_of_studies_without_patient_id() {
+        let root = std::env::temp_dir().join(format!("dicomweb-sqlite-{}", uuid::Uuid::new_v4()));
+        let backend = SqliteBackend::open(":memory:", &root).unwrap();
+        let first = trauma_ct(DOE_STUDY_UID, "Doe^John");
+        let second = trauma_ct(ROE_STUDY_UID, "Roe^Jane");
+        backend
+            .store_instances(&testing::principal(), &[first, second])
+            .await
+            .unwrap();
+
+        let query = QidoStudyQuery {
+            matches: vec![(tags::PATIENT_NAME, "Doe^John".to_string())],
+            ..QidoStudyQuery::default()
+        };
+        let studies = backend
+            .search_study(&testing::principal(), &query)
+            .await
+            .unwrap();
+        assert_eq!(studies.len(), 1);
+        assert_eq!(
+            text(&studies[0], tags::STUDY_INSTANCE_UID).as_deref(),
+            Some(DOE_STUDY_UID)
+        );
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
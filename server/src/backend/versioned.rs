@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use dicom::dictionary_std::tags;
+use dicom_object::{FileDicomObject, InMemDicomObject};
+use uuid::Uuid;
+
+use super::{search, BackendError, DicomWebBackend};
+use crate::{
+    auth::Principal, InstanceReference, QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery,
+    RejectionNote,
+};
+
+/// Version of a study, or of a series and its instances
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceVersion {
+    /// Opaque tag, which changes whenever the content changes
+    pub tag: String,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy)]
+struct Generation {
+    number: u64,
+    modified: DateTime<Utc>,
+}
+
+/// Versions the studies and series by the stores, rejections and deletions passing through it.
+///
+/// Every change of a series bumps the version of the series and of its study, so the
+/// WADO-RS endpoints answer conditional requests without retrieving the instances.
+/// Only use it for backends which are exclusively written through this server, and wrap
+/// it into an [`AuthorizedBackend`](super::AuthorizedBackend), so the versions are only
+/// revealed to authorized principals.
+pub struct VersionedBackend<B> {
+    backend: B,
+    /// Distinguishes the versions from the ones of an earlier run
+    epoch: Uuid,
+    /// Last modification of the resources, which weren't changed since the start
+    started: DateTime<Utc>,
+    generation: AtomicU64,
+    /// Generations by study and optional series
+    generations: RwLock<HashMap<(String, Option<String>), Generation>>,
+}
+
+impl<B: DicomWebBackend> VersionedBackend<B> {
+    pub fn new(backend: B) -> VersionedBackend<B> {
+        VersionedBackend {
+            backend,
+            epoch: Uuid::new_v4(),
+            started: Utc::now(),
+            generation: AtomicU64::new(0),
+            generations: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Bump the versions of the given series and their studies
+    fn bump<'a>(&self, series: impl IntoIterator<Item = (&'a str, &'a str)>) {
+        let generation = Generation {
+            number: self.generation.fetch_add(1, Ordering::Relaxed) + 1,
+            modified: Utc::now(),
+        };
+        let mut generations = self.generations.write().unwrap_or_else(|e| e.into_inner());
+        for (study_uid, series_uid) in series {
+            generations.insert((study_uid.to_string(), None), generation);
+            generations.insert(
+                (study_uid.to_string(), Some(series_uid.to_string())),
+                generation,
+            );
+        }
+    }
+
+    /// Bump the versions of the series of the given instances
+    fn bump_instances(&self, instances: &[FileDicomObject<InMemDicomObject>]) {
+        let series: Vec<(String, String)> = instances
+            .iter()
+            .filter_map(|instance| {
+                Some((
+                    search::uid(instance, tags::STUDY_INSTANCE_UID)?,
+                    search::uid(instance, tags::SERIES_INSTANCE_UID)?,
+                ))
+            })
+            .collect();
+        self.bump(
+            series
+                .iter()
+                .map(|(study, series)| (study.as_str(), series.as_str())),
+        );
+    }
+}
+
+#[async_trait]
+impl<B: DicomWebBackend> DicomWebBackend for VersionedBackend<B> {
+    async fn search_study(
+        &self,
+        principal: &Principal,
+        query: &QidoStudyQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.backend.search_study(principal, query).await
+    }
+
+    async fn search_series(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        query: &QidoSeriesQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.backend
+            .search_series(principal, study_uid, query)
+            .await
+    }
+
+    async fn search_instances(
+        &self,
+        principal: &Principal,
+        study_uid: Option<&str>,
+        series_uid: Option<&str>,
+        query: &QidoInstanceQuery,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.backend
+            .search_instances(principal, study_uid, series_uid, query)
+            .await
+    }
+
+    async fn retrieve_study(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.backend.retrieve_study(principal, study_uid).await
+    }
+
+    async fn retrieve_series(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<FileDicomObject<InMemDicomObject>>, BackendError> {
+        self.backend
+            .retrieve_series(principal, study_uid, series_uid)
+            .await
+    }
+
+    async fn retrieve_series_metadata(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+    ) -> Result<Vec<InMemDicomObject>, BackendError> {
+        self.backend
+            .retrieve_series_metadata(principal, study_uid, series_uid)
+            .await
+    }
+
+    async fn retrieve_instance(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+        self.backend
+            .retrieve_instance(principal, study_uid, series_uid, sop_instance_uid)
+            .await
+    }
+
+    async fn retrieve_frames(
+        &self,
+        principal: &Principal,
+        study_uid: &str,
+        series_uid: &str,
+        sop_instance_uid: &str,
+        frames: &[u32],
+    ) -> Result<Vec<Bytes>, BackendError> {
+        self.backend
+            .retrieve_frames(principal, study_uid, series_uid, sop_instance_uid, frames)
+            .await
+    }
+
+    async fn version(
+        &self,
+        _principal: &Principal,
+        study_uid: &str,
+        series_uid: Option<&str>,
+    ) -> Result<Option<ResourceVersion>, BackendError> {
+        let key = (study_uid.to_string(), series_uid.map(str::to_string));
+        let generation = self
+            .generations
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key)
+            .copied()
+            .unwrap_or(Generation {
+                number: 0,
+                modified: self.started,
+            });
+        Ok(Some(ResourceVersion {
+            tag: format!("{}-{}", self.epoch.simple(), generation.number),
+            last_modified: Some(generation.modified),
+        }))
+    }
+
+    async fn store_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        let result = self.backend.store_instances(principal, instances).await;
+        // Some instances may have been stored, even if others failed
+        self.bump_instances(instances);
+        result
+    }
+
+    async fn update_instances(
+        &self,
+        principal: &Principal,
+        instances: &[FileDicomObject<InMemDicomObject>],
+    ) -> Result<(), BackendError> {
+        let result = self.backend.update_instances(principal, instances).await;
+        self.bump_instances(instances);
+        result
+    }
+
+    async fn reject_instances(
+        &self,
+        principal: &Principal,
+        note: &RejectionNote,
+    ) -> Result<(), BackendError> {
+        let result = self.backend.reject_instances(principal, note).await;
+        self.bump(note.instances.iter().map(|instance| {
+            (
+                instance.study_instance_uid.as_str(),
+                instance.series_instance_uid.as_str(),
+            )
+        }));
+        result
+    }
+
+    async fn delete_instances(
+        &self,
+        principal: &Principal,
+        instances: &[InstanceReference],
+    ) -> Result<(), BackendError> {
+        let result = self.backend.delete_instances(principal, instances).await;
+        self.bump(instances.iter().map(|instance| {
+            (
+                instance.study_instance_uid.as_str(),
+                instance.series_instance_uid.as_str(),
+            )
+        }));
+        result
+    }
+}
@@ -0,0 +1,386 @@
+//! Server-side cache of frames and series metadata
+//!
+//! [`FrameCache`] keeps the instances as stored, their decoded frames and the metadata
+//! of whole series in a bounded, size-aware LRU cache. Entries evicted from memory can
+//! spill to a local disk cache directory, which is bounded as well. Use it with
+//! [`crate::backend::CachedBackend`], which fills the cache and invalidates the entries
+//! of the stored, rejected and deleted instances.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use bytes::Bytes;
+use uuid::Uuid;
+
+/// Kind of the cached entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheKind {
+    /// Instances as stored, the frames missing from the decoded ones are decoded from them
+    /// without a retrieval
+    EncodedFrames,
+    /// Frames with decoded, native pixel data
+    DecodedFrames,
+    /// DICOM JSON metadata of the instances of a series
+    SeriesMetadata,
+}
+
+/// Hits and misses of a kind of entries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HitStats {
+    pub hits: u64,
+    /// Hits, which were read back from the disk cache directory
+    pub disk_hits: u64,
+    pub misses: u64,
+}
+
+/// Statistics of a [`FrameCache`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub encoded_frames: HitStats,
+    pub decoded_frames: HitStats,
+    pub series_metadata: HitStats,
+    /// Entries evicted from memory, including the ones spilled to disk
+    pub evictions: u64,
+    pub memory_bytes: usize,
+    pub disk_bytes: usize,
+}
+
+impl CacheStats {
+    fn hit_stats(&mut self, kind: CacheKind) -> &mut HitStats {
+        match kind {
+            CacheKind::EncodedFrames => &mut self.encoded_frames,
+            CacheKind::DecodedFrames => &mut self.decoded_frames,
+            CacheKind::SeriesMetadata => &mut self.series_metadata,
+        }
+    }
+}
+
+type Key = (CacheKind, String);
+
+/// Least recently used entries, bounded by their total size
+struct Lru<V> {
+    entries: HashMap<Key, (V, usize, u64)>,
+    /// Keys by their last use
+    order: BTreeMap<u64, Key>,
+    size: usize,
+    capacity: usize,
+    clock: u64,
+}
+
+impl<V> Lru<V> {
+    fn new(capacity: usize) -> Lru<V> {
+        Lru {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            size: 0,
+            capacity,
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, key: &Key) -> Option<&V> {
+        let (_, _, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.order.insert(self.clock, key.clone());
+        self.entries.get(key).map(|(value, _, _)| value)
+    }
+
+    /// Insert an entry and return the evicted ones, entries larger than the capacity
+    /// are evicted right away
+    fn insert(&mut self, key: Key, value: V, size: usize) -> Vec<(Key, V)> {
+        self.remove(&key);
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        self.entries.insert(key, (value, size, self.clock));
+        self.size += size;
+
+        let mut evicted = Vec::new();
+        while self.size > self.capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((value, size, _)) = self.entries.remove(&key) {
+                self.size -= size;
+                evicted.push((key, value));
+            }
+        }
+        evicted
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<V> {
+        let (value, size, used) = self.entries.remove(key)?;
+        self.order.remove(&used);
+        self.size -= size;
+        Some(value)
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &Key> {
+        self.entries.keys()
+    }
+}
+
+/// Entries spilled to files below a directory, which only lives as long as the cache
+struct DiskCache {
+    dir: PathBuf,
+    files: Lru<PathBuf>,
+}
+
+impl DiskCache {
+    /// New file for a spilled entry, every spill gets its own, so a file being read or
+    /// written outside of the lock is never reused
+    fn path(&self) -> PathBuf {
+        self.dir.join(Uuid::new_v4().simple().to_string())
+    }
+}
+
+impl Drop for DiskCache {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            log::warn!(
+                "Failed to remove cache directory {}: {}",
+                self.dir.display(),
+                e
+            );
+        }
+    }
+}
+
+fn remove_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        log::warn!("Failed to remove cached file {}: {}", path.display(), e);
+    }
+}
+
+struct State {
+    memory: Lru<Bytes>,
+    disk: Option<DiskCache>,
+    stats: CacheStats,
+    /// Counts the invalidations, so spills racing with them are dropped
+    invalidations: u64,
+}
+
+/// Entries evicted from memory, to be written to disk outside of the lock
+struct Spill {
+    entries: Vec<(Key, Bytes, PathBuf)>,
+    invalidations: u64,
+}
+
+/// Bounded cache of frames and series metadata, cheap to clone and share
+#[derive(Clone)]
+pub struct FrameCache {
+    state: Arc<Mutex<State>>,
+}
+
+impl FrameCache {
+    /// Cache up to `capacity` bytes in memory
+    pub fn new(capacity: usize) -> FrameCache {
+        FrameCache {
+            state: Arc::new(Mutex::new(State {
+                memory: Lru::new(capacity),
+                disk: None,
+                stats: CacheStats::default(),
+                invalidations: 0,
+            })),
+        }
+    }
+
+    /// Spill up to `capacity` bytes of evicted entries to a new directory below `dir`,
+    /// which is removed along with the cache
+    pub fn with_disk_cache(self, dir: impl AsRef<Path>, capacity: usize) -> io::Result<FrameCache> {
+        let dir = dir.as_ref().join(Uuid::new_v4().simple().to_string());
+        fs::create_dir_all(&dir)?;
+        self.lock().disk = Some(DiskCache {
+            dir,
+            files: Lru::new(capacity),
+        });
+        Ok(self)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.lock();
+        CacheStats {
+            memory_bytes: state.memory.size,
+            disk_bytes: state.disk.as_ref().map_or(0, |disk| disk.files.size),
+            ..state.stats
+        }
+    }
+
+    pub fn get(&self, kind: CacheKind, id: &str) -> Option<Bytes> {
+        let key = (kind, id.to_string());
+        let path = {
+            let mut state = self.lock();
+            if let Some(value) = state.memory.get(&key).cloned() {
+                state.stats.hit_stats(kind).hits += 1;
+                return Some(value);
+            }
+            state.disk.as_mut().and_then(|disk| disk.files.remove(&key))
+        };
+
+        // Entries read back from disk move to memory again, the files are only touched
+        // outside of the lock
+        let value = path.and_then(|path| {
+            let value = fs::read(&path);
+            remove_file(&path);
+            value.ok().map(Bytes::from)
+        });
+        let mut state = self.lock();
+        let stats = state.stats.hit_stats(kind);
+        let Some(value) = value else {
+            stats.misses += 1;
+            return None;
+        };
+        stats.hits += 1;
+        stats.disk_hits += 1;
+        let spill = state.insert(key, value.clone());
+        drop(state);
+        self.spill(spill);
+        Some(value)
+    }
+
+    pub fn insert(&self, kind: CacheKind, id: &str, value: Bytes) {
+        let key = (kind, id.to_string());
+        let (spill, stale) = {
+            let mut state = self.lock();
+            let stale = state.disk.as_mut().and_then(|disk| disk.files.remove(&key));
+            (state.insert(key, value), stale)
+        };
+        if let Some(path) = stale {
+            remove_file(&path);
+        }
+        self.spill(spill);
+    }
+
+    /// Remove an entry and, with `prefix`, all entries whose id starts with it
+    pub fn invalidate(&self, kind: CacheKind, id: &str, prefix: Option<&str>) {
+        let matches = |key: &Key| {
+            key.0 == kind && (key.1 == id || prefix.is_some_and(|prefix| key.1.starts_with(prefix)))
+        };
+        let mut paths = Vec::new();
+        {
+            let mut state = self.lock();
+            state.invalidations += 1;
+            let keys: Vec<Key> = state
+                .memory
+                .keys()
+                .filter(|key| matches(key))
+                .cloned()
+                .collect();
+            for key in &keys {
+                state.memory.remove(key);
+            }
+            if let Some(disk) = state.disk.as_mut() {
+                let keys: Vec<Key> = disk
+                    .files
+                    .keys()
+                    .filter(|key| matches(key))
+                    .cloned()
+                    .collect();
+                paths.extend(keys.iter().filter_map(|key| disk.files.remove(key)));
+            }
+        }
+        for path in &paths {
+            remove_file(path);
+        }
+    }
+
+    /// Write the evicted entries to disk, and register them unless the cache was
+    /// invalidated meanwhile
+    fn spill(&self, spill: Spill) {
+        if spill.entries.is_empty() {
+            return;
+        }
+        let mut written = Vec::with_capacity(spill.entries.len());
+        for (key, value, path) in spill.entries {
+            match fs::write(&path, &value) {
+                Ok(()) => written.push((key, value.len(), path)),
+                Err(e) => {
+                    log::warn!("Failed to spill cached entry to {}: {}", path.display(), e)
+                }
+            }
+        }
+
+        let mut removed = Vec::new();
+        {
+            let mut state = self.lock();
+            let state = &mut *state;
+            let invalidated = state.invalidations != spill.invalidations;
+            match state.disk.as_mut() {
+                Some(disk) if !invalidated => {
+                    for (key, size, path) in written {
+                        // A newer value may have been inserted meanwhile
+                        if state.memory.entries.contains_key(&key) {
+                            removed.push(path);
+                            continue;
+                        }
+                        let evicted = disk.files.insert(key, path, size);
+                        removed.extend(evicted.into_iter().map(|(_, path)| path));
+                    }
+                }
+                _ => removed.extend(written.into_iter().map(|(_, _, path)| path)),
+            }
+        }
+        for path in &removed {
+            remove_file(path);
+        }
+    }
+}
+
+impl State {
+    /// Insert into memory and return the evicted entries to spill to disk
+    fn insert(&mut self, key: Key, value: Bytes) -> Spill {
+        let size = value.len();
+        let evicted = self.memory.insert(key, value, size);
+        self.stats.evictions += evicted.len() as u64;
+        let entries = match self.disk.as_ref() {
+            Some(disk) => evicted
+                .into_iter()
+                .map(|(key, value)| {
+                    let path = disk.path();
+                    (key, value, path)
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        Spill {
+            entries,
+            invalidations: self.invalidations,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spills_evicted_entries_to_disk() {
+        let dir = std::env::temp_dir().join("dicomweb-cache-test");
+        let cache = FrameCache::new(4).with_disk_cache(&dir, 16).unwrap();
+        cache.insert(CacheKind::DecodedFrames, "a", Bytes::from_static(b"aaaa"));
+        cache.insert(CacheKind::DecodedFrames, "b", Bytes::from_static(b"bbbb"));
+        assert_eq!(cache.stats().disk_bytes, 4);
+
+        assert_eq!(
+            cache.get(CacheKind::DecodedFrames, "a"),
+            Some(Bytes::from_static(b"aaaa"))
+        );
+        let stats = cache.stats();
+        assert_eq!(stats.decoded_frames.disk_hits, 1);
+        // Reading "a" back evicted "b" to disk
+        assert_eq!(stats.disk_bytes, 4);
+
+        cache.invalidate(CacheKind::DecodedFrames, "b", None);
+        assert_eq!(cache.get(CacheKind::DecodedFrames, "b"), None);
+        assert_eq!(cache.stats().disk_bytes, 0);
+    }
+}
@@ -0,0 +1,794 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Range,
+    sync::Mutex,
+};
+
+use dicom::{
+    core::{value::DataSetSequence, DataElement, PrimitiveValue, VR},
+    dictionary_std::tags,
+};
+use dicom_object::{FileDicomObject, InMemDicomObject, Tag};
+use uuid::Uuid;
+
+use crate::{
+    backend::BackendError,
+    redaction::{self, Rectangle, RedactionRule},
+};
+
+/// Options of the Basic Application Level Confidentiality Profile, see PS3.15 E.3
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeidentificationOption {
+    /// Keep descriptions, with the names and IDs of the patient removed from them
+    CleanDescriptors,
+    /// Keep the dates and times of the study, series and acquisition
+    RetainLongitudinalFullDates,
+    /// Keep age, sex, size, weight and other characteristics of the patient
+    RetainPatientCharacteristics,
+    /// Keep the station name and serial number of the device
+    RetainDeviceIdentity,
+    /// Keep the name, address and department of the institution
+    RetainInstitutionIdentity,
+    /// Keep the original UIDs instead of replacing them
+    RetainUids,
+}
+
+impl DeidentificationOption {
+    fn code(self) -> (&'static str, &'static str) {
+        match self {
+            DeidentificationOption::CleanDescriptors => ("113105", "Clean Descriptors Option"),
+            DeidentificationOption::RetainLongitudinalFullDates => (
+                "113106",
+                "Retain Longitudinal Temporal Information Full Dates Option",
+            ),
+            DeidentificationOption::RetainPatientCharacteristics => {
+                ("113108", "Retain Patient Characteristics Option")
+            }
+            DeidentificationOption::RetainDeviceIdentity => {
+                ("113109", "Retain Device Identity Option")
+            }
+            DeidentificationOption::RetainInstitutionIdentity => {
+                ("113112", "Retain Institution Identity Option")
+            }
+            DeidentificationOption::RetainUids => ("113110", "Retain UIDs Option"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Remove,
+    /// Replace with a zero length value
+    Empty,
+    /// Replace with a pseudonym, which is the same for every instance of the patient
+    Pseudonym,
+    /// Replace with a UID, which is the same for every occurrence of the original
+    Uid,
+    /// Remove the names and IDs of the patient from the text
+    Clean,
+}
+
+use Action::*;
+use DeidentificationOption::*;
+
+// Attributes of the profile, with the option which retains them
+// See https://dicom.nema.org/medical/dicom/current/output/html/part15.html#table_E.1-1
+// Retired attributes are listed as well, as older instances may still contain them.
+// Attributes which are neither listed here nor known to be safe are removed.
+#[allow(deprecated)]
+const PROFILE: [(Tag, Action, Option<DeidentificationOption>); 104] = [
+    (tags::INSTANCE_CREATOR_UID, Uid, Some(RetainUids)),
+    (tags::SOP_INSTANCE_UID, Uid, Some(RetainUids)),
+    (tags::STUDY_DATE, Empty, Some(RetainLongitudinalFullDates)),
+    (tags::SERIES_DATE, Remove, Some(RetainLongitudinalFullDates)),
+    (
+        tags::ACQUISITION_DATE,
+        Remove,
+        Some(RetainLongitudinalFullDates),
+    ),
+    (tags::CONTENT_DATE, Empty, Some(RetainLongitudinalFullDates)),
+    (
+        tags::ACQUISITION_DATE_TIME,
+        Remove,
+        Some(RetainLongitudinalFullDates),
+    ),
+    (tags::STUDY_TIME, Empty, Some(RetainLongitudinalFullDates)),
+    (tags::SERIES_TIME, Remove, Some(RetainLongitudinalFullDates)),
+    (
+        tags::ACQUISITION_TIME,
+        Remove,
+        Some(RetainLongitudinalFullDates),
+    ),
+    (tags::CONTENT_TIME, Empty, Some(RetainLongitudinalFullDates)),
+    (tags::ACCESSION_NUMBER, Empty, None),
+    (
+        tags::INSTITUTION_NAME,
+        Remove,
+        Some(RetainInstitutionIdentity),
+    ),
+    (
+        tags::INSTITUTION_ADDRESS,
+        Remove,
+        Some(RetainInstitutionIdentity),
+    ),
+    (tags::REFERRING_PHYSICIAN_NAME, Empty, None),
+    (tags::REFERRING_PHYSICIAN_ADDRESS, Remove, None),
+    (tags::REFERRING_PHYSICIAN_TELEPHONE_NUMBERS, Remove, None),
+    (
+        tags::TIMEZONE_OFFSET_FROM_UTC,
+        Remove,
+        Some(RetainLongitudinalFullDates),
+    ),
+    (tags::STATION_NAME, Remove, Some(RetainDeviceIdentity)),
+    (tags::STUDY_DESCRIPTION, Remove, Some(CleanDescriptors)),
+    (tags::SERIES_DESCRIPTION, Remove, Some(CleanDescriptors)),
+    (
+        tags::INSTITUTIONAL_DEPARTMENT_NAME,
+        Remove,
+        Some(RetainInstitutionIdentity),
+    ),
+    (tags::PHYSICIANS_OF_RECORD, Remove, None),
+    (tags::PERFORMING_PHYSICIAN_NAME, Remove, None),
+    (tags::NAME_OF_PHYSICIANS_READING_STUDY, Remove, None),
+    (tags::OPERATORS_NAME, Remove, None),
+    (
+        tags::ADMITTING_DIAGNOSES_DESCRIPTION,
+        Remove,
+        Some(CleanDescriptors),
+    ),
+    (tags::REFERENCED_STUDY_SEQUENCE, Remove, None),
+    (
+        tags::REFERENCED_PERFORMED_PROCEDURE_STEP_SEQUENCE,
+        Remove,
+        None,
+    ),
+    (tags::REFERENCED_PATIENT_SEQUENCE, Remove, None),
+    (tags::REFERENCED_SOP_INSTANCE_UID, Uid, Some(RetainUids)),
+    (tags::DERIVATION_DESCRIPTION, Remove, Some(CleanDescriptors)),
+    (tags::IRRADIATION_EVENT_UID, Uid, Some(RetainUids)),
+    (tags::PATIENT_NAME, Pseudonym, None),
+    (tags::PATIENT_ID, Pseudonym, None),
+    (tags::ISSUER_OF_PATIENT_ID, Remove, None),
+    (tags::PATIENT_BIRTH_DATE, Empty, None),
+    (tags::PATIENT_BIRTH_TIME, Remove, None),
+    (tags::PATIENT_SEX, Empty, Some(RetainPatientCharacteristics)),
+    (tags::PATIENT_INSURANCE_PLAN_CODE_SEQUENCE, Remove, None),
+    (tags::OTHER_PATIENT_I_DS, Remove, None),
+    (tags::OTHER_PATIENT_NAMES, Remove, None),
+    (tags::OTHER_PATIENT_I_DS_SEQUENCE, Remove, None),
+    (tags::PATIENT_BIRTH_NAME, Remove, None),
+    (
+        tags::PATIENT_AGE,
+        Remove,
+        Some(RetainPatientCharacteristics),
+    ),
+    (
+        tags::PATIENT_SIZE,
+        Remove,
+        Some(RetainPatientCharacteristics),
+    ),
+    (
+        tags::PATIENT_WEIGHT,
+        Remove,
+        Some(RetainPatientCharacteristics),
+    ),
+    (tags::PATIENT_ADDRESS, Remove, None),
+    (tags::PATIENT_MOTHER_BIRTH_NAME, Remove, None),
+    (tags::MILITARY_RANK, Remove, None),
+    (tags::BRANCH_OF_SERVICE, Remove, None),
+    (tags::MEDICAL_RECORD_LOCATOR, Remove, None),
+    (tags::MEDICAL_ALERTS, Remove, None),
+    (tags::ALLERGIES, Remove, None),
+    (tags::COUNTRY_OF_RESIDENCE, Remove, None),
+    (tags::PATIENT_TELEPHONE_NUMBERS, Remove, None),
+    (
+        tags::ETHNIC_GROUP,
+        Remove,
+        Some(RetainPatientCharacteristics),
+    ),
+    (tags::OCCUPATION, Remove, None),
+    (
+        tags::SMOKING_STATUS,
+        Remove,
+        Some(RetainPatientCharacteristics),
+    ),
+    (tags::ADDITIONAL_PATIENT_HISTORY, Remove, None),
+    (
+        tags::PREGNANCY_STATUS,
+        Remove,
+        Some(RetainPatientCharacteristics),
+    ),
+    (tags::PATIENT_RELIGIOUS_PREFERENCE, Remove, None),
+    (tags::PATIENT_COMMENTS, Remove, None),
+    (tags::CONTRAST_BOLUS_AGENT, Empty, None),
+    (
+        tags::DEVICE_SERIAL_NUMBER,
+        Remove,
+        Some(RetainDeviceIdentity),
+    ),
+    (tags::DEVICE_UID, Uid, Some(RetainUids)),
+    (tags::PROTOCOL_NAME, Remove, Some(CleanDescriptors)),
+    (
+        tags::ACQUISITION_DEVICE_PROCESSING_DESCRIPTION,
+        Remove,
+        Some(CleanDescriptors),
+    ),
+    (tags::ACQUISITION_COMMENTS, Remove, Some(CleanDescriptors)),
+    (tags::STUDY_INSTANCE_UID, Uid, Some(RetainUids)),
+    (tags::SERIES_INSTANCE_UID, Uid, Some(RetainUids)),
+    (tags::STUDY_ID, Empty, None),
+    (tags::FRAME_OF_REFERENCE_UID, Uid, Some(RetainUids)),
+    (
+        tags::SYNCHRONIZATION_FRAME_OF_REFERENCE_UID,
+        Uid,
+        Some(RetainUids),
+    ),
+    (tags::IMAGE_COMMENTS, Remove, Some(CleanDescriptors)),
+    (tags::REQUESTING_PHYSICIAN, Remove, None),
+    (
+        tags::REQUESTED_PROCEDURE_DESCRIPTION,
+        Remove,
+        Some(CleanDescriptors),
+    ),
+    (tags::ADMISSION_ID, Remove, None),
+    (tags::ADMITTING_DATE, Remove, None),
+    (tags::ADMITTING_TIME, Remove, None),
+    (tags::CURRENT_PATIENT_LOCATION, Remove, None),
+    (tags::PATIENT_STATE, Remove, None),
+    (
+        tags::PERFORMED_PROCEDURE_STEP_START_DATE,
+        Remove,
+        Some(RetainLongitudinalFullDates),
+    ),
+    (
+        tags::PERFORMED_PROCEDURE_STEP_START_TIME,
+        Remove,
+        Some(RetainLongitudinalFullDates),
+    ),
+    (tags::PERFORMED_PROCEDURE_STEP_ID, Remove, None),
+    (
+        tags::PERFORMED_PROCEDURE_STEP_DESCRIPTION,
+        Remove,
+        Some(CleanDescriptors),
+    ),
+    (tags::ACTUAL_HUMAN_PERFORMERS_SEQUENCE, Remove, None),
+    (tags::REQUEST_ATTRIBUTES_SEQUENCE, Remove, None),
+    (tags::REQUESTED_PROCEDURE_ID, Remove, None),
+    (tags::CONTENT_CREATOR_NAME, Empty, None),
+    (tags::STORAGE_MEDIA_FILE_SET_UID, Uid, Some(RetainUids)),
+    (tags::TEXT_COMMENTS, Remove, None),
+    (
+        tags::INSTANCE_CREATION_DATE,
+        Remove,
+        Some(RetainLongitudinalFullDates),
+    ),
+    (
+        tags::INSTANCE_CREATION_TIME,
+        Remove,
+        Some(RetainLongitudinalFullDates),
+    ),
+    (
+        tags::REFERRING_PHYSICIAN_IDENTIFICATION_SEQUENCE,
+        Remove,
+        None,
+    ),
+    (tags::REASON_FOR_STUDY, Remove, Some(CleanDescriptors)),
+    (
+        tags::REASON_FOR_THE_REQUESTED_PROCEDURE,
+        Remove,
+        Some(CleanDescriptors),
+    ),
+    (
+        tags::PLACER_ORDER_NUMBER_IMAGING_SERVICE_REQUEST,
+        Empty,
+        None,
+    ),
+    (
+        tags::FILLER_ORDER_NUMBER_IMAGING_SERVICE_REQUEST,
+        Empty,
+        None,
+    ),
+    (tags::PERSON_NAME, Empty, None),
+    (tags::VERIFYING_OBSERVER_NAME, Empty, None),
+    (tags::VERIFYING_ORGANIZATION, Empty, None),
+    (tags::CONTENT_SEQUENCE, Remove, None),
+    (tags::PIXEL_DATA_PROVIDER_URL, Remove, None),
+];
+
+// Attributes which don't identify the patient and are kept, along with the image pixel
+// description and the pixel data. Any other attribute missing from the profile is removed,
+// so newer or private extensions of the standard never pass through.
+const SAFE: [Tag; 72] = [
+    // SOP common and general image
+    tags::SPECIFIC_CHARACTER_SET,
+    tags::IMAGE_TYPE,
+    tags::SOP_CLASS_UID,
+    tags::MODALITY,
+    tags::CONVERSION_TYPE,
+    tags::PRESENTATION_INTENT_TYPE,
+    tags::BURNED_IN_ANNOTATION,
+    tags::RECOGNIZABLE_VISUAL_FEATURES,
+    tags::LOSSY_IMAGE_COMPRESSION,
+    tags::LOSSY_IMAGE_COMPRESSION_RATIO,
+    tags::LOSSY_IMAGE_COMPRESSION_METHOD,
+    tags::PATIENT_ORIENTATION,
+    tags::ANATOMIC_REGION_SEQUENCE,
+    tags::CODE_VALUE,
+    tags::CODING_SCHEME_DESIGNATOR,
+    tags::CODE_MEANING,
+    // References to other instances, whose UIDs are replaced by the profile
+    tags::REFERENCED_IMAGE_SEQUENCE,
+    tags::SOURCE_IMAGE_SEQUENCE,
+    tags::REFERENCED_SOP_CLASS_UID,
+    tags::REFERENCED_FRAME_NUMBER,
+    // Equipment and acquisition
+    tags::MANUFACTURER,
+    tags::MANUFACTURER_MODEL_NAME,
+    tags::SOFTWARE_VERSIONS,
+    tags::BODY_PART_EXAMINED,
+    tags::SCANNING_SEQUENCE,
+    tags::SEQUENCE_VARIANT,
+    tags::SCAN_OPTIONS,
+    tags::MR_ACQUISITION_TYPE,
+    tags::SLICE_THICKNESS,
+    tags::KVP,
+    tags::SPACING_BETWEEN_SLICES,
+    tags::REPETITION_TIME,
+    tags::ECHO_TIME,
+    tags::INVERSION_TIME,
+    tags::MAGNETIC_FIELD_STRENGTH,
+    tags::ECHO_TRAIN_LENGTH,
+    tags::DATA_COLLECTION_DIAMETER,
+    tags::RECONSTRUCTION_DIAMETER,
+    tags::DISTANCE_SOURCE_TO_DETECTOR,
+    tags::DISTANCE_SOURCE_TO_PATIENT,
+    tags::GANTRY_DETECTOR_TILT,
+    tags::TABLE_HEIGHT,
+    tags::ROTATION_DIRECTION,
+    tags::EXPOSURE_TIME,
+    tags::X_RAY_TUBE_CURRENT,
+    tags::EXPOSURE,
+    tags::FILTER_TYPE,
+    tags::FOCAL_SPOTS,
+    tags::CONVOLUTION_KERNEL,
+    tags::FLIP_ANGLE,
+    tags::IMAGER_PIXEL_SPACING,
+    tags::PATIENT_POSITION,
+    tags::VIEW_POSITION,
+    // Relationship and image plane
+    tags::SERIES_NUMBER,
+    tags::ACQUISITION_NUMBER,
+    tags::INSTANCE_NUMBER,
+    tags::IMAGE_POSITION_PATIENT,
+    tags::IMAGE_ORIENTATION_PATIENT,
+    tags::POSITION_REFERENCE_INDICATOR,
+    tags::LATERALITY,
+    tags::IMAGE_LATERALITY,
+    tags::SLICE_LOCATION,
+    tags::IN_STACK_POSITION_NUMBER,
+    tags::TEMPORAL_POSITION_IDENTIFIER,
+    tags::NUMBER_OF_TEMPORAL_POSITIONS,
+    // Search results
+    tags::MODALITIES_IN_STUDY,
+    tags::SOP_CLASSES_IN_STUDY,
+    tags::INSTANCE_AVAILABILITY,
+    tags::NUMBER_OF_PATIENT_RELATED_STUDIES,
+    tags::NUMBER_OF_STUDY_RELATED_SERIES,
+    tags::NUMBER_OF_STUDY_RELATED_INSTANCES,
+    tags::NUMBER_OF_SERIES_RELATED_INSTANCES,
+];
+
+/// Replaced UIDs, which are remembered by default
+const UID_CAPACITY: usize = 100_000;
+
+/// De-identifies datasets with the Basic Application Level Confidentiality Profile of
+/// PS3.15 Annex E and the enabled options.
+///
+/// Attributes the profile doesn't list are only kept if they are known not to identify the
+/// patient, all others are removed. UIDs and patients are replaced by values derived from
+/// the key, so they are the same in every response and across restarts. The most recently
+/// replaced UIDs are remembered to map them back, older ones have to be looked up again.
+pub struct Deidentifier {
+    namespace: Uuid,
+    options: Vec<DeidentificationOption>,
+    redactions: Vec<RedactionRule>,
+    uids: Mutex<UidMap>,
+}
+
+/// Original UIDs by their replacement, the oldest ones are forgotten beyond the capacity
+struct UidMap {
+    originals: HashMap<String, String>,
+    replaced: VecDeque<String>,
+    capacity: usize,
+}
+
+impl UidMap {
+    fn insert(&mut self, replaced: String, original: &str) {
+        if self.originals.contains_key(&replaced) {
+            return;
+        }
+        while self.replaced.len() >= self.capacity {
+            let Some(oldest) = self.replaced.pop_front() else {
+                break;
+            };
+            self.originals.remove(&oldest);
+        }
+        if self.capacity > 0 {
+            self.originals
+                .insert(replaced.clone(), original.to_string());
+            self.replaced.push_back(replaced);
+        }
+    }
+}
+
+impl Deidentifier {
+    /// Derive the replacements from a secret key, which must be kept to stay consistent
+    pub fn new(key: impl AsRef<[u8]>) -> Deidentifier {
+        Deidentifier {
+            namespace: Uuid::new_v5(&Uuid::NAMESPACE_OID, key.as_ref()),
+            options: Vec::new(),
+            redactions: Vec::new(),
+            uids: Mutex::new(UidMap {
+                originals: HashMap::new(),
+                replaced: VecDeque::new(),
+                capacity: UID_CAPACITY,
+            }),
+        }
+    }
+
+    /// Remember up to `capacity` replaced UIDs, 100000 by default
+    pub fn with_uid_capacity(self, capacity: usize) -> Deidentifier {
+        if let Ok(mut uids) = self.uids.lock() {
+            uids.capacity = capacity;
+        }
+        self
+    }
+
+    pub fn with_option(mut self, option: DeidentificationOption) -> Deidentifier {
+        if !self.options.contains(&option) {
+            self.options.push(option);
+        }
+        self
+    }
+
+    /// Black out burned-in annotations in the pixel data of instances matching the rule
+    pub fn with_redaction(mut self, rule: RedactionRule) -> Deidentifier {
+        self.redactions.push(rule);
+        self
+    }
+
+    /// Whether the pixel data may change, so frames have to be taken from the instance
+    pub(crate) fn has_redactions(&self) -> bool {
+        !self.redactions.is_empty()
+    }
+
+    fn has_option(&self, option: DeidentificationOption) -> bool {
+        self.options.contains(&option)
+    }
+
+    /// Whether the attribute is returned unchanged, e.g. to decide if matching on it is safe
+    pub fn is_retained(&self, tag: Tag) -> bool {
+        if is_removed(tag) {
+            return false;
+        }
+        match PROFILE
+            .iter()
+            .find(|(profile_tag, _, _)| *profile_tag == tag)
+        {
+            Some((_, Uid, option)) | Some((_, Pseudonym, option)) => {
+                option.is_some_and(|option| self.has_option(option))
+            }
+            Some((_, _, option)) => {
+                option.is_some_and(|option| self.has_option(option) && option != CleanDescriptors)
+            }
+            None => is_safe(tag),
+        }
+    }
+
+    /// Replacement of a UID, "2.25." followed by a UUID derived from the original
+    pub fn replace_uid(&self, uid: &str) -> String {
+        if self.has_option(RetainUids) {
+            return uid.to_string();
+        }
+        let replaced = format!(
+            "2.25.{}",
+            Uuid::new_v5(&self.namespace, uid.as_bytes()).as_u128()
+        );
+        if let Ok(mut uids) = self.uids.lock() {
+            uids.insert(replaced.clone(), uid);
+        }
+        replaced
+    }
+
+    /// Original of a replaced UID, if it is still remembered
+    pub fn original_uid(&self, uid: &str) -> Option<String> {
+        if self.has_option(RetainUids) {
+            return Some(uid.to_string());
+        }
+        self.uids.lock().ok()?.originals.get(uid).cloned()
+    }
+
+    /// Pseudonym of a patient, used as its name and ID.
+    ///
+    /// Search results often lack the IssuerOfPatientID, so only the PatientID is used.
+    pub fn pseudonym(&self, patient_id: &str) -> String {
+        let name = format!("patient:{}", patient_id);
+        Uuid::new_v5(&self.namespace, name.as_bytes())
+            .simple()
+            .to_string()[..16]
+            .to_uppercase()
+    }
+
+    /// De-identify an instance including its file meta and, if a redaction rule matches,
+    /// its pixel data
+    pub fn deidentify_file(
+        &self,
+        file: &mut FileDicomObject<InMemDicomObject>,
+    ) -> Result<(), BackendError> {
+        let rectangles: Vec<Rectangle> = self
+            .redactions
+            .iter()
+            .filter(|rule| rule.matches(file))
+            .flat_map(|rule| rule.rectangles().iter().copied())
+            .collect();
+        if !rectangles.is_empty() {
+            redaction::redact(file, &rectangles)?;
+        }
+
+        self.deidentify(file);
+        let sop_instance_uid = file
+            .meta()
+            .media_storage_sop_instance_uid
+            .trim_end_matches('\0')
+            .to_string();
+        file.meta_mut().media_storage_sop_instance_uid = self.replace_uid(&sop_instance_uid);
+        file.meta_mut().update_information_group_length();
+        Ok(())
+    }
+
+    pub fn deidentify(&self, dcm: &mut InMemDicomObject) {
+        let patient_id = string_value(dcm, tags::PATIENT_ID).unwrap_or_default();
+        let pseudonym = self.pseudonym(&patient_id);
+
+        // Names and IDs, which are removed from descriptions
+        let mut identifiers: Vec<String> = [tags::PATIENT_ID, tags::ACCESSION_NUMBER]
+            .into_iter()
+            .filter_map(|tag| string_value(dcm, tag))
+            .collect();
+        for tag in [tags::PATIENT_NAME, tags::PATIENT_BIRTH_NAME] {
+            if let Some(name) = string_value(dcm, tag) {
+                identifiers.extend(
+                    name.split(['^', ' ', '='])
+                        .filter(|part| part.len() > 1)
+                        .map(str::to_string),
+                );
+            }
+        }
+
+        self.deidentify_dataset(dcm, &pseudonym, &identifiers);
+
+        dcm.put_str(tags::PATIENT_IDENTITY_REMOVED, VR::CS, "YES");
+        dcm.put_str(
+            tags::DEIDENTIFICATION_METHOD,
+            VR::LO,
+            "Basic Application Level Confidentiality Profile",
+        );
+        let codes = std::iter::once(("113100", "Basic Application Confidentiality Profile"))
+            .chain(self.options.iter().map(|option| option.code()))
+            .map(|(value, meaning)| {
+                InMemDicomObject::from_element_iter([
+                    DataElement::new(tags::CODE_VALUE, VR::SH, PrimitiveValue::from(value)),
+                    DataElement::new(
+                        tags::CODING_SCHEME_DESIGNATOR,
+                        VR::SH,
+                        PrimitiveValue::from("DCM"),
+                    ),
+                    DataElement::new(tags::CODE_MEANING, VR::LO, PrimitiveValue::from(meaning)),
+                ])
+            })
+            .collect::<Vec<_>>();
+        dcm.put(DataElement::new(
+            tags::DEIDENTIFICATION_METHOD_CODE_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::from(codes),
+        ));
+        let dates = if self.has_option(RetainLongitudinalFullDates) {
+            "UNMODIFIED"
+        } else {
+            "REMOVED"
+        };
+        dcm.put_str(
+            tags::LONGITUDINAL_TEMPORAL_INFORMATION_MODIFIED,
+            VR::CS,
+            dates,
+        );
+    }
+
+    fn deidentify_dataset(
+        &self,
+        dcm: &mut InMemDicomObject,
+        pseudonym: &str,
+        identifiers: &[String],
+    ) {
+        // Items may reference another patient than the one of the dataset
+        let pseudonym = match string_value(dcm, tags::PATIENT_ID) {
+            Some(patient_id) => self.pseudonym(&patient_id),
+            None => pseudonym.to_string(),
+        };
+        let tags: Vec<Tag> = dcm.tags().collect();
+        for tag in tags {
+            if is_removed(tag) {
+                dcm.remove_element(tag);
+                continue;
+            }
+
+            let action = match PROFILE
+                .iter()
+                .find(|(profile_tag, _, _)| *profile_tag == tag)
+            {
+                Some((_, action, option)) => match option {
+                    Some(CleanDescriptors) if self.has_option(CleanDescriptors) => Some(Clean),
+                    Some(option) if self.has_option(*option) => None,
+                    _ => Some(*action),
+                },
+                None if is_safe(tag) => None,
+                None => Some(Remove),
+            };
+            let Some(vr) = dcm.get(tag).map(|elt| elt.vr()) else {
+                continue;
+            };
+            match action {
+                Some(Remove) => {
+                    dcm.remove_element(tag);
+                }
+                Some(Empty) => {
+                    dcm.put(DataElement::new(tag, vr, PrimitiveValue::Empty));
+                }
+                Some(Pseudonym) => {
+                    dcm.put_str(tag, vr, pseudonym.as_str());
+                }
+                Some(Uid) => {
+                    if let Some(uid) = string_value(dcm, tag) {
+                        dcm.put_str(tag, vr, self.replace_uid(&uid));
+                    }
+                }
+                Some(Clean) => {
+                    if let Some(text) = string_value(dcm, tag) {
+                        dcm.put_str(tag, vr, clean(&text, identifiers));
+                    }
+                }
+                None if vr == VR::SQ => {
+                    // Items are de-identified like the dataset, e.g. referenced instances
+                    dcm.update_value(tag, |value| {
+                        if let Some(items) = value.items_mut() {
+                            for item in items.iter_mut() {
+                                self.deidentify_dataset(item, &pseudonym, identifiers);
+                            }
+                        }
+                    });
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// Private attributes, curves and overlay data and comments are removed regardless of the profile
+fn is_removed(tag: Tag) -> bool {
+    let overlay =
+        tag.group() & 0xFF00 == 0x6000 && (tag.element() == 0x3000 || tag.element() == 0x4000);
+    tag.group() % 2 == 1 || tag.group() & 0xFF00 == 0x5000 || overlay
+}
+
+/// Attributes which are kept, although the profile doesn't list them
+fn is_safe(tag: Tag) -> bool {
+    // Image pixel description and pixel data, except the ones listed by the profile
+    tag.group() == 0x0028 || tag.group() == 0x7FE0 || SAFE.contains(&tag)
+}
+
+fn string_value(dcm: &InMemDicomObject, tag: Tag) -> Option<String> {
+    dcm.get(tag)
+        .and_then(|elt| elt.to_str().ok())
+        .map(|value| value.trim_end_matches('\0').trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Remove the identifiers from a text, ignoring their case
+fn clean(text: &str, identifiers: &[String]) -> String {
+    let mut cleaned = text.to_string();
+    for identifier in identifiers {
+        while let Some(range) = find_ignore_case(&cleaned, identifier) {
+            cleaned.replace_range(range, "");
+        }
+    }
+    cleaned.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Byte range of the first occurrence of a pattern, comparing the characters by their
+/// lowercase forms. Lowercasing may change the length of a text, so the range is taken
+/// from the characters of the text itself and always lies on their boundaries.
+fn find_ignore_case(text: &str, pattern: &str) -> Option<Range<usize>> {
+    if pattern.is_empty() {
+        return None;
+    }
+    text.char_indices().find_map(|(start, _)| {
+        let mut chars = text[start..].chars();
+        let mut end = start;
+        for expected in pattern.chars() {
+            let c = chars.next()?;
+            if !c.to_lowercase().eq(expected.to_lowercase()) {
+                return None;
+            }
+            end += c.len_utf8();
+        }
+        Some(start..end)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::dictionary_std::uids;
+
+    use super::*;
+    use crate::testing;
+
+    /// MR slice as it leaves the hospital, with a report of the responsible person
+    fn identified_mr_slice() -> InMemDicomObject {
+        let mut dcm = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SOP_CLASS_UID, VR::UI, uids::MR_IMAGE_STORAGE),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, "1.2.840.10008.44.1.1.1"),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, "1.2.840.10008.44.1"),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, "1.2.840.10008.44.1.1"),
+            DataElement::new(tags::PATIENT_NAME, VR::PN, "Doe^Jane"),
+            DataElement::new(tags::PATIENT_ID, VR::LO, "MRN-1234"),
+            DataElement::new(tags::MODALITY, VR::CS, "MR"),
+            DataElement::new(tags::RESPONSIBLE_PERSON, VR::PN, "Doe^Jane"),
+            DataElement::new(tags::PERSON_NAME, VR::PN, "Doe^Jane"),
+            DataElement::new(
+                tags::CONTENT_SEQUENCE,
+                VR::SQ,
+                DataSetSequence::from(vec![InMemDicomObject::from_element_iter([
+                    DataElement::new(tags::TEXT_VALUE, VR::UT, PrimitiveValue::from("Doe")),
+                ])]),
+            ),
+        ]);
+        dcm.extend(testing::grayscale_frames(2, 2, 1));
+        dcm
+    }
+
+    #[test]
+    fn cleans_identifiers_on_character_boundaries() {
+        // The lowercase of İ is longer than İ itself
+        let identifiers = ["Doe".to_string(), "İnce".to_string()];
+        assert_eq!(clean("İ DOE scan", &identifiers), "İ scan");
+        assert_eq!(clean("Follow-up of İNCE", &identifiers), "Follow-up of");
+        assert_eq!(clean("Ärztin doe, 2nd", &identifiers), "Ärztin , 2nd");
+    }
+
+    #[test]
+    fn removes_attributes_missing_from_the_profile() {
+        let mut dcm = identified_mr_slice();
+
+        let deidentifier = Deidentifier::new("key");
+        deidentifier.deidentify(&mut dcm);
+        assert!(dcm.get(tags::RESPONSIBLE_PERSON).is_none());
+        assert!(dcm.get(tags::CONTENT_SEQUENCE).is_none());
+        assert_eq!(string_value(&dcm, tags::PERSON_NAME), None);
+        assert_eq!(string_value(&dcm, tags::MODALITY).as_deref(), Some("MR"));
+        assert_eq!(string_value(&dcm, tags::ROWS).as_deref(), Some("2"));
+        assert!(dcm.get(tags::PIXEL_DATA).is_some());
+        assert!(!deidentifier.is_retained(tags::RESPONSIBLE_PERSON));
+        assert!(deidentifier.is_retained(tags::MODALITY));
+    }
+
+    #[test]
+    fn forgets_the_oldest_uids_beyond_the_capacity() {
+        let deidentifier = Deidentifier::new("key").with_uid_capacity(1);
+        let first = deidentifier.replace_uid("1.2.3");
+        let second = deidentifier.replace_uid("1.2.4");
+        assert_eq!(deidentifier.original_uid(&first), None);
+        assert_eq!(deidentifier.original_uid(&second).as_deref(), Some("1.2.4"));
+        // The replacement doesn't depend on the remembered UIDs
+        assert_eq!(deidentifier.replace_uid("1.2.3"), first);
+    }
+}
@@ -0,0 +1,522 @@
+//! DICOM message exchange (DIMSE) on top of the upper layer of `dicom::ul`
+//!
+//! Only the services needed to bridge DICOMweb are implemented: C-ECHO, C-FIND,
+//! C-MOVE and C-STORE. The upper layer is blocking, so associations are handled
+//! on their own threads.
+//!
+//! [`StorageScp`] accepts instances pushed by modalities, the bridge to a DIMSE
+//! archive is [`crate::backend::DimseBackend`].
+
+use dicom::{
+    core::{DataElement, PrimitiveValue, VR},
+    dictionary_std::{tags, uids},
+    encoding::TransferSyntaxIndex,
+    transfer_syntax::TransferSyntaxRegistry,
+    ul::{
+        association::{client::ClientAssociation, server::ServerAssociation},
+        pdu::{PDataValue, PDataValueType, PresentationContextResult},
+        Pdu,
+    },
+};
+use dicom_object::{meta::FileMetaTableBuilder, FileDicomObject, InMemDicomObject, Tag};
+
+use crate::backend::BackendError;
+
+mod scp;
+
+pub use scp::StorageScp;
+
+pub(crate) const C_STORE_RQ: u16 = 0x0001;
+pub(crate) const C_FIND_RQ: u16 = 0x0020;
+pub(crate) const C_MOVE_RQ: u16 = 0x0021;
+const C_ECHO_RQ: u16 = 0x0030;
+
+// Command Data Set Type of messages without a data set
+const NO_DATA_SET: u16 = 0x0101;
+
+pub(crate) const STATUS_SUCCESS: u16 = 0x0000;
+pub(crate) const STATUS_PENDING: u16 = 0xFF00;
+// Pending, but some optional keys aren't supported
+const STATUS_PENDING_WARNING: u16 = 0xFF01;
+// Refused: Out of Resources
+const STATUS_OUT_OF_RESOURCES: u16 = 0xA700;
+// Error: Cannot understand
+const STATUS_CANNOT_UNDERSTAND: u16 = 0xC000;
+
+// Size of the PDU and PDV headers, which precede each fragment of a message
+const PDATA_HEADER_LENGTH: usize = 12;
+// PDU length used if the peer has no limit or announced one, which can't hold a fragment
+const DEFAULT_MAX_PDU_LENGTH: usize = 16384;
+
+// Storage SOP classes, which are accepted from other nodes
+pub(crate) const STORAGE_SOP_CLASSES: [&str; 48] = [
+    uids::COMPUTED_RADIOGRAPHY_IMAGE_STORAGE,
+    uids::DIGITAL_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+    uids::DIGITAL_X_RAY_IMAGE_STORAGE_FOR_PROCESSING,
+    uids::DIGITAL_MAMMOGRAPHY_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+    uids::DIGITAL_MAMMOGRAPHY_X_RAY_IMAGE_STORAGE_FOR_PROCESSING,
+    uids::DIGITAL_INTRA_ORAL_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+    uids::BREAST_TOMOSYNTHESIS_IMAGE_STORAGE,
+    uids::CT_IMAGE_STORAGE,
+    uids::ENHANCED_CT_IMAGE_STORAGE,
+    uids::MR_IMAGE_STORAGE,
+    uids::ENHANCED_MR_IMAGE_STORAGE,
+    uids::MR_SPECTROSCOPY_STORAGE,
+    uids::ULTRASOUND_IMAGE_STORAGE,
+    uids::ULTRASOUND_MULTI_FRAME_IMAGE_STORAGE,
+    uids::ENHANCED_US_VOLUME_STORAGE,
+    uids::SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::MULTI_FRAME_SINGLE_BIT_SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::MULTI_FRAME_GRAYSCALE_BYTE_SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::MULTI_FRAME_GRAYSCALE_WORD_SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::MULTI_FRAME_TRUE_COLOR_SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::X_RAY_ANGIOGRAPHIC_IMAGE_STORAGE,
+    uids::ENHANCED_XA_IMAGE_STORAGE,
+    uids::X_RAY_RADIOFLUOROSCOPIC_IMAGE_STORAGE,
+    uids::ENHANCED_XRF_IMAGE_STORAGE,
+    uids::X_RAY3_D_ANGIOGRAPHIC_IMAGE_STORAGE,
+    uids::NUCLEAR_MEDICINE_IMAGE_STORAGE,
+    uids::POSITRON_EMISSION_TOMOGRAPHY_IMAGE_STORAGE,
+    uids::ENHANCED_PET_IMAGE_STORAGE,
+    uids::RT_IMAGE_STORAGE,
+    uids::RT_DOSE_STORAGE,
+    uids::RT_STRUCTURE_SET_STORAGE,
+    uids::RT_PLAN_STORAGE,
+    uids::VL_ENDOSCOPIC_IMAGE_STORAGE,
+    uids::VL_MICROSCOPIC_IMAGE_STORAGE,
+    uids::VL_PHOTOGRAPHIC_IMAGE_STORAGE,
+    uids::VL_WHOLE_SLIDE_MICROSCOPY_IMAGE_STORAGE,
+    uids::OPHTHALMIC_PHOTOGRAPHY8_BIT_IMAGE_STORAGE,
+    uids::OPHTHALMIC_TOMOGRAPHY_IMAGE_STORAGE,
+    uids::SEGMENTATION_STORAGE,
+    uids::GRAYSCALE_SOFTCOPY_PRESENTATION_STATE_STORAGE,
+    uids::KEY_OBJECT_SELECTION_DOCUMENT_STORAGE,
+    uids::BASIC_TEXT_SR_STORAGE,
+    uids::ENHANCED_SR_STORAGE,
+    uids::COMPREHENSIVE_SR_STORAGE,
+    uids::X_RAY_RADIATION_DOSE_SR_STORAGE,
+    uids::ENCAPSULATED_PDF_STORAGE,
+    uids::VIDEO_ENDOSCOPIC_IMAGE_STORAGE,
+    uids::RAW_DATA_STORAGE,
+];
+
+/// A received command, with its data set if it has one
+pub(crate) struct Message {
+    pub(crate) presentation_context_id: u8,
+    pub(crate) command: InMemDicomObject,
+    pub(crate) data: Option<Vec<u8>>,
+}
+
+impl Message {
+    pub(crate) fn field(&self, tag: Tag) -> Option<u16> {
+        self.command
+            .get(tag)
+            .and_then(|elt| elt.to_int::<u16>().ok())
+    }
+
+    pub(crate) fn text(&self, tag: Tag) -> Option<String> {
+        self.command
+            .get(tag)
+            .and_then(|elt| elt.to_str().ok())
+            .map(|text| text.trim_end_matches(['\0', ' ']).to_string())
+    }
+}
+
+/// The requesting and the accepting side of an association
+pub(crate) trait Association {
+    fn send(&mut self, pdu: &Pdu) -> Result<(), BackendError>;
+
+    fn receive(&mut self) -> Result<Pdu, BackendError>;
+
+    /// Largest PDU, which the other side accepts
+    fn peer_max_pdu_length(&self) -> u32;
+
+    fn presentation_contexts(&self) -> &[PresentationContextResult];
+}
+
+impl Association for ClientAssociation {
+    fn send(&mut self, pdu: &Pdu) -> Result<(), BackendError> {
+        Ok(ClientAssociation::send(self, pdu)?)
+    }
+
+    fn receive(&mut self) -> Result<Pdu, BackendError> {
+        Ok(ClientAssociation::receive(self)?)
+    }
+
+    fn peer_max_pdu_length(&self) -> u32 {
+        self.acceptor_max_pdu_length()
+    }
+
+    fn presentation_contexts(&self) -> &[PresentationContextResult] {
+        ClientAssociation::presentation_contexts(self)
+    }
+}
+
+impl Association for ServerAssociation {
+    fn send(&mut self, pdu: &Pdu) -> Result<(), BackendError> {
+        Ok(ServerAssociation::send(self, pdu)?)
+    }
+
+    fn receive(&mut self) -> Result<Pdu, BackendError> {
+        Ok(ServerAssociation::receive(self)?)
+    }
+
+    // The length requested by the peer isn't exposed, but the accepting side
+    // only sends responses without data set
+    fn peer_max_pdu_length(&self) -> u32 {
+        dicom::ul::pdu::reader::DEFAULT_MAX_PDU
+    }
+
+    fn presentation_contexts(&self) -> &[PresentationContextResult] {
+        ServerAssociation::presentation_contexts(self)
+    }
+}
+
+fn command(field: u16, sop_class_uid: &str, has_data: bool) -> InMemDicomObject {
+    InMemDicomObject::from_element_iter([
+        DataElement::new(
+            tags::AFFECTED_SOP_CLASS_UID,
+            VR::UI,
+            PrimitiveValue::from(sop_class_uid),
+        ),
+        DataElement::new(tags::COMMAND_FIELD, VR::US, PrimitiveValue::from(field)),
+        DataElement::new(
+            tags::COMMAND_DATA_SET_TYPE,
+            VR::US,
+            PrimitiveValue::from(if has_data { 0x0000 } else { NO_DATA_SET }),
+        ),
+    ])
+}
+
+/// Start the command set of a request
+pub(crate) fn request(
+    field: u16,
+    sop_class_uid: &str,
+    message_id: u16,
+    has_data: bool,
+) -> InMemDicomObject {
+    let mut request = command(field, sop_class_uid, has_data);
+    request.put(DataElement::new(
+        tags::MESSAGE_ID,
+        VR::US,
+        PrimitiveValue::from(message_id),
+    ));
+    // Medium priority
+    request.put(DataElement::new(
+        tags::PRIORITY,
+        VR::US,
+        PrimitiveValue::from(0x0000_u16),
+    ));
+    request
+}
+
+/// Start the command set of the response to a request, without data set
+pub(crate) fn response(request: &Message, status: u16, comment: Option<&str>) -> InMemDicomObject {
+    let field = request.field(tags::COMMAND_FIELD).unwrap_or_default() | 0x8000;
+    let sop_class_uid = request
+        .text(tags::AFFECTED_SOP_CLASS_UID)
+        .unwrap_or_default();
+    let mut response = command(field, &sop_class_uid, false);
+    response.put(DataElement::new(
+        tags::MESSAGE_ID_BEING_RESPONDED_TO,
+        VR::US,
+        PrimitiveValue::from(request.field(tags::MESSAGE_ID).unwrap_or_default()),
+    ));
+    response.put(DataElement::new(
+        tags::STATUS,
+        VR::US,
+        PrimitiveValue::from(status),
+    ));
+    if let Some(comment) = comment {
+        // Error comments are limited to 64 characters
+        let comment: String = comment.chars().take(64).collect();
+        response.put(DataElement::new(
+            tags::ERROR_COMMENT,
+            VR::LO,
+            PrimitiveValue::from(comment),
+        ));
+    }
+    response
+}
+
+/// Encode a command set, which always uses implicit VR little endian
+fn encode_command(command: &InMemDicomObject) -> Result<Vec<u8>, BackendError> {
+    let ts = transfer_syntax(uids::IMPLICIT_VR_LITTLE_ENDIAN)?;
+    let mut elements = Vec::new();
+    command.write_dataset_with_ts(&mut elements, ts)?;
+
+    let mut data = Vec::new();
+    InMemDicomObject::from_element_iter([DataElement::new(
+        tags::COMMAND_GROUP_LENGTH,
+        VR::UL,
+        PrimitiveValue::from(elements.len() as u32),
+    )])
+    .write_dataset_with_ts(&mut data, ts)?;
+    data.extend_from_slice(&elements);
+    Ok(data)
+}
+
+pub(crate) fn transfer_syntax(
+    uid: &str,
+) -> Result<&'static dicom::encoding::TransferSyntax, BackendError> {
+    let uid = uid.trim_end_matches(['\0', ' ']);
+    TransferSyntaxRegistry
+        .get(uid)
+        .ok_or_else(|| format!("Unknown transfer syntax {}", uid).into())
+}
+
+/// Transfer syntax, which was accepted for a presentation context
+pub(crate) fn accepted_transfer_syntax(
+    association: &impl Association,
+    presentation_context_id: u8,
+) -> Result<&'static dicom::encoding::TransferSyntax, BackendError> {
+    let context = association
+        .presentation_contexts()
+        .iter()
+        .find(|context| context.id == presentation_context_id)
+        .ok_or_else(|| {
+            format!(
+                "Presentation context {} wasn't accepted",
+                presentation_context_id
+            )
+        })?;
+    transfer_syntax(&context.transfer_syntax)
+}
+
+pub(crate) fn encode_dataset(
+    dataset: &InMemDicomObject,
+    transfer_syntax_uid: &str,
+) -> Result<Vec<u8>, BackendError> {
+    let mut data = Vec::new();
+    dataset.write_dataset_with_ts(&mut data, transfer_syntax(transfer_syntax_uid)?)?;
+    Ok(data)
+}
+
+/// Bytes of a data set per PDU, a maximum length of 0 means unlimited
+fn fragment_length(peer_max_pdu_length: u32) -> usize {
+    match peer_max_pdu_length as usize {
+        length if length > PDATA_HEADER_LENGTH => length - PDATA_HEADER_LENGTH,
+        _ => DEFAULT_MAX_PDU_LENGTH - PDATA_HEADER_LENGTH,
+    }
+}
+
+/// Send a command and its data set, which is split into fragments that fit the PDUs of the peer
+pub(crate) fn send_message(
+    association: &mut impl Association,
+    presentation_context_id: u8,
+    command: &InMemDicomObject,
+    data: Option<&[u8]>,
+) -> Result<(), BackendError> {
+    association.send(&Pdu::PData {
+        data: vec![PDataValue {
+            presentation_context_id,
+            value_type: PDataValueType::Command,
+            is_last: true,
+            data: encode_command(command)?,
+        }],
+    })?;
+
+    let Some(data) = data else {
+        return Ok(());
+    };
+    let fragment_length = fragment_length(association.peer_max_pdu_length());
+    let fragments = data.len().div_ceil(fragment_length).max(1);
+    for (index, fragment) in data.chunks(fragment_length).enumerate() {
+        association.send(&Pdu::PData {
+            data: vec![PDataValue {
+                presentation_context_id,
+                value_type: PDataValueType::Data,
+                is_last: index + 1 == fragments,
+                data: fragment.to_vec(),
+            }],
+        })?;
+    }
+    if data.is_empty() {
+        association.send(&Pdu::PData {
+            data: vec![PDataValue {
+                presentation_context_id,
+                value_type: PDataValueType::Data,
+                is_last: true,
+                data: Vec::new(),
+            }],
+        })?;
+    }
+    Ok(())
+}
+
+/// Receive the next message, `None` means that the peer released the association
+pub(crate) fn receive_message(
+    association: &mut impl Association,
+) -> Result<Option<Message>, BackendError> {
+    let mut command_data = Vec::new();
+    let mut command = None;
+    let mut data = Vec::new();
+
+    loop {
+        let values = match association.receive()? {
+            Pdu::PData { data } => data,
+            Pdu::ReleaseRQ => {
+                association.send(&Pdu::ReleaseRP)?;
+                return Ok(None);
+            }
+            Pdu::AbortRQ { source } => {
+                return Err(format!("Association aborted by {:?}", source).into())
+            }
+            pdu => return Err(format!("Unexpected PDU {:?}", pdu).into()),
+        };
+
+        for value in values {
+            let presentation_context_id = value.presentation_context_id;
+            match value.value_type {
+                PDataValueType::Command => {
+                    command_data.extend_from_slice(&value.data);
+                    if !value.is_last {
+                        continue;
+                    }
+                    let ts = transfer_syntax(uids::IMPLICIT_VR_LITTLE_ENDIAN)?;
+                    let parsed = InMemDicomObject::read_dataset_with_ts(&command_data[..], ts)?;
+                    let has_data = parsed
+                        .get(tags::COMMAND_DATA_SET_TYPE)
+                        .and_then(|elt| elt.to_int::<u16>().ok())
+                        != Some(NO_DATA_SET);
+                    if !has_data {
+                        return Ok(Some(Message {
+                            presentation_context_id,
+                            command: parsed,
+                            data: None,
+                        }));
+                    }
+                    command = Some(parsed);
+                }
+                PDataValueType::Data => {
+                    data.extend_from_slice(&value.data);
+                    if !value.is_last {
+                        continue;
+                    }
+                    let command = command
+                        .take()
+                        .ok_or("Received a data set without command")?;
+                    return Ok(Some(Message {
+                        presentation_context_id,
+                        command,
+                        data: Some(data),
+                    }));
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn is_pending(status: u16) -> bool {
+    status == STATUS_PENDING || status == STATUS_PENDING_WARNING
+}
+
+/// Check the status of a response, warnings are logged
+pub(crate) fn check_status(response: &Message) -> Result<u16, BackendError> {
+    let status = response.field(tags::STATUS).ok_or("Missing status")?;
+    match status {
+        STATUS_SUCCESS | STATUS_PENDING | STATUS_PENDING_WARNING => Ok(status),
+        0x0001 | 0xB000..=0xBFFF => {
+            log::warn!("Peer responded with warning status {:04X}", status);
+            Ok(status)
+        }
+        _ => Err(format!(
+            "Peer responded with status {:04X}: {}",
+            status,
+            response.text(tags::ERROR_COMMENT).unwrap_or_default()
+        )
+        .into()),
+    }
+}
+
+/// Answer C-ECHO and C-STORE requests, until the peer releases the association.
+///
+/// Received instances are passed to `store`, its errors are sent back as failure status.
+pub(crate) fn serve_storage(
+    association: &mut ServerAssociation,
+    mut store: impl FnMut(FileDicomObject<InMemDicomObject>) -> Result<(), BackendError>,
+) -> Result<(), BackendError> {
+    while let Some(request) = receive_message(association)? {
+        let response = match request.field(tags::COMMAND_FIELD) {
+            Some(C_ECHO_RQ) => response(&request, STATUS_SUCCESS, None),
+            Some(C_STORE_RQ) => {
+                let sop_instance_uid = request
+                    .text(tags::AFFECTED_SOP_INSTANCE_UID)
+                    .unwrap_or_default();
+                let mut response = match read_instance(association, &request).and_then(&mut store) {
+                    Ok(()) => response(&request, STATUS_SUCCESS, None),
+                    Err(e) => {
+                        log::error!("Failed to store instance {}: {}", sop_instance_uid, e);
+                        response(&request, STATUS_OUT_OF_RESOURCES, Some(&e.to_string()))
+                    }
+                };
+                response.put(DataElement::new(
+                    tags::AFFECTED_SOP_INSTANCE_UID,
+                    VR::UI,
+                    PrimitiveValue::from(sop_instance_uid),
+                ));
+                response
+            }
+            field => {
+                log::warn!("Unsupported command {:04X?}", field);
+                response(&request, STATUS_CANNOT_UNDERSTAND, None)
+            }
+        };
+        send_message(
+            association,
+            request.presentation_context_id,
+            &response,
+            None,
+        )?;
+    }
+    Ok(())
+}
+
+/// Read the data set of a C-STORE request into a file with the negotiated transfer syntax
+fn read_instance(
+    association: &ServerAssociation,
+    request: &Message,
+) -> Result<FileDicomObject<InMemDicomObject>, BackendError> {
+    let context = association
+        .presentation_contexts()
+        .iter()
+        .find(|context| context.id == request.presentation_context_id)
+        .ok_or("Unknown presentation context")?;
+    let ts = transfer_syntax(&context.transfer_syntax)?;
+    let data = request.data.as_deref().ok_or("Missing data set")?;
+    let dataset = InMemDicomObject::read_dataset_with_ts(data, ts)?;
+
+    let meta = FileMetaTableBuilder::new()
+        .transfer_syntax(ts.uid())
+        .media_storage_sop_class_uid(
+            request
+                .text(tags::AFFECTED_SOP_CLASS_UID)
+                .ok_or("Missing SOP Class UID")?,
+        )
+        .media_storage_sop_instance_uid(
+            request
+                .text(tags::AFFECTED_SOP_INSTANCE_UID)
+                .ok_or("Missing SOP Instance UID")?,
+        )
+        .build()?;
+    Ok(dataset.with_exact_meta(meta))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragments_fit_the_pdus_of_the_peer() {
+        assert_eq!(fragment_length(4096), 4096 - PDATA_HEADER_LENGTH);
+        // Unlimited or too small to hold a fragment
+        assert_eq!(
+            fragment_length(0),
+            DEFAULT_MAX_PDU_LENGTH - PDATA_HEADER_LENGTH
+        );
+        assert_eq!(
+            fragment_length(PDATA_HEADER_LENGTH as u32),
+            DEFAULT_MAX_PDU_LENGTH - PDATA_HEADER_LENGTH
+        );
+    }
+}
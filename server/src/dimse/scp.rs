@@ -0,0 +1,267 @@
+use std::{
+    net::{TcpListener, ToSocketAddrs},
+    sync::{mpsc, Arc},
+    thread,
+};
+
+use dicom::{
+    dictionary_std::uids,
+    ul::{
+        association::server::AccessControl, pdu::AssociationRJServiceUserReason,
+        ServerAssociationOptions,
+    },
+};
+use dicom_object::{FileDicomObject, InMemDicomObject};
+use futures_channel::mpsc as async_mpsc;
+use futures_util::StreamExt;
+
+use super::{serve_storage, STORAGE_SOP_CLASSES};
+use crate::{
+    api::stow::ingest_instances,
+    auth::{AuthScheme, Principal},
+    backend::{BackendError, DicomWebBackend},
+};
+
+// AE title of the SCP, if it accepts any called AE title
+const DEFAULT_AE_TITLE: &str = "DICOMWEB";
+
+type Delivery = (
+    Principal,
+    FileDicomObject<InMemDicomObject>,
+    mpsc::SyncSender<Result<(), BackendError>>,
+);
+
+/// C-STORE SCP for modalities, which only push over DIMSE.
+///
+/// Received instances go through the same ingestion as STOW-RS, so they can be
+/// searched and retrieved as soon as the C-STORE response is sent. To share the
+/// backend with the HTTP endpoints, pass both an `Arc` of it.
+pub struct StorageScp {
+    backend: Arc<dyn DicomWebBackend>,
+    ae_titles: Vec<String>,
+}
+
+/// Accepts associations called with one of the AE titles, or any if there are none
+struct CalledAeTitles(Vec<String>);
+
+impl AccessControl for CalledAeTitles {
+    fn check_access(
+        &self,
+        _this_ae_title: &str,
+        _calling_ae_title: &str,
+        called_ae_title: &str,
+    ) -> Result<(), AssociationRJServiceUserReason> {
+        let called_ae_title = called_ae_title.trim();
+        if self.0.is_empty() || self.0.iter().any(|ae_title| ae_title == called_ae_title) {
+            Ok(())
+        } else {
+            log::warn!("Rejecting association for AE title {}", called_ae_title);
+            Err(AssociationRJServiceUserReason::CalledAETitleNotRecognized)
+        }
+    }
+}
+
+impl StorageScp {
+    pub fn new(backend: impl DicomWebBackend + 'static) -> StorageScp {
+        StorageScp {
+            backend: Arc::new(backend),
+            ae_titles: Vec::new(),
+        }
+    }
+
+    /// Accept associations called with this AE title.
+    ///
+    /// Without any AE title, associations are accepted regardless of the called AE title.
+    pub fn with_ae_title(mut self, ae_title: impl Into<String>) -> StorageScp {
+        self.ae_titles.push(ae_title.into());
+        self
+    }
+
+    /// Listen on the given address, e.g. "0.0.0.0:11112", and store the received instances.
+    ///
+    /// Associations are handled on their own threads, while the backend is called from
+    /// the task awaiting this future. It only returns if the address can't be bound.
+    pub async fn serve(self, address: impl ToSocketAddrs) -> Result<(), BackendError> {
+        let listener = TcpListener::bind(address)?;
+        let ae_title = self
+            .ae_titles
+            .first()
+            .map_or(DEFAULT_AE_TITLE, String::as_str);
+        let mut options = ServerAssociationOptions::new()
+            .ae_access_control(CalledAeTitles(self.ae_titles.clone()))
+            .ae_title(ae_title.to_string())
+            .with_abstract_syntax(uids::VERIFICATION);
+        for sop_class_uid in STORAGE_SOP_CLASSES {
+            options = options.with_abstract_syntax(sop_class_uid);
+        }
+
+        let (sender, receiver) = async_mpsc::unbounded::<Delivery>();
+        thread::spawn(move || accept_associations(listener, options, sender));
+
+        let backend = self.backend.as_ref();
+        receiver
+            .for_each_concurrent(None, |(principal, instance, reply)| async move {
+                let result =
+                    ingest_instances(backend, &principal, std::slice::from_ref(&instance)).await;
+                let _ = reply.send(result);
+            })
+            .await;
+        Ok(())
+    }
+}
+
+fn accept_associations(
+    listener: TcpListener,
+    options: ServerAssociationOptions<'static, CalledAeTitles>,
+    sender: async_mpsc::UnboundedSender<Delivery>,
+) {
+    let options = Arc::new(options);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let options = options.clone();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let result = options
+                .establish(stream)
+                .map_err(BackendError::from)
+                .and_then(|mut association| {
+                    log::info!(
+                        "Accepted storage association from {}",
+                        association.client_ae_title()
+                    );
+                    // Instances are stored on behalf of the calling AE
+                    let principal =
+                        Principal::new(association.client_ae_title().trim(), AuthScheme::AeTitle);
+                    serve_storage(&mut association, |instance| {
+                        // Wait until the instance is stored, so the response reports the outcome
+                        let (reply, stored) = mpsc::sync_channel(1);
+                        sender
+                            .unbounded_send((principal.clone(), instance, reply))
+                            .map_err(|_| "The storage SCP was stopped")?;
+                        stored.recv().map_err(|_| "The instance wasn't stored")?
+                    })
+                });
+            if let Err(e) = result {
+                log::error!("Storage association failed: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{DataElement, PrimitiveValue, VR},
+        dictionary_std::tags,
+        ul::ClientAssociationOptions,
+    };
+    use dicom_object::meta::FileMetaTableBuilder;
+
+    use super::*;
+    use crate::{
+        backend::InMemoryBackend,
+        dimse::{
+            encode_dataset, receive_message, request, send_message, C_STORE_RQ,
+            STATUS_OUT_OF_RESOURCES, STATUS_SUCCESS,
+        },
+        DicomWebServer,
+    };
+
+    const STUDY_UID: &str = "1.2.840.10008.9.1";
+    const SERIES_UID: &str = "1.2.840.10008.9.1.1";
+    const SOP_UID: &str = "1.2.840.10008.9.1.1.1";
+
+    /// CT slice, as pushed by a scanner
+    fn ct_image() -> FileDicomObject<InMemDicomObject> {
+        let mut dcm = InMemDicomObject::new_empty();
+        let mut put = |tag, vr, value: &str| dcm.put(DataElement::new(tag, vr, value));
+        put(tags::SOP_CLASS_UID, VR::UI, uids::CT_IMAGE_STORAGE);
+        put(tags::SOP_INSTANCE_UID, VR::UI, SOP_UID);
+        put(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID);
+        put(tags::SERIES_INSTANCE_UID, VR::UI, SERIES_UID);
+        put(tags::PATIENT_ID, VR::LO, "SCANNED-1");
+        put(tags::MODALITY, VR::CS, "CT");
+        dcm.with_meta(
+            FileMetaTableBuilder::new()
+                .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN)
+                .media_storage_sop_class_uid(uids::CT_IMAGE_STORAGE)
+                .media_storage_sop_instance_uid(SOP_UID),
+        )
+        .unwrap()
+    }
+
+    /// Start the SCP on a free port
+    async fn start(scp: StorageScp) -> u16 {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        tokio::spawn(scp.serve(("127.0.0.1", port)));
+        // Let the SCP bind its port
+        tokio::task::yield_now().await;
+        port
+    }
+
+    /// Send the instance as C-STORE request like a modality, returns the response status
+    async fn c_store(port: u16, instance: FileDicomObject<InMemDicomObject>) -> u16 {
+        tokio::task::spawn_blocking(move || {
+            let mut association = ClientAssociationOptions::new()
+                .calling_ae_title("CT01")
+                .called_ae_title(DEFAULT_AE_TITLE)
+                .with_presentation_context(
+                    uids::CT_IMAGE_STORAGE,
+                    vec![uids::EXPLICIT_VR_LITTLE_ENDIAN],
+                )
+                .establish(("127.0.0.1", port))
+                .unwrap();
+            let context_id = association.presentation_contexts()[0].id;
+
+            let mut command = request(C_STORE_RQ, uids::CT_IMAGE_STORAGE, 1, true);
+            command.put(DataElement::new(
+                tags::AFFECTED_SOP_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from(SOP_UID),
+            ));
+            let data = encode_dataset(&instance, uids::EXPLICIT_VR_LITTLE_ENDIAN).unwrap();
+            send_message(&mut association, context_id, &command, Some(&data)).unwrap();
+            let response = receive_message(&mut association).unwrap().unwrap();
+            association.release().unwrap();
+            response.field(tags::STATUS).unwrap()
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn stores_pushed_instances_in_the_backend() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let port = start(StorageScp::new(backend.clone())).await;
+
+        assert_eq!(c_store(port, ct_image()).await, STATUS_SUCCESS);
+
+        let principal = Principal::new("CT01", AuthScheme::AeTitle);
+        let stored = backend
+            .retrieve_instance(&principal, STUDY_UID, SERIES_UID, SOP_UID)
+            .await
+            .unwrap();
+        assert_eq!(
+            stored.element(tags::PATIENT_ID).unwrap().to_str().unwrap(),
+            "SCANNED-1"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_backend_failures_in_the_status() {
+        // Fails all stores as unsupported
+        let port = start(StorageScp::new(DicomWebServer::default())).await;
+
+        assert_eq!(c_store(port, ct_image()).await, STATUS_OUT_OF_RESOURCES);
+    }
+}
@@ -1,7 +1,174 @@
+use dicom::{
+    core::{dictionary::DataDictionary, Tag, VR},
+    dictionary_std::{tags, StandardDataDictionary},
+};
 use dicom_object::InMemDicomObject;
 
-use crate::QidoStudyQuery;
+use crate::{QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery};
 
-pub fn study_filter(_dcm: &InMemDicomObject, _query: &QidoStudyQuery) -> bool {
-    true
+/// Matching of a single attribute
+///
+/// See http://dicom.nema.org/medical/dicom/current/output/chtml/part04/sect_C.2.2.2.html
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeMatch {
+    /// An empty value, which matches every dataset
+    Universal,
+    /// The value has to be equal
+    Single(String),
+    /// A value with `*` and `?` wildcards
+    Wildcard(String),
+    /// A date, time or datetime range, either end may be open
+    Range(Option<String>, Option<String>),
+    /// A list of UIDs, any of them matches
+    UidList(Vec<String>),
+}
+
+impl AttributeMatch {
+    /// Parse the value of a matching query parameter for an attribute of the given VR
+    pub fn parse(vr: VR, value: &str) -> AttributeMatch {
+        let value = value.trim();
+        if value.is_empty() || value == "*" {
+            return AttributeMatch::Universal;
+        }
+
+        match vr {
+            VR::UI if value.contains('\\') => {
+                AttributeMatch::UidList(value.split('\\').map(str::to_string).collect())
+            }
+            VR::DA | VR::TM | VR::DT if value.contains('-') => {
+                let (from, to) = value.split_once('-').unwrap_or((value, ""));
+                let bound = |bound: &str| (!bound.is_empty()).then(|| bound.to_string());
+                AttributeMatch::Range(bound(from), bound(to))
+            }
+            _ if value.contains(['*', '?']) => AttributeMatch::Wildcard(value.to_string()),
+            _ => AttributeMatch::Single(value.to_string()),
+        }
+    }
+
+    /// Check if a single attribute value matches.
+    ///
+    /// Person names are matched case insensitive. With fuzzy matching, it is
+    /// sufficient if a person name starts with the value.
+    pub fn matches(&self, vr: VR, value: &str, fuzzy: bool) -> bool {
+        let value = value.trim_end_matches(['\0', ' ']);
+        let person_name = vr == VR::PN;
+        let normalize = |text: &str| {
+            if person_name {
+                text.to_lowercase()
+            } else {
+                text.to_string()
+            }
+        };
+
+        match self {
+            AttributeMatch::Universal => true,
+            AttributeMatch::Single(expected) if person_name && fuzzy => {
+                normalize(value).starts_with(&normalize(expected))
+            }
+            AttributeMatch::Single(expected) => normalize(value) == normalize(expected),
+            AttributeMatch::Wildcard(pattern) => {
+                let value: Vec<char> = normalize(value).chars().collect();
+                let pattern: Vec<char> = normalize(pattern).chars().collect();
+                wildcard_matches(&pattern, &value)
+            }
+            AttributeMatch::Range(from, to) => {
+                from.as_deref().is_none_or(|from| value >= from)
+                    && to.as_deref().is_none_or(|to| value <= to)
+            }
+            AttributeMatch::UidList(uids) => uids.iter().any(|uid| uid == value),
+        }
+    }
+}
+
+fn wildcard_matches(pattern: &[char], value: &[char]) -> bool {
+    let (mut p, mut v) = (0, 0);
+    // Position of the last `*` and the value position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, v));
+            p += 1;
+        } else if let Some((star_p, star_v)) = star {
+            // Let the last `*` consume one more character
+            p = star_p + 1;
+            v = star_v + 1;
+            star = Some((star_p, star_v + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Get the VR of an attribute from the standard dictionary
+pub fn attribute_vr(tag: Tag) -> VR {
+    StandardDataDictionary
+        .by_tag(tag)
+        .map(|entry| entry.vr)
+        .unwrap_or(VR::UN)
+}
+
+/// Check if a dataset matches all of the given attribute values.
+///
+/// Multi-valued attributes match, if any of their values matches.
+pub fn matches_all(dcm: &InMemDicomObject, matches: &[(Tag, String)], fuzzy: bool) -> bool {
+    matches.iter().all(|(tag, value)| {
+        let vr = attribute_vr(*tag);
+        let attribute_match = AttributeMatch::parse(vr, value);
+        if attribute_match == AttributeMatch::Universal {
+            return true;
+        }
+
+        let Some(elt) = dcm.get(*tag) else {
+            return false;
+        };
+        let Ok(values) = elt.to_multi_str() else {
+            return false;
+        };
+        values
+            .iter()
+            .any(|value| attribute_match.matches(vr, value, fuzzy))
+    })
+}
+
+pub fn study_filter(dcm: &InMemDicomObject, query: &QidoStudyQuery) -> bool {
+    matches_all(dcm, &query.matches, query.fuzzymatching.unwrap_or(false))
+}
+
+pub fn series_filter(dcm: &InMemDicomObject, query: &QidoSeriesQuery) -> bool {
+    matches_all(dcm, &series_matches(query), false)
+}
+
+pub fn instance_filter(dcm: &InMemDicomObject, query: &QidoInstanceQuery) -> bool {
+    matches_all(dcm, &instance_matches(query), false)
+}
+
+/// The matching attributes of a series query, including its named parameters
+pub(crate) fn series_matches(query: &QidoSeriesQuery) -> Vec<(Tag, String)> {
+    let mut matches = query.matches.clone();
+    if let Some(modality) = &query.modality {
+        matches.push((tags::MODALITY, modality.clone()));
+    }
+    if let Some(series_instance_uid) = &query.series_instance_uid {
+        matches.push((tags::SERIES_INSTANCE_UID, series_instance_uid.clone()));
+    }
+    if let Some(series_description) = &query.series_description {
+        matches.push((tags::SERIES_DESCRIPTION, series_description.clone()));
+    }
+    matches
+}
+
+/// The matching attributes of an instance query, including its named parameters
+pub(crate) fn instance_matches(query: &QidoInstanceQuery) -> Vec<(Tag, String)> {
+    let mut matches = query.matches.clone();
+    if let Some(sop_instance_uid) = &query.sop_instance_uid {
+        matches.push((tags::SOP_INSTANCE_UID, sop_instance_uid.clone()));
+    }
+    if let Some(instance_number) = &query.instance_number {
+        matches.push((tags::INSTANCE_NUMBER, instance_number.clone()));
+    }
+    matches
 }
@@ -1,53 +1,101 @@
 use dicom::{dictionary_std::tags, object::InMemDicomObject};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+mod deidentify;
 mod filter;
+mod query;
+mod redaction;
+mod rejection;
+#[cfg(test)]
+mod testing;
+mod update;
 
 use dicom_object::{FileDicomObject, Tag};
 
-pub use filter::study_filter;
+pub use deidentify::{DeidentificationOption, Deidentifier};
+pub use filter::{
+    attribute_vr, instance_filter, matches_all, series_filter, study_filter, AttributeMatch,
+};
+pub use query::parse_attribute;
+pub use redaction::{Rectangle, RedactionRule};
+pub use rejection::{InstanceReference, RejectionNote, RejectionReason};
+pub use update::{generate_uid, InstanceUpdate, UpdateQuery};
 
 #[cfg(feature = "actix")]
 pub mod actix;
+pub mod api;
+pub mod audit;
+pub mod auth;
+#[cfg(feature = "axum")]
+pub mod axum;
+pub mod backend;
+pub mod cache;
+#[cfg(feature = "dimse")]
+pub mod dimse;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod multipart;
+#[cfg(feature = "opentelemetry")]
+pub mod telemetry;
+#[cfg(feature = "tower")]
+pub mod tower;
 
-const APPLICATION_DICOM_JSON: &str = "application/dicom+json";
+pub const APPLICATION_DICOM_JSON: &str = "application/dicom+json";
 
 /// QIDO-RS
 ///
 /// See https://www.dicomstandard.org/using/dicomweb/query-qido-rs for more information
 /// More detail can be found in PS3.18 10.6.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct QidoStudyQuery {
     pub limit: Option<usize>,
     pub offset: Option<usize>,
     pub fuzzymatching: Option<bool>,
-    #[serde(skip_deserializing)]
+    /// Also match instances which were rejected by an IOCM rejection note
+    pub includerejected: Option<bool>,
+    #[serde(skip)]
     pub includefields: Vec<String>,
-    #[serde(skip_deserializing)]
+    #[serde(skip)]
     pub matches: Vec<(Tag, String)>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct QidoSeriesQuery {
-    limit: Option<usize>,
-    offset: Option<usize>,
-    includefield: Option<String>,
-    modality: Option<String>,
-    series_instance_uid: Option<String>,
-    series_description: Option<String>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub includefield: Option<String>,
+    pub includerejected: Option<bool>,
+    pub modality: Option<String>,
+    pub series_instance_uid: Option<String>,
+    pub series_description: Option<String>,
+    #[serde(skip)]
+    pub matches: Vec<(Tag, String)>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct QidoInstanceQuery {
-    limit: Option<usize>,
-    offset: Option<usize>,
-    includefield: Option<String>,
-    sop_instance_uid: Option<String>,
-    instance_number: Option<String>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub includefield: Option<String>,
+    pub includerejected: Option<bool>,
+    pub sop_instance_uid: Option<String>,
+    pub instance_number: Option<String>,
+    #[serde(skip)]
+    pub matches: Vec<(Tag, String)>,
 }
 
 /// DICOMWeb Server
 /// Provide the callbacks for the QIDO-RS and WADO-RS endpoints.
+///
+/// Callbacks, which aren't provided, fail as unsupported:
+/// ```ignore
+/// let server = DicomWebServer {
+///     search_study,
+///     retrieve_study,
+///     ..DicomWebServer::default()
+/// };
+/// ```
+#[allow(clippy::type_complexity)]
 pub struct DicomWebServer {
     pub search_study:
         fn(&QidoStudyQuery) -> Result<Vec<InMemDicomObject>, Box<dyn std::error::Error>>,
@@ -76,7 +124,33 @@ pub struct DicomWebServer {
             &str, // sop_instance_uid
         ) -> Result<FileDicomObject<InMemDicomObject>, Box<dyn std::error::Error>>,
     pub store_instances:
-        fn(&Vec<FileDicomObject<InMemDicomObject>>) -> Result<(), Box<dyn std::error::Error>>,
+        fn(&[FileDicomObject<InMemDicomObject>]) -> Result<(), Box<dyn std::error::Error>>,
+    /// Hide or delete the instances referenced by a received rejection note, only instances
+    /// matching the study, series and SOP Instance UID of a reference
+    pub reject_instances: fn(&RejectionNote) -> Result<(), Box<dyn std::error::Error>>,
+    /// Remove instances which were replaced by an update with new UIDs, only instances
+    /// matching the study, series and SOP Instance UID of a reference
+    pub delete_instances: fn(&[InstanceReference]) -> Result<(), Box<dyn std::error::Error>>,
+}
+
+impl Default for DicomWebServer {
+    fn default() -> Self {
+        DicomWebServer {
+            search_study: |_| Err(unsupported()),
+            search_series: |_, _| Err(unsupported()),
+            search_instances: |_, _, _| Err(unsupported()),
+            retrieve_study: |_| Err(unsupported()),
+            retrieve_series: |_, _| Err(unsupported()),
+            retrieve_instance: |_, _, _| Err(unsupported()),
+            store_instances: |_| Err(unsupported()),
+            reject_instances: |_| Err(unsupported()),
+            delete_instances: |_| Err(unsupported()),
+        }
+    }
+}
+
+fn unsupported() -> Box<dyn std::error::Error> {
+    "Not supported by this server".into()
 }
 
 // http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_10.6.html#table_10.6.1-5
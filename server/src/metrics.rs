@@ -0,0 +1,193 @@
+//! Prometheus metrics of the DICOMweb transactions
+//!
+//! [`Metrics`] counts the requests, their duration and the transferred bytes per
+//! transaction and resource, e.g. `transaction="qido",resource="series"`. Use
+//! [`crate::backend::MeteredBackend`] to also measure the backend calls and count the
+//! stored instances. The framework integrations record the requests and serve the
+//! metrics at `/metrics` in the Prometheus text format.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::{header, Response, StatusCode};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::api::{error_response, DicomWebResponse};
+
+/// Registry of the DICOMweb metrics, cheap to clone and share between the endpoints
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    received_bytes: IntCounterVec,
+    sent_bytes: IntCounterVec,
+    instances: IntCounterVec,
+    backend_duration: HistogramVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::with_registry(Registry::new())
+    }
+
+    /// Register the metrics in an existing registry, e.g. next to the metrics of the application
+    pub fn with_registry(registry: Registry) -> Metrics {
+        let transaction = ["transaction", "resource"];
+        let metrics = Metrics {
+            requests: IntCounterVec::new(
+                Opts::new("dicomweb_requests_total", "DICOMweb requests"),
+                &["transaction", "resource", "status"],
+            )
+            .expect("valid metric"),
+            request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "dicomweb_request_duration_seconds",
+                    "Duration of the DICOMweb requests",
+                ),
+                &transaction,
+            )
+            .expect("valid metric"),
+            received_bytes: IntCounterVec::new(
+                Opts::new(
+                    "dicomweb_received_bytes_total",
+                    "Bytes received in request bodies",
+                ),
+                &transaction,
+            )
+            .expect("valid metric"),
+            sent_bytes: IntCounterVec::new(
+                Opts::new("dicomweb_sent_bytes_total", "Bytes sent in response bodies"),
+                &transaction,
+            )
+            .expect("valid metric"),
+            instances: IntCounterVec::new(
+                Opts::new(
+                    "dicomweb_instances_total",
+                    "Instances stored by the backend or failed to store",
+                ),
+                &["outcome"],
+            )
+            .expect("valid metric"),
+            backend_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "dicomweb_backend_duration_seconds",
+                    "Duration of the backend calls",
+                ),
+                &["operation"],
+            )
+            .expect("valid metric"),
+            registry,
+        };
+        for collector in [
+            Box::new(metrics.requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.request_duration.clone()),
+            Box::new(metrics.received_bytes.clone()),
+            Box::new(metrics.sent_bytes.clone()),
+            Box::new(metrics.instances.clone()),
+            Box::new(metrics.backend_duration.clone()),
+        ] {
+            if let Err(e) = metrics.registry.register(collector) {
+                log::warn!("Failed to register metric: {}", e);
+            }
+        }
+        metrics
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Record a finished request, for integrations with other frameworks
+    pub fn observe_request(
+        &self,
+        (transaction, resource): Transaction,
+        status: u16,
+        duration: Duration,
+        sent_bytes: u64,
+    ) {
+        self.requests
+            .with_label_values(&[transaction, resource, &status.to_string()])
+            .inc();
+        self.request_duration
+            .with_label_values(&[transaction, resource])
+            .observe(duration.as_secs_f64());
+        self.sent_bytes
+            .with_label_values(&[transaction, resource])
+            .inc_by(sent_bytes);
+    }
+
+    /// Counter of the bytes received for a transaction, increased while the body is read
+    pub fn received_bytes(&self, (transaction, resource): Transaction) -> IntCounter {
+        self.received_bytes
+            .with_label_values(&[transaction, resource])
+            .clone()
+    }
+
+    pub(crate) fn observe_backend(&self, operation: &str, duration: Duration) {
+        self.backend_duration
+            .with_label_values(&[operation])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn count_instances(&self, stored: bool, count: usize) {
+        let outcome = if stored { "stored" } else { "failed" };
+        self.instances
+            .with_label_values(&[outcome])
+            .inc_by(count as u64);
+    }
+
+    /// Respond with all metrics of the registry in the Prometheus text format
+    pub fn response(&self) -> DicomWebResponse {
+        let encoder = TextEncoder::new();
+        let mut body = Vec::new();
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut body) {
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, e);
+        }
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, encoder.format_type())
+            .body(Bytes::from(body))
+            .expect("valid response")
+    }
+}
+
+/// Transaction and resource of a request, the labels of its metrics
+pub type Transaction = (&'static str, &'static str);
+
+/// Classify a request by the end of its path, so the endpoints can be nested below any
+/// prefix. `None` for requests which aren't DICOMweb transactions.
+pub fn transaction(method: &str, path: &str) -> Option<Transaction> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let last = segments.last().copied();
+    let parent = segments.len().checked_sub(2).map(|i| segments[i]);
+    match method {
+        "GET" if parent == Some("frames") => Some(("wado", "frames")),
+        "GET" if last == Some("metadata") => Some(("wado", "metadata")),
+        "GET" => resource(last)
+            .map(|resource| ("qido", resource))
+            .or_else(|| resource(parent).map(|resource| ("wado", resource))),
+        "POST" if last == Some("studies") || parent == Some("studies") => Some(("stow", "studies")),
+        "PATCH" => resource(parent)
+            .filter(|resource| *resource != "instances")
+            .map(|resource| ("update", resource)),
+        _ => None,
+    }
+}
+
+fn resource(segment: Option<&str>) -> Option<&'static str> {
+    match segment? {
+        "studies" => Some("studies"),
+        "series" => Some("series"),
+        "instances" => Some("instances"),
+        _ => None,
+    }
+}
@@ -0,0 +1,39 @@
+use derive_more::Display;
+
+/// Errors which can occur while reading a multipart stream
+#[derive(Debug, Display)]
+pub enum MultipartError {
+    /// Content-Type header is not found
+    #[display(fmt = "No Content-Type header found")]
+    NoContentType,
+
+    /// Can not parse Content-Type header
+    #[display(fmt = "Can not parse Content-Type header")]
+    ParseContentType,
+
+    /// Multipart boundary is not found
+    #[display(fmt = "Multipart boundary is not found")]
+    Boundary,
+
+    /// Nested multipart is not supported
+    #[display(fmt = "Nested multipart is not supported")]
+    Nested,
+
+    /// Multipart stream is incomplete
+    #[display(fmt = "Multipart stream is incomplete")]
+    Incomplete,
+
+    /// Headers of a multipart field are malformed
+    #[display(fmt = "Multipart field headers are malformed")]
+    Header,
+
+    /// Not consumed
+    #[display(fmt = "Multipart stream is not consumed")]
+    NotConsumed,
+
+    /// Error from the underlying payload stream
+    #[display(fmt = "{}", _0)]
+    Payload(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl std::error::Error for MultipartError {}
@@ -1,5 +1,7 @@
+mod error;
 mod reader;
 mod writer;
 
+pub use error::*;
 pub use reader::*;
 pub use writer::*;
@@ -1,40 +1,37 @@
 //! MultipartReader response payload support.
 
 use std::{
-    cell::{Cell, RefCell, RefMut},
     cmp, fmt,
-    marker::PhantomData,
     pin::Pin,
-    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
     task::{Context, Poll},
 };
 
-use actix_multipart::MultipartError;
-use actix_web::{
-    error::{ParseError, PayloadError},
-    http::header::{self, ContentDisposition, HeaderMap, HeaderName, HeaderValue},
-    web::{Bytes, BytesMut},
-};
-use futures_util::stream::{LocalBoxStream, Stream};
-use local_waker::LocalWaker;
+use bytes::{Bytes, BytesMut};
+use futures_util::{stream::Stream, task::AtomicWaker};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+use super::MultipartError;
 
 const MAX_HEADERS: usize = 32;
 
-/// The server-side implementation of `multipart/related` requests.
+/// Reader for `multipart/related` payloads.
 ///
-/// This will parse the incoming stream into `MultipartItem` instances via its
-/// Stream implementation.
-/// `MultipartItem::Object` contains multipart field. `MultipartItem::MultipartReader`
-/// is used for nested multipart streams.
-pub struct MultipartReader {
+/// This will parse any stream of byte chunks into `Object` instances via its
+/// Stream implementation. It is used for STOW-RS requests on the server side
+/// and for WADO-RS responses on the client side.
+pub struct MultipartReader<S> {
     safety: Safety,
     error: Option<MultipartError>,
-    inner: Option<InnerMultipart>,
+    inner: Option<InnerMultipart<S>>,
 }
 
-enum InnerMultipartItem {
+enum InnerMultipartItem<S> {
     None,
-    Object(Rc<RefCell<InnerField>>),
+    Object(Arc<Mutex<InnerField<S>>>),
 }
 
 #[derive(PartialEq, Debug)]
@@ -52,44 +49,55 @@ enum InnerState {
     Headers,
 }
 
-struct InnerMultipart {
-    payload: PayloadRef,
+struct InnerMultipart<S> {
+    payload: PayloadRef<S>,
     boundary: String,
     state: InnerState,
-    item: InnerMultipartItem,
+    item: InnerMultipartItem<S>,
 }
 
-impl MultipartReader {
-    /// Create multipart instance for boundary.
-    pub fn new<S>(headers: &HeaderMap, stream: S) -> MultipartReader
-    where
-        S: Stream<Item = Result<Bytes, PayloadError>> + 'static,
-    {
-        match Self::boundary(headers) {
+impl<S, E> MultipartReader<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    /// Create multipart instance for the `Content-Type` of the given headers.
+    pub fn new(headers: &HeaderMap, stream: S) -> MultipartReader<S> {
+        let content_type = headers
+            .get(&header::CONTENT_TYPE)
+            .map(|content_type| content_type.to_str().unwrap_or_default());
+        Self::from_content_type(content_type, stream)
+    }
+
+    /// Create multipart instance for the given `Content-Type` header value.
+    pub fn from_content_type(content_type: Option<&str>, stream: S) -> MultipartReader<S> {
+        match Self::boundary(content_type) {
             Ok(boundary) => MultipartReader::from_boundary(boundary, stream),
             Err(err) => MultipartReader::from_error(err),
         }
     }
 
-    /// Extract boundary info from headers.
-    pub(crate) fn boundary(headers: &HeaderMap) -> Result<String, MultipartError> {
-        headers
-            .get(&header::CONTENT_TYPE)
-            .ok_or(MultipartError::NoContentType)?
-            .to_str()
-            .ok()
-            .and_then(|content_type| content_type.parse::<mime::Mime>().ok())
-            .ok_or(MultipartError::ParseContentType)?
-            .get_param(mime::BOUNDARY)
-            .map(|boundary| boundary.as_str().to_owned())
-            .ok_or(MultipartError::Boundary)
+    /// Extract boundary info from the `Content-Type` header value.
+    pub(crate) fn boundary(content_type: Option<&str>) -> Result<String, MultipartError> {
+        let content_type = content_type.ok_or(MultipartError::NoContentType)?;
+        match content_type.parse::<mime::Mime>() {
+            Ok(mime) => mime
+                .get_param(mime::BOUNDARY)
+                .map(|boundary| boundary.as_str().to_owned())
+                .ok_or(MultipartError::Boundary),
+            // Some servers send an unquoted `type` parameter, e.g. "type=application/dicom"
+            Err(_) if content_type.starts_with("multipart/") => content_type
+                .split(';')
+                .filter_map(|param| param.trim().split_once('='))
+                .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+                .map(|(_, boundary)| boundary.trim_matches('"').to_owned())
+                .ok_or(MultipartError::Boundary),
+            Err(_) => Err(MultipartError::ParseContentType),
+        }
     }
 
     /// Create multipart instance for given boundary and stream
-    pub(crate) fn from_boundary<S>(boundary: String, stream: S) -> MultipartReader
-    where
-        S: Stream<Item = Result<Bytes, PayloadError>> + 'static,
-    {
+    pub(crate) fn from_boundary(boundary: String, stream: S) -> MultipartReader<S> {
         MultipartReader {
             error: None,
             safety: Safety::new(),
@@ -103,7 +111,7 @@ impl MultipartReader {
     }
 
     /// Create MultipartReader instance from MultipartError
-    pub(crate) fn from_error(err: MultipartError) -> MultipartReader {
+    pub(crate) fn from_error(err: MultipartError) -> MultipartReader<S> {
         MultipartReader {
             error: Some(err),
             safety: Safety::new(),
@@ -112,8 +120,12 @@ impl MultipartReader {
     }
 }
 
-impl Stream for MultipartReader {
-    type Item = Result<Object, MultipartError>;
+impl<S, E> Stream for MultipartReader<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Item = Result<Object<S>, MultipartError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
@@ -132,16 +144,17 @@ impl Stream for MultipartReader {
 
                 inner.poll(&this.safety, cx)
             }
-            None => Poll::Ready(Some(Err(this
-                .error
-                .take()
-                .expect("MultipartReader polled after finish")))),
+            None => Poll::Ready(this.error.take().map(Err)),
         }
     }
 }
 
-impl InnerMultipart {
-    fn read_headers(payload: &mut PayloadBuffer) -> Result<Option<HeaderMap>, MultipartError> {
+impl<S, E> InnerMultipart<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    fn read_headers(payload: &mut PayloadBuffer<S>) -> Result<Option<HeaderMap>, MultipartError> {
         match payload.read_until(b"\r\n\r\n")? {
             None => {
                 if payload.eof {
@@ -159,23 +172,22 @@ impl InnerMultipart {
 
                         for h in hdrs {
                             let name =
-                                HeaderName::try_from(h.name).map_err(|_| ParseError::Header)?;
-                            let value =
-                                HeaderValue::try_from(h.value).map_err(|_| ParseError::Header)?;
+                                HeaderName::try_from(h.name).map_err(|_| MultipartError::Header)?;
+                            let value = HeaderValue::try_from(h.value)
+                                .map_err(|_| MultipartError::Header)?;
                             headers.append(name, value);
                         }
 
                         Ok(Some(headers))
                     }
-                    Ok(httparse::Status::Partial) => Err(ParseError::Header.into()),
-                    Err(err) => Err(ParseError::from(err).into()),
+                    Ok(httparse::Status::Partial) | Err(_) => Err(MultipartError::Header),
                 }
             }
         }
     }
 
     fn read_boundary(
-        payload: &mut PayloadBuffer,
+        payload: &mut PayloadBuffer<S>,
         boundary: &str,
     ) -> Result<Option<bool>, MultipartError> {
         // TODO: need to read epilogue
@@ -208,32 +220,31 @@ impl InnerMultipart {
     }
 
     fn skip_until_boundary(
-        payload: &mut PayloadBuffer,
+        payload: &mut PayloadBuffer<S>,
         boundary: &str,
     ) -> Result<Option<bool>, MultipartError> {
         let mut eof = false;
         loop {
-            match payload.readline()? {
+            // The close delimiter of a body without parts may not end with a line break
+            match payload.readline_or_eof()? {
                 Some(chunk) => {
                     if chunk.is_empty() {
-                        return Err(MultipartError::Boundary);
+                        return Err(MultipartError::Incomplete);
                     }
-                    if chunk.len() < boundary.len() {
+                    if chunk.len() < boundary.len() + 2 {
                         continue;
                     }
-                    if &chunk[..2] == b"--" && &chunk[2..chunk.len() - 2] == boundary.as_bytes() {
+                    let b: &[u8] = boundary.as_ref();
+                    if chunk.len() >= boundary.len() + 4
+                        && &chunk[..2] == b"--"
+                        && &chunk[2..boundary.len() + 2] == b
+                        && &chunk[boundary.len() + 2..boundary.len() + 4] == b"--"
+                    {
+                        eof = true;
+                        break;
+                    }
+                    if &chunk[..2] == b"--" && &chunk[2..chunk.len() - 2] == b {
                         break;
-                    } else {
-                        if chunk.len() < boundary.len() + 2 {
-                            continue;
-                        }
-                        let b: &[u8] = boundary.as_ref();
-                        if &chunk[..boundary.len()] == b
-                            && &chunk[boundary.len()..boundary.len() + 2] == b"--"
-                        {
-                            eof = true;
-                            break;
-                        }
                     }
                 }
                 None => {
@@ -252,7 +263,7 @@ impl InnerMultipart {
         &mut self,
         safety: &Safety,
         cx: &Context<'_>,
-    ) -> Poll<Option<Result<Object, MultipartError>>> {
+    ) -> Poll<Option<Result<Object<S>, MultipartError>>> {
         if self.state == InnerState::Eof {
             Poll::Ready(None)
         } else {
@@ -263,7 +274,7 @@ impl InnerMultipart {
                 if safety.current() {
                     let stop = match self.item {
                         InnerMultipartItem::Object(ref mut field) => {
-                            match field.borrow_mut().poll(safety) {
+                            match lock(field).poll(safety) {
                                 Poll::Pending => return Poll::Pending,
                                 Poll::Ready(Some(Ok(_))) => continue,
                                 Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
@@ -285,7 +296,7 @@ impl InnerMultipart {
                 match self.state {
                     // read until first boundary
                     InnerState::FirstBoundary => {
-                        match InnerMultipart::skip_until_boundary(&mut payload, &self.boundary)? {
+                        match Self::skip_until_boundary(&mut payload, &self.boundary)? {
                             Some(eof) => {
                                 if eof {
                                     self.state = InnerState::Eof;
@@ -299,7 +310,7 @@ impl InnerMultipart {
                     }
                     // read boundary
                     InnerState::Boundary => {
-                        match InnerMultipart::read_boundary(&mut payload, &self.boundary)? {
+                        match Self::read_boundary(&mut payload, &self.boundary)? {
                             None => return Poll::Pending,
                             Some(eof) => {
                                 if eof {
@@ -316,7 +327,7 @@ impl InnerMultipart {
 
                 // read field headers for next field
                 if self.state == InnerState::Headers {
-                    if let Some(headers) = InnerMultipart::read_headers(&mut payload)? {
+                    if let Some(headers) = Self::read_headers(&mut payload)? {
                         self.state = InnerState::Boundary;
                         headers
                     } else {
@@ -330,10 +341,6 @@ impl InnerMultipart {
                 return Poll::Pending;
             };
 
-            let cd = headers
-                .get(&header::CONTENT_DISPOSITION)
-                .and_then(|cd| ContentDisposition::from_raw(cd).ok());
-
             let ct: Option<mime::Mime> = headers
                 .get(&header::CONTENT_TYPE)
                 .and_then(|ct| ct.to_str().ok())
@@ -349,22 +356,16 @@ impl InnerMultipart {
             }
 
             let field =
-                InnerField::new_in_rc(self.payload.clone(), self.boundary.clone(), &headers)?;
+                InnerField::new_in_arc(self.payload.clone(), self.boundary.clone(), &headers)?;
 
-            self.item = InnerMultipartItem::Object(Rc::clone(&field));
+            self.item = InnerMultipartItem::Object(Arc::clone(&field));
 
-            Poll::Ready(Some(Ok(Object::new(
-                safety.clone(cx),
-                headers,
-                ct,
-                cd,
-                field,
-            ))))
+            Poll::Ready(Some(Ok(Object::new(safety.clone(cx), headers, ct, field))))
         }
     }
 }
 
-impl Drop for InnerMultipart {
+impl<S> Drop for InnerMultipart<S> {
     fn drop(&mut self) {
         // InnerMultipartItem::Object has to be dropped first because of Safety.
         self.item = InnerMultipartItem::None;
@@ -372,25 +373,22 @@ impl Drop for InnerMultipart {
 }
 
 /// A single field in a multipart stream
-pub struct Object {
+pub struct Object<S> {
     ct: Option<mime::Mime>,
-    cd: Option<ContentDisposition>,
     headers: HeaderMap,
-    inner: Rc<RefCell<InnerField>>,
+    inner: Arc<Mutex<InnerField<S>>>,
     safety: Safety,
 }
 
-impl Object {
+impl<S> Object<S> {
     fn new(
         safety: Safety,
         headers: HeaderMap,
         ct: Option<mime::Mime>,
-        cd: Option<ContentDisposition>,
-        inner: Rc<RefCell<InnerField>>,
+        inner: Arc<Mutex<InnerField<S>>>,
     ) -> Self {
         Object {
             ct,
-            cd,
             headers,
             inner,
             safety,
@@ -410,30 +408,18 @@ impl Object {
     pub fn content_type(&self) -> Option<&mime::Mime> {
         self.ct.as_ref()
     }
-
-    /// Returns the field's Content-Disposition.
-    ///
-    /// Per [RFC 7578 §4.2]: "Each part MUST contain a Content-Disposition header field where the
-    /// disposition type is `form-data`. The Content-Disposition header field MUST also contain an
-    /// additional parameter of `name`; the value of the `name` parameter is the original field name
-    /// from the form."
-    ///
-    /// This crate validates that it exists before returning a `Object`. As such, it is safe to
-    /// unwrap `.content_disposition().get_name()`. The [name](Self::name) method is provided as
-    /// a convenience.
-    ///
-    /// [RFC 7578 §4.2]: https://datatracker.ietf.org/doc/html/rfc7578#section-4.2
-    pub fn content_disposition(&self) -> Option<&ContentDisposition> {
-        self.cd.as_ref()
-    }
 }
 
-impl Stream for Object {
+impl<S, E> Stream for Object<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
     type Item = Result<Bytes, MultipartError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
-        let mut inner = this.inner.borrow_mut();
+        let mut inner = lock(&this.inner);
         if let Some(mut buffer) = inner.payload.as_ref().unwrap().get_mut(&this.safety) {
             // check safety and poll read payload to buffer.
             buffer.poll_stream(cx)?;
@@ -448,14 +434,14 @@ impl Stream for Object {
     }
 }
 
-impl fmt::Debug for Object {
+impl<S> fmt::Debug for Object<S> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Some(ct) = &self.ct {
             writeln!(f, "\nField: {}", ct)?;
         } else {
             writeln!(f, "\nField:")?;
         }
-        writeln!(f, "  boundary: {}", self.inner.borrow().boundary)?;
+        writeln!(f, "  boundary: {}", lock(&self.inner).boundary)?;
         writeln!(f, "  headers:")?;
         for (key, val) in self.headers.iter() {
             writeln!(f, "    {:?}: {:?}", key, val)?;
@@ -464,31 +450,35 @@ impl fmt::Debug for Object {
     }
 }
 
-struct InnerField {
-    payload: Option<PayloadRef>,
+struct InnerField<S> {
+    payload: Option<PayloadRef<S>>,
     boundary: String,
     eof: bool,
     length: Option<u64>,
 }
 
-impl InnerField {
-    fn new_in_rc(
-        payload: PayloadRef,
+impl<S, E> InnerField<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    fn new_in_arc(
+        payload: PayloadRef<S>,
         boundary: String,
         headers: &HeaderMap,
-    ) -> Result<Rc<RefCell<InnerField>>, PayloadError> {
-        Self::new(payload, boundary, headers).map(|this| Rc::new(RefCell::new(this)))
+    ) -> Result<Arc<Mutex<InnerField<S>>>, MultipartError> {
+        Self::new(payload, boundary, headers).map(|this| Arc::new(Mutex::new(this)))
     }
 
     fn new(
-        payload: PayloadRef,
+        payload: PayloadRef<S>,
         boundary: String,
         headers: &HeaderMap,
-    ) -> Result<InnerField, PayloadError> {
+    ) -> Result<InnerField<S>, MultipartError> {
         let len = if let Some(len) = headers.get(&header::CONTENT_LENGTH) {
             match len.to_str().ok().and_then(|len| len.parse::<u64>().ok()) {
                 Some(len) => Some(len),
-                None => return Err(PayloadError::Incomplete(None)),
+                None => return Err(MultipartError::Header),
             }
         } else {
             None
@@ -505,7 +495,7 @@ impl InnerField {
     /// Reads body part content chunk of the specified size.
     /// The body part must has `Content-Length` header with proper value.
     fn read_len(
-        payload: &mut PayloadBuffer,
+        payload: &mut PayloadBuffer<S>,
         size: &mut u64,
     ) -> Poll<Option<Result<Bytes, MultipartError>>> {
         if *size == 0 {
@@ -535,7 +525,7 @@ impl InnerField {
     /// Reads content chunk of body part with unknown length.
     /// The `Content-Length` header for body part is not necessary.
     fn read_stream(
-        payload: &mut PayloadBuffer,
+        payload: &mut PayloadBuffer<S>,
         boundary: &str,
     ) -> Poll<Option<Result<Bytes, MultipartError>>> {
         let mut pos = 0;
@@ -649,71 +639,76 @@ impl InnerField {
     }
 }
 
-struct PayloadRef {
-    payload: Rc<RefCell<PayloadBuffer>>,
+struct PayloadRef<S> {
+    payload: Arc<Mutex<PayloadBuffer<S>>>,
 }
 
-impl PayloadRef {
-    fn new(payload: PayloadBuffer) -> PayloadRef {
+impl<S> PayloadRef<S> {
+    fn new(payload: PayloadBuffer<S>) -> PayloadRef<S> {
         PayloadRef {
-            payload: Rc::new(payload.into()),
+            payload: Arc::new(Mutex::new(payload)),
         }
     }
 
-    fn get_mut(&self, s: &Safety) -> Option<RefMut<'_, PayloadBuffer>> {
+    fn get_mut(&self, s: &Safety) -> Option<MutexGuard<'_, PayloadBuffer<S>>> {
         if s.current() {
-            Some(self.payload.borrow_mut())
+            Some(lock(&self.payload))
         } else {
             None
         }
     }
 }
 
-impl Clone for PayloadRef {
-    fn clone(&self) -> PayloadRef {
+impl<S> Clone for PayloadRef<S> {
+    fn clone(&self) -> PayloadRef<S> {
         PayloadRef {
-            payload: Rc::clone(&self.payload),
+            payload: Arc::clone(&self.payload),
         }
     }
 }
 
+/// Lock a mutex, even if another thread panicked while holding it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 /// Counter. It tracks of number of clones of payloads and give access to payload only to top most.
 /// * When dropped, parent task is awakened. This is to support the case where Object is
-/// dropped in a separate task than MultipartReader.
+///   dropped in a separate task than MultipartReader.
 /// * Assumes that parent owners don't move to different tasks; only the top-most is allowed to.
 /// * If dropped and is not top most owner, is_clean flag is set to false.
 #[derive(Debug)]
 struct Safety {
-    task: LocalWaker,
+    task: AtomicWaker,
     level: usize,
-    payload: Rc<PhantomData<bool>>,
-    clean: Rc<Cell<bool>>,
+    payload: Arc<()>,
+    clean: Arc<AtomicBool>,
 }
 
 impl Safety {
     fn new() -> Safety {
-        let payload = Rc::new(PhantomData);
+        let payload = Arc::new(());
         Safety {
-            task: LocalWaker::new(),
-            level: Rc::strong_count(&payload),
-            clean: Rc::new(Cell::new(true)),
+            task: AtomicWaker::new(),
+            level: Arc::strong_count(&payload),
+            clean: Arc::new(AtomicBool::new(true)),
             payload,
         }
     }
 
     fn current(&self) -> bool {
-        Rc::strong_count(&self.payload) == self.level && self.clean.get()
+        Arc::strong_count(&self.payload) == self.level && self.is_clean()
     }
 
     fn is_clean(&self) -> bool {
-        self.clean.get()
+        self.clean.load(Ordering::Acquire)
     }
 
     fn clone(&self, cx: &Context<'_>) -> Safety {
-        let payload = Rc::clone(&self.payload);
+        let payload = Arc::clone(&self.payload);
         let s = Safety {
-            task: LocalWaker::new(),
-            level: Rc::strong_count(&payload),
+            task: AtomicWaker::new(),
+            level: Arc::strong_count(&payload),
             clean: self.clean.clone(),
             payload,
         };
@@ -724,9 +719,9 @@ impl Safety {
 
 impl Drop for Safety {
     fn drop(&mut self) {
-        if Rc::strong_count(&self.payload) != self.level {
+        if Arc::strong_count(&self.payload) != self.level {
             // MultipartReader dropped leaving a Object
-            self.clean.set(false);
+            self.clean.store(false, Ordering::Release);
         }
 
         self.task.wake();
@@ -734,18 +729,19 @@ impl Drop for Safety {
 }
 
 /// Payload buffer.
-struct PayloadBuffer {
+struct PayloadBuffer<S> {
     eof: bool,
     buf: BytesMut,
-    stream: LocalBoxStream<'static, Result<Bytes, PayloadError>>,
+    stream: Pin<Box<S>>,
 }
 
-impl PayloadBuffer {
+impl<S, E> PayloadBuffer<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
     /// Constructs new `PayloadBuffer` instance.
-    fn new<S>(stream: S) -> Self
-    where
-        S: Stream<Item = Result<Bytes, PayloadError>> + 'static,
-    {
+    fn new(stream: S) -> Self {
         PayloadBuffer {
             eof: false,
             buf: BytesMut::new(),
@@ -753,11 +749,11 @@ impl PayloadBuffer {
         }
     }
 
-    fn poll_stream(&mut self, cx: &mut Context<'_>) -> Result<(), PayloadError> {
+    fn poll_stream(&mut self, cx: &mut Context<'_>) -> Result<(), MultipartError> {
         loop {
-            match Pin::new(&mut self.stream).poll_next(cx) {
+            match self.stream.as_mut().poll_next(cx) {
                 Poll::Ready(Some(Ok(data))) => self.buf.extend_from_slice(&data),
-                Poll::Ready(Some(Err(err))) => return Err(err),
+                Poll::Ready(Some(Err(err))) => return Err(MultipartError::Payload(err.into())),
                 Poll::Ready(None) => {
                     self.eof = true;
                     return Ok(());
@@ -766,7 +762,9 @@ impl PayloadBuffer {
             }
         }
     }
+}
 
+impl<S> PayloadBuffer<S> {
     fn read_max(&mut self, size: u64) -> Result<Option<Bytes>, MultipartError> {
         if !self.buf.is_empty() {
             let size = std::cmp::min(self.buf.len() as u64, size) as usize;
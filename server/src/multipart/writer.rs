@@ -7,6 +7,12 @@ pub struct MultipartWriter {
     first: bool,
 }
 
+impl Default for MultipartWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MultipartWriter {
     pub fn new() -> MultipartWriter {
         MultipartWriter {
@@ -24,7 +30,7 @@ impl MultipartWriter {
         }
     }
 
-    pub fn add(self: &mut Self, mut reader: impl Read, headers: &str) -> io::Result<u64> {
+    pub fn add(&mut self, mut reader: impl Read, headers: &str) -> io::Result<u64> {
         // writer for our buffer
         let mut writer = std::io::BufWriter::new(&mut self.data);
 
@@ -32,6 +38,7 @@ impl MultipartWriter {
         if !self.first {
             writer.write_all(b"\r\n").unwrap();
         }
+        self.first = false;
 
         writer.write_all(b"--").unwrap();
         writer.write_all(self.boundary.as_bytes()).unwrap();
@@ -48,7 +55,7 @@ impl MultipartWriter {
         io::copy(&mut reader, &mut writer)
     }
 
-    pub fn finish(self: &mut Self) {
+    pub fn finish(&mut self) {
         // writer for our buffer
         let mut writer = std::io::BufWriter::new(&mut self.data);
 
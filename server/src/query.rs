@@ -0,0 +1,108 @@
+use dicom::{
+    core::dictionary::{DataDictionary, DataDictionaryEntry},
+    dictionary_std::StandardDataDictionary,
+};
+use dicom_object::Tag;
+use serde::de::DeserializeOwned;
+
+use crate::{QidoInstanceQuery, QidoSeriesQuery, QidoStudyQuery};
+
+// Query parameters which are not matching attributes
+pub(crate) const STUDY_PARAMS: [&str; 5] = [
+    "limit",
+    "offset",
+    "fuzzymatching",
+    "includerejected",
+    "includefield",
+];
+pub(crate) const SERIES_PARAMS: [&str; 7] = [
+    "limit",
+    "offset",
+    "includefield",
+    "includerejected",
+    "modality",
+    "series_instance_uid",
+    "series_description",
+];
+pub(crate) const INSTANCE_PARAMS: [&str; 6] = [
+    "limit",
+    "offset",
+    "includefield",
+    "includerejected",
+    "sop_instance_uid",
+    "instance_number",
+];
+
+/// Parse an attribute, which is given by keyword or as GGGGEEEE tag
+pub fn parse_attribute(name: &str) -> Option<Tag> {
+    if name.len() == 8 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+        let group = u16::from_str_radix(&name[..4], 16).ok()?;
+        let element = u16::from_str_radix(&name[4..], 16).ok()?;
+        return Some(Tag(group, element));
+    }
+
+    StandardDataDictionary
+        .by_name(name)
+        .map(|entry| entry.tag())
+}
+
+fn parse_query<T: DeserializeOwned>(query: &str) -> Result<(T, Vec<(String, String)>), String> {
+    let parsed: T = serde_urlencoded::from_str(query)
+        .map_err(|e| format!("Failed to deserialize query string: {}", e))?;
+    let params: Vec<(String, String)> = serde_urlencoded::from_str(query)
+        .map_err(|e| format!("Failed to deserialize query string: {}", e))?;
+    Ok((parsed, params))
+}
+
+fn parse_matches(params: &[(String, String)], known: &[&str]) -> Vec<(Tag, String)> {
+    params
+        .iter()
+        .filter(|(name, _)| !known.contains(&name.as_str()))
+        .filter_map(|(name, value)| match parse_attribute(name) {
+            Some(tag) => Some((tag, value.clone())),
+            None => {
+                log::debug!("Ignoring unknown query parameter {}", name);
+                None
+            }
+        })
+        .collect()
+}
+
+impl QidoStudyQuery {
+    /// Parse a QIDO-RS query string.
+    ///
+    /// Parameters, which are not part of the query, are matching attributes.
+    pub fn from_query_string(query: &str) -> Result<QidoStudyQuery, String> {
+        let (mut parsed, params): (QidoStudyQuery, _) = parse_query(query)?;
+        parsed.includefields = params
+            .iter()
+            .filter(|(name, _)| name == "includefield")
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::to_string)
+            .collect();
+        parsed.matches = parse_matches(&params, &STUDY_PARAMS);
+        Ok(parsed)
+    }
+}
+
+impl QidoSeriesQuery {
+    /// Parse a QIDO-RS query string.
+    ///
+    /// Parameters, which are not part of the query, are matching attributes.
+    pub fn from_query_string(query: &str) -> Result<QidoSeriesQuery, String> {
+        let (mut parsed, params): (QidoSeriesQuery, _) = parse_query(query)?;
+        parsed.matches = parse_matches(&params, &SERIES_PARAMS);
+        Ok(parsed)
+    }
+}
+
+impl QidoInstanceQuery {
+    /// Parse a QIDO-RS query string.
+    ///
+    /// Parameters, which are not part of the query, are matching attributes.
+    pub fn from_query_string(query: &str) -> Result<QidoInstanceQuery, String> {
+        let (mut parsed, params): (QidoInstanceQuery, _) = parse_query(query)?;
+        parsed.matches = parse_matches(&params, &INSTANCE_PARAMS);
+        Ok(parsed)
+    }
+}
@@ -0,0 +1,279 @@
+use dicom::{
+    core::{DataElement, PrimitiveValue, VR},
+    dictionary_std::tags,
+    encoding::TransferSyntaxIndex,
+    transfer_syntax::{entries::EXPLICIT_VR_LITTLE_ENDIAN, TransferSyntaxRegistry},
+};
+use dicom_object::{FileDicomObject, InMemDicomObject, Tag};
+use dicom_pixeldata::{
+    PhotometricInterpretation, PixelDecoder, PixelRepresentation, PlanarConfiguration, Transcode,
+};
+
+use crate::backend::BackendError;
+
+/// Region of an image, in pixels from its top left corner
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rectangle {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Regions with burned-in annotations in the images of a device.
+///
+/// A rule applies to the images matching all of its criteria, e.g. the ultrasound
+/// images of one model, which always show the patient name at the same place.
+#[derive(Clone, Debug, Default)]
+pub struct RedactionRule {
+    manufacturer: Option<String>,
+    model: Option<String>,
+    /// Columns and rows
+    size: Option<(u32, u32)>,
+    rectangles: Vec<Rectangle>,
+}
+
+impl RedactionRule {
+    pub fn new() -> RedactionRule {
+        RedactionRule::default()
+    }
+
+    /// Only apply to images of this Manufacturer, ignoring case
+    pub fn with_manufacturer(mut self, manufacturer: impl Into<String>) -> RedactionRule {
+        self.manufacturer = Some(manufacturer.into());
+        self
+    }
+
+    /// Only apply to images of this ManufacturerModelName, ignoring case
+    pub fn with_model(mut self, model: impl Into<String>) -> RedactionRule {
+        self.model = Some(model.into());
+        self
+    }
+
+    /// Only apply to images of this size
+    pub fn with_size(mut self, columns: u32, rows: u32) -> RedactionRule {
+        self.size = Some((columns, rows));
+        self
+    }
+
+    /// Black out this region, parts outside of the image are ignored
+    pub fn with_rectangle(mut self, x: u32, y: u32, width: u32, height: u32) -> RedactionRule {
+        self.rectangles.push(Rectangle {
+            x,
+            y,
+            width,
+            height,
+        });
+        self
+    }
+
+    pub(crate) fn matches(&self, dcm: &InMemDicomObject) -> bool {
+        let matches_string = |expected: &Option<String>, tag: Tag| {
+            expected.as_ref().is_none_or(|expected| {
+                string_value(dcm, tag).is_some_and(|value| value.eq_ignore_ascii_case(expected))
+            })
+        };
+        let matches_size = self.size.is_none_or(|(columns, rows)| {
+            int_value(dcm, tags::COLUMNS) == Some(columns)
+                && int_value(dcm, tags::ROWS) == Some(rows)
+        });
+        dcm.get(tags::PIXEL_DATA).is_some()
+            && matches_string(&self.manufacturer, tags::MANUFACTURER)
+            && matches_string(&self.model, tags::MANUFACTURER_MODEL_NAME)
+            && matches_size
+    }
+
+    pub(crate) fn rectangles(&self) -> &[Rectangle] {
+        &self.rectangles
+    }
+}
+
+fn string_value(dcm: &InMemDicomObject, tag: Tag) -> Option<String> {
+    dcm.get(tag)
+        .and_then(|elt| elt.to_str().ok())
+        .map(|value| value.trim_end_matches('\0').trim().to_string())
+}
+
+fn int_value(dcm: &InMemDicomObject, tag: Tag) -> Option<u32> {
+    dcm.get(tag).and_then(|elt| elt.to_int::<u32>().ok())
+}
+
+/// Sample values of a black pixel
+fn black(
+    photometric_interpretation: &PhotometricInterpretation,
+    samples_per_pixel: u16,
+    bits_allocated: u16,
+    bits_stored: u16,
+    pixel_representation: PixelRepresentation,
+) -> Result<Vec<i64>, BackendError> {
+    // The sample range is derived from the bits stored, which must fit the bits allocated
+    if bits_stored == 0 || bits_stored > bits_allocated {
+        return Err(format!(
+            "Can't redact pixel data with {} bits stored of {} bits allocated",
+            bits_stored, bits_allocated
+        )
+        .into());
+    }
+    let (min, max) = match pixel_representation {
+        PixelRepresentation::Unsigned => (0, (1i64 << bits_stored) - 1),
+        PixelRepresentation::Signed => (
+            -(1i64 << (bits_stored - 1)),
+            (1i64 << (bits_stored - 1)) - 1,
+        ),
+    };
+    let black = match (photometric_interpretation, samples_per_pixel) {
+        (PhotometricInterpretation::Monochrome1, 1) => vec![max],
+        (PhotometricInterpretation::Monochrome2, 1)
+        | (PhotometricInterpretation::PaletteColor, 1)
+        | (PhotometricInterpretation::Rgb, 3) => vec![min; samples_per_pixel as usize],
+        // Zero luminance with neutral chrominance
+        (PhotometricInterpretation::YbrFull, 3) => {
+            vec![0, 1 << (bits_stored - 1), 1 << (bits_stored - 1)]
+        }
+        (photometric_interpretation, _) => {
+            return Err(format!(
+                "Can't redact pixel data with photometric interpretation {}",
+                photometric_interpretation
+            )
+            .into())
+        }
+    };
+    Ok(black)
+}
+
+/// Black out the regions in every frame and set BurnedInAnnotation to NO.
+///
+/// Encapsulated pixel data is decoded and encoded again with its transfer syntax, or
+/// returned uncompressed if there is no encoder for it.
+pub(crate) fn redact(
+    file: &mut FileDicomObject<InMemDicomObject>,
+    rectangles: &[Rectangle],
+) -> Result<(), BackendError> {
+    let transfer_syntax_uid = file
+        .meta()
+        .transfer_syntax()
+        .trim_end_matches(['\0', ' '])
+        .to_string();
+    let transfer_syntax = TransferSyntaxRegistry
+        .get(&transfer_syntax_uid)
+        .ok_or_else(|| format!("Unknown transfer syntax {}", transfer_syntax_uid))?;
+
+    let pixel_data = file.decode_pixel_data()?;
+    let bits_allocated = pixel_data.bits_allocated();
+    if bits_allocated != 8 && bits_allocated != 16 {
+        return Err(format!(
+            "Can't redact pixel data with {} bits allocated",
+            bits_allocated
+        )
+        .into());
+    }
+    let columns = pixel_data.columns() as usize;
+    let rows = pixel_data.rows() as usize;
+    let samples_per_pixel = pixel_data.samples_per_pixel() as usize;
+    let bytes_per_sample = bits_allocated as usize / 8;
+    let frame_size = columns * rows * samples_per_pixel * bytes_per_sample;
+    let planar = pixel_data.planar_configuration() == PlanarConfiguration::PixelFirst;
+    // Decoders label the pixels as MONOCHROME2 without inverting them
+    let photometric_interpretation = if samples_per_pixel == 1 {
+        string_value(file, tags::PHOTOMETRIC_INTERPRETATION)
+            .map(|value| PhotometricInterpretation::from(value.as_str()))
+            .unwrap_or(PhotometricInterpretation::Monochrome2)
+    } else {
+        pixel_data.photometric_interpretation().clone()
+    };
+    let black = black(
+        &photometric_interpretation,
+        pixel_data.samples_per_pixel(),
+        bits_allocated,
+        pixel_data.bits_stored(),
+        pixel_data.pixel_representation(),
+    )?;
+    let mut data = pixel_data.data().to_vec();
+    if data.len() < frame_size * pixel_data.number_of_frames() as usize {
+        return Err("The pixel data is shorter than its frames".into());
+    }
+    let planar_configuration = pixel_data.planar_configuration();
+    let number_of_frames = pixel_data.number_of_frames() as usize;
+
+    for frame in 0..number_of_frames {
+        for rectangle in rectangles {
+            let x_end = columns.min(rectangle.x.saturating_add(rectangle.width) as usize);
+            let y_end = rows.min(rectangle.y.saturating_add(rectangle.height) as usize);
+            for y in (rectangle.y as usize)..y_end {
+                for x in (rectangle.x as usize)..x_end {
+                    for (sample, value) in black.iter().enumerate() {
+                        let index = if planar {
+                            sample * rows * columns + y * columns + x
+                        } else {
+                            (y * columns + x) * samples_per_pixel + sample
+                        };
+                        let offset = frame * frame_size + index * bytes_per_sample;
+                        data[offset..offset + bytes_per_sample]
+                            .copy_from_slice(&value.to_le_bytes()[..bytes_per_sample]);
+                    }
+                }
+            }
+        }
+    }
+
+    let value = if bits_allocated == 16 {
+        PrimitiveValue::U16(
+            data.chunks_exact(2)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                .collect(),
+        )
+    } else {
+        PrimitiveValue::from(data)
+    };
+    let vr = if bits_allocated == 16 { VR::OW } else { VR::OB };
+    file.put(DataElement::new(tags::PIXEL_DATA, vr, value));
+    file.put_str(
+        tags::PHOTOMETRIC_INTERPRETATION,
+        VR::CS,
+        photometric_interpretation.as_str(),
+    );
+    if samples_per_pixel > 1 {
+        file.put(DataElement::new(
+            tags::PLANAR_CONFIGURATION,
+            VR::US,
+            PrimitiveValue::from(planar_configuration as u16),
+        ));
+    }
+    file.put_str(tags::BURNED_IN_ANNOTATION, VR::CS, "NO");
+
+    if !transfer_syntax.is_codec_free() {
+        file.meta_mut()
+            .set_transfer_syntax(&EXPLICIT_VR_LITTLE_ENDIAN.erased());
+        let native = file.clone();
+        if let Err(e) = file.transcode(transfer_syntax) {
+            log::debug!(
+                "Returning redacted pixel data uncompressed, as it can't be encoded with {}: {}",
+                transfer_syntax_uid,
+                e
+            );
+            *file = native;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_bits_stored_outside_the_bits_allocated() {
+        let black = |bits_stored| {
+            black(
+                &PhotometricInterpretation::Monochrome2,
+                1,
+                16,
+                bits_stored,
+                PixelRepresentation::Signed,
+            )
+        };
+        assert!(black(0).is_err());
+        assert!(black(17).is_err());
+        assert_eq!(black(12).unwrap(), [-2048]);
+    }
+}
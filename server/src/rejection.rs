@@ -0,0 +1,151 @@
+use dicom::dictionary_std::{tags, uids};
+use dicom_object::{InMemDicomObject, Tag};
+
+// Rejection note document titles from CID 7011
+// http://dicom.nema.org/medical/dicom/current/output/chtml/part16/sect_CID_7011.html
+const CODING_SCHEME_DCM: &str = "DCM";
+
+/// Document title of an IHE IOCM rejection note
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionReason {
+    /// (113001, DCM, "Rejected for Quality Reasons")
+    QualityReasons,
+    /// (113037, DCM, "Rejected for Patient Safety Reasons")
+    PatientSafetyReasons,
+    /// (113038, DCM, "Incorrect Modality Worklist Entry")
+    IncorrectModalityWorklistEntry,
+    /// (113039, DCM, "Data Retention Policy Expired")
+    DataRetentionPolicyExpired,
+}
+
+impl RejectionReason {
+    pub fn from_code(coding_scheme: &str, code_value: &str) -> Option<RejectionReason> {
+        if coding_scheme != CODING_SCHEME_DCM {
+            return None;
+        }
+
+        match code_value {
+            "113001" => Some(RejectionReason::QualityReasons),
+            "113037" => Some(RejectionReason::PatientSafetyReasons),
+            "113038" => Some(RejectionReason::IncorrectModalityWorklistEntry),
+            "113039" => Some(RejectionReason::DataRetentionPolicyExpired),
+            _ => None,
+        }
+    }
+
+    pub fn code_value(&self) -> &'static str {
+        match self {
+            RejectionReason::QualityReasons => "113001",
+            RejectionReason::PatientSafetyReasons => "113037",
+            RejectionReason::IncorrectModalityWorklistEntry => "113038",
+            RejectionReason::DataRetentionPolicyExpired => "113039",
+        }
+    }
+
+    pub fn code_meaning(&self) -> &'static str {
+        match self {
+            RejectionReason::QualityReasons => "Rejected for Quality Reasons",
+            RejectionReason::PatientSafetyReasons => "Rejected for Patient Safety Reasons",
+            RejectionReason::IncorrectModalityWorklistEntry => "Incorrect Modality Worklist Entry",
+            RejectionReason::DataRetentionPolicyExpired => "Data Retention Policy Expired",
+        }
+    }
+}
+
+/// Identifies a single stored instance
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InstanceReference {
+    pub study_instance_uid: String,
+    pub series_instance_uid: String,
+    pub sop_instance_uid: String,
+}
+
+impl InstanceReference {
+    /// Read the identifying UIDs of a DICOM object
+    pub fn from_dicom(dcm: &InMemDicomObject) -> Option<InstanceReference> {
+        Some(InstanceReference {
+            study_instance_uid: string_value(dcm, tags::STUDY_INSTANCE_UID)?,
+            series_instance_uid: string_value(dcm, tags::SERIES_INSTANCE_UID)?,
+            sop_instance_uid: string_value(dcm, tags::SOP_INSTANCE_UID)?,
+        })
+    }
+
+    /// Check if a DICOM object is the referenced instance, in the referenced study and series
+    pub fn matches(&self, dcm: &InMemDicomObject) -> bool {
+        InstanceReference::from_dicom(dcm).as_ref() == Some(self)
+    }
+}
+
+/// Key Object Selection document which rejects previously stored instances
+///
+/// See IHE RAD TF-1 Imaging Object Change Management (IOCM) for more information
+#[derive(Debug, Clone)]
+pub struct RejectionNote {
+    pub reason: RejectionReason,
+    /// SOP Instance UID of the rejection note itself
+    pub sop_instance_uid: String,
+    /// The instances which are rejected by this note
+    pub instances: Vec<InstanceReference>,
+}
+
+impl RejectionNote {
+    /// Parse a rejection note from a DICOM object.
+    ///
+    /// Returns `None` if the object is not a Key Object Selection document
+    /// with one of the rejection document titles.
+    pub fn from_dicom(dcm: &InMemDicomObject) -> Option<RejectionNote> {
+        if string_value(dcm, tags::SOP_CLASS_UID)? != uids::KEY_OBJECT_SELECTION_DOCUMENT_STORAGE {
+            return None;
+        }
+
+        let title = dcm
+            .element(tags::CONCEPT_NAME_CODE_SEQUENCE)
+            .ok()?
+            .items()?
+            .first()?;
+        let reason = RejectionReason::from_code(
+            &string_value(title, tags::CODING_SCHEME_DESIGNATOR)?,
+            &string_value(title, tags::CODE_VALUE)?,
+        )?;
+
+        // Collect the referenced instances from the evidence sequence
+        let mut instances = Vec::new();
+        for study in sequence_items(dcm, tags::CURRENT_REQUESTED_PROCEDURE_EVIDENCE_SEQUENCE) {
+            let Some(study_uid) = string_value(study, tags::STUDY_INSTANCE_UID) else {
+                continue;
+            };
+            for series in sequence_items(study, tags::REFERENCED_SERIES_SEQUENCE) {
+                let Some(series_uid) = string_value(series, tags::SERIES_INSTANCE_UID) else {
+                    continue;
+                };
+                for sop in sequence_items(series, tags::REFERENCED_SOP_SEQUENCE) {
+                    if let Some(sop_uid) = string_value(sop, tags::REFERENCED_SOP_INSTANCE_UID) {
+                        instances.push(InstanceReference {
+                            study_instance_uid: study_uid.clone(),
+                            series_instance_uid: series_uid.clone(),
+                            sop_instance_uid: sop_uid,
+                        });
+                    }
+                }
+            }
+        }
+
+        Some(RejectionNote {
+            reason,
+            sop_instance_uid: string_value(dcm, tags::SOP_INSTANCE_UID)?,
+            instances,
+        })
+    }
+}
+
+fn string_value(dcm: &InMemDicomObject, tag: Tag) -> Option<String> {
+    let value = dcm.element(tag).ok()?.to_str().ok()?;
+    Some(value.trim_end_matches(['\0', ' ']).to_string())
+}
+
+fn sequence_items(dcm: &InMemDicomObject, tag: Tag) -> &[InMemDicomObject] {
+    dcm.element(tag)
+        .ok()
+        .and_then(|elt| elt.items())
+        .unwrap_or_default()
+}
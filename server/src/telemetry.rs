@@ -0,0 +1,51 @@
+//! OpenTelemetry export of the tracing spans
+//!
+//! The endpoints record spans with the requested UIDs, the query and the number of
+//! results, with child spans for the backend calls and the multipart parsing.
+//! [`Telemetry`] exports them with OTLP over HTTP, e.g. to a local collector.
+
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing::Subscriber;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+use crate::backend::BackendError;
+
+/// Exporter of the spans, add its [`layer`](Telemetry::layer) to the `tracing` subscriber
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Export the spans in batches to an OTLP/HTTP endpoint, e.g. `http://localhost:4318/v1/traces`
+    pub fn new(endpoint: &str, service_name: &str) -> Result<Telemetry, BackendError> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(service_name.to_string())
+                    .build(),
+            )
+            .build();
+        Ok(Telemetry { provider })
+    }
+
+    /// Layer exporting the spans, e.g. `tracing_subscriber::registry().with(telemetry.layer())`
+    pub fn layer<S>(&self) -> impl Layer<S>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.provider.tracer("dicomweb-server"))
+    }
+
+    /// Export the remaining spans, before the application exits
+    pub fn shutdown(&self) -> Result<(), BackendError> {
+        self.provider.shutdown()?;
+        Ok(())
+    }
+}
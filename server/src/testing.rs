@@ -0,0 +1,63 @@
+//! Fixtures of the tests
+
+use dicom::{
+    core::{DataElement, PrimitiveValue, VR},
+    dictionary_std::{tags, uids},
+};
+use dicom_object::{
+    mem::InMemElement, meta::FileMetaTableBuilder, FileDicomObject, InMemDicomObject,
+};
+
+use crate::auth::Principal;
+
+/// Image pixel module of `frames` 8 bit grayscale frames of `rows` x `columns` pixels,
+/// whose pixels hold their frame number
+pub(crate) fn grayscale_frames(rows: u16, columns: u16, frames: u8) -> [InMemElement; 10] {
+    let frame_length = usize::from(rows) * usize::from(columns);
+    let mut pixel_data: Vec<u8> = (1..=frames)
+        .flat_map(|frame| std::iter::repeat_n(frame, frame_length))
+        .collect();
+    // Padded to even length
+    pixel_data.resize(pixel_data.len() + pixel_data.len() % 2, 0);
+    [
+        DataElement::new(tags::SAMPLES_PER_PIXEL, VR::US, PrimitiveValue::from(1_u16)),
+        DataElement::new(
+            tags::PHOTOMETRIC_INTERPRETATION,
+            VR::CS,
+            PrimitiveValue::from("MONOCHROME2"),
+        ),
+        DataElement::new(
+            tags::NUMBER_OF_FRAMES,
+            VR::IS,
+            PrimitiveValue::from(frames.to_string()),
+        ),
+        DataElement::new(tags::ROWS, VR::US, PrimitiveValue::from(rows)),
+        DataElement::new(tags::COLUMNS, VR::US, PrimitiveValue::from(columns)),
+        DataElement::new(tags::BITS_ALLOCATED, VR::US, PrimitiveValue::from(8_u16)),
+        DataElement::new(tags::BITS_STORED, VR::US, PrimitiveValue::from(8_u16)),
+        DataElement::new(tags::HIGH_BIT, VR::US, PrimitiveValue::from(7_u16)),
+        DataElement::new(
+            tags::PIXEL_REPRESENTATION,
+            VR::US,
+            PrimitiveValue::from(0_u16),
+        ),
+        DataElement::new(tags::PIXEL_DATA, VR::OB, PrimitiveValue::from(pixel_data)),
+    ]
+}
+
+/// Wrap a data set into a file, whose meta group refers to its SOP Class and Instance UID
+pub(crate) fn file(dataset: InMemDicomObject) -> FileDicomObject<InMemDicomObject> {
+    let uid = |tag| {
+        let uid = dataset.element(tag).expect("UID of the fixture");
+        uid.to_str().expect("UID of the fixture").to_string()
+    };
+    let meta = FileMetaTableBuilder::new()
+        .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN)
+        .media_storage_sop_class_uid(uid(tags::SOP_CLASS_UID))
+        .media_storage_sop_instance_uid(uid(tags::SOP_INSTANCE_UID));
+    dataset.with_meta(meta).expect("Valid file meta group")
+}
+
+pub(crate) fn principal() -> Principal {
+    Principal::anonymous()
+}
@@ -0,0 +1,674 @@
+//! tower integration
+//!
+//! [`DicomWebService`] serves all endpoints as a `tower::Service`, so the server
+//! can be hosted by hyper or any other tower-based stack.
+
+#[cfg(feature = "metrics")]
+use std::time::Instant;
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use futures_util::TryStreamExt;
+use http::{header, HeaderMap, Method, Request, Response, StatusCode};
+use http_body::Body;
+use http_body_util::{BodyExt, Full};
+use serde::de::DeserializeOwned;
+use tower_service::Service;
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{self, Metrics};
+use crate::{
+    api::{
+        self, caching::Preconditions, capabilities::Deployment, error_response, DicomWebResponse,
+    },
+    auth::{Authentication, Principal},
+    backend::DicomWebBackend,
+};
+
+/// Serves the QIDO-RS, WADO-RS and STOW-RS endpoints, and their capabilities on `OPTIONS`.
+///
+/// Paths are matched from the root, use a nesting service of your stack to
+/// serve them under a prefix. Unknown paths respond with 404 Not Found, other methods
+/// of the resources with 405 Method Not Allowed.
+#[derive(Clone)]
+pub struct DicomWebService {
+    backend: Arc<dyn DicomWebBackend>,
+    /// Backends serving the paths below a route prefix
+    routes: Vec<(String, Arc<dyn DicomWebBackend>)>,
+    authentication: Option<Arc<Authentication>>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Metrics>,
+}
+
+impl DicomWebService {
+    pub fn new(backend: impl DicomWebBackend + 'static) -> DicomWebService {
+        DicomWebService {
+            backend: Arc::new(backend),
+            routes: Vec::new(),
+            authentication: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Serve the paths below a route prefix with another backend, e.g. a
+    /// [`DeidentifiedBackend`](crate::backend::DeidentifiedBackend) at `/research`
+    pub fn with_route_prefix(
+        mut self,
+        prefix: &str,
+        backend: impl DicomWebBackend + 'static,
+    ) -> DicomWebService {
+        self.routes
+            .push((format!("/{}", prefix.trim_matches('/')), Arc::new(backend)));
+        self
+    }
+
+    /// Backend of the route prefix of a request, whose path is made relative to the prefix
+    fn select<B>(&self, mut request: Request<B>) -> (Arc<dyn DicomWebBackend>, Request<B>) {
+        for (prefix, backend) in &self.routes {
+            let Some(path) = request
+                .uri()
+                .path()
+                .strip_prefix(prefix.as_str())
+                .filter(|path| path.is_empty() || path.starts_with('/'))
+            else {
+                continue;
+            };
+            let path = if path.is_empty() { "/" } else { path };
+            let path_and_query = match request.uri().query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path.to_string(),
+            };
+            if let Ok(uri) = path_and_query.parse() {
+                *request.uri_mut() = uri;
+            }
+            return (backend.clone(), request);
+        }
+        (self.backend.clone(), request)
+    }
+
+    /// Authenticate every request, without authentication requests fail with 500 Internal
+    /// Server Error instead of being served anonymously
+    pub fn with_authentication(mut self, authentication: Authentication) -> DicomWebService {
+        self.authentication = Some(Arc::new(authentication));
+        self
+    }
+
+    /// Record the metrics of the requests and serve them at `/metrics`, authenticated like the
+    /// other endpoints
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: Metrics) -> DicomWebService {
+        self.metrics = Some(metrics);
+        self
+    }
+}
+
+impl<B> Service<Request<B>> for DicomWebService
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let (backend, request) = self.select(request);
+        let principal = match &self.authentication {
+            Some(authentication) => {
+                api::authenticate(authentication, |name| header_str(request.headers(), name))
+            }
+            None => Err(api::unauthenticated_response()),
+        };
+        let authentication = self.authentication.clone();
+        #[cfg(feature = "metrics")]
+        let metrics = self.metrics.clone();
+        Box::pin(async move {
+            #[allow(unused_mut)]
+            let mut deployment = Deployment {
+                authentication: authentication.as_deref(),
+                ..Deployment::default()
+            };
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = metrics {
+                deployment.metrics = true;
+                let response =
+                    metered_respond(&metrics, backend.as_ref(), principal, request, deployment)
+                        .await;
+                return Ok(response.map(Full::new));
+            }
+            let response = respond(backend.as_ref(), principal, request, deployment).await;
+            Ok(response.map(Full::new))
+        })
+    }
+}
+
+async fn respond<B>(
+    backend: &dyn DicomWebBackend,
+    principal: Result<Principal, DicomWebResponse>,
+    request: Request<B>,
+    deployment: Deployment<'_>,
+) -> DicomWebResponse
+where
+    B: Body,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    match principal {
+        Ok(principal) => route(backend, &principal, request, deployment).await,
+        Err(response) => response,
+    }
+}
+
+/// Respond and record the metrics of the request, or serve the metrics
+#[cfg(feature = "metrics")]
+async fn metered_respond<B>(
+    metrics: &Metrics,
+    backend: &dyn DicomWebBackend,
+    principal: Result<Principal, DicomWebResponse>,
+    request: Request<B>,
+    deployment: Deployment<'_>,
+) -> DicomWebResponse
+where
+    B: Body,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    if request.method() == Method::GET && request.uri().path().trim_matches('/') == "metrics" {
+        return principal.map_or_else(|response| response, |_| metrics.response());
+    }
+    let Some(transaction) = metrics::transaction(request.method().as_str(), request.uri().path())
+    else {
+        return respond(backend, principal, request, deployment).await;
+    };
+    let start = Instant::now();
+    let received_bytes = metrics.received_bytes(transaction);
+    let request = request.map(|body| {
+        body.map_frame(move |frame| {
+            if let Some(data) = frame.data_ref() {
+                received_bytes.inc_by(data.remaining() as u64);
+            }
+            frame
+        })
+    });
+    let response = respond(backend, principal, request, deployment).await;
+    metrics.observe_request(
+        transaction,
+        response.status().as_u16(),
+        start.elapsed(),
+        response.body().len() as u64,
+    );
+    response
+}
+
+/// Get a header of the request as string
+fn header_str(headers: &HeaderMap, name: impl header::AsHeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn parse_query<T: DeserializeOwned>(query: Option<&str>) -> Result<T, String> {
+    serde_urlencoded::from_str(query.unwrap_or(""))
+        .map_err(|e| format!("Failed to deserialize query string: {}", e))
+}
+
+async fn route<B>(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    request: Request<B>,
+    deployment: Deployment<'_>,
+) -> DicomWebResponse
+where
+    B: Body,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let (parts, body) = request.into_parts();
+    let segments: Vec<&str> = parts.uri.path().trim_matches('/').split('/').collect();
+    let query = parts.uri.query();
+    let accept = header_str(&parts.headers, header::ACCEPT);
+    let content_type = header_str(&parts.headers, header::CONTENT_TYPE);
+    let preconditions = Preconditions::from_headers(|name| header_str(&parts.headers, name));
+
+    let result = match (&parts.method, segments.as_slice()) {
+        // QIDO-RS
+        (&Method::GET, ["studies"]) => {
+            Ok(api::qido::search_studies(backend, principal, accept, query).await)
+        }
+        (&Method::GET, ["series"]) => {
+            Ok(api::qido::search_series(backend, principal, accept, None, query).await)
+        }
+        (&Method::GET, ["instances"]) => {
+            Ok(api::qido::search_instances(backend, principal, accept, None, None, query).await)
+        }
+        (&Method::GET, ["studies", study_uid, "series"]) => {
+            Ok(api::qido::search_series(backend, principal, accept, Some(study_uid), query).await)
+        }
+        (&Method::GET, ["studies", study_uid, "instances"]) => Ok(api::qido::search_instances(
+            backend,
+            principal,
+            accept,
+            Some(study_uid),
+            None,
+            query,
+        )
+        .await),
+        (&Method::GET, ["studies", study_uid, "series", series_uid, "instances"]) => {
+            Ok(api::qido::search_instances(
+                backend,
+                principal,
+                accept,
+                Some(study_uid),
+                Some(series_uid),
+                query,
+            )
+            .await)
+        }
+        // WADO-RS
+        (&Method::GET, ["studies", study_uid]) => {
+            Ok(api::wado::retrieve_study(backend, principal, study_uid, &preconditions).await)
+        }
+        (&Method::GET, ["studies", study_uid, "metadata"]) => Ok(
+            api::wado::retrieve_study_metadata(backend, principal, study_uid, &preconditions).await,
+        ),
+        (&Method::GET, ["studies", study_uid, "series", series_uid]) => Ok(
+            api::wado::retrieve_series(backend, principal, study_uid, series_uid, &preconditions)
+                .await,
+        ),
+        (&Method::GET, ["studies", study_uid, "series", series_uid, "metadata"]) => {
+            Ok(api::wado::retrieve_series_metadata(
+                backend,
+                principal,
+                study_uid,
+                series_uid,
+                &preconditions,
+            )
+            .await)
+        }
+        (&Method::GET, ["studies", study_uid, "series", series_uid, "instances", instance_uid]) => {
+            Ok(api::wado::retrieve_instance(
+                backend,
+                principal,
+                study_uid,
+                series_uid,
+                instance_uid,
+                &preconditions,
+            )
+            .await)
+        }
+        (
+            &Method::GET,
+            ["studies", study_uid, "series", series_uid, "instances", instance_uid, "metadata"],
+        ) => Ok(api::wado::retrieve_instance_metadata(
+            backend,
+            principal,
+            study_uid,
+            series_uid,
+            instance_uid,
+            &preconditions,
+        )
+        .await),
+        (
+            &Method::GET,
+            ["studies", study_uid, "series", series_uid, "instances", instance_uid, "frames", frame_list],
+        ) => Ok(api::wado::retrieve_instance_frames(
+            backend,
+            principal,
+            study_uid,
+            series_uid,
+            instance_uid,
+            frame_list,
+            &preconditions,
+        )
+        .await),
+        // STOW-RS
+        (&Method::POST, ["studies"]) => {
+            Ok(store_instances(backend, principal, None, content_type, body).await)
+        }
+        (&Method::POST, ["studies", study_uid]) => {
+            Ok(store_instances(backend, principal, Some(study_uid), content_type, body).await)
+        }
+        // Metadata update
+        (&Method::PATCH, ["studies", study_uid]) => match (parse_query(query), collect(body).await)
+        {
+            (Ok(query), Ok(body)) => Ok(api::update::update_study(
+                backend,
+                principal,
+                study_uid,
+                content_type,
+                &query,
+                &body,
+            )
+            .await),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        },
+        (&Method::PATCH, ["studies", study_uid, "series", series_uid]) => {
+            match (parse_query(query), collect(body).await) {
+                (Ok(query), Ok(body)) => Ok(api::update::update_series(
+                    backend,
+                    principal,
+                    study_uid,
+                    series_uid,
+                    content_type,
+                    &query,
+                    &body,
+                )
+                .await),
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            }
+        }
+        // Capabilities
+        (&Method::OPTIONS, _)
+            if api::capabilities::allowed_methods(parts.uri.path(), deployment).is_some() =>
+        {
+            Ok(api::capabilities::capabilities(
+                parts.uri.path(),
+                accept,
+                deployment,
+            ))
+        }
+        // Other methods of the resources are not allowed, other paths don't exist
+        _ => match api::capabilities::allowed_methods(parts.uri.path(), deployment) {
+            Some(allow) => {
+                let mut response = api::status_response(StatusCode::METHOD_NOT_ALLOWED);
+                if let Ok(allow) = allow.join(", ").parse() {
+                    response.headers_mut().insert(header::ALLOW, allow);
+                }
+                Ok(response)
+            }
+            None => Ok(api::status_response(StatusCode::NOT_FOUND)),
+        },
+    };
+
+    // Invalid queries and bodies are rejected
+    result.unwrap_or_else(|e| error_response(StatusCode::BAD_REQUEST, e))
+}
+
+async fn store_instances<B>(
+    backend: &dyn DicomWebBackend,
+    principal: &Principal,
+    study_uid: Option<&str>,
+    content_type: Option<&str>,
+    body: B,
+) -> DicomWebResponse
+where
+    B: Body,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let stream = body
+        .into_data_stream()
+        .map_ok(|mut data| data.copy_to_bytes(data.remaining()));
+    api::stow::store_instances(backend, principal, study_uid, content_type, stream).await
+}
+
+async fn collect<B>(body: B) -> Result<Bytes, String>
+where
+    B: Body,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    match body.collect().await {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(e) => Err(e.into().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{value::DataSetSequence, DataElement, Length, VR},
+        dictionary_std::{tags, uids},
+    };
+    use dicom_object::{FileDicomObject, InMemDicomObject};
+
+    use super::*;
+    use crate::{backend::InMemoryBackend, multipart::MultipartWriter, testing};
+
+    const STUDY_UID: &str = "1.2.840.10008.31.1";
+    const SERIES_UID: &str = "1.2.840.10008.31.1.1";
+    const SOP_UID: &str = "1.2.840.10008.31.1.1.1";
+    const NOTE_SERIES_UID: &str = "1.2.840.10008.31.1.2";
+    const NOTE_SOP_UID: &str = "1.2.840.10008.31.1.2.1";
+
+    async fn stored(instances: &[FileDicomObject<InMemDicomObject>]) -> InMemoryBackend {
+        let backend = InMemoryBackend::new();
+        backend
+            .store_instances(&testing::principal(), instances)
+            .await
+            .unwrap();
+        backend
+    }
+
+    fn service(backend: InMemoryBackend) -> DicomWebService {
+        DicomWebService::new(backend)
+            .with_authentication(Authentication::new().with_anonymous_access())
+    }
+
+    async fn call(service: &mut DicomWebService, request: Request<Full<Bytes>>) -> Response<Bytes> {
+        let response = service.call(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        Response::from_parts(parts, body.collect().await.unwrap().to_bytes())
+    }
+
+    fn request(method: Method, uri: &str) -> Request<Full<Bytes>> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::ACCEPT, "application/dicom+json")
+            .body(Full::default())
+            .unwrap()
+    }
+
+    async fn search(service: &mut DicomWebService, uri: &str) -> Vec<serde_json::Value> {
+        let response = call(service, request(Method::GET, uri)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    fn store(instances: &[FileDicomObject<InMemDicomObject>]) -> Request<Full<Bytes>> {
+        let mut mp = MultipartWriter::new();
+        for instance in instances {
+            let mut data = Vec::new();
+            instance.write_all(&mut data).unwrap();
+            mp.add(&*data, "Content-Type: application/dicom").unwrap();
+        }
+        mp.finish();
+        Request::builder()
+            .method(Method::POST)
+            .uri("/studies")
+            .header(
+                header::CONTENT_TYPE,
+                format!(
+                    "multipart/related; type=\"application/dicom\"; boundary={}",
+                    mp.boundary
+                ),
+            )
+            .body(Full::new(Bytes::from(mp.data)))
+            .unwrap()
+    }
+
+    fn sequence(tag: dicom_object::Tag, item: InMemDicomObject) -> DataElement<InMemDicomObject> {
+        DataElement::new(
+            tag,
+            VR::SQ,
+            DataSetSequence::new(vec![item], Length::UNDEFINED),
+        )
+    }
+
+    /// Ultrasound cine loop of two 2x2 frames
+    fn cine_loop() -> FileDicomObject<InMemDicomObject> {
+        let mut dcm = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                uids::ULTRASOUND_MULTI_FRAME_IMAGE_STORAGE,
+            ),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, SOP_UID),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, SERIES_UID),
+            DataElement::new(tags::PATIENT_ID, VR::LO, "ECHO-1"),
+            DataElement::new(tags::MODALITY, VR::CS, "US"),
+        ]);
+        dcm.extend(testing::grayscale_frames(2, 2, 2));
+        testing::file(dcm)
+    }
+
+    /// Rejection note, which deletes the cine loop as its retention period expired
+    fn retention_note() -> FileDicomObject<InMemDicomObject> {
+        let series = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, SERIES_UID),
+            sequence(
+                tags::REFERENCED_SOP_SEQUENCE,
+                InMemDicomObject::from_element_iter([DataElement::new(
+                    tags::REFERENCED_SOP_INSTANCE_UID,
+                    VR::UI,
+                    SOP_UID,
+                )]),
+            ),
+        ]);
+        testing::file(InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                uids::KEY_OBJECT_SELECTION_DOCUMENT_STORAGE,
+            ),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, NOTE_SOP_UID),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, NOTE_SERIES_UID),
+            sequence(
+                tags::CONCEPT_NAME_CODE_SEQUENCE,
+                InMemDicomObject::from_element_iter([
+                    DataElement::new(tags::CODE_VALUE, VR::SH, "113039"),
+                    DataElement::new(tags::CODING_SCHEME_DESIGNATOR, VR::SH, "DCM"),
+                ]),
+            ),
+            sequence(
+                tags::CURRENT_REQUESTED_PROCEDURE_EVIDENCE_SEQUENCE,
+                InMemDicomObject::from_element_iter([
+                    DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+                    sequence(tags::REFERENCED_SERIES_SEQUENCE, series),
+                ]),
+            ),
+        ]))
+    }
+
+    #[tokio::test]
+    async fn routes_the_transactions() {
+        let mut service = service(InMemoryBackend::new());
+
+        // STOW-RS
+        let response = call(&mut service, store(&[cine_loop()])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // QIDO-RS
+        let series_path = format!("/studies/{}/series/{}", STUDY_UID, SERIES_UID);
+        let instance_path = format!("{}/instances/{}", series_path, SOP_UID);
+        for uri in [
+            "/studies?PatientID=ECHO-1".to_string(),
+            "/series".to_string(),
+            "/instances".to_string(),
+            format!("/studies/{}/series", STUDY_UID),
+            format!("/studies/{}/instances", STUDY_UID),
+            format!("{}/instances", series_path),
+        ] {
+            assert_eq!(search(&mut service, &uri).await.len(), 1, "{}", uri);
+        }
+        assert!(search(&mut service, "/studies?PatientID=ECHO-2")
+            .await
+            .is_empty());
+
+        // WADO-RS
+        for uri in [
+            format!("/studies/{}", STUDY_UID),
+            series_path.clone(),
+            instance_path.clone(),
+            format!("{}/frames/2", instance_path),
+        ] {
+            let mut retrieve = request(Method::GET, &uri);
+            retrieve.headers_mut().remove(header::ACCEPT);
+            let response = call(&mut service, retrieve).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+            assert!(header_str(response.headers(), header::CONTENT_TYPE)
+                .is_some_and(|content_type| content_type.starts_with("multipart/related")));
+        }
+        for uri in [
+            format!("/studies/{}/metadata", STUDY_UID),
+            format!("{}/metadata", series_path),
+            format!("{}/metadata", instance_path),
+        ] {
+            assert_eq!(search(&mut service, &uri).await.len(), 1, "{}", uri);
+        }
+
+        // Instances are deleted by storing a rejection note
+        let response = call(&mut service, store(&[retention_note()])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call(&mut service, request(Method::GET, &instance_path)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn serves_another_backend_below_a_route_prefix() {
+        let research = stored(&[cine_loop()]).await;
+        let mut service = service(InMemoryBackend::new()).with_route_prefix("/research/", research);
+
+        assert_eq!(search(&mut service, "/research/studies").await.len(), 1);
+        assert_eq!(
+            search(
+                &mut service,
+                &format!("/research/studies/{}/series", STUDY_UID)
+            )
+            .await
+            .len(),
+            1
+        );
+        assert!(search(&mut service, "/studies").await.is_empty());
+        // Only whole segments are prefixes
+        let response = call(&mut service, request(Method::GET, "/researchers/studies")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_paths_and_methods() {
+        let mut service = service(InMemoryBackend::new());
+
+        for uri in [
+            "/patients",
+            "/studies/1.2.3/frames",
+            "/studies/1.2.3/series/1/x",
+        ] {
+            let response = call(&mut service, request(Method::GET, uri)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+            let response = call(&mut service, request(Method::OPTIONS, uri)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+
+        let response = call(&mut service, request(Method::DELETE, "/studies/1.2.3")).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            response.headers()[header::ALLOW],
+            "GET, POST, PATCH, OPTIONS"
+        );
+        let response = call(&mut service, request(Method::PUT, "/studies")).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let mut options = request(Method::OPTIONS, "/studies/1.2.3/series");
+        options.headers_mut().insert(
+            header::ACCEPT,
+            "application/vnd.oai.openapi+json".parse().unwrap(),
+        );
+        let response = call(&mut service, options).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ALLOW], "GET, OPTIONS");
+        let mut options = request(Method::OPTIONS, "/");
+        options.headers_mut().remove(header::ACCEPT);
+        let response = call(&mut service, options).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ALLOW], "OPTIONS");
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use dicom::{
+    core::{value::DataSetSequence, DataElement, Length, VR},
+    dictionary_std::tags,
+};
+use dicom_object::{FileDicomObject, InMemDicomObject, Tag};
+use serde::Deserialize;
+use uuid::Uuid;
+
+const MODIFYING_SYSTEM: &str = "dicomweb-rs";
+
+// Attributes which identify an instance and can only be changed by generating new UIDs
+const PROTECTED_TAGS: [Tag; 5] = [
+    tags::STUDY_INSTANCE_UID,
+    tags::SERIES_INSTANCE_UID,
+    tags::SOP_INSTANCE_UID,
+    tags::SOP_CLASS_UID,
+    tags::PIXEL_DATA,
+];
+
+/// Query parameters of a metadata update request
+#[derive(Deserialize, Debug)]
+pub struct UpdateQuery {
+    /// Assign new Study, Series and SOP Instance UIDs to the updated instances
+    pub generateuids: Option<bool>,
+}
+
+/// Applies a set of changed attributes to stored instances.
+///
+/// The previous values are recorded in the OriginalAttributesSequence of every
+/// updated instance, added attributes without value.
+/// See http://dicom.nema.org/medical/dicom/current/output/chtml/part03/sect_C.12.html#sect_C.12.1.1.9
+pub struct InstanceUpdate {
+    attributes: InMemDicomObject,
+    generate_uids: bool,
+    // Series updates keep the instances in their study
+    keep_study_uid: bool,
+    // Keeps the UIDs consistent across all instances of the update
+    uids: HashMap<String, String>,
+    timestamp: String,
+}
+
+impl InstanceUpdate {
+    pub fn new(attributes: InMemDicomObject, generate_uids: bool) -> Result<Self, String> {
+        if let Some(tag) = attributes.tags().find(|tag| PROTECTED_TAGS.contains(tag)) {
+            return Err(format!("Attribute {} can not be updated", tag));
+        }
+
+        Ok(InstanceUpdate {
+            attributes,
+            generate_uids,
+            keep_study_uid: false,
+            uids: HashMap::new(),
+            timestamp: chrono::Utc::now()
+                .format("%Y%m%d%H%M%S%.6f+0000")
+                .to_string(),
+        })
+    }
+
+    /// Parse the changed attributes from a DICOM JSON body.
+    ///
+    /// Accepts either a single dataset or an array containing one dataset.
+    pub fn from_json(body: &[u8], generate_uids: bool) -> Result<Self, String> {
+        let value = match serde_json::from_slice(body).map_err(|e| e.to_string())? {
+            serde_json::Value::Array(mut datasets) if datasets.len() == 1 => datasets.remove(0),
+            serde_json::Value::Array(_) => {
+                return Err(String::from("Expected exactly one dataset"))
+            }
+            value => value,
+        };
+        let attributes = dicom_json::from_value(value).map_err(|e| e.to_string())?;
+
+        InstanceUpdate::new(attributes, generate_uids)
+    }
+
+    /// Update the instances of a single series, which keep their Study Instance UID
+    /// when new UIDs are generated
+    pub fn for_series(mut self) -> Self {
+        self.keep_study_uid = true;
+        self
+    }
+
+    /// Apply the update to a single instance
+    pub fn apply(&mut self, dcm: &mut FileDicomObject<InMemDicomObject>) {
+        let mut modified = InMemDicomObject::new_empty();
+
+        if self.generate_uids {
+            for tag in [
+                tags::STUDY_INSTANCE_UID,
+                tags::SERIES_INSTANCE_UID,
+                tags::SOP_INSTANCE_UID,
+            ] {
+                if tag == tags::STUDY_INSTANCE_UID && self.keep_study_uid {
+                    continue;
+                }
+                let Some(original) = dcm.element_opt(tag).ok().flatten().cloned() else {
+                    continue;
+                };
+                let Ok(uid) = original.to_str() else {
+                    continue;
+                };
+                let uid = self
+                    .uids
+                    .entry(uid.trim_end_matches('\0').to_string())
+                    .or_insert_with(generate_uid)
+                    .clone();
+
+                if tag == tags::SOP_INSTANCE_UID {
+                    dcm.meta_mut().media_storage_sop_instance_uid = uid.clone();
+                }
+                dcm.put(DataElement::new(tag, VR::UI, uid));
+                modified.put(original);
+            }
+        }
+
+        for elt in self.attributes.iter() {
+            match dcm.put(elt.clone()) {
+                Some(original) => modified.put(original),
+                // Added attributes are recorded without value
+                None => modified.put(DataElement::empty(elt.header().tag, elt.header().vr)),
+            };
+        }
+
+        let item = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::MODIFIED_ATTRIBUTES_SEQUENCE,
+                VR::SQ,
+                DataSetSequence::new(vec![modified], Length::UNDEFINED),
+            ),
+            DataElement::new(
+                tags::ATTRIBUTE_MODIFICATION_DATE_TIME,
+                VR::DT,
+                self.timestamp.as_str(),
+            ),
+            DataElement::new(tags::MODIFYING_SYSTEM, VR::LO, MODIFYING_SYSTEM),
+            DataElement::empty(tags::SOURCE_OF_PREVIOUS_VALUES, VR::LO),
+            DataElement::new(
+                tags::REASON_FOR_THE_ATTRIBUTE_MODIFICATION,
+                VR::CS,
+                "CORRECT",
+            ),
+        ]);
+
+        // Append to the existing history of modifications
+        let mut items = dcm
+            .element_opt(tags::ORIGINAL_ATTRIBUTES_SEQUENCE)
+            .ok()
+            .flatten()
+            .and_then(|elt| elt.items())
+            .map(|items| items.to_vec())
+            .unwrap_or_default();
+        items.push(item);
+
+        dcm.put(DataElement::new(
+            tags::ORIGINAL_ATTRIBUTES_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::new(items, Length::UNDEFINED),
+        ));
+    }
+}
+
+/// Generate a new UID from a random UUID
+///
+/// See http://dicom.nema.org/medical/dicom/current/output/chtml/part05/sect_B.2.html
+pub fn generate_uid() -> String {
+    format!("2.25.{}", Uuid::new_v4().as_u128())
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{core::header::HasLength, dictionary_std::uids};
+
+    use super::*;
+    use crate::testing;
+
+    const STUDY_UID: &str = "1.2.840.10008.27.1";
+    const SERIES_UID: &str = "1.2.840.10008.27.1.1";
+    const SOP_UID: &str = "1.2.840.10008.27.1.1.1";
+
+    /// MR slice of a series, which was sent without description
+    fn undescribed_mr_image() -> FileDicomObject<InMemDicomObject> {
+        testing::file(InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SOP_CLASS_UID, VR::UI, uids::MR_IMAGE_STORAGE),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, SOP_UID),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, STUDY_UID),
+            DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, SERIES_UID),
+            DataElement::new(tags::MODALITY, VR::CS, "MR"),
+        ]))
+    }
+
+    fn text(dcm: &InMemDicomObject, tag: Tag) -> Option<String> {
+        dcm.element_opt(tag)
+            .ok()
+            .flatten()
+            .and_then(|elt| elt.to_str().ok())
+            .map(|value| value.trim_end_matches('\0').to_string())
+    }
+
+    #[test]
+    fn series_updates_keep_the_study() {
+        let attributes = InMemDicomObject::from_element_iter([DataElement::new(
+            tags::SERIES_DESCRIPTION,
+            VR::LO,
+            "Corrected",
+        )]);
+        let mut update = InstanceUpdate::new(attributes, true).unwrap().for_series();
+        let mut dcm = undescribed_mr_image();
+        update.apply(&mut dcm);
+
+        assert_eq!(
+            text(&dcm, tags::STUDY_INSTANCE_UID).as_deref(),
+            Some(STUDY_UID)
+        );
+        assert_ne!(
+            text(&dcm, tags::SERIES_INSTANCE_UID).as_deref(),
+            Some(SERIES_UID)
+        );
+        assert_ne!(text(&dcm, tags::SOP_INSTANCE_UID).as_deref(), Some(SOP_UID));
+
+        // The description was added, so it is recorded without value
+        let history = dcm.element(tags::ORIGINAL_ATTRIBUTES_SEQUENCE).unwrap();
+        let modified = history.items().unwrap()[0]
+            .element(tags::MODIFIED_ATTRIBUTES_SEQUENCE)
+            .unwrap()
+            .items()
+            .unwrap()[0]
+            .clone();
+        assert_eq!(
+            modified.element(tags::SERIES_DESCRIPTION).unwrap().length(),
+            Length(0)
+        );
+        assert_eq!(
+            text(&modified, tags::SERIES_INSTANCE_UID).as_deref(),
+            Some(SERIES_UID)
+        );
+        assert!(modified.element(tags::STUDY_INSTANCE_UID).is_err());
+    }
+}